@@ -0,0 +1,136 @@
+//! Benchmark of the prediction cache, see the `cache` option in the configuration file
+//!
+//! Fits the bimodal example three times: without the cache, with an empty (cold) cache, and with the cache filled by the
+//! previous fit (warm), and reports the mean cycle time of each fit.
+//! The number of cycles defaults to 10, and can be set with the `NPCORE_CONFIG_CYCLES` environment variable, as for any fit.
+//!
+//! Run with `cargo bench --bench cache`.
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use npcore::prelude::{
+    algorithms::initialize_algorithm,
+    datafile::{self, CovLine, Infusion, Scenario},
+    predict::{cache_len, clear_cache, Engine, Predict},
+    settings::read_settings,
+};
+use ode_solvers::*;
+
+const ATOL: f64 = 1e-4;
+const RTOL: f64 = 1e-4;
+/// Number of cycles of each fit, unless set in the environment
+const DEFAULT_CYCLES: usize = 10;
+
+type State = Vector1<f64>;
+type Time = f64;
+
+/// One-compartment model with first-order elimination, as in `examples/bimodal_ke`
+#[derive(Debug, Clone)]
+struct Model {
+    ke: f64,
+    v: f64,
+    infusions: Vec<Infusion>,
+}
+
+impl ode_solvers::System<State> for Model {
+    fn system(&self, t: Time, y: &State, dy: &mut State) {
+        let mut rateiv = [0.0];
+        for infusion in &self.infusions {
+            if t >= infusion.time && t < (infusion.dur + infusion.time) {
+                rateiv[infusion.compartment] += infusion.amount / infusion.dur;
+            }
+        }
+        dy[0] = -self.ke * y[0] + rateiv[0];
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Ode {}
+
+impl<'a> Predict<'a> for Ode {
+    type Model = Model;
+    type State = State;
+    fn initial_system(&self, params: &Vec<f64>, scenario: Scenario) -> (Self::Model, Scenario) {
+        let model = Model {
+            ke: params[0],
+            v: params[1],
+            infusions: vec![],
+        };
+        (model, scenario.reorder_with_lag(vec![(0.0, 1)]))
+    }
+    fn get_output(&self, _time: f64, x: &Self::State, system: &Self::Model, outeq: usize) -> f64 {
+        match outeq {
+            1 => x[0] / system.v,
+            _ => panic!("Invalid output equation"),
+        }
+    }
+    fn initial_state(&self) -> State {
+        State::default()
+    }
+    fn add_infusion(&self, system: &mut Self::Model, infusion: Infusion) {
+        system.infusions.push(infusion);
+    }
+    fn add_covs(&self, _system: &mut Self::Model, _cov: Option<HashMap<String, CovLine>>) {}
+    fn add_dose(&self, state: &mut Self::State, dose: f64, compartment: usize) {
+        state[compartment] += dose;
+    }
+    fn state_step(&self, x: &mut Self::State, system: &Self::Model, time: f64, next_time: f64) {
+        let mut stepper = Dopri5::new(system.clone(), time, next_time, 1e-3, *x, RTOL, ATOL);
+        let _ = stepper.integrate();
+        *x = *stepper.y_out().last().unwrap();
+    }
+}
+
+/// Fits the bimodal example, and returns the mean cycle time and the number of cycles
+fn fit(engine: &Engine<Ode>, scenarios: &[Scenario], cache: bool) -> (Duration, usize) {
+    let mut settings = read_settings("examples/bimodal_ke/config.toml".to_string())
+        .expect("Unable to read the settings of the bimodal example");
+    if std::env::var("NPCORE_CONFIG_CYCLES").is_err() {
+        settings.config.cycles = DEFAULT_CYCLES;
+    }
+    settings.config.cache = cache;
+    settings.config.cache_first_cycle = cache;
+    settings.config.tui = false;
+    settings.config.output = false;
+
+    let (tx, _rx) = tokio::sync::mpsc::unbounded_channel();
+    let now = Instant::now();
+    let result = initialize_algorithm(engine.clone(), settings, scenarios.to_vec(), tx)
+        .unwrap()
+        .fit();
+    let elapsed = now.elapsed();
+    (elapsed / result.cycles.max(1) as u32, result.cycles)
+}
+
+fn main() {
+    let engine = Engine::new(Ode {});
+    let scenarios =
+        datafile::parse("examples/data/bimodal_ke.csv").expect("Unable to read the data");
+
+    println!(
+        "{:<8} {:>8} {:>16} {:>16}",
+        "cache", "cycles", "cycle time", "cached before"
+    );
+    let report = |name: &str, cache: bool| {
+        let cached = cache_len();
+        let (cycle_time, cycles) = fit(&engine, &scenarios, cache);
+        println!(
+            "{:<8} {:>8} {:>16.2?} {:>16}",
+            name, cycles, cycle_time, cached
+        );
+        cycle_time
+    };
+
+    clear_cache();
+    let off = report("off", false);
+    clear_cache();
+    let cold = report("cold", true);
+    // The second fit is identical to the first, so all its predictions are cached
+    let warm = report("warm", true);
+
+    println!(
+        "Speedup relative to no cache: cold {:.2}x, warm {:.2}x",
+        off.as_secs_f64() / cold.as_secs_f64(),
+        off.as_secs_f64() / warm.as_secs_f64()
+    );
+}
@@ -0,0 +1,30 @@
+//! Records the git commit NPcore is built from, if built from a git checkout, in the run manifest, see `NPResult::manifest`
+use std::path::Path;
+use std::process::Command;
+
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    let manifest_dir = std::env::var("CARGO_MANIFEST_DIR").unwrap_or_default();
+    let git = Path::new(&manifest_dir).join(".git");
+    // Not a git checkout of NPcore, e.g. a crate downloaded from a registry, which must not report the commit of an enclosing repository
+    if !git.exists() {
+        return;
+    }
+    if git.is_dir() {
+        println!("cargo:rerun-if-changed={}", git.join("HEAD").display());
+        println!("cargo:rerun-if-changed={}", git.join("refs").display());
+    } else {
+        // In a worktree, `.git` is a file pointing to the git directory
+        println!("cargo:rerun-if-changed={}", git.display());
+    }
+    let commit = Command::new("git")
+        .args(["rev-parse", "HEAD"])
+        .current_dir(&manifest_dir)
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok());
+    if let Some(commit) = commit {
+        println!("cargo:rustc-env=GIT_COMMIT_HASH={}", commit.trim());
+    }
+}
@@ -3,8 +3,8 @@ use std::collections::HashMap;
 use eyre::Result;
 use npcore::prelude::{
     datafile::{CovLine, Infusion, Scenario},
-    predict::{Engine, Predict},
-    start,
+    predict::{Engine, Predict, VolumeOutput},
+    simulation, start,
 };
 use ode_solvers::*;
 
@@ -46,7 +46,7 @@ impl ode_solvers::System<State> for Model {
         // Get the infusions that are active at time `t`
         let mut rateiv = [0.0];
         for infusion in &self.infusions {
-            if t >= infusion.time && t <= (infusion.dur + infusion.time) {
+            if t >= infusion.time && t < (infusion.dur + infusion.time) {
                 rateiv[infusion.compartment] += infusion.amount / infusion.dur;
             }
         }
@@ -65,7 +65,7 @@ impl<'a> Predict<'a> for Ode {
     type Model = Model;
     type State = State;
     fn initial_system(&self, params: &Vec<f64>, scenario: Scenario) -> (Self::Model, Scenario) {
-        let params = HashMap::from([("ke".to_string(), params[0]), ("v".to_string(), params[1])]);
+        let params = HashMap::from([("ke".to_string(), params[0])]);
         (
             Model {
                 params,
@@ -78,21 +78,32 @@ impl<'a> Predict<'a> for Ode {
     }
 
     // This function is used to get the output from the model, defined by the output equations (outeq) supplied by the user
-    fn get_output(&self, time: f64, x: &Self::State, system: &Self::Model, outeq: usize) -> f64 {
-        // Get parameters from the model also used for calculating the output equations
-        let v = system.get_param("v");
-        #[allow(unused_variables)]
-        let t = time;
-        match outeq {
-            1 => x[0] / v, // Concentration of the central compartment defined by the amount of drug, x[0], divided by the volume of the central compartment, v
-            _ => panic!("Invalid output equation"),
-        }
+    // Output equations given by `volume_outputs` below are computed by the engine, and never reach this function
+    fn get_output(&self, _time: f64, _x: &Self::State, _system: &Self::Model, outeq: usize) -> f64 {
+        panic!("Invalid output equation {}", outeq)
+    }
+    // The amount of drug in each compartment
+    fn amounts(&self, x: &Self::State) -> Vec<f64> {
+        vec![x[0]]
+    }
+    // The concentration of the central compartment (outeq 1) is the amount of drug, x[0], divided by the volume V,
+    // which is referenced by its name in config.toml rather than by its position among the parameters
+    fn volume_outputs(&self) -> Vec<VolumeOutput> {
+        vec![VolumeOutput {
+            outeq: 1,
+            compartment: 0,
+            volume: "V".to_string(),
+        }]
     }
 
     // Set the initial state of the compartments
     fn initial_state(&self) -> State {
         State::default()
     }
+    // The number of compartments which can receive doses, used to validate the datafile
+    fn n_compartments(&self) -> Option<usize> {
+        Some(1)
+    }
     // Add any possible infusions
     fn add_infusion(&self, system: &mut Self::Model, infusion: Infusion) {
         system.infusions.push(infusion);
@@ -111,10 +122,36 @@ impl<'a> Predict<'a> for Ode {
         if time >= next_time {
             panic!("time error")
         }
-        let mut stepper = Dopri5::new(system.clone(), time, next_time, 1e-3, *x, RTOL, ATOL);
-        let _res = stepper.integrate();
-        let y = stepper.y_out();
-        *x = *y.last().unwrap();
+        // The maximum number of steps is taken from `max_steps` in the configuration file, the other arguments are the defaults of `Dopri5::new`
+        let mut stepper = Dopri5::from_param(
+            system.clone(),
+            time,
+            next_time,
+            1e-3,
+            *x,
+            RTOL,
+            ATOL,
+            0.9,
+            0.04,
+            0.2,
+            10.0,
+            next_time - time,
+            0.0,
+            simulation::stats::max_steps(),
+            1000,
+            dop_shared::OutputType::Dense,
+        );
+        match stepper.integrate() {
+            Ok(stats) => {
+                // Only collected if `solver_stats` is enabled in the configuration file
+                simulation::stats::record(&stats);
+                let y = stepper.y_out();
+                *x = *y.last().unwrap();
+            }
+            // The integration was aborted, e.g. as it exceeded the maximum number of steps
+            // A non-finite state marks the prediction as failed, rather than keeping the last partial state
+            Err(_) => *x = State::from_element(f64::NAN),
+        }
     }
 }
 
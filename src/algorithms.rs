@@ -1,25 +1,76 @@
 use crate::prelude::{self, settings::Settings};
 
+use crate::routines::warnings::{Category, Warning};
+use crate::tui::control::RunControl;
+use evaluation::sigma::Sigma;
+use eyre::Result;
+use ndarray::{Array1, Array2};
 use output::NPResult;
 use prelude::{datafile::Scenario, *};
 use simulation::predict::{Engine, Predict};
 use tokio::sync::mpsc;
 
-mod npag;
+pub(crate) mod npag;
 mod npod;
 mod postprob;
 
 pub trait Algorithm {
     fn fit(&mut self) -> NPResult;
     fn to_npresult(&self) -> NPResult;
+    /// Warnings raised so far, which are returned in [NPResult::warnings]
+    fn warnings_mut(&mut self) -> &mut Vec<Warning>;
+    /// Sets the handle used to pause, resume and stop the run between cycles
+    ///
+    /// Algorithms without cycles ignore it.
+    fn set_control(&mut self, _control: RunControl) {}
+    /// Sets the error model, which replaces the error polynomial of the settings, e.g. to model heteroscedastic errors which are neither additive nor proportional
+    ///
+    /// A supplied error model has no gamma or lambda, so these are not optimized.
+    fn set_sigma(&mut self, sigma: Box<dyn Sigma + Sync>);
 }
 
+/// The support points, their likelihoods and weights, the objective function and the error model at the end of a cycle
+///
+/// An algorithm which fails in a later cycle, e.g. in the IPM, returns this state, so the result is consistent, see [StopReason::Error](crate::routines::evaluation::convergence::StopReason::Error).
+#[derive(Debug, Clone)]
+pub(crate) struct CycleState {
+    pub theta: Array2<f64>,
+    pub psi: Array2<f64>,
+    pub w: Array1<f64>,
+    /// Log-likelihood, i.e. `-objf / 2` of [NPResult]
+    pub objf: f64,
+    pub gamma: f64,
+    pub c: (f64, f64, f64, f64),
+}
+
+impl CycleState {
+    /// The state of a run which failed before completing a cycle, without support points and with a log-likelihood of -∞
+    pub fn empty(
+        n_subjects: usize,
+        n_parameters: usize,
+        gamma: f64,
+        c: (f64, f64, f64, f64),
+    ) -> Self {
+        Self {
+            theta: Array2::zeros((0, n_parameters)),
+            psi: Array2::zeros((n_subjects, 0)),
+            w: Array1::zeros(0),
+            objf: f64::NEG_INFINITY,
+            gamma,
+            c,
+        }
+    }
+}
+
+/// Initializes the algorithm given by the `engine` of the settings, with the initial grid and any seed points
+///
+/// Returns an error if the model, the data or the settings are invalid, e.g. an unknown algorithm or an unreadable seed file.
 pub fn initialize_algorithm<S>(
     engine: Engine<S>,
     settings: Settings,
     scenarios: Vec<Scenario>,
     tx: mpsc::UnboundedSender<Comm>,
-) -> Box<dyn Algorithm>
+) -> Result<Box<dyn Algorithm>>
 where
     S: Predict<'static> + std::marker::Sync + Clone + 'static,
 {
@@ -29,11 +80,113 @@ where
             Err(err) => panic!("Unable to remove previous stop file: {}", err),
         }
     }
+    let engine = engine
+        .configure(&settings)
+        .map_err(|err| eyre::eyre!(err))?;
+    engine
+        .validate(&scenarios)
+        .map_err(|err| eyre::eyre!(err))?;
+    let observations: Vec<f64> = scenarios.iter().flat_map(|s| s.obs.clone()).collect();
+    settings
+        .error
+        .validate_observations(&observations)
+        .map_err(|err| eyre::eyre!(err))?;
+    simulation::stats::enable(settings.config.solver_stats);
     let ranges = settings.random.ranges();
-    let theta = initialization::sample_space(&settings, &ranges);
+    let mut warnings = Vec::new();
+    for (name, value) in settings.random.degenerate() {
+        warnings.push(Warning::new(
+            Category::Parameters,
+            format!(
+                "Random parameter {} has equal lower and upper bounds, and is held constant at {}",
+                name, value
+            ),
+        ));
+    }
+    let mut unknown: Vec<&String> = settings
+        .config
+        .subject_weights
+        .iter()
+        .flat_map(|weights| weights.keys())
+        .filter(|id| !scenarios.iter().any(|scenario| &scenario.id == *id))
+        .collect();
+    if !unknown.is_empty() {
+        unknown.sort();
+        warnings.push(Warning::new(
+            Category::Data,
+            format!(
+                "The subject weights are given for the IDs {:?}, which are not in the data",
+                unknown
+            ),
+        ));
+    }
+    let names = settings.random.names();
+    let insensitive = if settings.config.check_parameters {
+        engine.insensitive_parameters(&scenarios, &ranges)
+    } else {
+        Vec::new()
+    };
+    for j in insensitive {
+        warnings.push(Warning::new(
+            Category::Parameters,
+            format!(
+                "Random parameter {} does not affect any prediction, and may be unused by the model",
+                names[j]
+            ),
+        ));
+    }
+    for warning in &warnings {
+        tracing::warn!("{}", warning.message);
+    }
+    let mut theta = initialization::sample_space(&settings, &ranges);
+    match initialization::seed_points(&settings, &ranges) {
+        Ok(seeds) if seeds.nrows() > 0 => {
+            tracing::info!("Adding {} seed point(s) to the initial grid", seeds.nrows());
+            for seed in seeds.rows() {
+                theta.push_row(seed).unwrap();
+            }
+        }
+        Ok(_) => {}
+        Err(err) => eyre::bail!("{}", err),
+    }
+    if settings.config.wls_seed {
+        let seeds = initialization::seed_from_wls(&engine, &scenarios, &ranges);
+        tracing::info!(
+            "Adding {} support point(s) from individual WLS estimates",
+            seeds.nrows()
+        );
+        for seed in seeds.rows() {
+            theta.push_row(seed).unwrap();
+        }
+    }
+    let memory = prob::projected_memory(
+        &scenarios,
+        theta.nrows(),
+        theta.ncols(),
+        settings.config.subject_batch_size,
+    );
+    if memory > prob::MEMORY_WARNING {
+        let suggestion = match prob::batch_size_within(
+            &scenarios,
+            theta.nrows(),
+            theta.ncols(),
+            prob::MEMORY_WARNING,
+        ) {
+            Some(size) => format!("consider setting subject_batch_size to {} or less", size),
+            None => "consider reducing init_points".to_string(),
+        };
+        let message = format!(
+            "The initial grid of {} support points is projected to use {:.1} GiB of memory in the first cycle, {}",
+            theta.nrows(),
+            memory as f64 / (1u64 << 30) as f64,
+            suggestion
+        );
+        tracing::warn!("{}", message);
+        warnings.push(Warning::new(Category::Memory, message));
+    }
 
     //This should be a macro, so it can automatically expands as soon as we add a new option in the Type Enum
-    match settings.config.engine.as_str() {
+    let mut algorithm: Box<dyn Algorithm> = match settings.config.engine.as_str() {
         "NPAG" => Box::new(npag::NPAG::new(
             engine,
             ranges,
@@ -60,9 +213,8 @@ where
             tx,
             settings,
         )),
-        alg => {
-            eprintln!("Error: Algorithm not recognized: {}", alg);
-            std::process::exit(-1)
-        }
-    }
+        alg => eyre::bail!("Algorithm not recognized: {}", alg),
+    };
+    algorithm.warnings_mut().extend(warnings);
+    Ok(algorithm)
 }
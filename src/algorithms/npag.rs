@@ -1,28 +1,41 @@
 use crate::{
     prelude::{
-        algorithms::Algorithm,
+        algorithms::{Algorithm, CycleState},
+        condensation::{
+            fallback::retain_highest_weight, frozen::FrozenPoints, order::sort_lexicographic,
+        },
+        convergence::{check_stop, deadline, Combine, StopCriterion, StopReason, STOP_FILE},
         datafile::Scenario,
-        evaluation::sigma::{ErrorPoly, ErrorType},
+        evaluation::sigma::{ErrorPoly, ErrorType, Sigma},
         ipm,
         output::NPResult,
         output::{CycleLog, NPCycle},
         prob, qr,
         settings::Settings,
         simulation::predict::Engine,
-        simulation::predict::{sim_obs, Predict},
+        simulation::predict::{
+            cache_is_consistent, clear_cache, predictions_are_deterministic, Predict,
+        },
+        simulation::stats,
     },
-    routines::expansion::adaptative_grid::adaptative_grid,
-    tui::ui::Comm,
+    routines::expansion::adaptative_grid::{adaptative_grid_in, limit, sensitive_dimensions},
+    routines::timing::{self, CycleTimer, Stage},
+    routines::warnings::{check_sigma, Category, Warning},
+    tui::{control::RunControl, ui::Comm},
 };
 
-use ndarray::{Array, Array1, Array2, Axis};
-use ndarray_stats::{DeviationExt, QuantileExt};
+use ndarray::{Array1, Array2, Axis};
+use std::path::Path;
 use tokio::sync::mpsc::UnboundedSender;
 
 const THETA_E: f64 = 1e-4; // Convergence criteria
 const THETA_G: f64 = 1e-4; // Objective function convergence criteria
 const THETA_F: f64 = 1e-2;
 const THETA_D: f64 = 1e-4;
+/// Number of cycles at a grid resolution for which the adaptive schedule halves `eps`, see [NPAG::eps_factor]
+const EPS_RATE: f64 = 4.0;
+/// Bounds of the factor by which the adaptive schedule divides `eps`
+const EPS_FACTOR: (f64, f64) = (1.25, 4.0);
 
 pub struct NPAG<S>
 where
@@ -35,20 +48,42 @@ where
     lambda: Array1<f64>,
     w: Array1<f64>,
     eps: f64,
+    /// Number of cycles since `eps` was last changed
+    eps_cycles: usize,
+    /// Log-likelihood of the previous cycle
     last_objf: f64,
+    /// Log-likelihood of the current cycle, which is maximized; it is reported as -2LL, `-2 * objf`
     objf: f64,
     f0: f64,
     f1: f64,
     cycle: usize,
     gamma_delta: f64,
     gamma: f64,
+    poly_delta: [f64; 4],
     error_type: ErrorType,
     converged: bool,
+    /// Reason the run stopped, see [NPResult::stop_reason]
+    stop_reason: Option<StopReason>,
+    /// Number of support points left by the condensation of the previous cycle, to count the points added by the expansion, see [NPCycle::added]
+    condensed_nspp: usize,
+    /// State at the end of the last completed cycle, which is returned if a later cycle fails
+    completed: Option<CycleState>,
+    stop_criterion: StopCriterion,
     cycle_log: CycleLog,
+    timer: CycleTimer,
+    frozen: FrozenPoints,
     cache: bool,
     scenarios: Vec<Scenario>,
+    /// Weights of the log-likelihoods of the subjects in the objective function, see [Config::subject_weights](crate::routines::settings::Config::subject_weights)
+    subject_weights: Array1<f64>,
     c: (f64, f64, f64, f64),
+    /// Error model used in place of the error polynomial, see [Algorithm::set_sigma]
+    sigma: Option<Box<dyn Sigma + Sync>>,
     tx: UnboundedSender<Comm>,
+    control: RunControl,
+    warnings: Vec<Warning>,
+    /// Time the run started, see [NPResult::with_start]
+    started: chrono::DateTime<chrono::Local>,
     settings: Settings,
 }
 
@@ -59,7 +94,18 @@ where
     fn fit(&mut self) -> NPResult {
         self.run()
     }
+    fn set_control(&mut self, control: RunControl) {
+        self.control = control;
+    }
+    fn set_sigma(&mut self, sigma: Box<dyn Sigma + Sync>) {
+        self.sigma = Some(sigma);
+    }
+    fn warnings_mut(&mut self) -> &mut Vec<Warning> {
+        &mut self.warnings
+    }
     fn to_npresult(&self) -> NPResult {
+        let mut settings = self.settings.clone();
+        settings.error.poly = self.c;
         NPResult::new(
             self.scenarios.clone(),
             self.theta.clone(),
@@ -68,8 +114,13 @@ where
             -2. * self.objf,
             self.cycle,
             self.converged,
-            self.settings.clone(),
+            settings,
         )
+        .with_cycle_log(&self.cycle_log)
+        .with_timings(self.timer.records())
+        .with_warnings(&self.warnings)
+        .with_start(self.started)
+        .with_stop_reason(self.stop_reason)
     }
 }
 
@@ -95,7 +146,7 @@ where
     pub fn new(
         sim_eng: Engine<S>,
         ranges: Vec<(f64, f64)>,
-        theta: Array2<f64>,
+        mut theta: Array2<f64>,
         scenarios: Vec<Scenario>,
         c: (f64, f64, f64, f64),
         tx: UnboundedSender<Comm>,
@@ -104,6 +155,23 @@ where
     where
         S: Predict<'static> + std::marker::Sync,
     {
+        let frozen = FrozenPoints::new(settings.config.frozen.clone().unwrap_or_default());
+        frozen.insert_missing(&mut theta);
+        let mut cycle_log =
+            CycleLog::new(&settings.random.names(), settings.config.output_precision);
+        if settings.config.error_magnitude {
+            cycle_log = cycle_log.with_error_magnitude(&settings.error);
+        }
+        if let (true, Some(interval)) = (settings.config.output, settings.config.snapshot_interval)
+        {
+            cycle_log = cycle_log.with_snapshots(interval, std::path::Path::new("snapshots"));
+        }
+        let mut stop_criterion = StopCriterion::new(THETA_G, settings.config.convergence_cycles);
+        if let Some(tolerance) = settings.config.distribution_tolerance {
+            let combine =
+                Combine::from_name(&settings.config.distribution_combine).unwrap_or_default();
+            stop_criterion = stop_criterion.with_distribution(tolerance, combine);
+        }
         Self {
             engine: sim_eng,
             ranges,
@@ -112,6 +180,7 @@ where
             lambda: Array1::default(0),
             w: Array1::default(0),
             eps: 0.2,
+            eps_cycles: 0,
             last_objf: -1e30,
             objf: f64::INFINITY,
             f0: -1e30,
@@ -119,59 +188,112 @@ where
             cycle: 1,
             gamma_delta: 0.1,
             gamma: settings.error.value,
+            poly_delta: [0.1; 4],
             error_type: match settings.error.class.to_lowercase().as_str() {
                 "additive" => ErrorType::Add,
                 "proportional" => ErrorType::Prop,
                 _ => panic!("Error type not supported"),
             },
             converged: false,
-            cycle_log: CycleLog::new(&settings.random.names()),
+            stop_reason: None,
+            condensed_nspp: 0,
+            completed: None,
+            stop_criterion,
+            cycle_log,
+            timer: CycleTimer::new(settings.config.timing),
+            frozen,
             cache: settings.config.cache,
             tx,
+            control: RunControl::new(),
+            warnings: Vec::new(),
+            started: chrono::Local::now(),
+            subject_weights: scenarios
+                .iter()
+                .map(|scenario| settings.config.subject_weight(&scenario.id))
+                .collect(),
             settings,
             scenarios,
             c,
+            sigma: None,
+        }
+    }
+
+    /// Reconstructs an NPAG instance from the final state of a previous run, to continue it for `extra_cycles` cycles
+    ///
+    /// The support points, weights, error model and cycle count are taken from `result`. The grid resolution `eps` is reset to its initial value,
+    /// and the grid is expanded as at the end of a cycle, so the run continues as if it had not stopped, but with a new convergence check.
+    /// Output files are not written, and no TUI is used, see [NPResult::continue_run].
+    pub fn from_result(
+        sim_eng: Engine<S>,
+        result: NPResult,
+        extra_cycles: usize,
+        tx: UnboundedSender<Comm>,
+    ) -> Self {
+        let mut settings = result.settings;
+        settings.config.cycles = result.cycles + extra_cycles;
+        settings.config.output = false;
+        settings.config.tui = false;
+        if let Some(last) = result.cycle_records.last() {
+            settings.error.value = last.gamlam;
         }
+        let log_likelihood = -result.objf / 2.;
+        let mut npag = Self::new(
+            sim_eng,
+            settings.random.ranges(),
+            result.theta,
+            result.scenarios,
+            settings.error.poly,
+            tx,
+            settings,
+        );
+        npag.psi = result.psi;
+        npag.w = result.w.clone();
+        npag.lambda = result.w;
+        npag.objf = log_likelihood;
+        npag.last_objf = log_likelihood;
+        npag.f0 = log_likelihood;
+        npag.cycle = result.cycles + 1;
+        npag.condensed_nspp = npag.theta.nrows();
+        npag.completed = Some(npag.cycle_state());
+        npag.adaptative_grid();
+        npag
     }
 
-    fn optim_gamma(&mut self) {
+    fn optim_gamma(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let (ipm_tol, ipm_max_iter) = (
+            self.settings.config.ipm_tol,
+            self.settings.config.ipm_max_iter,
+        );
         //Gam/Lam optimization
         // TODO: Move this to e.g. /evaluation/error.rs
         let gamma_up = self.gamma * (1.0 + self.gamma_delta);
         let gamma_down = self.gamma / (1.0 + self.gamma_delta);
-        let ypred = sim_obs(&self.engine, &self.scenarios, &self.theta, self.cache);
-        let psi_up = prob::calculate_psi(
-            &ypred,
+        let sigma_up = ErrorPoly::new(self.c, gamma_up, &self.error_type)
+            .with_reference(self.settings.error.reference)
+            .with_df(self.settings.error.df)
+            .with_transform(self.settings.error.transform());
+        let sigma_down = ErrorPoly::new(self.c, gamma_down, &self.error_type)
+            .with_reference(self.settings.error.reference)
+            .with_df(self.settings.error.df)
+            .with_transform(self.settings.error.transform());
+        let (mut psis, simulation, likelihood) = prob::simulate_psi_timed(
+            &self.engine,
             &self.scenarios,
-            &ErrorPoly {
-                c: self.c,
-                gl: gamma_up,
-                e_type: &self.error_type,
-            },
+            &self.theta,
+            &[&sigma_up, &sigma_down],
+            self.cache,
+            self.settings.config.subject_batch_size,
         );
-        let psi_down = prob::calculate_psi(
-            &ypred,
-            &self.scenarios,
-            &ErrorPoly {
-                c: self.c,
-                gl: gamma_down,
-                e_type: &self.error_type,
-            },
-        );
-        let (lambda_up, objf_up) = match ipm::burke(&psi_up) {
-            Ok((lambda, objf)) => (lambda, objf),
-            Err(err) => {
-                //todo: write out report
-                panic!("Error in IPM: {:?}", err);
-            }
-        };
-        let (lambda_down, objf_down) = match ipm::burke(&psi_down) {
-            Ok((lambda, objf)) => (lambda, objf),
-            Err(err) => {
-                //todo: write out report
-                panic!("Error in IPM: {:?}", err);
-            }
-        };
+        self.timer.add(Stage::Simulation, simulation);
+        self.timer.add(Stage::Likelihood, likelihood);
+        let psi_down = psis.pop().unwrap();
+        let psi_up = psis.pop().unwrap();
+        let (lambda_up, objf_up) = self.timer.time(Stage::Ipm, || {
+            ipm::burke_weighted(&psi_up, &self.subject_weights, ipm_tol, ipm_max_iter)
+        })?;
+        let (lambda_down, objf_down) = self.timer.time(Stage::Ipm, || {
+            ipm::burke_weighted(&psi_down, &self.subject_weights, ipm_tol, ipm_max_iter)
+        })?;
         if objf_up > self.objf {
             self.gamma = gamma_up;
             self.objf = objf_up;
@@ -190,117 +312,324 @@ where
         if self.gamma_delta <= 0.01 {
             self.gamma_delta = 0.1;
         }
+        Ok(())
     }
 
+    /// Optimizes the non-zero coefficients of the error polynomial, e.g. the additive SD (C0) and proportional CV (C1), by coordinate ascent on the objective function
+    ///
+    /// This replaces [NPAG::optim_gamma], as gamma is then held fixed. Each coefficient is scaled up and down by its step size,
+    /// and the best of these candidates is accepted if it improves the objective function. The step size of the accepted coefficient is increased,
+    /// and the others decreased, as for gamma. The candidates of all coefficients are evaluated from a single simulation. Coefficients which are zero are kept at zero.
+    fn optim_poly(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let (ipm_tol, ipm_max_iter) = (
+            self.settings.config.ipm_tol,
+            self.settings.config.ipm_max_iter,
+        );
+        let coefficients: Vec<usize> = (0..4).filter(|&k| poly_get(self.c, k) != 0.0).collect();
+        if coefficients.is_empty() {
+            return Ok(());
+        }
+        let candidates: Vec<(f64, f64, f64, f64)> = coefficients
+            .iter()
+            .flat_map(|&k| {
+                let value = poly_get(self.c, k);
+                let delta = 1.0 + self.poly_delta[k];
+                [
+                    poly_set(self.c, k, value * delta),
+                    poly_set(self.c, k, value / delta),
+                ]
+            })
+            .collect();
+        let sigmas: Vec<ErrorPoly> = candidates
+            .iter()
+            .map(|c| {
+                ErrorPoly::new(*c, self.gamma, &self.error_type)
+                    .with_reference(self.settings.error.reference)
+                    .with_df(self.settings.error.df)
+                    .with_transform(self.settings.error.transform())
+            })
+            .collect();
+        let (psis, simulation, likelihood) = prob::simulate_psi_timed(
+            &self.engine,
+            &self.scenarios,
+            &self.theta,
+            &sigmas.iter().collect::<Vec<_>>(),
+            self.cache,
+            self.settings.config.subject_batch_size,
+        );
+        self.timer.add(Stage::Simulation, simulation);
+        self.timer.add(Stage::Likelihood, likelihood);
+
+        let mut best = None;
+        for (i, psi) in psis.into_iter().enumerate() {
+            let (lambda, objf) = self.timer.time(Stage::Ipm, || {
+                ipm::burke_weighted(&psi, &self.subject_weights, ipm_tol, ipm_max_iter)
+            })?;
+            if objf > self.objf {
+                self.objf = objf;
+                best = Some((i, lambda, psi));
+            }
+        }
+        let mut improved = None;
+        if let Some((i, lambda, psi)) = best {
+            // The candidates are ordered by coefficient, scaled up and down
+            improved = Some(coefficients[i / 2]);
+            self.c = candidates[i];
+            self.lambda = lambda;
+            self.psi = psi;
+        }
+        for &k in &coefficients {
+            if improved == Some(k) {
+                self.poly_delta[k] *= 4.;
+            }
+            self.poly_delta[k] *= 0.5;
+            if self.poly_delta[k] <= 0.01 {
+                self.poly_delta[k] = 0.1;
+            }
+        }
+        Ok(())
+    }
+
+    /// Expands the grid, see [adaptative_grid_in], keeping it within the maximum grid size of the settings if provided
+    ///
+    /// Frozen points are inserted first, so they count against the maximum. New support points beyond the maximum are discarded, see [limit],
+    /// and if the grid already reaches the maximum, the expansion is skipped, leaving the next condensation to reduce the grid.
     fn adaptative_grid(&mut self) {
-        adaptative_grid(&mut self.theta, self.eps, &self.ranges, THETA_D);
+        self.frozen.insert_missing(&mut self.theta);
+        let existing = self.theta.nrows();
+        if let Some(max_size) = self.settings.config.max_grid_size {
+            if existing >= max_size {
+                let message = format!(
+                    "The grid has {} support points, reaching the maximum grid size of {}, skipping the expansion",
+                    existing, max_size
+                );
+                tracing::warn!("{}", message);
+                self.record(Category::Grid, message);
+                return;
+            }
+        }
+        let dimensions = match self.settings.config.expansion_dimensions {
+            Some(n) => sensitive_dimensions(&self.theta, &self.lambda, &self.ranges, n),
+            None => (0..self.ranges.len()).collect(),
+        };
+        self.timer.time(Stage::Expansion, || {
+            adaptative_grid_in(
+                &mut self.theta,
+                self.eps,
+                &self.ranges,
+                THETA_D,
+                &dimensions,
+            )
+        });
+        if let Some(max_size) = self.settings.config.max_grid_size {
+            let expanded = self.theta.nrows();
+            let seed = self.settings.config.seed + self.cycle;
+            let discarded = limit(&mut self.theta, existing, max_size, seed);
+            if discarded > 0 {
+                let message = format!(
+                    "Expanding the grid to {} support points exceeds the maximum grid size of {}, discarding {} new support point(s)",
+                    expanded,
+                    max_size,
+                    discarded
+                );
+                tracing::warn!("{}", message);
+                self.record(Category::Grid, message);
+            }
+        }
     }
 
     pub fn run(&mut self) -> NPResult {
+        let (ipm_tol, ipm_max_iter) = (
+            self.settings.config.ipm_tol,
+            self.settings.config.ipm_max_iter,
+        );
+        let deadline = deadline(self.settings.config.time_limit);
         while self.eps > THETA_E {
             // Enter a span for each cycle, provding context for further errors
             let cycle_span = tracing::span!(tracing::Level::INFO, "Cycle", cycle = self.cycle);
             let _enter = cycle_span.enter();
+            self.timer.start_cycle(self.cycle);
+            let n_start = self.theta.nrows();
 
             // psi n_sub rows, nspp columns
-            let cache = if self.cycle == 1 { false } else { self.cache };
-            let ypred = sim_obs(&self.engine, &self.scenarios, &self.theta, cache);
-
-            self.psi = prob::calculate_psi(
-                &ypred,
+            // The initial grid is large, and most of its support points are removed by condensation.
+            // Caching their predictions would fill the cache with values which are never reused, so by default
+            // the cache is bypassed (neither read nor written) in the first cycle, and primed from the second cycle.
+            let cache = if self.cycle == 1 {
+                self.cache && self.settings.config.cache_first_cycle
+            } else {
+                self.cache
+            };
+            if cache
+                && self.cycle == 2
+                && !cache_is_consistent(&self.engine, &self.scenarios, &self.theta)
+            {
+                let message =
+                    "Cached predictions disagree with simulated predictions, disabling the cache";
+                tracing::error!("{}", message);
+                self.record(Category::Cache, message.to_string());
+                clear_cache();
+                self.cache = false;
+            }
+            let cache = cache && self.cache;
+            if self.settings.config.check_determinism
+                && self.cycle == 2
+                && !predictions_are_deterministic(&self.engine, &self.scenarios, &self.theta)
+            {
+                let message =
+                    "Repeated simulations of the same support points give different predictions";
+                tracing::error!("{}", message);
+                self.record(Category::Determinism, message.to_string());
+            }
+            let poly = self.error_poly();
+            let sigma = self.sigma.as_deref().unwrap_or(&poly);
+            let (mut psis, simulation, likelihood) = prob::simulate_psi_timed(
+                &self.engine,
                 &self.scenarios,
-                &ErrorPoly {
-                    c: self.c,
-                    gl: self.gamma,
-                    e_type: &self.error_type,
-                },
+                &self.theta,
+                &[sigma],
+                cache,
+                self.settings.config.subject_batch_size,
             );
-            (self.lambda, _) = match ipm::burke(&self.psi) {
+            self.timer.add(Stage::Simulation, simulation);
+            self.timer.add(Stage::Likelihood, likelihood);
+            stats::log_parallelism(self.scenarios.len(), self.theta.nrows());
+            self.psi = psis.remove(0);
+            (self.lambda, _) = match self.timer.time(Stage::Ipm, || {
+                ipm::burke_weighted(&self.psi, &self.subject_weights, ipm_tol, ipm_max_iter)
+            }) {
                 Ok((lambda, objf)) => (lambda, objf),
                 Err(err) => {
-                    //todo: write out report
-                    panic!("Error in IPM: {:?}", err);
+                    self.fail(err);
+                    break;
                 }
             };
 
             let mut keep = Vec::<usize>::new();
+            // NaN weights are ignored, rather than failing the comparison
+            let max_lambda = self
+                .lambda
+                .iter()
+                .copied()
+                .fold(f64::NEG_INFINITY, f64::max);
             for (index, lam) in self.lambda.iter().enumerate() {
-                if *lam > self.lambda.max().unwrap() / 1000_f64 {
+                if *lam > max_lambda / 1000_f64 {
                     keep.push(index);
                 }
             }
+            self.frozen.protect(&self.theta, &mut keep);
+            if retain_highest_weight(&mut keep, &self.lambda) {
+                self.record_fallback("lambda filter");
+            }
+            sort_lexicographic(&self.theta, &mut keep);
 
             self.theta = self.theta.select(Axis(0), &keep);
             self.psi = self.psi.select(Axis(1), &keep);
+            let lambda = self.lambda.select(Axis(0), &keep);
 
             //Rank-Revealing Factorization
-            let (r, perm) = qr::calculate_r(&self.psi);
-
-            let mut keep = Vec::<usize>::new();
-            //The minimum between the number of subjects and the actual number of support points
-            let lim_loop = self.psi.nrows().min(self.psi.ncols());
-            for i in 0..lim_loop {
-                let test = norm_zero(&r.column(i).to_owned());
-                let ratio = r.get((i, i)).unwrap() / test;
-                if ratio.abs() >= 1e-8 {
-                    keep.push(*perm.get(i).unwrap());
-                }
+            let mut keep = self
+                .timer
+                .time(Stage::Qr, || qr::keep_independent(&self.psi));
+            self.frozen.protect(&self.theta, &mut keep);
+            if retain_highest_weight(&mut keep, &lambda) {
+                self.record_fallback("QR factorization");
             }
+            sort_lexicographic(&self.theta, &mut keep);
 
             // If a support point is dropped, log it
             if self.psi.ncols() != keep.len() {
-                tracing::debug!(
+                let message = format!(
                     "QRD dropped {} support point(s)",
                     self.psi.ncols() - keep.len(),
                 );
+                tracing::debug!("{}", message);
+                self.record(Category::Condensation, message);
             }
 
             self.theta = self.theta.select(Axis(0), &keep);
             self.psi = self.psi.select(Axis(1), &keep);
 
-            (self.lambda, self.objf) = match ipm::burke(&self.psi) {
+            (self.lambda, self.objf) = match self.timer.time(Stage::Ipm, || {
+                ipm::burke_weighted(&self.psi, &self.subject_weights, ipm_tol, ipm_max_iter)
+            }) {
                 Ok((lambda, objf)) => (lambda, objf),
                 Err(err) => {
-                    //todo: write out report
-                    panic!("Error in IPM: {:?}", err);
+                    self.fail(err);
+                    break;
                 }
             };
 
-            self.optim_gamma();
+            // A supplied error model has no parameters to optimize
+            if self.sigma.is_none() {
+                // Gamma and the polynomial scale the same standard deviation, so only one of them is optimized
+                let optimized = if self.settings.error.optimize_poly {
+                    self.optim_poly()
+                } else {
+                    self.optim_gamma()
+                };
+                if let Err(err) = optimized {
+                    self.fail(err);
+                    break;
+                }
+            }
 
             let state = NPCycle {
                 cycle: self.cycle,
                 objf: -2. * self.objf,
-                delta_objf: (self.last_objf - self.objf).abs(),
+                delta_objf: (2. * (self.last_objf - self.objf)).abs(),
                 nspp: self.theta.shape()[0],
+                added: n_start - self.condensed_nspp,
+                removed: n_start - self.theta.shape()[0],
                 theta: self.theta.clone(),
+                weights: self.lambda.clone(),
                 gamlam: self.gamma,
+                poly: Some(self.c),
             };
             self.tx.send(Comm::NPCycle(state.clone())).unwrap();
+            self.condensed_nspp = self.theta.shape()[0];
+            stats::log_cycle(
+                &self.scenarios,
+                self.engine.take_retries(),
+                self.engine.take_clamped(),
+                self.engine.take_non_finite(),
+            );
 
-            // Increasing objf signals instability or model misspecification.
+            // `objf` is the log-likelihood, which should not decrease between cycles, i.e. the reported -2LL should not increase.
+            // An increasing -2LL signals instability or model misspecification.
             if self.last_objf > self.objf {
-                tracing::info!(
-                    "Objective function decreased from {} to {}",
-                    self.last_objf,
-                    self.objf
+                let message = format!(
+                    "-2LL increased from {} to {}",
+                    -2. * self.last_objf,
+                    -2. * self.objf
                 );
+                tracing::info!("{}", message);
+                self.record(Category::Objective, message);
             }
 
             self.w = self.lambda.clone();
+            self.completed = Some(self.cycle_state());
             let pyl = self.psi.dot(&self.w);
 
             self.cycle_log
                 .push_and_write(state, self.settings.config.output);
 
             // Stop if we have reached convergence criteria
-            if (self.last_objf - self.objf).abs() <= THETA_G && self.eps > THETA_E {
-                self.eps /= 2.;
+            self.eps_cycles += 1;
+            if self.stop_criterion.update_with_distribution(
+                self.last_objf - self.objf,
+                &self.theta,
+                &self.lambda,
+            ) && self.eps > THETA_E
+            {
+                self.eps /= self.eps_factor();
+                self.eps_cycles = 0;
                 if self.eps <= THETA_E {
-                    self.f1 = pyl.mapv(|x| x.ln()).sum();
+                    self.f1 = self.subject_weights.dot(&pyl.mapv(|x| x.ln()));
                     if (self.f1 - self.f0).abs() <= THETA_F {
-                        tracing::info!("The run converged");
+                        tracing::info!("{}", StopReason::Converged);
                         self.converged = true;
+                        self.stop_reason = Some(StopReason::Converged);
                         break;
                     } else {
                         self.f0 = self.f1;
@@ -309,15 +638,17 @@ where
                 }
             }
 
-            // Stop if we have reached maximum number of cycles
-            if self.cycle >= self.settings.config.cycles {
-                tracing::warn!("Maximum number of cycles reached");
-                break;
-            }
-
-            // Stop if stopfile exists
-            if std::path::Path::new("stop").exists() {
-                tracing::warn!("Stopfile detected - breaking");
+            // Stop if we have reached the maximum number of cycles, or if a stop was requested
+            if let Some(reason) = check_stop(
+                self.cycle,
+                self.settings.config.cycles,
+                &self.control,
+                Path::new(STOP_FILE),
+                deadline,
+            ) {
+                tracing::warn!("{}", reason);
+                self.record(Category::Convergence, reason.to_string());
+                self.stop_reason = Some(reason);
                 break;
             }
 
@@ -326,11 +657,97 @@ where
             self.cycle += 1;
             self.last_objf = self.objf;
         }
+        self.timer.finish();
+        timing::log_summary(self.timer.records());
+        self.check_sigma();
 
         self.to_npresult()
     }
+
+    /// Factor by which `eps` is divided once the objective function has converged at the current grid resolution
+    ///
+    /// By default, `eps` is halved. With the `adaptive_eps` setting, the factor is inversely proportional to the number of cycles spent at the current resolution,
+    /// so runs which converge within a few cycles refine the grid faster, and runs which take many cycles refine it more slowly.
+    /// The factor is 2 after [EPS_RATE] cycles, and bounded by [EPS_FACTOR].
+    fn eps_factor(&self) -> f64 {
+        if self.settings.config.adaptive_eps {
+            (2.0 * EPS_RATE / self.eps_cycles.max(1) as f64).clamp(EPS_FACTOR.0, EPS_FACTOR.1)
+        } else {
+            2.0
+        }
+    }
+
+    /// Records a warning raised in the current cycle, see [Warning]
+    fn record(&mut self, category: Category, message: String) {
+        self.warnings
+            .push(Warning::new(category, message).in_cycle(self.cycle));
+    }
+
+    /// The current support points, weights, objective function and error model, see [CycleState]
+    fn cycle_state(&self) -> CycleState {
+        CycleState {
+            theta: self.theta.clone(),
+            psi: self.psi.clone(),
+            w: self.w.clone(),
+            objf: self.objf,
+            gamma: self.gamma,
+            c: self.c,
+        }
+    }
+
+    /// Logs and records `err`, and stops the run with the state of the last completed cycle, see [StopReason::Error]
+    fn fail(&mut self, err: Box<dyn std::error::Error>) {
+        let message = format!("Error in IPM, stopping the run: {}", err);
+        tracing::error!("{}", message);
+        self.record(Category::Convergence, message);
+        self.stop_reason = Some(StopReason::Error);
+        let state = self.completed.take().unwrap_or_else(|| {
+            CycleState::empty(self.scenarios.len(), self.ranges.len(), self.gamma, self.c)
+        });
+        self.theta = state.theta;
+        self.psi = state.psi;
+        self.w = state.w.clone();
+        self.lambda = state.w;
+        self.objf = state.objf;
+        self.gamma = state.gamma;
+        self.c = state.c;
+    }
+
+    /// Logs and records that `filter` dropped every support point, see [retain_highest_weight]
+    fn record_fallback(&mut self, filter: &str) {
+        let message = format!(
+            "The {} dropped every support point, retaining the support point with the highest weight",
+            filter
+        );
+        tracing::error!("{}", message);
+        self.record(Category::Condensation, message);
+    }
+
+    /// The error polynomial with the current gamma or lambda, which is the error model unless one is supplied, see [Algorithm::set_sigma]
+    fn error_poly(&self) -> ErrorPoly<'_> {
+        ErrorPoly::new(self.c, self.gamma, &self.error_type)
+            .with_reference(self.settings.error.reference)
+            .with_df(self.settings.error.df)
+            .with_transform(self.settings.error.transform())
+    }
+
+    /// Records a warning if the final error model gives standard deviations near zero, see [check_sigma]
+    fn check_sigma(&mut self) {
+        let poly = self.error_poly();
+        let sigma = self.sigma.as_deref().unwrap_or(&poly);
+        if let Some(warning) = check_sigma(&self.scenarios, sigma) {
+            tracing::warn!("{}", warning.message);
+            self.warnings.push(warning);
+        }
+    }
 }
-fn norm_zero(a: &Array1<f64>) -> f64 {
-    let zeros: Array1<f64> = Array::zeros(a.len());
-    a.l2_dist(&zeros).unwrap()
+
+fn poly_get(c: (f64, f64, f64, f64), k: usize) -> f64 {
+    [c.0, c.1, c.2, c.3][k]
+}
+
+fn poly_set(c: (f64, f64, f64, f64), k: usize, value: f64) -> (f64, f64, f64, f64) {
+    let mut c = [c.0, c.1, c.2, c.3];
+    c[k] = value;
+    (c[0], c[1], c[2], c[3])
 }
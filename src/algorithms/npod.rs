@@ -1,9 +1,13 @@
 use crate::{
     prelude::{
-        algorithms::Algorithm,
+        algorithms::{Algorithm, CycleState},
         condensation::prune::prune,
+        condensation::{
+            fallback::retain_highest_weight, frozen::FrozenPoints, order::sort_lexicographic,
+        },
+        convergence::{check_stop, deadline, StopReason, STOP_FILE},
         datafile::Scenario,
-        evaluation::sigma::{ErrorPoly, ErrorType},
+        evaluation::sigma::{ErrorPoly, ErrorType, Sigma},
         ipm,
         optimization::d_optimizer::SppOptimizer,
         output::NPResult,
@@ -11,13 +15,17 @@ use crate::{
         prob, qr,
         settings::Settings,
         simulation::predict::Engine,
-        simulation::predict::{sim_obs, Predict},
+        simulation::predict::{
+            cache_is_consistent, clear_cache, predictions_are_deterministic, Predict,
+        },
+        simulation::stats,
     },
-    tui::ui::Comm,
+    routines::warnings::{check_sigma, Category, Warning},
+    tui::{control::RunControl, ui::Comm},
 };
 use ndarray::parallel::prelude::*;
-use ndarray::{Array, Array1, Array2, Axis};
-use ndarray_stats::{DeviationExt, QuantileExt};
+use ndarray::{Array1, Array2, Axis};
+use std::path::Path;
 use tokio::sync::mpsc::UnboundedSender;
 
 const THETA_D: f64 = 1e-4;
@@ -33,18 +41,35 @@ where
     theta: Array2<f64>,
     lambda: Array1<f64>,
     w: Array1<f64>,
+    /// Log-likelihood of the previous cycle
     last_objf: f64,
+    /// Log-likelihood of the current cycle, which is maximized; it is reported as -2LL, `-2 * objf`
     objf: f64,
     cycle: usize,
     gamma_delta: f64,
     gamma: f64,
     error_type: ErrorType,
     converged: bool,
+    /// Reason the run stopped, see [NPResult::stop_reason]
+    stop_reason: Option<StopReason>,
+    /// Number of support points left by the condensation of the previous cycle, to count the points added by the expansion, see [NPCycle::added]
+    condensed_nspp: usize,
+    /// State at the end of the last completed cycle, which is returned if a later cycle fails
+    completed: Option<CycleState>,
     cycle_log: CycleLog,
+    frozen: FrozenPoints,
     cache: bool,
     scenarios: Vec<Scenario>,
+    /// Weights of the log-likelihoods of the subjects in the objective function, see [Config::subject_weights](crate::routines::settings::Config::subject_weights)
+    subject_weights: Array1<f64>,
     c: (f64, f64, f64, f64),
+    /// Error model used in place of the error polynomial, see [Algorithm::set_sigma]
+    sigma: Option<Box<dyn Sigma + Sync>>,
     tx: UnboundedSender<Comm>,
+    control: RunControl,
+    warnings: Vec<Warning>,
+    /// Time the run started, see [NPResult::with_start]
+    started: chrono::DateTime<chrono::Local>,
     settings: Settings,
 }
 
@@ -55,17 +80,30 @@ where
     fn fit(&mut self) -> NPResult {
         self.run()
     }
+    fn set_control(&mut self, control: RunControl) {
+        self.control = control;
+    }
+    fn set_sigma(&mut self, sigma: Box<dyn Sigma + Sync>) {
+        self.sigma = Some(sigma);
+    }
+    fn warnings_mut(&mut self) -> &mut Vec<Warning> {
+        &mut self.warnings
+    }
     fn to_npresult(&self) -> NPResult {
         NPResult::new(
             self.scenarios.clone(),
             self.theta.clone(),
             self.psi.clone(),
             self.w.clone(),
-            self.objf,
+            -2. * self.objf,
             self.cycle,
             self.converged,
             self.settings.clone(),
         )
+        .with_cycle_log(&self.cycle_log)
+        .with_warnings(&self.warnings)
+        .with_start(self.started)
+        .with_stop_reason(self.stop_reason)
     }
 }
 
@@ -91,7 +129,7 @@ where
     pub fn new(
         sim_eng: Engine<S>,
         ranges: Vec<(f64, f64)>,
-        theta: Array2<f64>,
+        mut theta: Array2<f64>,
         scenarios: Vec<Scenario>,
         c: (f64, f64, f64, f64),
         tx: UnboundedSender<Comm>,
@@ -100,6 +138,17 @@ where
     where
         S: Predict<'static> + std::marker::Sync,
     {
+        let frozen = FrozenPoints::new(settings.config.frozen.clone().unwrap_or_default());
+        frozen.insert_missing(&mut theta);
+        let mut cycle_log =
+            CycleLog::new(&settings.random.names(), settings.config.output_precision);
+        if settings.config.error_magnitude {
+            cycle_log = cycle_log.with_error_magnitude(&settings.error);
+        }
+        if let (true, Some(interval)) = (settings.config.output, settings.config.snapshot_interval)
+        {
+            cycle_log = cycle_log.with_snapshots(interval, std::path::Path::new("snapshots"));
+        }
         Self {
             engine: sim_eng,
             ranges,
@@ -118,53 +167,58 @@ where
                 _ => panic!("Error type not supported"),
             },
             converged: false,
-            cycle_log: CycleLog::new(&settings.random.names()),
+            stop_reason: None,
+            condensed_nspp: 0,
+            completed: None,
+            cycle_log,
+            frozen,
             cache: settings.config.cache,
             tx,
+            control: RunControl::new(),
+            warnings: Vec::new(),
+            started: chrono::Local::now(),
+            subject_weights: scenarios
+                .iter()
+                .map(|scenario| settings.config.subject_weight(&scenario.id))
+                .collect(),
             settings,
             scenarios,
             c,
+            sigma: None,
         }
     }
 
-    fn optim_gamma(&mut self) {
+    fn optim_gamma(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let (ipm_tol, ipm_max_iter) = (
+            self.settings.config.ipm_tol,
+            self.settings.config.ipm_max_iter,
+        );
         //Gam/Lam optimization
         // TODO: Move this to e.g. /evaluation/error.rs
         let gamma_up = self.gamma * (1.0 + self.gamma_delta);
         let gamma_down = self.gamma / (1.0 + self.gamma_delta);
-        let ypred = sim_obs(&self.engine, &self.scenarios, &self.theta, self.cache);
-        let psi_up = prob::calculate_psi(
-            &ypred,
-            &self.scenarios,
-            &ErrorPoly {
-                c: self.c,
-                gl: gamma_up,
-                e_type: &self.error_type,
-            },
-        );
-        let psi_down = prob::calculate_psi(
-            &ypred,
+        let sigma_up = ErrorPoly::new(self.c, gamma_up, &self.error_type)
+            .with_reference(self.settings.error.reference)
+            .with_df(self.settings.error.df)
+            .with_transform(self.settings.error.transform());
+        let sigma_down = ErrorPoly::new(self.c, gamma_down, &self.error_type)
+            .with_reference(self.settings.error.reference)
+            .with_df(self.settings.error.df)
+            .with_transform(self.settings.error.transform());
+        let mut psis = prob::simulate_psi(
+            &self.engine,
             &self.scenarios,
-            &ErrorPoly {
-                c: self.c,
-                gl: gamma_down,
-                e_type: &self.error_type,
-            },
+            &self.theta,
+            &[&sigma_up, &sigma_down],
+            self.cache,
+            self.settings.config.subject_batch_size,
         );
-        let (lambda_up, objf_up) = match ipm::burke(&psi_up) {
-            Ok((lambda, objf)) => (lambda, objf),
-            Err(err) => {
-                //todo: write out report
-                panic!("Error in IPM: {:?}", err);
-            }
-        };
-        let (lambda_down, objf_down) = match ipm::burke(&psi_down) {
-            Ok((lambda, objf)) => (lambda, objf),
-            Err(err) => {
-                //todo: write out report
-                panic!("Error in IPM: {:?}", err);
-            }
-        };
+        let psi_down = psis.pop().unwrap();
+        let psi_up = psis.pop().unwrap();
+        let (lambda_up, objf_up) =
+            ipm::burke_weighted(&psi_up, &self.subject_weights, ipm_tol, ipm_max_iter)?;
+        let (lambda_down, objf_down) =
+            ipm::burke_weighted(&psi_down, &self.subject_weights, ipm_tol, ipm_max_iter)?;
         if objf_up > self.objf {
             self.gamma = gamma_up;
             self.objf = objf_up;
@@ -183,100 +237,187 @@ where
         if self.gamma_delta <= 0.01 {
             self.gamma_delta = 0.1;
         }
+        Ok(())
     }
 
     pub fn run(&mut self) -> NPResult {
+        let (ipm_tol, ipm_max_iter) = (
+            self.settings.config.ipm_tol,
+            self.settings.config.ipm_max_iter,
+        );
+        let deadline = deadline(self.settings.config.time_limit);
         while (self.last_objf - self.objf).abs() > THETA_F {
             self.last_objf = self.objf;
+            let n_start = self.theta.nrows();
             // log::info!("Cycle: {}", cycle);
             // psi n_sub rows, nspp columns
-            let cache = if self.cycle == 1 { false } else { self.cache };
-            let ypred = sim_obs(&self.engine, &self.scenarios, &self.theta, cache);
-
-            self.psi = prob::calculate_psi(
-                &ypred,
+            // The initial grid is large, and most of its support points are removed by condensation.
+            // Caching their predictions would fill the cache with values which are never reused, so by default
+            // the cache is bypassed (neither read nor written) in the first cycle, and primed from the second cycle.
+            let cache = if self.cycle == 1 {
+                self.cache && self.settings.config.cache_first_cycle
+            } else {
+                self.cache
+            };
+            if cache
+                && self.cycle == 2
+                && !cache_is_consistent(&self.engine, &self.scenarios, &self.theta)
+            {
+                let message =
+                    "Cached predictions disagree with simulated predictions, disabling the cache";
+                tracing::error!("{}", message);
+                self.record(Category::Cache, message.to_string());
+                clear_cache();
+                self.cache = false;
+            }
+            let cache = cache && self.cache;
+            if self.settings.config.check_determinism
+                && self.cycle == 2
+                && !predictions_are_deterministic(&self.engine, &self.scenarios, &self.theta)
+            {
+                let message =
+                    "Repeated simulations of the same support points give different predictions";
+                tracing::error!("{}", message);
+                self.record(Category::Determinism, message.to_string());
+            }
+            let poly = self.error_poly();
+            let sigma = self.sigma.as_deref().unwrap_or(&poly);
+            self.psi = prob::simulate_psi(
+                &self.engine,
                 &self.scenarios,
-                &ErrorPoly {
-                    c: self.c,
-                    gl: self.gamma,
-                    e_type: &self.error_type,
-                },
-            );
-            (self.lambda, _) = match ipm::burke(&self.psi) {
+                &self.theta,
+                &[sigma],
+                cache,
+                self.settings.config.subject_batch_size,
+            )
+            .remove(0);
+            stats::log_parallelism(self.scenarios.len(), self.theta.nrows());
+            (self.lambda, _) = match ipm::burke_weighted(
+                &self.psi,
+                &self.subject_weights,
+                ipm_tol,
+                ipm_max_iter,
+            ) {
                 Ok((lambda, objf)) => (lambda, objf),
                 Err(err) => {
-                    //todo: write out report
-                    panic!("Error in IPM: {:?}", err);
+                    self.fail(err);
+                    break;
                 }
             };
 
             let mut keep = Vec::<usize>::new();
+            // NaN weights are ignored, rather than failing the comparison
+            let max_lambda = self
+                .lambda
+                .iter()
+                .copied()
+                .fold(f64::NEG_INFINITY, f64::max);
             for (index, lam) in self.lambda.iter().enumerate() {
-                if *lam > self.lambda.max().unwrap() / 1000_f64 {
+                if *lam > max_lambda / 1000_f64 {
                     keep.push(index);
                 }
             }
+            self.frozen.protect(&self.theta, &mut keep);
+            if retain_highest_weight(&mut keep, &self.lambda) {
+                self.record_fallback("lambda filter");
+            }
+            sort_lexicographic(&self.theta, &mut keep);
 
             self.theta = self.theta.select(Axis(0), &keep);
             self.psi = self.psi.select(Axis(1), &keep);
+            let lambda = self.lambda.select(Axis(0), &keep);
 
             //Rank-Revealing Factorization
-            let (r, perm) = qr::calculate_r(&self.psi);
-
-            let mut keep = Vec::<usize>::new();
-            //The minimum between the number of subjects and the actual number of support points
-            let lim_loop = self.psi.nrows().min(self.psi.ncols());
-            for i in 0..lim_loop {
-                let test = norm_zero(&r.column(i).to_owned());
-                let ratio = r.get((i, i)).unwrap() / test;
-                if ratio.abs() >= 1e-8 {
-                    keep.push(*perm.get(i).unwrap());
-                }
+            let mut keep = qr::keep_independent(&self.psi);
+            self.frozen.protect(&self.theta, &mut keep);
+            if retain_highest_weight(&mut keep, &lambda) {
+                self.record_fallback("QR factorization");
             }
+            sort_lexicographic(&self.theta, &mut keep);
             tracing::info!(
                 "QR decomp, cycle {}, kept: {}, thrown {}",
                 self.cycle,
                 keep.len(),
                 self.psi.ncols() - keep.len()
             );
+            if self.psi.ncols() != keep.len() {
+                self.record(
+                    Category::Condensation,
+                    format!(
+                        "QRD dropped {} support point(s)",
+                        self.psi.ncols() - keep.len()
+                    ),
+                );
+            }
             self.theta = self.theta.select(Axis(0), &keep);
             self.psi = self.psi.select(Axis(1), &keep);
 
-            (self.lambda, self.objf) = match ipm::burke(&self.psi) {
+            (self.lambda, self.objf) = match ipm::burke_weighted(
+                &self.psi,
+                &self.subject_weights,
+                ipm_tol,
+                ipm_max_iter,
+            ) {
                 Ok((lambda, objf)) => (lambda, objf),
                 Err(err) => {
-                    //todo: write out report
-                    panic!("Error in IPM: {:?}", err);
+                    self.fail(err);
+                    break;
                 }
             };
 
-            self.optim_gamma();
+            // A supplied error model has no parameters to optimize
+            if self.sigma.is_none() {
+                if let Err(err) = self.optim_gamma() {
+                    self.fail(err);
+                    break;
+                }
+            }
 
             let state = NPCycle {
                 cycle: self.cycle,
                 objf: -2. * self.objf,
-                delta_objf: (self.last_objf - self.objf).abs(),
+                delta_objf: (2. * (self.last_objf - self.objf)).abs(),
                 nspp: self.theta.shape()[0],
+                added: n_start - self.condensed_nspp,
+                removed: n_start - self.theta.shape()[0],
                 theta: self.theta.clone(),
+                weights: self.lambda.clone(),
                 gamlam: self.gamma,
+                poly: Some(self.c),
             };
             self.tx.send(Comm::NPCycle(state.clone())).unwrap();
+            self.condensed_nspp = self.theta.shape()[0];
+            stats::log_cycle(
+                &self.scenarios,
+                self.engine.take_retries(),
+                self.engine.take_clamped(),
+                self.engine.take_non_finite(),
+            );
 
-            // If the objective function decreased, log an error.
-            // Increasing objf signals instability of model misspecification.
+            // `objf` is the log-likelihood, so if it decreased, i.e. the reported -2LL increased, log an error.
+            // An increasing -2LL signals instability or model misspecification.
             if self.last_objf > self.objf {
-                tracing::error!("Objective function decreased");
+                tracing::error!("-2LL increased");
+                self.record(
+                    Category::Objective,
+                    format!(
+                        "-2LL increased from {} to {}",
+                        -2. * self.last_objf,
+                        -2. * self.objf
+                    ),
+                );
             }
 
             self.w = self.lambda.clone();
+            self.completed = Some(self.cycle_state());
             let pyl = self.psi.dot(&self.w);
 
+            self.cycle_log
+                .push_and_write(state, self.settings.config.output);
+
             // Add new point to theta based on the optimization of the D function
-            let sigma = ErrorPoly {
-                c: self.c,
-                gl: self.gamma,
-                e_type: &self.error_type,
-            };
+            let poly = self.error_poly();
+            let sigma = self.sigma.as_deref().unwrap_or(&poly);
             // for spp in self.theta.clone().rows() {
             //     let optimizer = SppOptimizer::new(&self.engine, &self.scenarios, &sigma, &pyl);
             //     let candidate_point = optimizer.optimize_point(spp.to_owned()).unwrap();
@@ -287,39 +428,116 @@ where
                 candididate_points.push(spp.to_owned());
             }
             candididate_points.par_iter_mut().for_each(|spp| {
-                let optimizer = SppOptimizer::new(&self.engine, &self.scenarios, &sigma, &pyl);
-                let candidate_point = optimizer.optimize_point(spp.to_owned()).unwrap();
+                let optimizer = SppOptimizer::new(
+                    &self.engine,
+                    &self.scenarios,
+                    sigma,
+                    &pyl,
+                    &self.subject_weights,
+                );
+                let mut candidate_point = optimizer.optimize_point(spp.to_owned()).unwrap();
+                // Parameters with equal bounds are held constant
+                for (value, &(lo, hi)) in candidate_point.iter_mut().zip(&self.ranges) {
+                    if lo == hi {
+                        *value = lo;
+                    }
+                }
                 *spp = candidate_point;
             });
             for cp in candididate_points {
                 prune(&mut self.theta, cp, &self.ranges, THETA_D);
             }
 
-            // Stop if we have reached maximum number of cycles
-            if self.cycle >= self.settings.config.cycles {
-                tracing::warn!("Maximum number of cycles reached");
-                break;
-            }
-
-            // Stop if stopfile exists
-            if std::path::Path::new("stop").exists() {
-                tracing::warn!("Stopfile detected - breaking");
+            // Stop if we have reached the maximum number of cycles, or if a stop was requested
+            if let Some(reason) = check_stop(
+                self.cycle,
+                self.settings.config.cycles,
+                &self.control,
+                Path::new(STOP_FILE),
+                deadline,
+            ) {
+                tracing::warn!("{}", reason);
+                self.record(Category::Convergence, reason.to_string());
+                self.stop_reason = Some(reason);
                 break;
             }
-            //TODO: the cycle migh break before reaching this point
-            self.cycle_log
-                .push_and_write(state, self.settings.config.output);
-
             self.cycle += 1;
 
             // log::info!("cycle: {}, objf: {}", self.cycle, self.objf);
             // dbg!((self.last_objf - self.objf).abs());
         }
+        // The loop only ends without a stop reason when the objective function converged
+        if self.stop_reason.is_none() {
+            tracing::info!("{}", StopReason::Converged);
+            self.converged = true;
+            self.stop_reason = Some(StopReason::Converged);
+        }
+        self.check_sigma();
 
         self.to_npresult()
     }
-}
-fn norm_zero(a: &Array1<f64>) -> f64 {
-    let zeros: Array1<f64> = Array::zeros(a.len());
-    a.l2_dist(&zeros).unwrap()
+
+    /// Records a warning raised in the current cycle, see [Warning]
+    fn record(&mut self, category: Category, message: String) {
+        self.warnings
+            .push(Warning::new(category, message).in_cycle(self.cycle));
+    }
+
+    /// The current support points, weights, objective function and error model, see [CycleState]
+    fn cycle_state(&self) -> CycleState {
+        CycleState {
+            theta: self.theta.clone(),
+            psi: self.psi.clone(),
+            w: self.w.clone(),
+            objf: self.objf,
+            gamma: self.gamma,
+            c: self.c,
+        }
+    }
+
+    /// Logs and records `err`, and stops the run with the state of the last completed cycle, see [StopReason::Error]
+    fn fail(&mut self, err: Box<dyn std::error::Error>) {
+        let message = format!("Error in IPM, stopping the run: {}", err);
+        tracing::error!("{}", message);
+        self.record(Category::Convergence, message);
+        self.stop_reason = Some(StopReason::Error);
+        let state = self.completed.take().unwrap_or_else(|| {
+            CycleState::empty(self.scenarios.len(), self.ranges.len(), self.gamma, self.c)
+        });
+        self.theta = state.theta;
+        self.psi = state.psi;
+        self.w = state.w.clone();
+        self.lambda = state.w;
+        self.objf = state.objf;
+        self.gamma = state.gamma;
+        self.c = state.c;
+    }
+
+    /// Logs and records that `filter` dropped every support point, see [retain_highest_weight]
+    fn record_fallback(&mut self, filter: &str) {
+        let message = format!(
+            "The {} dropped every support point, retaining the support point with the highest weight",
+            filter
+        );
+        tracing::error!("{}", message);
+        self.record(Category::Condensation, message);
+    }
+
+    /// The error polynomial with the current gamma or lambda, which is the error model unless one is supplied, see [Algorithm::set_sigma]
+    fn error_poly(&self) -> ErrorPoly<'_> {
+        ErrorPoly::new(self.c, self.gamma, &self.error_type)
+            .with_reference(self.settings.error.reference)
+            .with_df(self.settings.error.df)
+            .with_transform(self.settings.error.transform())
+    }
+
+    /// Records a warning if the final error model gives standard deviations near zero, see [check_sigma]
+    fn check_sigma(&mut self) {
+        let poly = self.error_poly();
+        let sigma = self.sigma.as_deref().unwrap_or(&poly);
+        if let Some(warning) = check_sigma(&self.scenarios, sigma) {
+            tracing::warn!("{}", warning.message);
+            self.warnings.push(warning);
+        }
+    }
 }
@@ -1,15 +1,17 @@
 use crate::{
     prelude::{
-        algorithms::Algorithm,
+        algorithms::{Algorithm, CycleState},
+        convergence::StopReason,
         datafile::Scenario,
-        evaluation::sigma::{ErrorPoly, ErrorType},
+        evaluation::sigma::{ErrorPoly, ErrorType, Sigma},
         ipm,
         output::NPResult,
         prob,
         settings::Settings,
         simulation::predict::Engine,
-        simulation::predict::{sim_obs, Predict},
+        simulation::predict::Predict,
     },
+    routines::warnings::{Category, Warning},
     tui::ui::Comm,
 };
 
@@ -29,12 +31,21 @@ where
     objf: f64,
     cycle: usize,
     converged: bool,
+    /// Reason the run stopped, see [NPResult::stop_reason]
+    stop_reason: Option<StopReason>,
     gamma: f64,
     error_type: ErrorType,
     scenarios: Vec<Scenario>,
+    /// Weights of the log-likelihoods of the subjects in the objective function, see [Config::subject_weights](crate::routines::settings::Config::subject_weights)
+    subject_weights: Array1<f64>,
     c: (f64, f64, f64, f64),
+    /// Error model used in place of the error polynomial, see [Algorithm::set_sigma]
+    sigma: Option<Box<dyn Sigma + Sync>>,
     #[allow(dead_code)]
     tx: UnboundedSender<Comm>,
+    warnings: Vec<Warning>,
+    /// Time the run started, see [NPResult::with_start]
+    started: chrono::DateTime<chrono::Local>,
     settings: Settings,
 }
 
@@ -45,6 +56,12 @@ where
     fn fit(&mut self) -> NPResult {
         self.run()
     }
+    fn warnings_mut(&mut self) -> &mut Vec<Warning> {
+        &mut self.warnings
+    }
+    fn set_sigma(&mut self, sigma: Box<dyn Sigma + Sync>) {
+        self.sigma = Some(sigma);
+    }
     fn to_npresult(&self) -> NPResult {
         NPResult::new(
             self.scenarios.clone(),
@@ -56,6 +73,9 @@ where
             self.converged,
             self.settings.clone(),
         )
+        .with_warnings(&self.warnings)
+        .with_start(self.started)
+        .with_stop_reason(self.stop_reason)
     }
 }
 
@@ -82,6 +102,7 @@ where
             objf: f64::INFINITY,
             cycle: 0,
             converged: false,
+            stop_reason: None,
             gamma: settings.error.value,
             error_type: match settings.error.class.as_str() {
                 "additive" => ErrorType::Add,
@@ -89,26 +110,60 @@ where
                 _ => panic!("Error type not supported"),
             },
             tx,
+            warnings: Vec::new(),
+            started: chrono::Local::now(),
+            subject_weights: scenarios
+                .iter()
+                .map(|scenario| settings.config.subject_weight(&scenario.id))
+                .collect(),
             settings,
             scenarios,
             c,
+            sigma: None,
         }
     }
 
     pub fn run(&mut self) -> NPResult {
-        let ypred = sim_obs(&self.engine, &self.scenarios, &self.theta, false);
-        self.psi = prob::calculate_psi(
-            &ypred,
+        let poly = ErrorPoly::new(self.c, self.gamma, &self.error_type)
+            .with_reference(self.settings.error.reference)
+            .with_df(self.settings.error.df)
+            .with_transform(self.settings.error.transform());
+        let sigma = self.sigma.as_deref().unwrap_or(&poly);
+        self.psi = prob::simulate_psi(
+            &self.engine,
             &self.scenarios,
-            &ErrorPoly {
-                c: self.c,
-                gl: self.gamma,
-                e_type: &self.error_type,
-            },
-        );
-        let (w, objf) = ipm::burke(&self.psi).expect("Error in IPM");
-        self.w = w;
-        self.objf = objf;
+            &self.theta,
+            &[sigma],
+            false,
+            self.settings.config.subject_batch_size,
+        )
+        .remove(0);
+        match ipm::burke_weighted(
+            &self.psi,
+            &self.subject_weights,
+            self.settings.config.ipm_tol,
+            self.settings.config.ipm_max_iter,
+        ) {
+            Ok((w, objf)) => {
+                self.w = w;
+                self.objf = objf;
+                self.stop_reason = Some(StopReason::Completed);
+            }
+            Err(err) => {
+                // The result has no support points, as the grid could not be weighted
+                let message = format!("Error in IPM, stopping the run: {}", err);
+                tracing::error!("{}", message);
+                self.warnings
+                    .push(Warning::new(Category::Convergence, message));
+                let state =
+                    CycleState::empty(self.scenarios.len(), self.theta.ncols(), self.gamma, self.c);
+                self.theta = state.theta;
+                self.psi = state.psi;
+                self.w = state.w;
+                self.objf = state.objf;
+                self.stop_reason = Some(StopReason::Error);
+            }
+        }
         self.to_npresult()
     }
 }
@@ -6,14 +6,20 @@ use crate::prelude::{
 };
 use crate::routines::datafile::Scenario;
 use crate::routines::settings::*;
+use crate::routines::warnings::{Category, Warning};
+use crate::tui::control::RunControl;
+use crate::tui::replay;
 
 use csv::{ReaderBuilder, WriterBuilder};
 use eyre::Result;
 
-use ndarray::Array2;
+use ndarray::{Array1, Array2};
 use ndarray_csv::Array2Reader;
 use predict::sim_obs;
+use rand::{rngs::StdRng, seq::SliceRandom, SeedableRng};
+use std::collections::BTreeMap;
 use std::fs::File;
+use std::path::Path;
 use std::thread::spawn;
 use std::time::Instant;
 use tokio::sync::mpsc::{self};
@@ -26,56 +32,167 @@ use tokio::sync::mpsc::{self};
 /// - `point`: support point index (0-indexed)
 /// - `time`: prediction time
 /// - `pred`: simulated prediction
+/// - `obs`: simulated observation, i.e. the prediction with a residual error drawn from the error model in the `error` section, see [sigma::sample_residual]
+/// - `event_type`: `observation` for observations in the data, `dose` for predictions at the time of a dose, and `interpolated` for other predictions, see [Scenario::obs_annotations]
+/// - `last_dose`: amount of the most recent dose at or before the prediction, empty before the first dose
+///
+/// If `amounts` is set in the `config` section, the amount in each compartment is written to `amounts.csv` with the columns `id`, `point`, `time` and `A1`, ..., `An`, see [Engine::simulate_amounts].
 ///
 /// # Arguments
 /// The user can specify the desired settings in a TOML configuration file, see `routines::settings::simulator` for details.
+/// The support points are read from the prior, or sampled from the parametric distribution in the `simulation` section, see [Simulation].
 /// - `idelta`: the interval between predictions. Default is 0.0.
-/// - `tad`: the time after dose, which if greater than the last prediction time is the time for which it will predict . Default is 0.0.
+/// - `tad`: the time after the last dose to predict until, if later than the last event of the subject. Default is 0.0.
+///
+/// Predictions are made at the observations, at every multiple of `idelta`, at the end time, see [Scenario::add_event_interval], and at each dose, see [Scenario::add_dose_times].
+/// If `prediction_times` is set in the `config` section, all subjects are instead predicted at those times only, see [Scenario::with_prediction_times].
 pub fn simulate<S>(engine: Engine<S>, settings_path: String) -> Result<()>
 where
     S: Predict<'static> + std::marker::Sync + std::marker::Send + 'static + Clone,
 {
     let settings: Settings = read_settings(settings_path).unwrap();
-    let theta_file = File::open(settings.paths.prior.unwrap()).unwrap();
-    let mut reader = ReaderBuilder::new()
-        .has_headers(true)
-        .from_reader(theta_file);
-    let theta: Array2<f64> = reader.deserialize_array2_dynamic().unwrap();
+    let engine = engine.configure(&settings).map_err(|e| eyre::eyre!(e))?;
+    let theta = match &settings.simulation {
+        Some(simulation) => simulation
+            .sample(&settings.random, settings.config.seed)
+            .map_err(|e| eyre::eyre!(e))?,
+        None => read_theta(settings.paths.prior.as_deref())?,
+    };
 
     // Expand data
-    let idelta = settings.config.idelta;
-    let tad = settings.config.tad;
-    let mut scenarios = datafile::parse(&settings.paths.data).unwrap();
-    scenarios.iter_mut().for_each(|scenario| {
-        *scenario = scenario.add_event_interval(idelta, tad);
-    });
+    let mut idelta = settings.config.idelta;
+    let mut tad = settings.config.tad;
+    let mut scenarios = read_data(&settings)?;
+    let precision = settings.config.output_precision;
+    if let Some(times) = &settings.config.prediction_times {
+        scenarios.iter_mut().for_each(|scenario| {
+            *scenario = scenario.with_prediction_times(times);
+        });
+        // The prediction times replace the expansion of the data
+        (idelta, tad) = (0.0, 0.0);
+    }
+    if settings.config.amounts {
+        write_amounts(&engine, &scenarios, &theta, idelta, tad, precision)?;
+    }
+    if settings.config.prediction_times.is_none() {
+        scenarios.iter_mut().for_each(|scenario| {
+            *scenario = scenario.add_event_interval(idelta, tad).add_dose_times();
+        });
+    }
 
     // Perform simulation
     let ypred = sim_obs(&engine, &scenarios, &theta, false);
 
+    // Residual error model
+    let error_type =
+        sigma::ErrorType::from_class(&settings.error.class).map_err(|e| eyre::eyre!(e))?;
+    let error_poly = sigma::ErrorPoly::new(settings.error.poly, settings.error.value, &error_type)
+        .with_reference(settings.error.reference)
+        .with_df(settings.error.df)
+        .with_transform(settings.error.transform());
+    let mut rng = StdRng::seed_from_u64(settings.config.seed as u64);
+
     // Prepare writer
     let sim_file = File::create("simulation_output.csv").unwrap();
     let mut sim_writer = WriterBuilder::new()
         .has_headers(false)
         .from_writer(sim_file);
     sim_writer
-        .write_record(["id", "point", "time", "pred"])
+        .write_record([
+            "id",
+            "point",
+            "time",
+            "pred",
+            "obs",
+            "event_type",
+            "last_dose",
+        ])
         .unwrap();
 
     // Write output
     for (id, scenario) in scenarios.iter().enumerate() {
         let time = scenario.obs_times.clone();
+        let annotations = scenario.obs_annotations();
         for (point, _spp) in theta.rows().into_iter().enumerate() {
             for (i, time) in time.iter().enumerate() {
+                let pred = *ypred.get((id, point)).unwrap().get(i).unwrap();
+                let obs = pred + sigma::sample_residual(pred, &error_poly, &mut rng);
+                let (event_type, last_dose) = annotations[i];
                 sim_writer.write_record(&[
                     id.to_string(),
                     point.to_string(),
-                    time.to_string(),
-                    ypred.get((id, point)).unwrap().get(i).unwrap().to_string(),
+                    output::format_float(*time, precision),
+                    output::format_float(pred, precision),
+                    output::format_float(obs, precision),
+                    event_type.to_string(),
+                    last_dose.map_or(String::new(), |dose| output::format_float(dose, precision)),
                 ])?;
             }
         }
     }
+    sim_writer.flush()?;
+    Ok(())
+}
+
+/// Reads the support points to simulate from `path`, a CSV file with a header and one column per parameter, see [simulate]
+pub(crate) fn read_theta(path: Option<&str>) -> Result<Array2<f64>> {
+    let path = path.ok_or_else(|| {
+        eyre::eyre!("Simulation requires the support points to simulate, given as the prior in the paths section")
+    })?;
+    let theta_file =
+        File::open(path).map_err(|e| eyre::eyre!("Unable to open the prior {}: {}", path, e))?;
+    let mut reader = ReaderBuilder::new()
+        .has_headers(true)
+        .from_reader(theta_file);
+    reader
+        .deserialize_array2_dynamic()
+        .map_err(|e| eyre::eyre!("Unable to read the prior {}: {}", path, e))
+}
+
+/// Write the amount in each compartment for each scenario and support point to `amounts.csv`
+fn write_amounts<S>(
+    engine: &Engine<S>,
+    scenarios: &[Scenario],
+    theta: &Array2<f64>,
+    idelta: f64,
+    tad: f64,
+    precision: Option<usize>,
+) -> Result<()>
+where
+    S: Predict<'static> + std::marker::Sync + std::marker::Send + 'static + Clone,
+{
+    let mut rows = vec![];
+    let mut n_compartments = 0;
+    for (id, scenario) in scenarios.iter().enumerate() {
+        for (point, spp) in theta.rows().into_iter().enumerate() {
+            let trajectory = engine.simulate_amounts(scenario, &spp.to_vec(), idelta, tad);
+            for (time, amounts) in trajectory {
+                n_compartments = n_compartments.max(amounts.len());
+                rows.push((id, point, time, amounts));
+            }
+        }
+    }
+    if n_compartments == 0 {
+        tracing::warn!("The model does not report compartment amounts, amounts.csv is not written");
+        return Ok(());
+    }
+
+    let mut writer = WriterBuilder::new()
+        .has_headers(false)
+        .from_path("amounts.csv")?;
+    let mut header = vec!["id".to_string(), "point".to_string(), "time".to_string()];
+    header.extend((1..=n_compartments).map(|i| format!("A{}", i)));
+    writer.write_record(&header)?;
+    for (id, point, time, amounts) in rows {
+        let mut record = vec![
+            id.to_string(),
+            point.to_string(),
+            output::format_float(time, precision),
+        ];
+        record.extend(amounts.iter().map(|a| output::format_float(*a, precision)));
+        writer.write_record(&record)?;
+    }
+    writer.flush()?;
     Ok(())
 }
 
@@ -87,7 +204,6 @@ pub fn start<S>(engine: Engine<S>, settings_path: String) -> Result<NPResult>
 where
     S: Predict<'static> + std::marker::Sync + std::marker::Send + 'static + Clone,
 {
-    let now = Instant::now();
     let settings = match read_settings(settings_path) {
         Ok(s) => s,
         Err(e) => {
@@ -95,31 +211,70 @@ where
             std::process::exit(-1);
         }
     };
-    let (tx, rx) = mpsc::unbounded_channel::<Comm>();
+    start_with_settings(engine, settings)
+}
+
+/// Runs the algorithm with already parsed settings
+///
+/// Behaves as [start], i.e. it starts the TUI (if enabled) and writes the output files (if configured).
+pub fn start_with_settings<S>(engine: Engine<S>, settings: Settings) -> Result<NPResult>
+where
+    S: Predict<'static> + std::marker::Sync + std::marker::Send + 'static + Clone,
+{
+    let now = Instant::now();
+    let engine = engine.configure(&settings).map_err(|e| eyre::eyre!(e))?;
+    let (tx, mut rx) = mpsc::unbounded_channel::<Comm>();
+    if let Some(path) = &settings.config.record_messages {
+        rx = replay::record_messages(rx, path)?;
+    }
     let maintx = tx.clone();
     logger::setup_log(&settings, tx.clone());
-    tracing::info!("Starting NPcore");
+    tracing::info!(
+        "Starting NPcore with {} threads",
+        rayon::current_num_threads()
+    );
 
     // Read input data and remove excluded scenarios (if any)
-    let mut scenarios = datafile::parse(&settings.paths.data).unwrap();
+    let data_hash = output::hash_file(&settings.paths.data);
+    let mut scenarios = read_data(&settings)?;
+    let mut warnings = Vec::new();
     if let Some(exclude) = &settings.config.exclude {
-        for val in exclude {
-            scenarios.remove(val.as_ptr() as usize);
-        }
+        let n_subjects = scenarios.len();
+        scenarios.retain(|scenario| !exclude.contains(&scenario.id));
+        let warning = Warning::new(
+            Category::Data,
+            format!(
+                "Excluded {} subject(s) with the IDs {:?}",
+                n_subjects - scenarios.len(),
+                exclude
+            ),
+        );
+        tracing::warn!("{}", warning.message);
+        warnings.push(warning);
+    }
+    datafile::check_scenarios(&scenarios)
+        .map_err(|e| eyre::eyre!("{} in {}", e, settings.paths.data))?;
+    if let Some(seed) = settings.config.shuffle_seed {
+        let permutation = datafile::shuffle(&mut scenarios, seed);
+        let order: Vec<&str> = scenarios.iter().map(|s| s.id.as_str()).collect();
+        tracing::info!(
+            "Shuffled the subjects with seed {}, to the order of IDs {:?} (original indices {:?})",
+            seed,
+            order,
+            permutation
+        );
     }
 
     // Provide information of the input data
-    tracing::info!(
-        "Datafile contains {} subjects with a total of {} observations",
-        scenarios.len(),
-        scenarios.iter().map(|s| s.obs_times.len()).sum::<usize>()
-    );
+    datafile::log_summary(&scenarios, &engine.covariates());
 
     // Spawn new thread for TUI
     let settings_tui = settings.clone();
+    let control = RunControl::new();
+    let control_tui = control.clone();
     let handle = if settings.config.tui {
         spawn(move || {
-            start_ui(rx, settings_tui).expect("Failed to start TUI");
+            start_ui(rx, settings_tui, control_tui).expect("Failed to start TUI");
         })
     } else {
         // Drop messages if TUI is not enabled to reduce memory usage
@@ -129,9 +284,28 @@ where
     };
 
     // Initialize algorithm and run
-    let mut algorithm = initialize_algorithm(engine.clone(), settings.clone(), scenarios, tx);
-    let result = algorithm.fit();
+    let mut algorithm = match initialize_algorithm(engine.clone(), settings.clone(), scenarios, tx)
+    {
+        Ok(algorithm) => algorithm,
+        Err(err) => {
+            maintx.send(Comm::StopUI).unwrap();
+            handle.join().unwrap();
+            return Err(err);
+        }
+    };
+    algorithm.set_control(control);
+    algorithm.warnings_mut().splice(0..0, warnings);
+    let result = algorithm.fit().with_data_hash(data_hash);
     tracing::info!("Total time: {:.2?}", now.elapsed());
+    tracing::info!(
+        "Objective function (-2LL): {:.4}{}",
+        result.reported_objf(),
+        if settings.config.likelihood_constants {
+            ""
+        } else {
+            ", without normalization constants"
+        }
+    );
 
     // Write output files (if configured)
     if settings.config.output {
@@ -147,6 +321,184 @@ where
     Ok(result)
 }
 
+/// Usage text for [run_cli]
+pub const CLI_USAGE: &str = "\
+Usage: <program> <config.toml> [options]
+
+Options:
+  --resume             Use the final distribution of a previous run (theta.csv in the output directory) as prior
+  --dry-run            Read the settings and data, report them, and exit without fitting
+  --output-dir <dir>   Write all output files, including the log, to <dir>
+  --threads <n>        Limit the number of threads used for simulations
+  -h, --help           Print this message";
+
+/// Command-line arguments, see [run_cli]
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CliArgs {
+    pub config: String,
+    pub resume: bool,
+    pub dry_run: bool,
+    pub output_dir: Option<String>,
+    pub threads: Option<usize>,
+    pub help: bool,
+}
+
+impl CliArgs {
+    /// Parses the command-line arguments, excluding the program name
+    pub fn parse<I>(args: I) -> Result<Self>
+    where
+        I: IntoIterator<Item = String>,
+    {
+        let mut cli = CliArgs::default();
+        let mut config = None;
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--resume" => cli.resume = true,
+                "--dry-run" => cli.dry_run = true,
+                "-h" | "--help" => cli.help = true,
+                "--output-dir" => match args.next() {
+                    Some(dir) => cli.output_dir = Some(dir),
+                    None => eyre::bail!("Missing value for --output-dir"),
+                },
+                "--threads" => match args.next().map(|n| n.parse::<usize>()) {
+                    Some(Ok(n)) if n > 0 => cli.threads = Some(n),
+                    _ => eyre::bail!("--threads requires a positive integer"),
+                },
+                flag if flag.starts_with('-') => eyre::bail!("Unknown option: {}", flag),
+                path => {
+                    if config.replace(path.to_string()).is_some() {
+                        eyre::bail!("Only one configuration file may be given");
+                    }
+                }
+            }
+        }
+        match config {
+            Some(config) => cli.config = config,
+            None if cli.help => {}
+            None => eyre::bail!("Missing path to the configuration file"),
+        }
+        Ok(cli)
+    }
+
+    /// Applies the arguments as overrides to the settings
+    ///
+    /// - `--resume` sets `paths.prior` to `theta.csv`, relative to the output directory
+    /// - `--output-dir` resolves the relative `paths.data`, `paths.prior` and `paths.seed_points` against `launch_dir`, the directory the program was started from,
+    ///   as the program changes its working directory to the output directory
+    pub fn apply(&self, settings: &mut Settings, launch_dir: &Path) {
+        if self.output_dir.is_some() {
+            settings.paths.data = resolve_path(launch_dir, &settings.paths.data);
+            settings.paths.prior = settings
+                .paths
+                .prior
+                .as_deref()
+                .map(|prior| resolve_path(launch_dir, prior));
+            settings.paths.seed_points = settings
+                .paths
+                .seed_points
+                .as_deref()
+                .map(|seed_points| resolve_path(launch_dir, seed_points));
+        }
+        if self.resume {
+            settings.paths.prior = Some("theta.csv".to_string());
+        }
+    }
+}
+
+fn resolve_path(dir: &Path, path: &str) -> String {
+    dir.join(path).to_string_lossy().to_string()
+}
+
+/// Entrypoint for command-line programs
+///
+/// Parses the command-line arguments (see [CLI_USAGE] and [CliArgs::apply]), and runs the algorithm as [start] would.
+/// The configuration file is given as the first positional argument, and may still be overridden by environment variables, see [read_settings].
+///
+/// Returns `None` if no fit was performed, i.e. for `--dry-run` and `--help`.
+/// Invalid arguments are returned as an error including [CLI_USAGE], leaving it to the caller to exit, e.g. by returning the error from `main`.
+pub fn run_cli<S>(engine: Engine<S>) -> Result<Option<NPResult>>
+where
+    S: Predict<'static> + std::marker::Sync + std::marker::Send + 'static + Clone,
+{
+    let args = CliArgs::parse(std::env::args().skip(1))
+        .map_err(|e| eyre::eyre!("{}\n\n{}", e, CLI_USAGE))?;
+    if args.help {
+        println!("{}", CLI_USAGE);
+        return Ok(None);
+    }
+    if let Some(threads) = args.threads {
+        simulation::stats::thread_pool(threads).build_global()?;
+    }
+
+    // The configuration file is resolved before changing to the output directory
+    let launch_dir = std::env::current_dir()?;
+    let config_path = std::fs::canonicalize(&args.config)?;
+    if let Some(dir) = &args.output_dir {
+        std::fs::create_dir_all(dir)?;
+        std::env::set_current_dir(dir)?;
+    }
+    let mut settings = read_settings(config_path.to_string_lossy().to_string())?;
+    args.apply(&mut settings, &launch_dir);
+
+    if args.dry_run {
+        let scenarios = read_data(&settings)?;
+        println!("{:#?}", settings);
+        println!(
+            "Datafile contains {} subjects with a total of {} observations",
+            scenarios.len(),
+            scenarios.iter().map(|s| s.obs_times.len()).sum::<usize>()
+        );
+        for summary in datafile::covariate_summary(&scenarios, &engine.covariates()) {
+            if !summary.missing.is_empty() {
+                println!(
+                    "Warning: covariate {} is missing for subject(s): {}",
+                    summary.name,
+                    summary.missing.join(", ")
+                );
+            }
+        }
+        let names = settings.random.names();
+        for j in engine.insensitive_parameters(&scenarios, &settings.random.ranges()) {
+            println!(
+                "Warning: random parameter {} does not affect any prediction, and may be unused by the model",
+                names[j]
+            );
+        }
+        return Ok(None);
+    }
+    start_with_settings(engine, settings).map(Some)
+}
+
+/// Reads the data file of the settings, handling observations on dose rows as configured, see [datafile::parse_with]
+///
+/// Observations outside the configured observation windows are removed, see [datafile::filter_observation_windows].
+fn read_data(settings: &Settings) -> Result<Vec<Scenario>> {
+    read_data_from(&settings.paths.data, settings)
+}
+
+/// Reads the data file at `path` as [read_data] does, e.g. for a data file given to the [server](crate::server::serve)
+pub(crate) fn read_data_from(path: &str, settings: &Settings) -> Result<Vec<Scenario>> {
+    let dose_row_observations =
+        datafile::DoseRowObservations::from_name(&settings.config.dose_row_observations)
+            .map_err(|e| eyre::eyre!(e))?;
+    let mut scenarios = datafile::parse_with(path, dose_row_observations)?;
+    if settings.config.observation_windows.is_some()
+        || settings.config.subject_observation_windows.is_some()
+    {
+        let removed = datafile::filter_observation_windows(
+            &mut scenarios,
+            settings.config.observation_windows.as_deref(),
+            settings.config.subject_observation_windows.as_ref(),
+        );
+        tracing::info!(
+            "Removed {} observation(s) outside the observation windows",
+            removed
+        );
+    }
+    Ok(scenarios)
+}
+
 /// Alternative entrypoint, primarily meant for third-party libraries or APIs
 ///
 /// This entrypoint takes an `Engine` (from the model), `Data` from the settings, and `scenarios` containing dose information and observations
@@ -162,11 +514,43 @@ pub fn start_internal<S>(
 where
     S: Predict<'static> + std::marker::Sync + std::marker::Send + 'static + Clone,
 {
+    fit_internal(engine, settings, scenarios, None)
+}
+
+/// As [start_internal], but with a user-defined error model in place of the error polynomial of the settings
+///
+/// This allows error models which are neither additive nor proportional, e.g. with an error depending on the time of the observation.
+/// The error model is fixed, so gamma and lambda are not optimized, see [Algorithm::set_sigma](crate::algorithms::Algorithm::set_sigma).
+pub fn start_internal_with_sigma<S>(
+    engine: Engine<S>,
+    settings: Settings,
+    scenarios: Vec<Scenario>,
+    sigma: Box<dyn sigma::Sigma + Sync>,
+) -> Result<NPResult>
+where
+    S: Predict<'static> + std::marker::Sync + std::marker::Send + 'static + Clone,
+{
+    fit_internal(engine, settings, scenarios, Some(sigma))
+}
+
+fn fit_internal<S>(
+    engine: Engine<S>,
+    settings: Settings,
+    scenarios: Vec<Scenario>,
+    sigma: Option<Box<dyn sigma::Sigma + Sync>>,
+) -> Result<NPResult>
+where
+    S: Predict<'static> + std::marker::Sync + std::marker::Send + 'static + Clone,
+{
+    datafile::check_scenarios(&scenarios).map_err(|e| eyre::eyre!(e))?;
     let now = Instant::now();
     let (tx, rx) = mpsc::unbounded_channel::<Comm>();
     logger::setup_log(&settings, tx.clone());
 
-    let mut algorithm = initialize_algorithm(engine.clone(), settings.clone(), scenarios, tx);
+    let mut algorithm = initialize_algorithm(engine.clone(), settings.clone(), scenarios, tx)?;
+    if let Some(sigma) = sigma {
+        algorithm.set_sigma(sigma);
+    }
 
     let _ = spawn(move || {
         drop_messages(rx);
@@ -177,6 +561,290 @@ where
     Ok(result)
 }
 
+/// Predictive performance of a fit on one fold of a cross-validation, see [cross_validate]
+#[derive(Debug, Clone, PartialEq)]
+pub struct CrossValidationFold {
+    pub fold: usize,
+    /// Number of subjects the population was fitted on
+    pub n_train: usize,
+    /// Number of held-out subjects
+    pub n_test: usize,
+    /// Log-likelihood of the held-out subjects, i.e. the sum of their log marginal likelihoods under the fitted population
+    pub log_likelihood: f64,
+}
+
+/// Entrypoint for k-fold cross-validation
+///
+/// Reads the data as [start] does, see [cross_validate_internal] for details.
+/// If output is enabled, the result of each fold is written to `cross_validation.csv`.
+pub fn cross_validate<S>(
+    engine: Engine<S>,
+    settings: Settings,
+    k: usize,
+) -> Result<Vec<CrossValidationFold>>
+where
+    S: Predict<'static> + std::marker::Sync + std::marker::Send + 'static + Clone,
+{
+    let (tx, _rx) = mpsc::unbounded_channel::<Comm>();
+    logger::setup_log(&settings, tx);
+    let scenarios = read_data(&settings)?;
+    let folds = cross_validate_internal(engine, settings.clone(), scenarios, k)?;
+    if settings.config.output {
+        let mut writer = WriterBuilder::new()
+            .has_headers(false)
+            .from_path("cross_validation.csv")?;
+        writer.write_record(["fold", "n_train", "n_test", "log_likelihood"])?;
+        for fold in &folds {
+            writer.write_record(&[
+                fold.fold.to_string(),
+                fold.n_train.to_string(),
+                fold.n_test.to_string(),
+                output::format_float(fold.log_likelihood, settings.config.output_precision),
+            ])?;
+        }
+        writer.flush()?;
+    }
+    Ok(folds)
+}
+
+/// Entrypoint for fitting groups of subjects independently
+///
+/// Reads the data as [start] does, see [fit_by_group_internal] for details.
+/// If output is enabled, the population model of each group is written to `population_model_<group>.json`,
+/// and a summary of the groups to `groups.csv`.
+pub fn fit_by_group<S>(
+    engine: Engine<S>,
+    settings: Settings,
+    group_covariate: &str,
+) -> Result<Vec<(String, NPResult)>>
+where
+    S: Predict<'static> + std::marker::Sync + std::marker::Send + 'static + Clone,
+{
+    let (tx, _rx) = mpsc::unbounded_channel::<Comm>();
+    logger::setup_log(&settings, tx);
+    let scenarios = read_data(&settings)?;
+    let groups = fit_by_group_internal(engine, settings.clone(), scenarios, group_covariate)?;
+    if settings.config.output {
+        let mut writer = WriterBuilder::new()
+            .has_headers(false)
+            .from_path("groups.csv")?;
+        writer.write_record(["group", "subjects", "objf", "cycles", "converged", "nspp"])?;
+        for (group, result) in &groups {
+            let file_name: String = group
+                .chars()
+                .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+                .collect();
+            result
+                .export_population_model(&format!("population_model_{}.json", file_name))
+                .map_err(|e| eyre::eyre!("{}", e))?;
+            writer.write_record(&[
+                group.clone(),
+                result.scenarios.len().to_string(),
+                output::format_float(result.objf, settings.config.output_precision),
+                result.cycles.to_string(),
+                result.converged.to_string(),
+                result.theta.nrows().to_string(),
+            ])?;
+        }
+        writer.flush()?;
+    }
+    Ok(groups)
+}
+
+/// Fits each group of `scenarios` independently, where the groups are given by the value of the `group_covariate` column
+///
+/// The value is read from the metadata of the subjects, i.e. the first row of each subject, and every subject must have one.
+/// Each group is fitted with the same settings, as by [start_internal], and the results are returned in the order of the group values.
+/// No output files are written, and no TUI is started.
+pub fn fit_by_group_internal<S>(
+    engine: Engine<S>,
+    mut settings: Settings,
+    scenarios: Vec<Scenario>,
+    group_covariate: &str,
+) -> Result<Vec<(String, NPResult)>>
+where
+    S: Predict<'static> + std::marker::Sync + std::marker::Send + 'static + Clone,
+{
+    settings.config.output = false;
+    settings.config.tui = false;
+    let engine = engine.configure(&settings).map_err(|e| eyre::eyre!(e))?;
+
+    let mut groups: BTreeMap<String, Vec<Scenario>> = BTreeMap::new();
+    for scenario in scenarios {
+        let group = match scenario.metadata.get(group_covariate) {
+            Some(value) if !value.trim().is_empty() && value.trim() != "." => {
+                value.trim().to_string()
+            }
+            _ => eyre::bail!(
+                "Subject {} has no value of the group covariate '{}'",
+                scenario.id,
+                group_covariate
+            ),
+        };
+        groups.entry(group).or_default().push(scenario);
+    }
+
+    let mut results = Vec::with_capacity(groups.len());
+    for (group, scenarios) in groups {
+        datafile::check_scenarios(&scenarios).map_err(|e| eyre::eyre!(e))?;
+        tracing::info!(
+            "Fitting group {} = {} with {} subject(s)",
+            group_covariate,
+            group,
+            scenarios.len()
+        );
+        // The cache is keyed by the index of the subject, so predictions cached for another group are stale
+        if settings.config.cache {
+            predict::clear_cache();
+        }
+        let (tx, _rx) = mpsc::unbounded_channel::<Comm>();
+        let result = initialize_algorithm(engine.clone(), settings.clone(), scenarios, tx)?.fit();
+        results.push((group, result));
+    }
+    Ok(results)
+}
+
+/// Performs k-fold cross-validation on `scenarios`
+///
+/// The subjects are shuffled, using the seed of the settings, and partitioned into `k` folds of (nearly) equal size.
+/// For each fold, the population is fitted on the other folds, and the held-out subjects are evaluated under the fitted population,
+/// using the final support points, weights and error model of the fit. No output files are written, and no TUI is started.
+pub fn cross_validate_internal<S>(
+    engine: Engine<S>,
+    mut settings: Settings,
+    scenarios: Vec<Scenario>,
+    k: usize,
+) -> Result<Vec<CrossValidationFold>>
+where
+    S: Predict<'static> + std::marker::Sync + std::marker::Send + 'static + Clone,
+{
+    if k < 2 || k > scenarios.len() {
+        eyre::bail!(
+            "Cross-validation requires between 2 and {} folds, got {}",
+            scenarios.len(),
+            k
+        );
+    }
+    settings.config.output = false;
+    settings.config.tui = false;
+    let engine = engine.configure(&settings).map_err(|e| eyre::eyre!(e))?;
+
+    let mut order: Vec<usize> = (0..scenarios.len()).collect();
+    order.shuffle(&mut StdRng::seed_from_u64(settings.config.seed as u64));
+
+    let mut folds = Vec::with_capacity(k);
+    for fold in 0..k {
+        let (test, train): (Vec<usize>, Vec<usize>) =
+            (0..order.len()).partition(|position| position % k == fold);
+        let select = |positions: &[usize]| -> Vec<Scenario> {
+            positions
+                .iter()
+                .map(|&position| scenarios[order[position]].clone())
+                .collect()
+        };
+        let (train, test) = (select(&train), select(&test));
+
+        // The cache is keyed by the index of the subject, so predictions cached for another fold are stale
+        if settings.config.cache {
+            predict::clear_cache();
+        }
+        let (tx, _rx) = mpsc::unbounded_channel::<Comm>();
+        let result =
+            initialize_algorithm(engine.clone(), settings.clone(), train.clone(), tx)?.fit();
+
+        let model = output::PopulationModel::from(&result);
+        let log_likelihood =
+            marginal_log_likelihood(&engine, &test, &result.theta, &result.w, &model.error)?;
+        tracing::info!(
+            "Fold {} of {}: predictive log-likelihood of {} held-out subject(s) is {:.4}",
+            fold + 1,
+            k,
+            test.len(),
+            log_likelihood
+        );
+        folds.push(CrossValidationFold {
+            fold: fold + 1,
+            n_train: train.len(),
+            n_test: test.len(),
+            log_likelihood,
+        });
+    }
+    Ok(folds)
+}
+
+/// Evaluates the population log-likelihood of an external parameter distribution on the data given in the settings
+///
+/// See [evaluate_distribution_internal] for details.
+pub fn evaluate_distribution<S>(
+    engine: Engine<S>,
+    settings: Settings,
+    theta: Array2<f64>,
+    weights: Array1<f64>,
+) -> Result<f64>
+where
+    S: Predict<'static> + std::marker::Sync + std::marker::Send + 'static + Clone,
+{
+    let scenarios = read_data(&settings)?;
+    evaluate_distribution_internal(engine, settings, scenarios, theta, weights)
+}
+
+/// Evaluates the population log-likelihood of a parameter distribution on `scenarios`, e.g. a distribution estimated by another tool
+///
+/// The distribution is given by the support points `theta`, with one column per random parameter in the order of the settings,
+/// and their `weights`, which are normalized to sum to one. The error model is taken from the settings, with `error.value` as gamma or lambda.
+/// Returns the sum over subjects of the log of their marginal likelihood, so the result is comparable to -1/2 times the objective function of a fit.
+pub fn evaluate_distribution_internal<S>(
+    engine: Engine<S>,
+    settings: Settings,
+    scenarios: Vec<Scenario>,
+    theta: Array2<f64>,
+    weights: Array1<f64>,
+) -> Result<f64>
+where
+    S: Predict<'static> + std::marker::Sync + std::marker::Send + 'static + Clone,
+{
+    if theta.nrows() != weights.len() {
+        eyre::bail!(
+            "The distribution has {} support points, but {} weights",
+            theta.nrows(),
+            weights.len()
+        );
+    }
+    if theta.ncols() != settings.random.parameters.len() {
+        eyre::bail!(
+            "The support points have {} parameters, but there are {} random parameters",
+            theta.ncols(),
+            settings.random.parameters.len()
+        );
+    }
+    if weights.iter().any(|w| w.is_nan() || *w < 0.0) || weights.sum() <= 0.0 {
+        eyre::bail!("The weights must be non-negative, and not all zero");
+    }
+    let weights = &weights / weights.sum();
+    let engine = engine.configure(&settings).map_err(|e| eyre::eyre!(e))?;
+    marginal_log_likelihood(&engine, &scenarios, &theta, &weights, &settings.error)
+}
+
+/// Sum of the log marginal likelihoods of `scenarios` under the distribution given by `theta` and `weights`
+fn marginal_log_likelihood<S>(
+    engine: &Engine<S>,
+    scenarios: &[Scenario],
+    theta: &Array2<f64>,
+    weights: &Array1<f64>,
+    error: &Error,
+) -> Result<f64>
+where
+    S: Predict<'static> + std::marker::Sync + Clone,
+{
+    let error_type = sigma::ErrorType::from_class(&error.class).map_err(|e| eyre::eyre!(e))?;
+    let sigma = sigma::ErrorPoly::new(error.poly, error.value, &error_type)
+        .with_reference(error.reference)
+        .with_df(error.df)
+        .with_transform(error.transform());
+    let psi = prob::simulate_psi(engine, scenarios, theta, &[&sigma], false, None).remove(0);
+    Ok(psi.dot(weights).mapv(f64::ln).sum())
+}
+
 fn drop_messages(mut rx: mpsc::UnboundedReceiver<Comm>) {
     loop {
         match rx.try_recv() {
@@ -10,6 +10,9 @@ pub mod routines {
     }
     pub mod output;
     pub mod condensation {
+        pub mod fallback;
+        pub mod frozen;
+        pub mod order;
         pub mod prune;
     }
     pub mod expansion {
@@ -17,26 +20,42 @@ pub mod routines {
     }
 
     pub mod settings;
+    pub mod timing;
+    pub mod warnings;
     pub mod evaluation {
 
+        pub mod convergence;
         pub mod ipm;
         pub mod prob;
         pub mod qr;
         pub mod sigma;
     }
     pub mod simulation {
+        pub mod covariates;
+        pub mod parametric;
         pub mod predict;
+        pub mod stats;
     }
 }
 pub mod entrypoints;
 pub mod logger;
+#[cfg(feature = "server")]
+pub mod server;
 pub mod tui;
 
 pub mod prelude {
     pub use crate::algorithms;
+    pub use crate::entrypoints::cross_validate;
+    pub use crate::entrypoints::cross_validate_internal;
+    pub use crate::entrypoints::evaluate_distribution;
+    pub use crate::entrypoints::evaluate_distribution_internal;
+    pub use crate::entrypoints::fit_by_group;
+    pub use crate::entrypoints::fit_by_group_internal;
+    pub use crate::entrypoints::run_cli;
     pub use crate::entrypoints::simulate;
     pub use crate::entrypoints::start;
     pub use crate::entrypoints::start_internal;
+    pub use crate::entrypoints::start_internal_with_sigma;
     pub use crate::logger;
     pub use crate::prelude::evaluation::{prob, sigma, *};
     pub use crate::routines::condensation;
@@ -45,6 +64,8 @@ pub mod prelude {
     pub use crate::routines::optimization;
     pub use crate::routines::simulation::*;
     pub use crate::routines::*;
+    #[cfg(feature = "server")]
+    pub use crate::server::serve;
     pub use crate::tui::ui::*;
 }
 
@@ -0,0 +1,20 @@
+use ndarray::Array1;
+
+/// Retains the support point with the highest weight if the keep-set is empty, returning true if it did
+///
+/// In a pathological cycle, the lambda filter or the QR factorization may drop every support point, which would leave an empty grid.
+/// `weights` are indexed like the keep-set, i.e. by the support points before the filter. Weights which are NaN are never preferred,
+/// and if every weight is NaN, the first support point is retained. Nothing is retained if there are no support points.
+pub fn retain_highest_weight(keep: &mut Vec<usize>, weights: &Array1<f64>) -> bool {
+    if !keep.is_empty() || weights.is_empty() {
+        return false;
+    }
+    let best = weights
+        .iter()
+        .enumerate()
+        .filter(|(_, weight)| !weight.is_nan())
+        .max_by(|(_, a), (_, b)| a.total_cmp(b))
+        .map_or(0, |(index, _)| index);
+    keep.push(best);
+    true
+}
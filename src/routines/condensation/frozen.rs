@@ -0,0 +1,58 @@
+use ndarray::{Array2, ArrayView1};
+
+/// Relative tolerance used when matching support points to frozen coordinates
+const TOLERANCE: f64 = 1e-10;
+
+/// Support points which are protected from condensation
+///
+/// Frozen points are never removed by the lambda or QR filters, and are (re-)inserted into the grid if missing.
+/// Otherwise they are treated as regular support points, i.e. the IPM still estimates a weight for them, which may be close to zero.
+/// The coordinates are given in the same order as the random parameters, see `settings::Random::names`.
+#[derive(Debug, Clone, Default)]
+pub struct FrozenPoints {
+    points: Vec<Vec<f64>>,
+}
+
+impl FrozenPoints {
+    pub fn new(points: Vec<Vec<f64>>) -> Self {
+        Self { points }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.points.is_empty()
+    }
+
+    /// Returns true if the support point matches any of the frozen points
+    pub fn contains(&self, spp: ArrayView1<f64>) -> bool {
+        self.points.iter().any(|point| matches(point, spp))
+    }
+
+    /// Adds the indices of all frozen support points in `theta` to the keep-set, if not already present
+    pub fn protect(&self, theta: &Array2<f64>, keep: &mut Vec<usize>) {
+        if self.is_empty() {
+            return;
+        }
+        for (index, spp) in theta.rows().into_iter().enumerate() {
+            if self.contains(spp) && !keep.contains(&index) {
+                keep.push(index);
+            }
+        }
+    }
+
+    /// Appends any frozen point which is not present in `theta`
+    pub fn insert_missing(&self, theta: &mut Array2<f64>) {
+        for point in &self.points {
+            if !theta.rows().into_iter().any(|spp| matches(point, spp)) {
+                theta.push_row(ArrayView1::from(point)).unwrap();
+            }
+        }
+    }
+}
+
+fn matches(point: &[f64], spp: ArrayView1<f64>) -> bool {
+    point.len() == spp.len()
+        && point
+            .iter()
+            .zip(spp.iter())
+            .all(|(a, b)| (a - b).abs() <= TOLERANCE * a.abs().max(1.0))
+}
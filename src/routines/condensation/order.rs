@@ -0,0 +1,21 @@
+use ndarray::Array2;
+use std::cmp::Ordering;
+
+/// Sorts the indices of a keep-set by the coordinates of their support points in `theta`, in lexicographic order
+///
+/// Condensation is applied to the support points in the order of the keep-set. In particular, when support points are tied,
+/// e.g. with identical likelihoods, the column pivoting of the QR factorization keeps the first of them, see `qr::keep_independent`.
+/// Sorting the keep-set makes the retained support points independent of the order of the grid, such that identical runs retain identical points.
+/// Support points with identical coordinates are ordered by index.
+pub fn sort_lexicographic(theta: &Array2<f64>, keep: &mut [usize]) {
+    keep.sort_by(|&a, &b| {
+        theta
+            .row(a)
+            .iter()
+            .zip(theta.row(b).iter())
+            .map(|(x, y)| x.total_cmp(y))
+            .find(|ordering| *ordering != Ordering::Equal)
+            .unwrap_or(Ordering::Equal)
+            .then(a.cmp(&b))
+    });
+}
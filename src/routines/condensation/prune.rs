@@ -9,7 +9,10 @@ pub fn prune(
     for spp in theta.rows() {
         let mut dist: f64 = 0.;
         for (i, val) in candidate.clone().into_iter().enumerate() {
-            dist += (val - spp.get(i).unwrap()).abs() / (limits[i].1 - limits[i].0);
+            // Parameters with equal bounds are held constant, and do not contribute to the distance
+            if limits[i].1 > limits[i].0 {
+                dist += (val - spp.get(i).unwrap()).abs() / (limits[i].1 - limits[i].0);
+            }
         }
         if dist <= min_dist {
             return;
@@ -1,9 +1,98 @@
+use flate2::read::MultiGzDecoder;
+use rand::{rngs::StdRng, seq::SliceRandom, SeedableRng};
 use std::cmp::Ordering;
 use std::collections::HashMap;
 use std::error::Error;
-use std::process::exit;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read};
 
 type Record = HashMap<String, String>;
+/// Lower and upper bound of an interval-censored observation, see [Event::interval]
+pub type Interval = (f64, f64);
+
+/// Columns which every data file must have, see [parse]
+const REQUIRED_COLUMNS: [&str; 14] = [
+    "ID", "EVID", "TIME", "DUR", "DOSE", "ADDL", "II", "INPUT", "OUT", "OUTEQ", "C0", "C1", "C2",
+    "C3",
+];
+
+/// Error while reading a data file, see [parse]
+#[derive(Debug)]
+pub enum DatafileError {
+    /// The data file could not be opened or read
+    Io { path: String, error: std::io::Error },
+    /// The data file is not valid CSV, e.g. rows with a different number of fields than the header
+    Csv(csv::Error),
+    /// The data is not valid, e.g. a missing column or an invalid value, with the line and column of the value if known
+    Validation {
+        line: Option<u64>,
+        column: Option<String>,
+        message: String,
+    },
+    /// The data file is empty, without even a header
+    Empty,
+}
+
+impl DatafileError {
+    fn invalid(line: Option<u64>, column: &str, message: String) -> Self {
+        DatafileError::Validation {
+            line,
+            column: Some(column.to_string()),
+            message,
+        }
+    }
+}
+
+impl std::fmt::Display for DatafileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DatafileError::Io { path, error } => {
+                write!(f, "Unable to read data file {}: {}", path, error)
+            }
+            DatafileError::Csv(error) => write!(f, "Invalid CSV in the data file: {}", error),
+            DatafileError::Validation {
+                line,
+                column,
+                message,
+            } => match (line, column) {
+                (Some(line), Some(column)) => {
+                    write!(f, "Line {}, column {}: {}", line, column, message)
+                }
+                (Some(line), None) => write!(f, "Line {}: {}", line, message),
+                (None, Some(column)) => write!(f, "Column {}: {}", column, message),
+                (None, None) => write!(f, "{}", message),
+            },
+            DatafileError::Empty => write!(f, "The data file is empty"),
+        }
+    }
+}
+
+impl Error for DatafileError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            DatafileError::Io { error, .. } => Some(error),
+            DatafileError::Csv(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<csv::Error> for DatafileError {
+    fn from(error: csv::Error) -> Self {
+        DatafileError::Csv(error)
+    }
+}
+
+/// Errors without a position in the file, e.g. of the events of a subject
+impl From<String> for DatafileError {
+    fn from(message: String) -> Self {
+        DatafileError::Validation {
+            line: None,
+            column: None,
+            message,
+        }
+    }
+}
 
 /// A Scenario is a collection of blocks that represent a single subject in the datafile
 /// Each block is a collection of events that represent a single dose, possibly followed by observations
@@ -14,6 +103,10 @@ pub struct Scenario {
     pub obs: Vec<f64>,
     pub obs_times: Vec<f64>,
     pub times: Vec<f64>,
+    /// Interval of each of the observations which are interval-censored, aligned with `obs`, see [Event::interval]
+    pub obs_intervals: Vec<Option<Interval>>,
+    /// Values of the unrecognized columns in the first row of the subject, which are echoed into the per-subject outputs, see [parse]
+    pub metadata: HashMap<String, String>,
 }
 
 impl Scenario {
@@ -23,9 +116,36 @@ impl Scenario {
         Ok(scenario)
     }
 
+    /// Builds the scenario of the subject `id` from its events, e.g. to generate data in code rather than reading it with [parse]
+    ///
+    /// The events are validated as by [parse]: the ID of each event must be `id`, additional doses are expanded, see [expand_additional_doses],
+    /// and doses, infusions and observations must have the required fields. The events are (stably) sorted by time,
+    /// and the observations, blocks and covariate lines are derived as for a parsed subject. The scenario has no metadata.
+    pub fn from_events(id: &str, mut events: Vec<Event>) -> Result<Self, Box<dyn Error>> {
+        if events.is_empty() {
+            return Err(format!("Subject {} has no events", id).into());
+        }
+        if let Some(event) = events.iter().find(|event| event.id != id) {
+            return Err(format!(
+                "The event at time {} belongs to subject {}, not {}",
+                event.time, event.id, id
+            )
+            .into());
+        }
+        if let Some(event) = events.iter().find(|event| !event.time.is_finite()) {
+            return Err(format!("Subject {} has an event at time {}", id, event.time).into());
+        }
+        expand_additional_doses(&mut events)?;
+        events.sort_by(|a, b| a.cmp_by_id_then_time(b));
+        Self::new(events)
+    }
+
     /// Adds "mock" events to a Scenario in order to generate predictions at those times
     /// The interval is mapped to the `idelta`-setting in the configuration file
-    /// Time after dose (`tad`) will ensure that predictions are made until the last dose + tad
+    ///
+    /// Predictions are made until the end time, which is the time of the last event, or the last dose + time after dose (`tad`) if that is later.
+    /// Predictions are added at every multiple of the interval after the first event, up to and including the end time, and at the end time itself.
+    /// If the interval is not positive, only the prediction at the end time is added.
     pub fn add_event_interval(&self, interval: f64, tad: f64) -> Self {
         // Clone the underlying Event data instead of the references
         let all_events = self
@@ -58,32 +178,33 @@ impl Scenario {
         outeqs.sort_unstable();
         outeqs.dedup();
 
+        // Prediction times on multiples of the interval, rounded to 4 decimal places, and the end time
+        let time_tolerance = TIME_TOLERANCE;
+        let mut times = vec![];
+        if interval > 0.0 {
+            let mut step = (start_time / interval).floor() + 1.0;
+            loop {
+                let time = decimals(step * interval, 4);
+                if time > end_time + time_tolerance {
+                    break;
+                }
+                times.push(time);
+                step += 1.0;
+            }
+        }
+        if times
+            .last()
+            .is_none_or(|&last| (last - end_time).abs() >= time_tolerance)
+        {
+            times.push(end_time);
+        }
+
         // Generate dummy events
         let mut new_events = vec![];
-        let mut current_time = start_time + interval; // Start from the first interval after the start time
-        while current_time <= end_time {
-            current_time = (current_time / interval).round() * interval; // Round to the nearest interval
-            current_time = decimals(current_time, 4); // Round to 4 decimal places
+        for current_time in times {
             for outeq in &outeqs {
-                new_events.push(Event {
-                    id: self.id.clone(),
-                    evid: 0,
-                    time: current_time,
-                    dur: None,
-                    dose: None,
-                    _addl: None,
-                    _ii: None,
-                    input: None,
-                    out: Some(-99.0),
-                    outeq: Some(*outeq),
-                    _c0: None,
-                    _c1: None,
-                    _c2: None,
-                    _c3: None,
-                    covs: HashMap::new(),
-                });
+                new_events.push(self.mock_event(current_time, *outeq));
             }
-            current_time += interval;
         }
 
         // Combine all_events with new_events
@@ -94,13 +215,183 @@ impl Scenario {
         combined_events.sort_by(|a, b| a.cmp_by_id_then_time(b));
         // Remove duplicate events based on time and outeq
         // In essence, no need to have two predictions at the same time for the same outeq
-        let time_tolerance = 1e-4;
         combined_events.sort_by(|a, b| a.cmp_by_id_then_time(b));
         combined_events.dedup_by(|a, b| {
             (a.time - b.time).abs() < time_tolerance && a.outeq == b.outeq && a.evid == b.evid
         });
 
-        Scenario::new(combined_events).unwrap()
+        let mut scenario = Scenario::new(combined_events).unwrap();
+        scenario.metadata = self.metadata.clone();
+        scenario
+    }
+
+    /// Replaces the observations of a Scenario with "mock" events at the given prediction times
+    ///
+    /// Predictions are made for each output equation observed in the scenario, or for output equation 1 if there are no observations.
+    /// Unlike [Scenario::add_event_interval], the observations themselves are not predicted, so all scenarios are predicted at the same times.
+    pub fn with_prediction_times(&self, times: &[f64]) -> Self {
+        let all_events = self
+            .blocks
+            .iter()
+            .flat_map(|block| block.events.iter().cloned());
+        let mut outeqs: Vec<usize> = all_events.clone().filter_map(|event| event.outeq).collect();
+        outeqs.sort_unstable();
+        outeqs.dedup();
+        if outeqs.is_empty() {
+            outeqs.push(1);
+        }
+
+        let mut events: Vec<Event> = all_events.filter(|event| event.evid != 0).collect();
+        for &time in times {
+            for &outeq in &outeqs {
+                events.push(self.mock_event(time, outeq));
+            }
+        }
+        events.sort_by(|a, b| a.cmp_by_id_then_time(b));
+
+        let mut scenario = Scenario::new(events).unwrap();
+        scenario.metadata = self.metadata.clone();
+        scenario
+    }
+
+    /// Removes the observations outside the time `windows`, and returns the scenario with the number of removed observations
+    ///
+    /// Each window is an inclusive range of times `(lower, upper)`, and an observation is kept if it is within any of the windows. Doses are always kept.
+    pub fn within_windows(&self, windows: &[(f64, f64)]) -> (Self, usize) {
+        let within = |time: f64| {
+            windows
+                .iter()
+                .any(|&(lower, upper)| lower <= time && time <= upper)
+        };
+        let keep: Vec<bool> = self.obs_times.iter().map(|&time| within(time)).collect();
+        let removed = keep.iter().filter(|&&keep| !keep).count();
+        if removed == 0 {
+            return (self.clone(), 0);
+        }
+        let events: Vec<Event> = self
+            .blocks
+            .iter()
+            .flat_map(|block| block.events.iter().cloned())
+            .filter(|event| event.evid != 0 || within(event.time))
+            .collect();
+        let mut scenario = Scenario::new(events).unwrap();
+        // The observations are taken from the scenario rather than the events, in case they were replaced, e.g. by simulated values
+        scenario.obs.clear();
+        scenario.obs_intervals.clear();
+        for ((&obs, &interval), &keep) in self.obs.iter().zip(&self.obs_intervals).zip(&keep) {
+            if keep {
+                scenario.obs.push(obs);
+                scenario.obs_intervals.push(interval);
+            }
+        }
+        scenario.metadata = self.metadata.clone();
+        (scenario, removed)
+    }
+
+    /// Adds "mock" events at the time of each dose, so the predictions include a row for every dose, see [Scenario::obs_annotations]
+    ///
+    /// Predictions are added for each output equation of the scenario, unless the scenario already has an observation of that output equation at the time of the dose.
+    pub fn add_dose_times(&self) -> Self {
+        let all_events: Vec<Event> = self
+            .blocks
+            .iter()
+            .flat_map(|block| block.events.iter().cloned())
+            .collect();
+        let mut outeqs: Vec<usize> = all_events.iter().filter_map(|event| event.outeq).collect();
+        outeqs.sort_unstable();
+        outeqs.dedup();
+
+        let mut new_events = vec![];
+        for dose in all_events.iter().filter(|event| event.evid == 1) {
+            for &outeq in &outeqs {
+                let observed = all_events.iter().any(|event| {
+                    event.evid == 0
+                        && event.outeq == Some(outeq)
+                        && (event.time - dose.time).abs() < TIME_TOLERANCE
+                });
+                let added = new_events.iter().any(|event: &Event| {
+                    event.outeq == Some(outeq) && (event.time - dose.time).abs() < TIME_TOLERANCE
+                });
+                if !observed && !added {
+                    new_events.push(self.mock_event(dose.time, outeq));
+                }
+            }
+        }
+        let mut events = all_events;
+        events.extend(new_events);
+        events.sort_by(|a, b| a.cmp_by_id_then_time(b));
+
+        let mut scenario = Scenario::new(events).unwrap();
+        scenario.metadata = self.metadata.clone();
+        scenario
+    }
+
+    /// The output equation of each observation of the scenario, aligned with `obs`, where observations without one are of output equation 1
+    pub fn obs_outeqs(&self) -> Vec<usize> {
+        self.blocks
+            .iter()
+            .flat_map(|block| block.events.iter())
+            .filter(|event| event.evid == 0)
+            .map(|event| event.outeq.unwrap_or(1))
+            .collect()
+    }
+
+    /// The type of each observation of the scenario, and the amount of the most recent dose at or before it, if any
+    ///
+    /// An observation is an [EventType::Observation] if it has an observed value, i.e. is not -99 as the "mock" events of e.g. [Scenario::add_event_interval],
+    /// otherwise an [EventType::Dose] if it is at the time of a dose, and an [EventType::Interpolated] point if not.
+    /// Of several doses at the same time, the last one in the data is the most recent.
+    pub fn obs_annotations(&self) -> Vec<(EventType, Option<f64>)> {
+        let doses: Vec<(f64, Option<f64>)> = self
+            .blocks
+            .iter()
+            .flat_map(|block| block.events.iter())
+            .filter(|event| event.evid == 1)
+            .map(|event| (event.time, event.dose))
+            .collect();
+        self.obs_times
+            .iter()
+            .zip(self.obs.iter())
+            .map(|(&time, &obs)| {
+                let event_type = if obs != -99.0 {
+                    EventType::Observation
+                } else if doses
+                    .iter()
+                    .any(|(dose_time, _)| (dose_time - time).abs() < TIME_TOLERANCE)
+                {
+                    EventType::Dose
+                } else {
+                    EventType::Interpolated
+                };
+                let last_dose = doses
+                    .iter()
+                    .rfind(|(dose_time, _)| *dose_time <= time + TIME_TOLERANCE)
+                    .and_then(|(_, dose)| *dose);
+                (event_type, last_dose)
+            })
+            .collect()
+    }
+
+    /// A "mock" observation of the output equation `outeq` at `time`, i.e. without an observed value, used to generate predictions
+    fn mock_event(&self, time: f64, outeq: usize) -> Event {
+        Event {
+            id: self.id.clone(),
+            evid: 0,
+            time,
+            dur: None,
+            dose: None,
+            addl: None,
+            ii: None,
+            input: None,
+            out: Some(-99.0),
+            interval: None,
+            outeq: Some(outeq),
+            _c0: None,
+            _c1: None,
+            _c2: None,
+            _c3: None,
+            covs: HashMap::new(),
+        }
     }
 
     pub fn reorder_with_lag(&self, lag_inputs: Vec<(f64, usize)>) -> Self {
@@ -124,6 +415,7 @@ impl Scenario {
 
         let mut scenario = Self::parse_events(events).unwrap();
         scenario.inyect_covariates_regressions();
+        scenario.metadata = self.metadata.clone();
         scenario
     }
 
@@ -135,6 +427,7 @@ impl Scenario {
             covs: HashMap::new(),
         };
         let mut obs: Vec<f64> = vec![];
+        let mut obs_intervals: Vec<Option<Interval>> = vec![];
         let mut times: Vec<f64> = vec![];
         let mut obs_times: Vec<f64> = vec![];
 
@@ -143,8 +436,10 @@ impl Scenario {
             //Covariate forward filling
             for (key, val) in &mut event.covs {
                 if val.is_none() {
-                    //This will fail if for example the block is empty, Doses always must have the cov values
-                    *val = *block.events.last().unwrap().covs.get(key).unwrap();
+                    // Values missing from the first event of a subject remain missing, see `covariate_summary`
+                    if let Some(last) = block.events.last() {
+                        *val = last.covs.get(key).copied().flatten();
+                    }
                 }
             }
             if event.evid == 1 {
@@ -166,9 +461,14 @@ impl Scenario {
                 check_obs(&event)?;
                 obs_times.push(event.time);
                 obs.push(event.out.unwrap());
+                obs_intervals.push(event.interval);
             } else {
-                tracing::error!("Error: Unsupported evid: {evid}", evid = event.evid);
-                exit(-1);
+                // EVID 2 (other event), 3 (reset) and 4 (reset and dose) are not implemented, and must not be silently dropped
+                return Err(format!(
+                    "Subject {} has an event at time {} with unsupported EVID {}, only observations (EVID 0) and doses (EVID 1) are supported",
+                    event.id, event.time, event.evid
+                )
+                .into());
             }
             block.events.push(event);
         }
@@ -181,38 +481,72 @@ impl Scenario {
             obs,
             obs_times,
             times,
+            obs_intervals,
+            metadata: HashMap::new(),
         })
     }
 
+    /// Sets the covariates of each block to lines through the values at the start of the block and at the start of the next block
+    ///
+    /// The values are taken from the first event of each block, i.e. the dose starting the block, where values missing from an event are
+    /// carried forward from the previous event, see [Scenario::parse_events]. The covariates may thus be given on dose rows, observation rows, or both.
+    /// Blocks before the first value of a covariate, e.g. observations before the first dose when the covariate is only given on dose rows,
+    /// or observations before the first measurement of the covariate, use the first value of the subject. The last block is constant.
+    /// Lines are not extrapolated before the start of their block, see [CovLine::interp]. Covariates without any value are left out.
     fn inyect_covariates_regressions(&mut self) {
+        let mut first_values: HashMap<String, f64> = HashMap::new();
+        for event in self.blocks.iter().flat_map(|block| block.events.iter()) {
+            for (key, value) in &event.covs {
+                if let Some(value) = value {
+                    first_values.entry(key.clone()).or_insert(*value);
+                }
+            }
+        }
+        let value_at_start = |block: &Block, key: &String| -> Option<f64> {
+            block
+                .events
+                .first()
+                .unwrap()
+                .covs
+                .get(key)
+                .copied()
+                .flatten()
+                .or_else(|| first_values.get(key).copied())
+        };
         let mut b_it = self.blocks.iter_mut().peekable();
         while let Some(block) = b_it.next() {
             let mut block_covs: HashMap<String, CovLine> = HashMap::new();
             if let Some(next_block) = b_it.peek() {
-                for (key, p_v) in &block.events.first().unwrap().covs {
-                    let p_v = p_v.unwrap();
+                for key in block.events.first().unwrap().covs.keys() {
+                    // Missing covariates are left out of the block
+                    let Some(p_v) = value_at_start(block, key) else {
+                        continue;
+                    };
                     let p_t = block.events.first().unwrap().time;
-                    let f_v = next_block
-                        .events
-                        .first()
-                        .unwrap()
-                        .covs
-                        .get(key)
-                        .unwrap()
-                        .unwrap();
+                    let f_v = value_at_start(next_block, key).unwrap_or(p_v);
                     let f_t = next_block.events.first().unwrap().time;
                     let slope = (f_v - p_v) / (f_t - p_t);
                     let intercept = p_v - slope * p_t;
-                    block_covs.insert(key.clone(), CovLine { intercept, slope });
+                    block_covs.insert(
+                        key.clone(),
+                        CovLine {
+                            intercept,
+                            slope,
+                            start: p_t,
+                        },
+                    );
                 }
             } else {
-                for (key, p_v) in &block.events.first().unwrap().covs {
-                    let p_v = p_v.unwrap();
+                for key in block.events.first().unwrap().covs.keys() {
+                    let Some(p_v) = value_at_start(block, key) else {
+                        continue;
+                    };
                     block_covs.insert(
                         key.clone(),
                         CovLine {
                             intercept: p_v,
                             slope: 0.0,
+                            start: block.events.first().unwrap().time,
                         },
                     );
                 }
@@ -221,6 +555,151 @@ impl Scenario {
         }
     }
 }
+/// Summary of a covariate across the subjects of a dataset, see [covariate_summary]
+#[derive(Debug, Clone, PartialEq)]
+pub struct CovariateSummary {
+    pub name: String,
+    /// Number of subjects with at least one value of the covariate
+    pub n_subjects: usize,
+    /// Range of the values of the covariate, `None` if no subject has a value
+    pub range: Option<(f64, f64)>,
+    /// Subjects without any value of the covariate
+    pub missing: Vec<String>,
+}
+
+/// Checks that the data contains at least one subject with at least one observation, i.e. that there is something to fit
+///
+/// Missing observations, coded as -99, do not count.
+pub fn check_scenarios(scenarios: &[Scenario]) -> Result<(), String> {
+    if scenarios.is_empty() {
+        return Err("The data contains no subjects".to_string());
+    }
+    if !scenarios
+        .iter()
+        .flat_map(|scenario| scenario.obs.iter())
+        .any(|obs| *obs != -99.0)
+    {
+        return Err(format!(
+            "The data contains {} subject(s), but no observations",
+            scenarios.len()
+        ));
+    }
+    Ok(())
+}
+
+/// Summarizes the covariates in the data, and the covariates `required` by the model which are not in the data
+///
+/// Covariates are sorted by name. A subject is missing a covariate if none of its events have a value for it.
+pub fn covariate_summary(scenarios: &[Scenario], required: &[String]) -> Vec<CovariateSummary> {
+    let mut names: Vec<String> = scenarios
+        .iter()
+        .flat_map(|scenario| scenario.blocks.iter().flat_map(|block| block.events.iter()))
+        .flat_map(|event| event.covs.keys().cloned())
+        .chain(required.iter().cloned())
+        .collect();
+    names.sort();
+    names.dedup();
+
+    names
+        .into_iter()
+        .map(|name| {
+            let mut summary = CovariateSummary {
+                name: name.clone(),
+                n_subjects: 0,
+                range: None,
+                missing: vec![],
+            };
+            for scenario in scenarios {
+                let values: Vec<f64> = scenario
+                    .blocks
+                    .iter()
+                    .flat_map(|block| block.events.iter())
+                    .filter_map(|event| event.covs.get(&name).copied().flatten())
+                    .collect();
+                if values.is_empty() {
+                    summary.missing.push(scenario.id.clone());
+                    continue;
+                }
+                summary.n_subjects += 1;
+                for value in values {
+                    summary.range = Some(match summary.range {
+                        Some((min, max)) => (min.min(value), max.max(value)),
+                        None => (value, value),
+                    });
+                }
+            }
+            summary
+        })
+        .collect()
+}
+
+/// Shuffles the subjects reproducibly with `seed`, and returns the permutation as the original index of each subject
+///
+/// Fits should not depend on the order of the subjects, beyond floating point differences, so this is a debugging tool for order dependence.
+pub fn shuffle(scenarios: &mut Vec<Scenario>, seed: u64) -> Vec<usize> {
+    let mut permutation: Vec<usize> = (0..scenarios.len()).collect();
+    permutation.shuffle(&mut StdRng::seed_from_u64(seed));
+    *scenarios = permutation.iter().map(|&i| scenarios[i].clone()).collect();
+    permutation
+}
+
+/// Removes the observations outside the time windows of each subject, see [Scenario::within_windows], and returns the number of removed observations
+///
+/// The windows of a subject are those in `subject_windows`, if any, and otherwise the `windows` of all subjects. Subjects without any windows are kept as they are.
+pub fn filter_observation_windows(
+    scenarios: &mut [Scenario],
+    windows: Option<&[(f64, f64)]>,
+    subject_windows: Option<&HashMap<String, Vec<(f64, f64)>>>,
+) -> usize {
+    let mut removed = 0;
+    for scenario in scenarios.iter_mut() {
+        let windows = subject_windows
+            .and_then(|subject_windows| subject_windows.get(&scenario.id))
+            .map(Vec::as_slice)
+            .or(windows);
+        if let Some(windows) = windows {
+            let (filtered, n) = scenario.within_windows(windows);
+            *scenario = filtered;
+            removed += n;
+        }
+    }
+    removed
+}
+
+/// Logs the number of subjects and observations, and a summary of the covariates, see [covariate_summary]
+///
+/// Subjects missing a covariate are logged as a warning.
+pub fn log_summary(scenarios: &[Scenario], required: &[String]) {
+    tracing::info!(
+        "Datafile contains {} subjects with a total of {} observations",
+        scenarios.len(),
+        scenarios.iter().map(|s| s.obs_times.len()).sum::<usize>()
+    );
+    for summary in covariate_summary(scenarios, required) {
+        match summary.range {
+            Some((min, max)) => tracing::info!(
+                "Covariate {} is present for {} subjects, ranging from {} to {}",
+                summary.name,
+                summary.n_subjects,
+                min,
+                max
+            ),
+            None => tracing::info!("Covariate {} is not present in the data", summary.name),
+        }
+        if !summary.missing.is_empty() {
+            tracing::warn!(
+                "Covariate {} is missing for subject(s): {}",
+                summary.name,
+                summary.missing.join(", ")
+            );
+        }
+    }
+}
+
+/// An infusion of `amount` into `compartment`, at a constant rate from `time` to `time + dur`
+///
+/// The rate should be active on the half-open interval `[time, time + dur)`. Integration is restarted at the end of each infusion,
+/// so the interval which starts at the end of an infusion does not see its rate.
 #[derive(Debug, Clone)]
 pub struct Infusion {
     pub time: f64,
@@ -234,15 +713,19 @@ pub struct Dose {
     pub amount: f64,
     pub compartment: usize,
 }
+/// Line of a covariate over a block, see [Scenario::inyect_covariates_regressions]
 #[derive(Debug, Clone)]
 pub struct CovLine {
     slope: f64,
     intercept: f64,
+    /// Time of the start of the block, before which the line is not extrapolated
+    start: f64,
 }
 
 impl CovLine {
+    /// The covariate at time `x`, which is the value at the start of the block for earlier times
     pub fn interp(&self, x: f64) -> f64 {
-        self.slope * x + self.intercept
+        self.slope * x.max(self.start) + self.intercept
     }
 }
 
@@ -253,6 +736,31 @@ pub struct Block {
     pub covs: HashMap<String, CovLine>,
 }
 
+/// Tolerance when comparing the times of events, as prediction times are rounded to 4 decimal places, see [Scenario::add_event_interval]
+const TIME_TOLERANCE: f64 = 1e-4;
+
+/// Type of a predicted observation, see [Scenario::obs_annotations]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventType {
+    /// An observation in the data
+    Observation,
+    /// A prediction at the time of a dose
+    Dose,
+    /// A prediction between the events of the data
+    Interpolated,
+}
+
+impl std::fmt::Display for EventType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let str = match self {
+            EventType::Observation => "observation",
+            EventType::Dose => "dose",
+            EventType::Interpolated => "interpolated",
+        };
+        write!(f, "{}", str)
+    }
+}
+
 /// A Event represent a single row in the Datafile
 #[derive(Debug, Clone)]
 pub struct Event {
@@ -261,10 +769,17 @@ pub struct Event {
     pub time: f64,
     pub dur: Option<f64>,
     pub dose: Option<f64>,
-    pub _addl: Option<isize>,
-    pub _ii: Option<isize>,
+    /// Number of additional doses, identical to this dose, see [expand_additional_doses]
+    pub addl: Option<isize>,
+    /// Interval between the additional doses
+    pub ii: Option<f64>,
     pub input: Option<usize>,
     pub out: Option<f64>,
+    /// Lower and upper bound of an interval-censored observation, where `out` is the midpoint of the interval, or its finite bound if the interval is open-ended
+    ///
+    /// In the data file, the interval is given in the `OUT` column as `lower..upper`, e.g. `2..5`. Either bound may be omitted,
+    /// e.g. `..0.5` for an observation below the limit of quantification of 0.5, see [parse_out].
+    pub interval: Option<Interval>,
     pub outeq: Option<usize>,
     pub _c0: Option<f32>,
     pub _c1: Option<f32>,
@@ -273,6 +788,27 @@ pub struct Event {
     pub covs: HashMap<String, Option<f64>>,
 }
 
+/// Magic bytes at the start of a gzip stream
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// Opens the data file at `path`, decompressing it if it is gzip-compressed
+///
+/// Files are treated as compressed if they have a `.gz` extension or start with the gzip magic bytes.
+pub fn open_data(path: &str) -> Result<Box<dyn Read>, DatafileError> {
+    let io_error = |error| DatafileError::Io {
+        path: path.to_string(),
+        error,
+    };
+    let mut file = BufReader::new(File::open(path).map_err(io_error)?);
+    let compressed =
+        path.ends_with(".gz") || file.fill_buf().map_err(io_error)?.starts_with(&GZIP_MAGIC);
+    if compressed {
+        Ok(Box::new(MultiGzDecoder::new(file)))
+    } else {
+        Ok(Box::new(file))
+    }
+}
+
 impl Event {
     pub fn cmp_by_id_then_time(&self, other: &Self) -> Ordering {
         match self.id.cmp(&other.id) {
@@ -282,40 +818,228 @@ impl Event {
     }
 }
 
-pub fn parse(path: &String) -> Result<Vec<Scenario>, Box<dyn Error>> {
+/// Handling of an observation in the `OUT` column of a dose row (EVID 1), see [parse_with]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DoseRowObservations {
+    /// The observation is ignored, as only rows with EVID 0 are observations
+    #[default]
+    Ignore,
+    /// The row is split into an observation followed by the dose, i.e. the observation is taken just before the dose, e.g. a trough concentration
+    Split,
+}
+
+impl DoseRowObservations {
+    /// Parses the `dose_row_observations` setting, either "ignore" or "split" (case-insensitive)
+    pub fn from_name(name: &str) -> Result<Self, String> {
+        match name.to_lowercase().as_str() {
+            "ignore" => Ok(DoseRowObservations::Ignore),
+            "split" => Ok(DoseRowObservations::Split),
+            _ => Err(format!(
+                "Handling of observations on dose rows not supported: {}, expected \"ignore\" or \"split\"",
+                name
+            )),
+        }
+    }
+}
+
+/// Parses the data file at `path` into one [Scenario] per subject, sorted by ID
+///
+/// Columns which are not part of the data format are covariates, and are also kept as metadata of the subjects, e.g. a site or study arm.
+/// Columns without any numeric value are only kept as metadata, and do not affect the fit.
+/// Gzip-compressed files are decompressed while reading, see [open_data].
+///
+/// The optional `TOFFSET` column aligns the clocks of subjects with different time origins, e.g. when pooling studies.
+/// It is the time, on the clock of the subject, of the common time zero, and is subtracted from all times of the subject, see [apply_time_offset].
+/// Rows without a value use the offset of the other rows of the subject.
+///
+/// Observations on dose rows are ignored, see [parse_with] to handle them otherwise.
+///
+/// Fails with a [DatafileError], which gives the line and column of invalid values.
+pub fn parse(path: &str) -> Result<Vec<Scenario>, DatafileError> {
+    parse_with(path, DoseRowObservations::Ignore)
+}
+
+/// As [parse], with the handling of observations on dose rows given by `dose_row_observations`
+///
+/// The optional `MDV` column, as in NONMEM, marks rows without an observation (1) or with an observation (0), and is checked against the EVID.
+/// An observation (EVID 0) must not have MDV 1. A dose with MDV 0 and an observation is split if the handling is [DoseRowObservations::Split],
+/// and otherwise fails, as its observation would be ignored. A dose with MDV 1 is never split.
+pub fn parse_with(
+    path: &str,
+    dose_row_observations: DoseRowObservations,
+) -> Result<Vec<Scenario>, DatafileError> {
     let mut rdr = csv::ReaderBuilder::new()
         // .delimiter(b',')
         // .escape(Some(b'\\'))
         .comment(Some(b'#'))
-        .from_path(path)
-        .unwrap();
+        .from_reader(open_data(path)?);
     let mut events: Vec<Event> = vec![];
+    let mut metadata: HashMap<String, HashMap<String, String>> = HashMap::new();
+    let mut offsets: HashMap<String, f64> = HashMap::new();
+
+    let headers = rdr.headers()?.clone();
+    if headers.is_empty() {
+        return Err(DatafileError::Empty);
+    }
+    if let Some(column) = REQUIRED_COLUMNS
+        .iter()
+        .find(|&&column| !headers.iter().any(|header| header == column))
+    {
+        return Err(DatafileError::invalid(
+            Some(1),
+            column,
+            "The column is missing".to_string(),
+        ));
+    }
 
-    for result in rdr.deserialize() {
-        let mut record: Record = result?;
-        events.push(Event {
-            id: record.remove("ID").unwrap(),
-            evid: record.remove("EVID").unwrap().parse::<isize>().unwrap(),
-            time: record.remove("TIME").unwrap().parse::<f64>().unwrap(),
-            dur: record.remove("DUR").unwrap().parse::<f64>().ok(),
-            dose: record.remove("DOSE").unwrap().parse::<f64>().ok(),
-            _addl: record.remove("ADDL").unwrap().parse::<isize>().ok(), //TODO: To Be Implemented
-            _ii: record.remove("II").unwrap().parse::<isize>().ok(),     //TODO: To Be Implemented
-            input: record.remove("INPUT").unwrap().parse::<usize>().ok(),
-            out: record.remove("OUT").unwrap().parse::<f64>().ok(),
-            outeq: record.remove("OUTEQ").unwrap().parse::<usize>().ok(),
-            _c0: record.remove("C0").unwrap().parse::<f32>().ok(), //TODO: To Be Implemented
-            _c1: record.remove("C1").unwrap().parse::<f32>().ok(), //TODO: To Be Implemented
-            _c2: record.remove("C2").unwrap().parse::<f32>().ok(), //TODO: To Be Implemented
-            _c3: record.remove("C3").unwrap().parse::<f32>().ok(), //TODO: To Be Implemented
+    let mut row = csv::StringRecord::new();
+    while rdr.read_record(&mut row)? {
+        let line = row.position().map(|position| position.line());
+        let mut record: Record = row.deserialize(Some(&headers))?;
+        // The required columns are present in every row, as the rows have as many fields as the header
+        let mut take = |column: &str| record.remove(column).unwrap_or_default();
+        let id = take("ID");
+        let offset = take("TOFFSET");
+        let evid = take("EVID");
+        let time = take("TIME");
+        let out = take("OUT");
+        let mdv = take("MDV");
+        let mut event = Event {
+            id: id.clone(),
+            evid: evid.parse::<isize>().map_err(|_| {
+                DatafileError::invalid(
+                    line,
+                    "EVID",
+                    format!("Subject {} has an invalid EVID '{}'", id, evid),
+                )
+            })?,
+            time: time.parse::<f64>().map_err(|_| {
+                DatafileError::invalid(
+                    line,
+                    "TIME",
+                    format!("Subject {} has an invalid time '{}'", id, time),
+                )
+            })?,
+            dur: take("DUR").parse::<f64>().ok(),
+            dose: take("DOSE").parse::<f64>().ok(),
+            addl: take("ADDL").parse::<isize>().ok(),
+            ii: take("II").parse::<f64>().ok(),
+            input: take("INPUT").parse::<usize>().ok(),
+            out: None,
+            interval: None,
+            outeq: take("OUTEQ").parse::<usize>().ok(),
+            _c0: take("C0").parse::<f32>().ok(), //TODO: To Be Implemented
+            _c1: take("C1").parse::<f32>().ok(), //TODO: To Be Implemented
+            _c2: take("C2").parse::<f32>().ok(), //TODO: To Be Implemented
+            _c3: take("C3").parse::<f32>().ok(), //TODO: To Be Implemented
             covs: record
-                .into_iter()
+                .iter()
                 .map(|(key, value)| {
                     let val = value.parse::<f64>().ok();
-                    (key, val)
+                    (key.clone(), val)
                 })
                 .collect(),
-        });
+        };
+        (event.out, event.interval) = parse_out(&out)
+            .map_err(|e| DatafileError::invalid(line, "OUT", format!("Subject {}: {}", id, e)))?;
+        if let Ok(offset) = offset.parse::<f64>() {
+            match offsets.get(&id) {
+                Some(&previous) if previous != offset => {
+                    return Err(DatafileError::invalid(
+                        line,
+                        "TOFFSET",
+                        format!(
+                            "Subject {} has different time offsets, {} and {}",
+                            id, previous, offset
+                        ),
+                    ))
+                }
+                _ => {
+                    offsets.insert(id.clone(), offset);
+                }
+            }
+        }
+        let mdv = match mdv.trim() {
+            "" | "." => None,
+            "0" => Some(false),
+            "1" => Some(true),
+            mdv => {
+                return Err(DatafileError::invalid(
+                    line,
+                    "MDV",
+                    format!("Subject {} has an invalid MDV '{}'", id, mdv),
+                ))
+            }
+        };
+        match (event.evid, mdv) {
+            (0, Some(true)) => {
+                return Err(DatafileError::invalid(
+                    line,
+                    "MDV",
+                    format!(
+                        "Subject {} has an observation at time {} with MDV 1",
+                        id, event.time
+                    ),
+                ))
+            }
+            (1, mdv) if event.out.is_some() && mdv != Some(true) => {
+                match dose_row_observations {
+                    DoseRowObservations::Split => {
+                        events.push(Event {
+                            evid: 0,
+                            dur: None,
+                            dose: None,
+                            addl: None,
+                            ii: None,
+                            input: None,
+                            ..event.clone()
+                        });
+                        event.out = None;
+                        event.interval = None;
+                        event.outeq = None;
+                    }
+                    DoseRowObservations::Ignore if mdv == Some(false) => {
+                        return Err(DatafileError::invalid(
+                            line,
+                            "MDV",
+                            format!(
+                                "Subject {} has a dose with an observation and MDV 0 at time {}, which is ignored unless dose_row_observations is \"split\"",
+                                id, event.time
+                            ),
+                        ))
+                    }
+                    DoseRowObservations::Ignore => {}
+                }
+            }
+            _ => {}
+        }
+        events.push(event);
+        // The remaining columns are unrecognized
+        metadata.entry(id).or_insert(record);
+    }
+
+    // Columns without any numeric value, e.g. site names, are not covariates
+    let numeric: std::collections::HashSet<&String> = events
+        .iter()
+        .flat_map(|event| event.covs.iter())
+        .filter(|(_, value)| value.is_some())
+        .map(|(key, _)| key)
+        .collect();
+    let non_numeric: Vec<String> = events
+        .first()
+        .map(|event| {
+            event
+                .covs
+                .keys()
+                .filter(|key| !numeric.contains(key))
+                .cloned()
+                .collect()
+        })
+        .unwrap_or_default();
+    for event in &mut events {
+        for key in &non_numeric {
+            event.covs.remove(key);
+        }
     }
 
     let mut event_groups: HashMap<String, Vec<Event>> = HashMap::new();
@@ -328,8 +1052,13 @@ pub fn parse(path: &String) -> Result<Vec<Scenario>, Box<dyn Error>> {
 
     let mut scenarios: Vec<Scenario> = vec![];
 
-    for (_id, s_events) in event_groups {
-        let scenario = Scenario::new(s_events)?;
+    for (id, mut s_events) in event_groups {
+        if let Some(&offset) = offsets.get(&id) {
+            apply_time_offset(&mut s_events, offset)?;
+        }
+        expand_additional_doses(&mut s_events)?;
+        let mut scenario = Scenario::new(s_events).map_err(|e| e.to_string())?;
+        scenario.metadata = metadata.remove(&scenario.id).unwrap_or_default();
         scenarios.push(scenario);
     }
 
@@ -338,47 +1067,155 @@ pub fn parse(path: &String) -> Result<Vec<Scenario>, Box<dyn Error>> {
     Ok(scenarios)
 }
 
+/// Shifts the events of a subject to the common clock, by subtracting the `offset` of the subject from their times
+///
+/// The `offset` is the time, on the clock of the subject, of the common time zero, see [parse]. It must be finite.
+pub fn apply_time_offset(events: &mut [Event], offset: f64) -> Result<(), String> {
+    if !offset.is_finite() {
+        return Err(format!(
+            "Subject {} has an invalid time offset {}",
+            events.first().map(|event| event.id.as_str()).unwrap_or(""),
+            offset
+        ));
+    }
+    for event in events.iter_mut() {
+        event.time -= offset;
+    }
+    Ok(())
+}
+
+/// Materializes the additional doses given by the `ADDL` and `II` columns of the events of a subject
+///
+/// A dose with `ADDL` = n and `II` = ii is followed by n identical doses, ii, 2 ii, ..., n ii after it, e.g. to express chronic dosing in a single row.
+/// The additional doses are placed after any events of the subject at the same time. Missing, zero or negative `ADDL` adds no doses.
+pub fn expand_additional_doses(events: &mut Vec<Event>) -> Result<(), String> {
+    let mut additional = Vec::new();
+    for event in events.iter() {
+        let addl = match event.addl {
+            // A negative ADDL marks steady-state dosing in Pmetrics, which is not implemented
+            None | Some(..=0) => continue,
+            Some(addl) => addl,
+        };
+        if event.evid != 1 {
+            return Err(format!(
+                "Subject {} has ADDL {} at time {}, but additional doses are only supported for doses (EVID 1)",
+                event.id, addl, event.time
+            ));
+        }
+        let ii = match event.ii {
+            Some(ii) if ii > 0.0 && ii.is_finite() => ii,
+            ii => {
+                return Err(format!(
+                    "Subject {} has ADDL {} at time {}, which requires a positive II, got {:?}",
+                    event.id, addl, event.time, ii
+                ))
+            }
+        };
+        for k in 1..=addl {
+            let mut dose = event.clone();
+            dose.time = event.time + k as f64 * ii;
+            dose.addl = None;
+            dose.ii = None;
+            additional.push(dose);
+        }
+    }
+    if !additional.is_empty() {
+        events.extend(additional);
+        // The sort is stable, so the additional doses follow the events in the data at the same time
+        events.sort_by(|a, b| a.cmp_by_id_then_time(b));
+    }
+    Ok(())
+}
+
+/// Parses the `OUT` column, which is either a value or an interval `lower..upper` of an interval-censored observation
+///
+/// Omitted bounds of an interval are infinite. Values which are not numbers, e.g. `.`, are missing.
+pub fn parse_out(value: &str) -> Result<(Option<f64>, Option<Interval>), String> {
+    let Some((lower, upper)) = value.split_once("..") else {
+        return Ok((value.parse::<f64>().ok(), None));
+    };
+    let bound = |bound: &str, default: f64| -> Result<f64, String> {
+        if bound.trim().is_empty() {
+            return Ok(default);
+        }
+        bound
+            .trim()
+            .parse::<f64>()
+            .map_err(|_| format!("Invalid bound '{}' of the interval '{}'", bound, value))
+    };
+    let lower = bound(lower, f64::NEG_INFINITY)?;
+    let upper = bound(upper, f64::INFINITY)?;
+    if lower.partial_cmp(&upper) != Some(Ordering::Less)
+        || (lower.is_infinite() && upper.is_infinite())
+    {
+        return Err(format!(
+            "The interval '{}' must have a lower bound less than its upper bound, and at least one finite bound",
+            value
+        ));
+    }
+    let out = match (lower.is_finite(), upper.is_finite()) {
+        (true, true) => (lower + upper) / 2.0,
+        (true, false) => lower,
+        _ => upper,
+    };
+    Ok((Some(out), Some((lower, upper))))
+}
+
 fn check_dose(event: &Event) -> Result<(), Box<dyn Error>> {
     if event.dose.is_none() {
-        tracing::error!("Error: Dose event without dose");
-        //return Err("Error: Dose event without dose".into());
-        exit(-1);
+        return Err(format!(
+            "Subject {} has a dose event at time {} without dose",
+            event.id, event.time
+        )
+        .into());
     }
     if event.input.is_none() {
-        tracing::error!("Error: Dose event without input");
-        //return Err("Error: Dose event without input".into());
-        exit(-1);
+        return Err(format!(
+            "Subject {} has a dose event at time {} without input",
+            event.id, event.time
+        )
+        .into());
     }
     Ok(())
 }
 fn check_infusion(event: &Event) -> Result<(), Box<dyn Error>> {
     if event.dose.is_none() {
-        tracing::error!("Error: Infusion event without dose");
-        //return Err("Error: Infusion event without dose".into());
-        exit(-1);
+        return Err(format!(
+            "Subject {} has an infusion at time {} without dose",
+            event.id, event.time
+        )
+        .into());
     }
     if event.dur.is_none() {
-        tracing::error!("Error: Infusion event without duration");
-        //return Err("Error: Infusion event without duration".into());
-        exit(-1);
+        return Err(format!(
+            "Subject {} has an infusion at time {} without duration",
+            event.id, event.time
+        )
+        .into());
     }
     if event.input.is_none() {
-        tracing::error!("Error: Infusion event without input");
-        //return Err("Error: Infusion event without input".into());
-        exit(-1);
+        return Err(format!(
+            "Subject {} has an infusion at time {} without input",
+            event.id, event.time
+        )
+        .into());
     }
     Ok(())
 }
 fn check_obs(event: &Event) -> Result<(), Box<dyn Error>> {
     if event.out.is_none() {
-        tracing::error!("Error: Obs event without out");
-        //return Err("Error: Obs event without out".into());
-        exit(-1);
+        return Err(format!(
+            "Subject {} has an observation at time {} without value",
+            event.id, event.time
+        )
+        .into());
     }
     if event.outeq.is_none() {
-        tracing::error!("Error: Obs event without outeq");
-        //return Err("Error: Obs event without outeq".into());
-        exit(-1);
+        return Err(format!(
+            "Subject {} has an observation at time {} without output equation",
+            event.id, event.time
+        )
+        .into());
     }
     Ok(())
 }
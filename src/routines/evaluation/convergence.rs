@@ -0,0 +1,230 @@
+use crate::tui::control::RunControl;
+use ndarray::{Array1, Array2};
+use serde_derive::{Deserialize, Serialize};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+/// Stop criterion on the change in objective function between cycles
+///
+/// A single cycle with a small change may be a coincidence on a noisy objective function trajectory.
+/// The criterion is therefore only met once the change has been within the tolerance for `required` consecutive cycles.
+/// Optionally, the change in the parameter distribution is also considered, see [StopCriterion::with_distribution].
+#[derive(Debug, Clone)]
+pub struct StopCriterion {
+    tolerance: f64,
+    required: usize,
+    count: usize,
+    distribution: Option<DistributionCriterion>,
+}
+
+/// How the objective function and distribution criteria are combined, see [StopCriterion::with_distribution]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Combine {
+    /// Both criteria must be met
+    #[default]
+    And,
+    /// Either criterion must be met
+    Or,
+}
+
+impl Combine {
+    /// Parses the `distribution_combine` setting, either "and" or "or" (case-insensitive)
+    pub fn from_name(name: &str) -> Result<Self, String> {
+        match name.to_lowercase().as_str() {
+            "and" => Ok(Combine::And),
+            "or" => Ok(Combine::Or),
+            _ => Err(format!(
+                "Convergence combination not supported: {}, expected \"and\" or \"or\"",
+                name
+            )),
+        }
+    }
+}
+
+/// Criterion on the change in the weighted mean and variance of the parameters between cycles
+#[derive(Debug, Clone)]
+struct DistributionCriterion {
+    tolerance: f64,
+    combine: Combine,
+    previous: Option<(Array1<f64>, Array1<f64>)>,
+}
+
+impl StopCriterion {
+    /// Create a new criterion, `required` is at least one cycle
+    pub fn new(tolerance: f64, required: usize) -> Self {
+        Self {
+            tolerance,
+            required: required.max(1),
+            count: 0,
+            distribution: None,
+        }
+    }
+
+    /// Also requires, or accepts, a change in the parameter distribution within `tolerance`, depending on `combine`
+    ///
+    /// The change is the largest relative change in the weighted mean or variance of any parameter, see [distribution_change].
+    /// The distribution criterion is not met in the first cycle, which has no previous distribution.
+    pub fn with_distribution(mut self, tolerance: f64, combine: Combine) -> Self {
+        self.distribution = Some(DistributionCriterion {
+            tolerance,
+            combine,
+            previous: None,
+        });
+        self
+    }
+
+    /// Registers the absolute change in objective function of a cycle, and returns true if the criterion is met
+    ///
+    /// The count of consecutive cycles restarts when the criterion is met, or when the change exceeds the tolerance.
+    pub fn update(&mut self, delta_objf: f64) -> bool {
+        self.register(delta_objf.abs() <= self.tolerance)
+    }
+
+    /// Registers the change in objective function and the support points `theta` with weights `w` of a cycle, and returns true if the criterion is met
+    ///
+    /// Without a distribution criterion, this is equivalent to [StopCriterion::update].
+    pub fn update_with_distribution(
+        &mut self,
+        delta_objf: f64,
+        theta: &Array2<f64>,
+        w: &Array1<f64>,
+    ) -> bool {
+        let objf_met = delta_objf.abs() <= self.tolerance;
+        let Some(distribution) = &mut self.distribution else {
+            return self.register(objf_met);
+        };
+        let (mean, variance) = weighted_moments(theta, w);
+        let distribution_met = match &distribution.previous {
+            Some((previous_mean, previous_variance)) => {
+                distribution_change(previous_mean, previous_variance, &mean, &variance)
+                    <= distribution.tolerance
+            }
+            None => false,
+        };
+        distribution.previous = Some((mean, variance));
+        let met = match distribution.combine {
+            Combine::And => objf_met && distribution_met,
+            Combine::Or => objf_met || distribution_met,
+        };
+        self.register(met)
+    }
+
+    fn register(&mut self, met: bool) -> bool {
+        if met {
+            self.count += 1;
+        } else {
+            self.count = 0;
+        }
+        if self.count >= self.required {
+            self.count = 0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// The weighted mean and variance of each parameter, i.e. column of `theta`, with weights `w` which sum to one
+fn weighted_moments(theta: &Array2<f64>, w: &Array1<f64>) -> (Array1<f64>, Array1<f64>) {
+    let mean = theta.t().dot(w);
+    let variance = (theta - &mean).mapv(|x| x * x).t().dot(w);
+    (mean, variance)
+}
+
+/// The largest relative change in the mean or variance of any parameter between two distributions
+///
+/// Changes are relative to the previous value, or absolute if the previous value is zero. A change which is not a number is infinite.
+pub fn distribution_change(
+    previous_mean: &Array1<f64>,
+    previous_variance: &Array1<f64>,
+    mean: &Array1<f64>,
+    variance: &Array1<f64>,
+) -> f64 {
+    let relative = |previous: f64, current: f64| {
+        let change = (current - previous).abs();
+        if previous == 0.0 {
+            change
+        } else {
+            change / previous.abs()
+        }
+    };
+    previous_mean
+        .iter()
+        .zip(mean)
+        .chain(previous_variance.iter().zip(variance))
+        .map(|(previous, current)| relative(*previous, *current))
+        .fold(0.0, |largest, change| {
+            // A change which is not a number is never within tolerance
+            if change.is_nan() {
+                f64::INFINITY
+            } else {
+                largest.max(change)
+            }
+        })
+}
+
+/// Name of the file which stops a run before its next cycle when it exists in the working directory
+pub const STOP_FILE: &str = "stop";
+
+/// Reason a run stopped, see [NPResult::stop_reason](crate::routines::output::NPResult::stop_reason)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum StopReason {
+    /// The objective function and the grid resolution converged
+    Converged,
+    /// The maximum number of cycles, `config.cycles`, was reached before convergence
+    MaxCycles,
+    /// A stop was requested through the [RunControl], e.g. from the TUI
+    Requested,
+    /// The [STOP_FILE] was found in the working directory
+    StopFile,
+    /// The run exceeded the time limit, `config.time_limit`
+    TimeLimit,
+    /// The algorithm evaluates the initial grid once, without cycles, e.g. POSTPROB
+    Completed,
+    /// The run failed, e.g. in the IPM, and the result is that of the last completed cycle, if any, see [NPResult::warnings](crate::routines::output::NPResult::warnings) for the error
+    Error,
+}
+
+impl std::fmt::Display for StopReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let description = match self {
+            StopReason::Converged => "The run converged",
+            StopReason::MaxCycles => "Maximum number of cycles reached",
+            StopReason::Requested => "Stopped by request",
+            StopReason::StopFile => "Stopped by stop file",
+            StopReason::TimeLimit => "Time limit reached",
+            StopReason::Completed => "The run completed",
+            StopReason::Error => "The run failed",
+        };
+        write!(f, "{}", description)
+    }
+}
+
+/// Returns the reason to stop a run after `cycle` other than convergence, if any
+///
+/// The maximum number of cycles is checked first, then a stop requested through `control`, which blocks while the run is paused, see [RunControl::wait_while_paused],
+/// then the existence of `stop_file`, and finally whether the `deadline` of the time limit has passed.
+pub fn check_stop(
+    cycle: usize,
+    max_cycles: usize,
+    control: &RunControl,
+    stop_file: &Path,
+    deadline: Option<Instant>,
+) -> Option<StopReason> {
+    if cycle >= max_cycles {
+        Some(StopReason::MaxCycles)
+    } else if control.wait_while_paused() {
+        Some(StopReason::Requested)
+    } else if stop_file.exists() {
+        Some(StopReason::StopFile)
+    } else if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+        Some(StopReason::TimeLimit)
+    } else {
+        None
+    }
+}
+
+/// The time at which a run started now is stopped by `time_limit`, in seconds, see [check_stop]
+pub fn deadline(time_limit: Option<f64>) -> Option<Instant> {
+    time_limit.map(|seconds| Instant::now() + Duration::from_secs_f64(seconds))
+}
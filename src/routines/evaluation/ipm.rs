@@ -5,6 +5,11 @@ use ndarray::{array, Array, Array2, ArrayBase, Dim, OwnedRepr};
 use ndarray_stats::{DeviationExt, QuantileExt};
 type OneDimArray = ArrayBase<OwnedRepr<f64>, ndarray::Dim<[usize; 1]>>;
 
+/// Default convergence tolerance of [burke], mapped to the `ipm_tol`-setting in the configuration file
+pub const DEFAULT_TOL: f64 = 1e-8;
+/// Default maximum number of iterations of [burke], mapped to the `ipm_max_iter`-setting in the configuration file
+pub const DEFAULT_MAX_ITER: usize = 1000;
+
 /// Apply the Burke's Interior Point Method (IPM) to solve a specific optimization problem.
 ///
 /// The Burke's IPM is an iterative optimization technique used for solving convex optimization
@@ -33,11 +38,51 @@ type OneDimArray = ArrayBase<OwnedRepr<f64>, ndarray::Dim<[usize; 1]>>;
 /// Note: This function applies the Interior Point Method (IPM) to iteratively update variables
 /// until convergence, solving the convex optimization problem.
 ///
+/// The default tolerance and maximum number of iterations are used, see [burke_with].
 pub fn burke(
     psi: &ArrayBase<OwnedRepr<f64>, Dim<[usize; 2]>>,
+) -> Result<(OneDimArray, f64), Box<dyn error::Error>> {
+    burke_with(psi, DEFAULT_TOL, DEFAULT_MAX_ITER)
+}
+
+/// [burke] with the convergence tolerance `tol` and at most `max_iter` iterations
+///
+/// The method has converged when the duality measure, the residual and the relative duality gap are all within `tol`.
+/// Returns an error if the method has not converged after `max_iter` iterations.
+pub fn burke_with(
+    psi: &ArrayBase<OwnedRepr<f64>, Dim<[usize; 2]>>,
+    tol: f64,
+    max_iter: usize,
+) -> Result<(OneDimArray, f64), Box<dyn error::Error>> {
+    burke_weighted(psi, &Array::ones(psi.nrows()), tol, max_iter)
+}
+
+/// [burke_with], maximizing the weighted sum of the log-likelihoods of the subjects (rows), with the positive `weights`
+///
+/// The returned objective function is the weighted sum Σ w<sub>i</sub> ln((Ψλ)<sub>i</sub>). A subject with a weight of 2 has the same
+/// influence on the weights λ as two copies of the subject, and a weight of 1 for all subjects gives the result of [burke_with].
+pub fn burke_weighted(
+    psi: &ArrayBase<OwnedRepr<f64>, Dim<[usize; 2]>>,
+    weights: &OneDimArray,
+    tol: f64,
+    max_iter: usize,
 ) -> Result<(OneDimArray, f64), Box<dyn error::Error>> {
     let psi = psi.mapv(|x| x.abs());
     let (row, col) = psi.dim();
+    if weights.len() != row {
+        return Err(format!(
+            "There are {} subject weights, but PSI has {} rows",
+            weights.len(),
+            row
+        )
+        .into());
+    }
+    if weights
+        .iter()
+        .any(|weight| weight.is_nan() || *weight <= 0.0)
+    {
+        return Err("The subject weights must be positive".into());
+    }
     // if row>col {
     //     return Err("The matrix PSI has row>col".into());
     // }
@@ -49,11 +94,13 @@ pub fn burke(
     // if plam.min().unwrap() <= &1e-15 {
     //     return Err("The vector psi*e has a non-positive entry".into());
     // }
-    let eps = 1e-8;
+    let eps = tol;
     let mut sig = 0.;
-    let erow: ArrayBase<OwnedRepr<f64>, Dim<[usize; 1]>> = Array::ones(row);
+    // At the optimum, w * (psi * lam) equals the subject weights, which are all one without weights
+    let erow = weights;
+    let sum_log_erow = erow.dot(&erow.mapv(f64::ln));
     let mut lam = ecol.clone();
-    let mut w = 1. / &plam;
+    let mut w = erow / &plam;
     let mut ptw = psi.t().dot(&w);
     let shrink = 2. * *ptw.max()?;
     lam *= shrink;
@@ -61,22 +108,33 @@ pub fn burke(
     w /= shrink;
     ptw /= shrink;
     let mut y = &ecol - &ptw;
-    let mut r = &erow - &w * &plam;
+    let mut r = erow - &w * &plam;
     let mut norm_r = norm_inf(r);
-    let sum_log_plam = plam.mapv(|x: f64| x.ln()).sum();
-    let mut gap = (w.mapv(|x: f64| x.ln()).sum() + sum_log_plam).abs() / (1. + sum_log_plam);
+    let sum_log_plam = erow.dot(&plam.mapv(|x: f64| x.ln()));
+    let mut gap = (erow.dot(&w.mapv(|x: f64| x.ln())) + sum_log_plam - sum_log_erow).abs()
+        / (1. + sum_log_plam);
     let mut mu = lam.t().dot(&y) / col as f64;
 
+    let mut iter = 0;
     while mu > eps || norm_r > eps || gap > eps {
+        if iter == max_iter {
+            return Err(format!(
+                "IPM did not converge within {} iterations (tolerance {:e}, mu {:e}, residual {:e}, gap {:e}), consider increasing ipm_max_iter or ipm_tol",
+                max_iter, tol, mu, norm_r, gap
+            )
+            .into());
+        }
+        iter += 1;
         // log::info!("IPM cyle");
         let smu = sig * mu;
-        let inner = &lam / &y; //divide(&lam, &y);
-        let w_plam = &plam / &w; //divide(&plam, &w);
-        let h = psi.dot(&Array2::from_diag(&inner)).dot(&psi.t()) + Array2::from_diag(&w_plam);
+        let inner = &lam / &y;
+        let w_plam = &plam / &w;
+        // Scaling the columns of psi by `inner` is psi * diag(inner), without the dense diagonal matrix of size `col` x `col`
+        let h = (&psi * &inner).dot(&psi.t()) + Array2::from_diag(&w_plam);
         let uph = h.cholesky()?;
         let uph = uph.t();
         let smuyinv = smu * (&ecol / &y);
-        let rhsdw = &erow / &w - (psi.dot(&smuyinv));
+        let rhsdw = erow / &w - (psi.dot(&smuyinv));
         let a = rhsdw.clone().into_shape((rhsdw.len(), 1))?;
         //todo: cleanup this aux variable
         // //dbg!(uph.t().is_triangular(linfa_linalg::triangular::UPLO::Upper));
@@ -98,11 +156,12 @@ pub fn burke(
         y = y + alfdual * &dy;
         mu = lam.t().dot(&y) / col as f64;
         plam = psi.dot(&lam);
-        r = &erow - &w * &plam;
+        r = erow - &w * &plam;
         ptw = ptw - alfdual * dy;
         norm_r = norm_inf(r);
-        let sum_log_plam = plam.mapv(|x: f64| x.ln()).sum();
-        gap = (w.mapv(|x: f64| x.ln()).sum() + sum_log_plam).abs() / (1. + sum_log_plam);
+        let sum_log_plam = erow.dot(&plam.mapv(|x: f64| x.ln()));
+        gap = (erow.dot(&w.mapv(|x: f64| x.ln())) + sum_log_plam - sum_log_erow).abs()
+            / (1. + sum_log_plam);
         if mu < eps && norm_r > eps {
             sig = 1.0;
         } else {
@@ -115,8 +174,8 @@ pub fn burke(
             .min(0.3);
         }
     }
-    lam /= row as f64;
-    let obj = psi.dot(&lam).mapv(|x| x.ln()).sum();
+    lam /= erow.sum();
+    let obj = erow.dot(&psi.dot(&lam).mapv(|x| x.ln()));
     lam = &lam / lam.sum();
     Ok((lam, obj))
 }
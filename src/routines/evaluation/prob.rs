@@ -3,19 +3,18 @@ use datafile::Scenario;
 use ndarray::parallel::prelude::*;
 use ndarray::prelude::*;
 use ndarray::{Array, Array2};
+use predict::{sim_obs_offset, Engine, Predict};
 use sigma::Sigma;
+use std::time::{Duration, Instant};
 
-const FRAC_1_SQRT_2PI: f64 =
+/// Normalization constant of the normal density, 1/(2π)<sup>0.5</sup>
+pub const FRAC_1_SQRT_2PI: f64 =
     std::f64::consts::FRAC_2_SQRT_PI * std::f64::consts::FRAC_1_SQRT_2 / 2.0;
 
 /// Calculate the Ψ (psi) matrix, which contains the likelihood of each support point (column) for each subject (row)
-pub fn calculate_psi<S>(
-    ypred: &Array2<Array1<f64>>,
-    scenarios: &Vec<Scenario>,
-    sig: &S,
-) -> Array2<f64>
+pub fn calculate_psi<S>(ypred: &Array2<Array1<f64>>, scenarios: &[Scenario], sig: &S) -> Array2<f64>
 where
-    S: Sigma + Sync,
+    S: Sigma + Sync + ?Sized,
 {
     let mut prob = Array2::<f64>::zeros((scenarios.len(), ypred.ncols()).f());
     // let mut prob2 = Array2::from_elem((3, 4), (0usize, 0usize, 0.0f64));
@@ -28,28 +27,307 @@ where
                 .enumerate()
                 .for_each(|(j, mut element)| {
                     let scenario = scenarios.get(i).unwrap();
-                    let yobs = Array::from(scenario.obs.clone());
+                    let transform = sig.transform();
+                    // Missing observations, coded as -99, have a likelihood of one, and are dropped before they are transformed
+                    let observed: Vec<usize> = (0..scenario.obs.len())
+                        .filter(|&k| scenario.obs[k] != -99.0)
+                        .collect();
+                    // Values outside the domain of the transformation are NaN, so the likelihood is that of a failed prediction
+                    let apply = |x: f64| transform.apply(x).unwrap_or(f64::NAN);
+                    let yobs = Array::from(scenario.obs.clone())
+                        .select(Axis(0), &observed)
+                        .mapv(apply);
                     let sigma = sig.sigma(&yobs);
-                    let ll = normal_likelihood(ypred.get((i, j)).unwrap(), &yobs, &sigma);
+                    let ypred = &ypred
+                        .get((i, j))
+                        .unwrap()
+                        .select(Axis(0), &observed)
+                        .mapv(apply);
+                    let mut ll = if scenario.obs_intervals.iter().any(Option::is_some) {
+                        let intervals: Vec<Option<datafile::Interval>> = observed
+                            .iter()
+                            .map(|&k| scenario.obs_intervals[k])
+                            .map(|interval| {
+                                interval.map(|(lower, upper)| {
+                                    (transform.apply_bound(lower), transform.apply_bound(upper))
+                                })
+                            })
+                            .collect();
+                        censored_likelihood(ypred, &yobs, &sigma, &intervals, sig.df())
+                    } else {
+                        residual_likelihood(ypred, &yobs, &sigma, sig.df())
+                    };
                     if ll.is_nan() || ll.is_infinite() {
                         tracing::info!(
                             "NaN or Inf Likelihood detected!\nLL:{:?}\nypred: {:?}\nsubject: {}\nSpp: {}",
                             ll,
-                            &ypred.get((i, j)),
+                            ypred,
                             i,
                             j
                         )
                     }
+                    // Failed predictions, see `Engine::pred`, exclude the support point for this subject
+                    if ll.is_nan() {
+                        ll = 0.0;
+                    }
                     element.fill(ll);
                 });
         });
     prob
 }
 
-/// Calculate the normal likelihood
+/// Simulate the support points and calculate the Ψ (psi) matrix for each of the error models in `sigmas`
+///
+/// If `batch_size` is given, subjects are simulated in batches of that size, and only the predictions of one batch are held in memory at a time.
+/// The predictions are the largest intermediate result, with one vector of predictions for each subject and support point,
+/// so batching reduces their memory footprint by a factor of approximately `scenarios.len() / batch_size`. The result is identical to the non-batched path.
+pub fn simulate_psi<S, P>(
+    engine: &Engine<P>,
+    scenarios: &[Scenario],
+    support_points: &Array2<f64>,
+    sigmas: &[&S],
+    cache: bool,
+    batch_size: Option<usize>,
+) -> Vec<Array2<f64>>
+where
+    S: Sigma + Sync + ?Sized,
+    P: Predict<'static> + Sync + Clone,
+{
+    simulate_psi_timed(engine, scenarios, support_points, sigmas, cache, batch_size).0
+}
+
+/// As [simulate_psi], but also returns the time spent simulating the predictions and calculating the likelihoods, respectively
+pub fn simulate_psi_timed<S, P>(
+    engine: &Engine<P>,
+    scenarios: &[Scenario],
+    support_points: &Array2<f64>,
+    sigmas: &[&S],
+    cache: bool,
+    batch_size: Option<usize>,
+) -> (Vec<Array2<f64>>, Duration, Duration)
+where
+    S: Sigma + Sync + ?Sized,
+    P: Predict<'static> + Sync + Clone,
+{
+    let batch_size = batch_size.unwrap_or(scenarios.len()).max(1);
+    let mut psis =
+        vec![Array2::<f64>::zeros((scenarios.len(), support_points.nrows()).f()); sigmas.len()];
+    let (mut simulation, mut likelihood) = (Duration::ZERO, Duration::ZERO);
+    for (batch, chunk) in scenarios.chunks(batch_size).enumerate() {
+        let offset = batch * batch_size;
+        let start = Instant::now();
+        let ypred = sim_obs_offset(engine, chunk, support_points, cache, offset);
+        simulation += start.elapsed();
+        let start = Instant::now();
+        for (psi, sig) in psis.iter_mut().zip(sigmas) {
+            psi.slice_mut(s![offset..offset + chunk.len(), ..])
+                .assign(&calculate_psi(&ypred, chunk, *sig));
+        }
+        likelihood += start.elapsed();
+    }
+    (psis, simulation, likelihood)
+}
+
+/// Projected memory use above which a warning is raised, see [projected_memory]
+pub const MEMORY_WARNING: usize = 1 << 30;
+
+/// Projected memory use in bytes of the support points and of one evaluation of the Ψ (psi) matrix with [simulate_psi], for `n_points` support points with `n_params` parameters
+///
+/// This is the size of the support points, of Ψ and the two copies of it made by [ipm::burke], and of the predictions of the largest batch of subjects,
+/// which are the largest intermediate result, see [simulate_psi]. Only the predictions depend on `batch_size`, so batching can not reduce the size of the support points or of Ψ.
+pub fn projected_memory(
+    scenarios: &[Scenario],
+    n_points: usize,
+    n_params: usize,
+    batch_size: Option<usize>,
+) -> usize {
+    let float = std::mem::size_of::<f64>();
+    let batch_size = batch_size.unwrap_or(scenarios.len()).max(1);
+    // Each prediction vector is an array of its own, with the observations of the subject
+    let predictions = scenarios
+        .chunks(batch_size)
+        .map(|chunk| {
+            chunk
+                .iter()
+                .map(|scenario| scenario.obs.len() * float + std::mem::size_of::<Array1<f64>>())
+                .sum::<usize>()
+        })
+        .max()
+        .unwrap_or(0);
+    n_points * (n_params * float + 3 * scenarios.len() * float + predictions)
+}
+
+/// The largest subject batch size for which the [projected_memory] is at most `limit`, if any
+///
+/// Returns `None` if even batches of a single subject exceed the limit.
+pub fn batch_size_within(
+    scenarios: &[Scenario],
+    n_points: usize,
+    n_params: usize,
+    limit: usize,
+) -> Option<usize> {
+    (1..=scenarios.len())
+        .rev()
+        .find(|&size| projected_memory(scenarios, n_points, n_params, Some(size)) <= limit)
+}
+
+/// Sum of the log normalization constants of the likelihood of `scenarios`, i.e. ln(1/(2π)<sup>0.5</sup>) for each observation with a [normal_likelihood]
+///
+/// With degrees of freedom `df`, the constant of each observation is that of the [student_t_likelihood] instead.
+/// Interval-censored observations contribute a probability, see [censored_likelihood], which has no normalization constant.
+/// The factor 1/SD of each observation is not a constant, as it depends on the error model, and is not included.
+pub fn log_normalization(scenarios: &[Scenario], df: Option<f64>) -> f64 {
+    let n_obs = scenarios
+        .iter()
+        .flat_map(|scenario| scenario.obs_intervals.iter())
+        .filter(|interval| interval.is_none())
+        .count();
+    let constant = match df {
+        Some(df) => student_t_log_constant(df),
+        None => FRAC_1_SQRT_2PI.ln(),
+    };
+    n_obs as f64 * constant
+}
+
+/// Likelihood of the residuals, i.e. the [student_t_likelihood] if degrees of freedom `df` are given, and the [normal_likelihood] otherwise
+pub fn residual_likelihood(
+    ypred: &Array1<f64>,
+    yobs: &Array1<f64>,
+    sigma: &Array1<f64>,
+    df: Option<f64>,
+) -> f64 {
+    match df {
+        Some(df) => student_t_likelihood(ypred, yobs, sigma, df),
+        None => normal_likelihood(ypred, yobs, sigma),
+    }
+}
+
+/// Calculate the likelihood of the residuals under a normal distribution with standard deviation `sigma`
 pub fn normal_likelihood(ypred: &Array1<f64>, yobs: &Array1<f64>, sigma: &Array1<f64>) -> f64 {
     let diff = (yobs - ypred).mapv(|x| x.powi(2));
-    let two_sigma_sq = (2.0 * sigma).mapv(|x| x.powi(2));
+    let two_sigma_sq = 2.0 * sigma.mapv(|x| x.powi(2));
     let aux_vec = FRAC_1_SQRT_2PI * (-&diff / two_sigma_sq).mapv(|x| x.exp()) / sigma;
     aux_vec.product()
 }
+
+/// Calculate the likelihood of the residuals under a Student-t distribution with `df` degrees of freedom and scale `sigma`
+///
+/// The tails of the Student-t distribution are heavier than those of the normal distribution, so that an outlying observation
+/// lowers the likelihood polynomially rather than exponentially in its residual, and has less influence on the fit.
+/// This changes the objective function, which is therefore not comparable to that of a fit with a [normal_likelihood].
+/// The Student-t distribution approaches the normal distribution as `df` increases.
+pub fn student_t_likelihood(
+    ypred: &Array1<f64>,
+    yobs: &Array1<f64>,
+    sigma: &Array1<f64>,
+    df: f64,
+) -> f64 {
+    let constant = student_t_log_constant(df).exp();
+    let z_sq = ((yobs - ypred) / sigma).mapv(|x| x.powi(2));
+    let aux_vec = constant * z_sq.mapv(|x| (1.0 + x / df).powf(-(df + 1.0) / 2.0)) / sigma;
+    aux_vec.product()
+}
+
+/// Logarithm of the normalization constant of the Student-t density, Γ((ν+1)/2) / (Γ(ν/2) (νπ)<sup>0.5</sup>)
+fn student_t_log_constant(df: f64) -> f64 {
+    libm::lgamma((df + 1.0) / 2.0) - libm::lgamma(df / 2.0) - 0.5 * (df * std::f64::consts::PI).ln()
+}
+
+/// Calculate the likelihood of observations of which some are interval-censored
+///
+/// Observations without an interval contribute their [residual_likelihood] with degrees of freedom `df`. An interval-censored observation contributes the probability
+/// of the prediction with standard deviation `sigma` falling within its interval, i.e. the difference of the CDFs at its bounds,
+/// of the normal distribution, or of the Student-t distribution with scale `sigma` if `df` is given, as for the uncensored observations.
+pub fn censored_likelihood(
+    ypred: &Array1<f64>,
+    yobs: &Array1<f64>,
+    sigma: &Array1<f64>,
+    intervals: &[Option<datafile::Interval>],
+    df: Option<f64>,
+) -> f64 {
+    let points: Vec<usize> = (0..yobs.len())
+        .filter(|&i| intervals[i].is_none())
+        .collect();
+    let mut likelihood = residual_likelihood(
+        &ypred.select(Axis(0), &points),
+        &yobs.select(Axis(0), &points),
+        &sigma.select(Axis(0), &points),
+        df,
+    );
+    for (i, interval) in intervals.iter().enumerate() {
+        if let Some((lower, upper)) = interval {
+            let cdf = |bound: f64| {
+                let z = (bound - ypred[i]) / sigma[i];
+                match df {
+                    Some(df) => student_t_cdf(z, df),
+                    None => normal_cdf(z),
+                }
+            };
+            likelihood *= cdf(*upper) - cdf(*lower);
+        }
+    }
+    likelihood
+}
+
+/// Cumulative distribution function of the standard normal distribution
+pub fn normal_cdf(x: f64) -> f64 {
+    0.5 * libm::erfc(-x * std::f64::consts::FRAC_1_SQRT_2)
+}
+
+/// Cumulative distribution function of the Student-t distribution with `df` degrees of freedom
+///
+/// For t > 0, the CDF is 1 - I<sub>x</sub>(ν/2, 1/2) / 2 with x = ν / (ν + t<sup>2</sup>), where I is the [regularized_incomplete_beta] function, and symmetric otherwise.
+pub fn student_t_cdf(t: f64, df: f64) -> f64 {
+    if t.is_infinite() {
+        return if t > 0.0 { 1.0 } else { 0.0 };
+    }
+    let tail = 0.5 * regularized_incomplete_beta(df / (df + t * t), df / 2.0, 0.5);
+    if t > 0.0 {
+        1.0 - tail
+    } else {
+        tail
+    }
+}
+
+/// Regularized incomplete beta function I<sub>x</sub>(a, b), evaluated by its continued fraction
+fn regularized_incomplete_beta(x: f64, a: f64, b: f64) -> f64 {
+    if x <= 0.0 {
+        return 0.0;
+    }
+    if x >= 1.0 {
+        return 1.0;
+    }
+    let ln_front =
+        libm::lgamma(a + b) - libm::lgamma(a) - libm::lgamma(b) + a * x.ln() + b * (1.0 - x).ln();
+    // The continued fraction converges rapidly for x below (a + 1) / (a + b + 2), and the symmetry I_x(a, b) = 1 - I_(1-x)(b, a) is used otherwise
+    if x < (a + 1.0) / (a + b + 2.0) {
+        ln_front.exp() * beta_continued_fraction(x, a, b) / a
+    } else {
+        1.0 - ln_front.exp() * beta_continued_fraction(1.0 - x, b, a) / b
+    }
+}
+
+/// Continued fraction of the incomplete beta function, evaluated by the modified Lentz method
+fn beta_continued_fraction(x: f64, a: f64, b: f64) -> f64 {
+    const TINY: f64 = 1e-300;
+    let clamp = |value: f64| if value.abs() < TINY { TINY } else { value };
+    let mut c = 1.0;
+    let mut d = 1.0 / clamp(1.0 - (a + b) * x / (a + 1.0));
+    let mut h = d;
+    for m in 1..=300 {
+        let m = m as f64;
+        let even = m * (b - m) * x / ((a + 2.0 * m - 1.0) * (a + 2.0 * m));
+        d = 1.0 / clamp(1.0 + even * d);
+        c = clamp(1.0 + even / c);
+        h *= d * c;
+        let odd = -(a + m) * (a + b + m) * x / ((a + 2.0 * m) * (a + 2.0 * m + 1.0));
+        d = 1.0 / clamp(1.0 + odd * d);
+        c = clamp(1.0 + odd / c);
+        let delta = d * c;
+        h *= delta;
+        if (delta - 1.0).abs() < 1e-15 {
+            break;
+        }
+    }
+    h
+}
@@ -22,7 +22,13 @@ pub fn calculate_r(x: &Array2<f64>) -> (Array2<f64>, Vec<usize>) {
     let mut n_x = x.clone();
     n_x.axis_iter_mut(Axis(0))
         .into_par_iter()
-        .for_each(|mut row| row /= row.sum());
+        .for_each(|mut row| {
+            let sum = row.sum();
+            // Subjects with zero likelihood for every support point are left as zeros, rather than divided into NaN
+            if sum > 0.0 {
+                row /= sum
+            }
+        });
     let mat_x = n_x.view().into_faer();
     let qr = mat_x.col_piv_qr();
     let r_mat = qr.compute_r();
@@ -31,3 +37,32 @@ pub fn calculate_r(x: &Array2<f64>) -> (Array2<f64>, Vec<usize>) {
     let perm = Vec::from(forward);
     (r, perm)
 }
+
+/// Selects the linearly independent support points (columns) of `psi`, by a rank-revealing QR factorization
+///
+/// A support point is kept if the ratio between the diagonal element of R and the norm of its column is at least `1e-8`.
+/// Columns with a zero norm, e.g. a support point with zero likelihood for every subject, are dropped explicitly rather than through a NaN ratio.
+/// The indices of the kept support points are returned in the order of the column pivoting.
+pub fn keep_independent(psi: &Array2<f64>) -> Vec<usize> {
+    let (r, perm) = calculate_r(psi);
+
+    let mut keep = Vec::<usize>::new();
+    //The minimum between the number of subjects and the actual number of support points
+    let lim_loop = psi.nrows().min(psi.ncols());
+    for i in 0..lim_loop {
+        let norm = r.column(i).dot(&r.column(i)).sqrt();
+        if !(norm.is_finite() && norm > 0.0) {
+            tracing::debug!(
+                "QRD dropped support point {} with a column norm of {}",
+                perm[i],
+                norm
+            );
+            continue;
+        }
+        let ratio = r[(i, i)] / norm;
+        if ratio.abs() >= 1e-8 {
+            keep.push(perm[i]);
+        }
+    }
+    keep
+}
@@ -1,4 +1,6 @@
-use ndarray::Array1;
+use ndarray::{array, Array1};
+use rand::Rng;
+use rand_distr::{Distribution, Normal, StudentT};
 
 /// Contains information on the observation error
 pub trait Sigma {
@@ -12,6 +14,114 @@ pub trait Sigma {
     ///
     /// A 1-dimensional Array representing the estimated standard deviation of the observation error.
     fn sigma(&self, yobs: &Array1<f64>) -> Array1<f64>;
+
+    /// Degrees of freedom of the Student-t distribution of the residuals, or `None` for normally distributed residuals
+    ///
+    /// See [student_t_likelihood](crate::routines::evaluation::prob::student_t_likelihood).
+    fn df(&self) -> Option<f64> {
+        None
+    }
+
+    /// Transformation of the observations and predictions before the likelihood is computed, see [Transform]
+    ///
+    /// The standard deviation is then that of the transformed observations, given by [Sigma::sigma] of the transformed observations.
+    fn transform(&self) -> Transform {
+        Transform::Identity
+    }
+}
+
+/// Transformation of the observations and predictions before the likelihood is computed
+///
+/// The likelihood is that of the transformed observations, without the Jacobian of the transformation, which is the same for all support points.
+/// The objective function is therefore not comparable between transformations.
+/// Predictions outside the domain of the transformation, e.g. a negative prediction with [Transform::Sqrt], have a likelihood of zero, as failed predictions.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Transform {
+    #[default]
+    Identity,
+    /// The natural logarithm, for positive values
+    Log,
+    /// The square root, for non-negative values
+    Sqrt,
+    /// The Box-Cox transformation with parameter lambda, (x<sup>λ</sup> - 1) / λ, or ln(x) for λ = 0, for positive values
+    BoxCox(f64),
+}
+
+impl Transform {
+    /// Parses the transform of the error settings, either "identity", "log", "sqrt" or "boxcox(lambda)", e.g. "boxcox(0.5)" (case-insensitive)
+    pub fn from_name(name: &str) -> Result<Self, String> {
+        let name = name.trim().to_lowercase();
+        match name.as_str() {
+            "identity" => return Ok(Transform::Identity),
+            "log" => return Ok(Transform::Log),
+            "sqrt" => return Ok(Transform::Sqrt),
+            _ => {}
+        }
+        let lambda = name
+            .strip_prefix("boxcox(")
+            .and_then(|rest| rest.strip_suffix(')'))
+            .and_then(|lambda| lambda.trim().parse::<f64>().ok())
+            .filter(|lambda| lambda.is_finite());
+        match lambda {
+            Some(lambda) => Ok(Transform::BoxCox(lambda)),
+            None => Err(format!(
+                "Transform not supported: {}, expected \"identity\", \"log\", \"sqrt\" or \"boxcox(lambda)\"",
+                name
+            )),
+        }
+    }
+
+    /// Whether `x` is within the domain of the transformation
+    pub fn in_domain(&self, x: f64) -> bool {
+        match self {
+            Transform::Identity => true,
+            Transform::Sqrt => x >= 0.0,
+            Transform::Log | Transform::BoxCox(_) => x > 0.0,
+        }
+    }
+
+    /// Transforms `x`, or returns an error if it is outside the domain of the transformation
+    pub fn apply(&self, x: f64) -> Result<f64, String> {
+        if !self.in_domain(x) {
+            return Err(format!(
+                "{} is outside the domain of the {} transform",
+                x, self
+            ));
+        }
+        Ok(match *self {
+            Transform::Identity => x,
+            Transform::Log => x.ln(),
+            Transform::Sqrt => x.sqrt(),
+            Transform::BoxCox(0.0) => x.ln(),
+            Transform::BoxCox(lambda) => (x.powf(lambda) - 1.0) / lambda,
+        })
+    }
+
+    /// Inverts the transformation, where values outside its range are mapped to the bound of its domain, e.g. a negative value to zero for [Transform::Sqrt]
+    pub fn invert(&self, y: f64) -> f64 {
+        match *self {
+            Transform::Identity => y,
+            Transform::Log | Transform::BoxCox(0.0) => y.exp(),
+            Transform::Sqrt => y.max(0.0).powi(2),
+            Transform::BoxCox(lambda) => (lambda * y + 1.0).max(0.0).powf(1.0 / lambda),
+        }
+    }
+
+    /// Transforms the bound of a censoring interval, where bounds below the domain of the increasing transformation are mapped to -∞
+    pub fn apply_bound(&self, x: f64) -> f64 {
+        self.apply(x).unwrap_or(f64::NEG_INFINITY)
+    }
+}
+
+impl std::fmt::Display for Transform {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Transform::Identity => write!(f, "identity"),
+            Transform::Log => write!(f, "log"),
+            Transform::Sqrt => write!(f, "sqrt"),
+            Transform::BoxCox(lambda) => write!(f, "boxcox({})", lambda),
+        }
+    }
 }
 
 /// ErrorPoly contains the information on uncertainties in observations
@@ -23,6 +133,12 @@ pub struct ErrorPoly<'a> {
     pub c: (f64, f64, f64, f64),
     pub gl: f64,
     pub e_type: &'a ErrorType,
+    /// Reference concentration for the proportional error, see [ErrorType]
+    pub reference: Option<f64>,
+    /// Degrees of freedom of a Student-t distribution of the residuals, with the SD as its scale, for a likelihood that is robust to outliers
+    pub df: Option<f64>,
+    /// Transformation of the observations and predictions, see [Transform]
+    pub transform: Transform,
 }
 
 /// ErrorType defines the current error model
@@ -30,6 +146,10 @@ pub struct ErrorPoly<'a> {
 /// # Multiplicative / Proportional
 /// error = SD * γ (gamma)
 ///
+/// By default, SD is evaluated at each observation, so the error is relative to the instantaneous concentration.
+/// If a reference concentration is given, SD is instead evaluated at the reference, so the error is the same for all observations,
+/// e.g. for an assay with an error specified relative to a nominal concentration. This also keeps the error from following momentary spikes.
+///
 /// # Additive
 /// error = (SD<sup>2</sup> + lambda<sup>2</sup>)<sup>0.5</sup>
 #[derive(Debug, Clone)]
@@ -38,12 +158,97 @@ pub enum ErrorType {
     Prop,
 }
 
+impl ErrorType {
+    /// Parses the error class of the settings, either "additive" or "proportional" (case-insensitive)
+    pub fn from_class(class: &str) -> Result<Self, String> {
+        match class.to_lowercase().as_str() {
+            "additive" => Ok(ErrorType::Add),
+            "proportional" => Ok(ErrorType::Prop),
+            _ => Err(format!("Error type not supported: {}", class)),
+        }
+    }
+}
+
+/// Magnitude of the observation error in interpretable units, see [ErrorPoly::magnitude]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ErrorMagnitude {
+    /// Additive standard deviation, i.e. the standard deviation at a concentration of zero
+    pub sd: f64,
+    /// Proportional coefficient of variation in percent
+    pub cv: f64,
+}
+
+impl std::fmt::Display for ErrorMagnitude {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "SD {:.4}, CV {:.2}%", self.sd, self.cv)
+    }
+}
+
+impl<'a> ErrorPoly<'a> {
+    /// Creates an error model with the polynomial `c` and gamma or lambda `gl`, without a reference concentration or transformation, and with normally distributed residuals
+    pub fn new(c: (f64, f64, f64, f64), gl: f64, e_type: &'a ErrorType) -> Self {
+        ErrorPoly {
+            c,
+            gl,
+            e_type,
+            reference: None,
+            df: None,
+            transform: Transform::Identity,
+        }
+    }
+
+    /// Sets the reference concentration of the proportional error, see [ErrorType]
+    pub fn with_reference(mut self, reference: Option<f64>) -> Self {
+        self.reference = reference;
+        self
+    }
+
+    /// Sets the degrees of freedom of a Student-t distribution of the residuals, see [ErrorPoly::df]
+    pub fn with_df(mut self, df: Option<f64>) -> Self {
+        self.df = df;
+        self
+    }
+
+    /// Sets the transformation of the observations and predictions, see [Transform]
+    pub fn with_transform(mut self, transform: Transform) -> Self {
+        self.transform = transform;
+        self
+    }
+
+    /// Back-transforms gamma or lambda, together with the error polynomial, to an additive SD and a proportional CV%
+    ///
+    /// For the proportional class, SD = γ C0 and CV = 100 γ C1, and with a reference concentration the whole error is
+    /// additive, SD = γ (C0 + C1 x ref + ...). For the additive class, SD = (C0<sup>2</sup> + lambda<sup>2</sup>)<sup>0.5</sup> and CV = 100 C1.
+    /// The higher-order terms C2 and C3 are not included.
+    pub fn magnitude(&self) -> ErrorMagnitude {
+        let (c0, c1, c2, c3) = self.c;
+        match (self.e_type, self.reference) {
+            (ErrorType::Prop, Some(x)) => ErrorMagnitude {
+                sd: self.gl * (c0 + c1 * x + c2 * x.powi(2) + c3 * x.powi(3)),
+                cv: 0.0,
+            },
+            (ErrorType::Prop, None) => ErrorMagnitude {
+                sd: self.gl * c0,
+                cv: 100.0 * self.gl * c1,
+            },
+            (ErrorType::Add, _) => ErrorMagnitude {
+                sd: (c0.powi(2) + self.gl.powi(2)).sqrt(),
+                cv: 100.0 * c1,
+            },
+        }
+    }
+}
+
 /// Computes the error of an observation given its value, the error model, and the error polynomial
 /// Observations are weighted by 1/error<sup>2</sup>
 impl<'a> Sigma for ErrorPoly<'a> {
     fn sigma(&self, yobs: &Array1<f64>) -> Array1<f64> {
+        let yobs = match (self.e_type, self.reference) {
+            (ErrorType::Prop, Some(reference)) => yobs.mapv(|_| reference),
+            _ => yobs.clone(),
+        };
         let alpha = self.c.0
-            + self.c.1 * yobs
+            + self.c.1 * &yobs
             + self.c.2 * yobs.mapv(|x| x.powi(2))
             + self.c.3 * yobs.mapv(|x| x.powi(3));
 
@@ -64,4 +269,37 @@ impl<'a> Sigma for ErrorPoly<'a> {
             }
         })
     }
+
+    fn df(&self) -> Option<f64> {
+        self.df
+    }
+
+    fn transform(&self) -> Transform {
+        self.transform
+    }
+}
+
+/// Draws a residual error for a prediction from the distribution implied by the error model
+///
+/// The standard deviation is computed from the error polynomial evaluated at the prediction, see [ErrorPoly].
+/// For additive errors the residual is drawn from N(0, (SD<sup>2</sup> + lambda<sup>2</sup>)<sup>0.5</sup>),
+/// for proportional errors from N(0, SD * γ).
+/// With degrees of freedom, see [ErrorPoly::df], the residual is instead drawn from a Student-t distribution scaled by the SD.
+/// With a [Transform], the residual is drawn on the transformed scale, as in the likelihood, and returned on the original scale,
+/// where a prediction outside the domain of the transformation has no residual.
+/// The simulated observation is then `pred + sample_residual(pred, error_poly, rng)`.
+pub fn sample_residual<R: Rng + ?Sized>(pred: f64, error_poly: &ErrorPoly, rng: &mut R) -> f64 {
+    let transformed = match error_poly.transform.apply(pred) {
+        Ok(transformed) => transformed,
+        Err(_) => return 0.0,
+    };
+    let sd = error_poly.sigma(&array![transformed])[0];
+    if sd == 0.0 {
+        return 0.0;
+    }
+    let residual = match error_poly.df {
+        Some(df) => sd * StudentT::new(df).unwrap().sample(rng),
+        None => Normal::new(0.0, sd).unwrap().sample(rng),
+    };
+    error_poly.transform.invert(transformed + residual) - pred
 }
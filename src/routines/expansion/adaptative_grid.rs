@@ -1,4 +1,5 @@
-use ndarray::{Array, Array2};
+use ndarray::{Array, Array1, Array2, Axis};
+use rand::{rngs::StdRng, seq::index::sample, SeedableRng};
 
 use crate::routines::condensation::prune::prune;
 
@@ -9,15 +10,30 @@ use crate::routines::condensation::prune::prune;
 /// New support points are symmetrically placed around the original support point, at a distance of eps * (range_max - range_min)
 ///
 /// If the new support point is too close to an existing support point, or it is outside the given range, it is discarded
+///
+/// Existing support points, including frozen points (see `condensation::frozen`), are never moved or removed
 pub fn adaptative_grid(
     theta: &mut Array2<f64>,
     eps: f64,
     ranges: &[(f64, f64)],
     min_dist: f64,
+) -> Array2<f64> {
+    let dimensions: Vec<usize> = (0..ranges.len()).collect();
+    adaptative_grid_in(theta, eps, ranges, min_dist, &dimensions)
+}
+
+/// Adaptive grid algorithm which only expands the support points along the given `dimensions`, see [adaptative_grid]
+pub fn adaptative_grid_in(
+    theta: &mut Array2<f64>,
+    eps: f64,
+    ranges: &[(f64, f64)],
+    min_dist: f64,
+    dimensions: &[usize],
 ) -> Array2<f64> {
     let old_theta = theta.clone();
     for spp in old_theta.rows() {
-        for (j, val) in spp.into_iter().enumerate() {
+        for &j in dimensions {
+            let val = spp[j];
             let l = eps * (ranges[j].1 - ranges[j].0); //abs?
             if val + l < ranges[j].1 {
                 let mut plus = Array::zeros(spp.len());
@@ -37,3 +53,59 @@ pub fn adaptative_grid(
     }
     theta.to_owned()
 }
+
+/// Selects the `n` dimensions with the largest weighted variance of the support points `theta` with weights `w`, i.e. the least determined parameters
+///
+/// The variance of each parameter is relative to the square of its range, so parameters on different scales are comparable.
+/// Parameters with equal bounds have no variance. The dimensions are returned in increasing order.
+/// If `w` does not have a weight for each support point, every dimension is returned.
+pub fn sensitive_dimensions(
+    theta: &Array2<f64>,
+    w: &Array1<f64>,
+    ranges: &[(f64, f64)],
+    n: usize,
+) -> Vec<usize> {
+    let mut dimensions: Vec<usize> = (0..ranges.len()).collect();
+    if w.len() != theta.nrows() || n >= ranges.len() {
+        return dimensions;
+    }
+    let total = w.sum();
+    let variance: Vec<f64> = theta
+        .columns()
+        .into_iter()
+        .zip(ranges)
+        .map(|(column, (lower, upper))| {
+            let width = upper - lower;
+            if width <= 0.0 || total <= 0.0 {
+                return 0.0;
+            }
+            let mean = column.dot(w) / total;
+            column.mapv(|x| (x - mean).powi(2)).dot(w) / total / (width * width)
+        })
+        .collect();
+    dimensions.sort_by(|&a, &b| variance[b].total_cmp(&variance[a]).then(a.cmp(&b)));
+    dimensions.truncate(n);
+    dimensions.sort();
+    dimensions
+}
+
+/// Limits the grid to `max_size` support points by discarding new support points, returning the number of discarded support points
+///
+/// The first `existing` support points are never discarded, even if they exceed `max_size`. The new support points appended by [adaptative_grid] are
+/// subsampled uniformly, reproducibly for a given `seed`, so the expansion is not biased towards the candidates of the first support points.
+pub fn limit(theta: &mut Array2<f64>, existing: usize, max_size: usize, seed: usize) -> usize {
+    let size = max_size.max(existing);
+    if theta.nrows() <= size {
+        return 0;
+    }
+    let candidates = theta.nrows() - existing;
+    let mut rng = StdRng::seed_from_u64(seed as u64);
+    let mut kept = sample(&mut rng, candidates, size - existing).into_vec();
+    kept.sort();
+    let rows: Vec<usize> = (0..existing)
+        .chain(kept.into_iter().map(|i| existing + i))
+        .collect();
+    let discarded = theta.nrows() - size;
+    *theta = theta.select(Axis(0), &rows);
+    discarded
+}
@@ -1,77 +1,377 @@
 use std::fs::File;
 
-use ndarray::Array2;
+use argmin::{
+    core::{CostFunction, Error, Executor},
+    solver::neldermead::NelderMead,
+};
+use ndarray::{Array1, Array2};
+use rand::{rngs::StdRng, SeedableRng};
+use rand_distr::{Distribution, Normal};
+use rayon::prelude::*;
+use serde_derive::{Deserialize, Serialize};
 
-use crate::prelude::settings::Settings;
+use crate::prelude::{
+    datafile::Scenario,
+    output::PopulationModel,
+    predict::{Engine, Predict},
+    settings::Settings,
+};
 
 pub mod sobol;
 
-pub fn sample_space(settings: &Settings, ranges: &Vec<(f64, f64)>) -> Array2<f64> {
-    match &settings.paths.prior {
-        Some(prior_path) => {
-            tracing::info!("Reading prior from {}", prior_path);
-            let file = File::open(prior_path).unwrap();
-            let mut reader = csv::ReaderBuilder::new()
-                .has_headers(true)
-                .from_reader(file);
-
-            let mut parameter_names: Vec<String> = reader
-                .headers()
-                .unwrap()
-                .clone()
-                .into_iter()
-                .map(|s| s.trim().to_owned())
-                .collect();
-
-            // Remove "prob" column if present
-            if let Some(index) = parameter_names.iter().position(|name| name == "prob") {
-                parameter_names.remove(index);
-            }
+/// Sampler of a single random parameter of the initial grid, see [generate_mixed]
+///
+/// Given in the settings as e.g. `tlag = { kind = "normal", mean = 0.5, sd = 0.1 }` in `[config.init_samplers]`.
+#[derive(Debug, Deserialize, Clone, Copy, Serialize, PartialEq)]
+#[serde(tag = "kind", rename_all = "lowercase", deny_unknown_fields)]
+pub enum Sampler {
+    /// Uniform coverage of the range of the parameter by a Sobol sequence, the default
+    Sobol,
+    /// Samples concentrated around `mean`, from a normal distribution with standard deviation `sd` truncated to the range of the parameter
+    Normal { mean: f64, sd: f64 },
+}
 
-            // Check and reorder parameters to match names in settings.parsed.random
-            let random_names: Vec<String> = settings.random.names();
-
-            let mut reordered_indices: Vec<usize> = Vec::new();
-            for random_name in &random_names {
-                match parameter_names.iter().position(|name| name == random_name) {
-                    Some(index) => {
-                        reordered_indices.push(index);
-                    }
-                    None => {
-                        panic!("Parameter {} is not present in the CSV file.", random_name);
-                    }
+impl Sampler {
+    /// Validate that the sampler can sample within `range`
+    pub fn validate(&self, range: (f64, f64)) -> Result<(), String> {
+        match *self {
+            Sampler::Sobol => Ok(()),
+            Sampler::Normal { mean, sd } => {
+                if sd.is_nan() || sd <= 0.0 || sd.is_infinite() {
+                    return Err(format!(
+                        "The standard deviation of a normal sampler must be positive, got {}",
+                        sd
+                    ));
+                }
+                if !(range.0..=range.1).contains(&mean) {
+                    return Err(format!(
+                        "The mean of a normal sampler must be within the range [{}, {}], got {}",
+                        range.0, range.1, mean
+                    ));
                 }
+                Ok(())
             }
+        }
+    }
+}
 
-            // Check if there are remaining parameters not present in settings.parsed.random
-            if parameter_names.len() > random_names.len() {
-                let extra_parameters: Vec<&String> = parameter_names.iter().collect();
-                panic!(
-                    "Found parameters in the prior not present in configuration: {:?}",
-                    extra_parameters
+/// Samples the initial grid, or reads it from the prior given in `paths.prior`
+///
+/// The prior is either a CSV file with one column per random parameter, such as `theta.csv`, or a population model exported by
+/// [NPResult::export_population_model](crate::routines::output::NPResult::export_population_model) (with a `.json` extension).
+/// Only the support points of the prior are used, so a previous result can be refitted with a different error model.
+/// Note that gamma (or lambda) is always initialized from `error.value` in the settings, not from the prior,
+/// to continue from the previously estimated value it must be copied from the `error` field of the exported model.
+/// If the prior can not be read, e.g. as the file is missing or malformed, a warning is logged and the grid is sampled as without a prior.
+///
+/// Without a prior, each random parameter is sampled by its sampler in `config.init_samplers`, by default [Sampler::Sobol], see [generate_mixed].
+pub fn sample_space(settings: &Settings, ranges: &[(f64, f64)]) -> Array2<f64> {
+    let samplers: Vec<Sampler> = settings
+        .random
+        .names()
+        .iter()
+        .map(|name| {
+            settings
+                .config
+                .init_samplers
+                .as_ref()
+                .and_then(|samplers| samplers.get(name))
+                .copied()
+                .unwrap_or(Sampler::Sobol)
+        })
+        .collect();
+    let sobol = || {
+        generate_mixed(
+            settings.config.init_points,
+            ranges,
+            &samplers,
+            settings.config.seed,
+        )
+    };
+    match &settings.paths.prior {
+        Some(prior_path) => match read_prior(prior_path, &settings.random.names()) {
+            Ok(theta) => theta,
+            Err(e) => {
+                tracing::warn!(
+                    "Unable to read the prior {}, sampling {} support points instead: {}",
+                    prior_path,
+                    settings.config.init_points,
+                    e
                 );
+                sobol()
             }
+        },
+        None => sobol(),
+    }
+}
+
+/// Generates `n_points` points within `ranges`, sampling each parameter (column) by its sampler in `samplers`
+///
+/// The parameters with a [Sampler::Sobol] are sampled jointly by [sobol::generate], as if the others were not random,
+/// so with only Sobol samplers the grid is the same as that of [sobol::generate]. Each parameter with a [Sampler::Normal]
+/// is then sampled independently, and samples outside its range are redrawn. Parameters with equal lower and upper bounds are held at that value.
+pub fn generate_mixed(
+    n_points: usize,
+    ranges: &[(f64, f64)],
+    samplers: &[Sampler],
+    seed: usize,
+) -> Array2<f64> {
+    let sobol_ranges: Vec<(f64, f64)> = ranges
+        .iter()
+        .zip(samplers)
+        .map(|(&(min, max), sampler)| match sampler {
+            Sampler::Sobol => (min, max),
+            Sampler::Normal { .. } => (min, min),
+        })
+        .collect();
+    let mut points = sobol::generate(n_points, &sobol_ranges, seed);
+    let mut rng = StdRng::seed_from_u64(seed as u64);
+    for (i, (&(min, max), sampler)) in ranges.iter().zip(samplers).enumerate() {
+        let Sampler::Normal { mean, sd } = *sampler else {
+            continue;
+        };
+        if min == max {
+            continue;
+        }
+        let normal = Normal::new(mean, sd).unwrap();
+        for value in points.column_mut(i) {
+            // Redraw samples outside the range, and fall back to the mean if the range is far in the tails
+            *value = (0..1000)
+                .map(|_| normal.sample(&mut rng))
+                .find(|x| (min..=max).contains(x))
+                .unwrap_or(mean);
+        }
+    }
+    points
+}
+
+/// Reads the support points of the prior at `path`, with the columns in the order of `random_names`, see [sample_space]
+pub fn read_prior(
+    path: &str,
+    random_names: &[String],
+) -> Result<Array2<f64>, Box<dyn std::error::Error>> {
+    if !path.ends_with(".json") {
+        tracing::info!("Reading prior from {}", path);
+        return read_points(path, random_names);
+    }
+    tracing::info!("Reading prior from population model {}", path);
+    let model = PopulationModel::read(path)
+        .map_err(|e| format!("Unable to read the population model: {}", e))?;
+    let theta = model.theta();
+    if model.parameters.len() != random_names.len() {
+        return Err(format!(
+            "The parameters of the prior {:?} do not match the random parameters {:?}",
+            model.parameters, random_names
+        )
+        .into());
+    }
+    let reordered_indices = random_names
+        .iter()
+        .map(|random_name| {
+            model
+                .parameters
+                .iter()
+                .position(|name| name == random_name)
+                .ok_or_else(|| format!("Parameter {} is not present in the prior.", random_name))
+        })
+        .collect::<Result<Vec<usize>, String>>()?;
+    Ok(theta.select(ndarray::Axis(1), &reordered_indices))
+}
+
+/// Reads the support points given in `paths.seed_points`, which are added to the initial grid
+///
+/// The file is a CSV file with one column per random parameter, as for the prior, see [sample_space].
+/// Returns an empty grid if no seed points are given, and an error if any of the points are outside the `ranges` of the random parameters.
+pub fn seed_points(
+    settings: &Settings,
+    ranges: &[(f64, f64)],
+) -> Result<Array2<f64>, Box<dyn std::error::Error>> {
+    let Some(path) = &settings.paths.seed_points else {
+        return Ok(Array2::zeros((0, ranges.len())));
+    };
+    let names = settings.random.names();
+    let points = read_points(path, &names)?;
+    for point in points.rows() {
+        for ((value, (min, max)), name) in point.iter().zip(ranges).zip(&names) {
+            if !(min..=max).contains(&value) {
+                return Err(format!(
+                    "Seed point {} has {} = {}, outside its range [{}, {}]",
+                    point, name, value, min, max
+                )
+                .into());
+            }
+        }
+    }
+    Ok(points)
+}
+
+/// Reads support points from a CSV file with a header, reordering the columns to match `random_names`
+///
+/// A `prob` column, as in `theta.csv`, is ignored.
+fn read_points(
+    path: &str,
+    random_names: &[String],
+) -> Result<Array2<f64>, Box<dyn std::error::Error>> {
+    let file = File::open(path)?;
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(true)
+        .from_reader(file);
 
-            // Read parameter values row by row, keeping only those associated with the reordered parameters
-            let mut theta_values = Vec::new();
-            for result in reader.records() {
-                let record = result.unwrap();
-                let values: Vec<f64> = reordered_indices
-                    .iter()
-                    .map(|&i| record[i].parse::<f64>().unwrap())
-                    .collect();
-                theta_values.push(values);
+    let mut parameter_names: Vec<String> = reader
+        .headers()?
+        .clone()
+        .into_iter()
+        .map(|s| s.trim().to_owned())
+        .collect();
+
+    // Remove "prob" column if present
+    if let Some(index) = parameter_names.iter().position(|name| name == "prob") {
+        parameter_names.remove(index);
+    }
+
+    // Check and reorder parameters to match names in settings.parsed.random
+    let mut reordered_indices: Vec<usize> = Vec::new();
+    for random_name in random_names {
+        match parameter_names.iter().position(|name| name == random_name) {
+            Some(index) => {
+                reordered_indices.push(index);
             }
+            None => {
+                return Err(
+                    format!("Parameter {} is not present in {}.", random_name, path).into(),
+                );
+            }
+        }
+    }
 
-            let n_points = theta_values.len();
-            let n_params = random_names.len();
+    // Check if there are remaining parameters not present in settings.parsed.random
+    if parameter_names.len() > random_names.len() {
+        return Err(format!(
+            "Found parameters in {} not present in configuration: {:?}",
+            path, parameter_names
+        )
+        .into());
+    }
+
+    // Read parameter values row by row, keeping only those associated with the reordered parameters
+    let mut theta_values = Vec::new();
+    for result in reader.records() {
+        let record = result?;
+        for &i in &reordered_indices {
+            theta_values.push(record[i].trim().parse::<f64>()?);
+        }
+    }
 
-            // Convert nested Vec into a single Vec
-            let theta_values: Vec<f64> = theta_values.into_iter().flatten().collect();
+    let n_params = random_names.len();
+    let n_points = theta_values.len() / n_params.max(1);
+    Ok(Array2::from_shape_vec((n_points, n_params), theta_values)?)
+}
 
-            Array2::from_shape_vec((n_points, n_params), theta_values)
-                .expect("Failed to create theta Array2")
+/// Weighted least squares objective for a single subject
+///
+/// Observations are weighted by 1/obs<sup>2</sup>, i.e. a constant coefficient of variation is assumed.
+struct WlsCost<'a, S>
+where
+    S: Predict<'static> + Sync + Clone,
+{
+    engine: &'a Engine<S>,
+    scenario: &'a Scenario,
+    ranges: &'a [(f64, f64)],
+}
+
+impl<'a, S> CostFunction for WlsCost<'a, S>
+where
+    S: Predict<'static> + Sync + Clone,
+{
+    type Param = Array1<f64>;
+    type Output = f64;
+    fn cost(&self, spp: &Self::Param) -> Result<Self::Output, Error> {
+        // Parameters outside the ranges are not valid support points
+        let outside = spp
+            .iter()
+            .zip(self.ranges)
+            .any(|(val, (min, max))| val < min || val > max);
+        if outside {
+            return Ok(f64::INFINITY);
+        }
+        let ypred = self.engine.pred(self.scenario.clone(), spp.to_vec());
+        let sse: f64 = ypred
+            .iter()
+            .zip(&self.scenario.obs)
+            .map(|(pred, obs)| (obs - pred).powi(2) / obs.powi(2).max(f64::EPSILON))
+            .sum();
+        if sse.is_nan() {
+            return Ok(f64::INFINITY);
         }
-        None => sobol::generate(settings.config.init_points, ranges, settings.config.seed),
+        Ok(sse)
+    }
+}
+
+/// Generates candidate support points from naive individual parameter estimates
+///
+/// A weighted least squares fit is performed for each subject, starting from the center of `ranges`.
+/// Each row of the returned array is the estimate of one subject, and is guaranteed to lie within `ranges`.
+/// Subjects for which the fit fails are skipped, so the result may have fewer rows than there are subjects.
+pub fn seed_from_wls<S>(
+    engine: &Engine<S>,
+    scenarios: &[Scenario],
+    ranges: &[(f64, f64)],
+) -> Array2<f64>
+where
+    S: Predict<'static> + Sync + Clone,
+{
+    let estimates: Vec<Array1<f64>> = scenarios
+        .par_iter()
+        .filter_map(|scenario| match fit_wls(engine, scenario, ranges) {
+            Ok(estimate) => Some(estimate),
+            Err(err) => {
+                tracing::warn!("WLS fit failed for subject {}: {}", scenario.id, err);
+                None
+            }
+        })
+        .collect();
+
+    let mut seeds = Array2::zeros((0, ranges.len()));
+    for estimate in estimates {
+        seeds.push_row(estimate.view()).unwrap();
+    }
+    seeds
+}
+
+fn fit_wls<S>(
+    engine: &Engine<S>,
+    scenario: &Scenario,
+    ranges: &[(f64, f64)],
+) -> Result<Array1<f64>, Error>
+where
+    S: Predict<'static> + Sync + Clone,
+{
+    let center: Array1<f64> = ranges.iter().map(|(min, max)| (min + max) / 2.0).collect();
+    let mut simplex = vec![center.clone()];
+    for (i, (min, max)) in ranges.iter().enumerate() {
+        let mut vertex = center.clone();
+        vertex[i] += 0.25 * (max - min);
+        simplex.push(vertex);
     }
+
+    let cost = WlsCost {
+        engine,
+        scenario,
+        ranges,
+    };
+    let solver = NelderMead::new(simplex).with_sd_tolerance(1e-8)?;
+    let res = Executor::new(cost, solver)
+        .configure(|state| state.max_iters(500))
+        .run()?;
+    let estimate = res
+        .state
+        .best_param
+        .ok_or_else(|| Error::msg("No parameter estimate"))?;
+
+    // Keep the estimate strictly within the ranges
+    Ok(estimate
+        .iter()
+        .zip(ranges)
+        .map(|(val, (min, max))| val.clamp(*min, *max))
+        .collect())
 }
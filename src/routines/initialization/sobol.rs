@@ -2,7 +2,14 @@ use ndarray::prelude::*;
 use ndarray::{Array, ArrayBase, OwnedRepr};
 use sobol_burley::sample;
 
+/// Number of points of the Sobol sequence of a single seed
+const MAX_POINTS: usize = 1 << 16;
+
 /// Generates a 2-dimensional array containing a Sobol sequence within the given ranges
+///
+/// Parameters with equal lower and upper bounds are held at that value, and do not use a dimension of the sequence,
+/// such that the other parameters are sampled as if the parameter was not random.
+/// The sequence has at most 2<sup>16</sup> points per seed, so larger grids continue with the sequence of the next seed for each further 2<sup>16</sup> points.
 /// # Returns
 /// A 2D array where each row is a point, and each column corresponds to a parameter.
 pub fn generate(
@@ -15,8 +22,15 @@ pub fn generate(
     for i in 0..n_points {
         let mut row = seq.slice_mut(s![i, ..]);
         let mut point: Vec<f64> = Vec::new();
-        for j in 0..n_params {
-            point.push(sample(i.try_into().unwrap(), j.try_into().unwrap(), seed as u32) as f64)
+        let mut dimension: u32 = 0;
+        for (min, max) in range_params {
+            if min == max {
+                point.push(0.0);
+                continue;
+            }
+            let (index, block) = (i % MAX_POINTS, i / MAX_POINTS);
+            point.push(sample(index as u32, dimension, (seed + block) as u32) as f64);
+            dimension += 1;
         }
         row.assign(&Array::from(point));
     }
@@ -13,18 +13,20 @@ use crate::prelude::{prob, sigma::Sigma};
 
 pub struct SppOptimizer<'a, S, P>
 where
-    S: Sigma + Sync,
+    S: Sigma + Sync + ?Sized,
     P: Predict<'static> + Sync + Clone,
 {
     engine: &'a Engine<P>,
     scenarios: &'a Vec<Scenario>,
     sig: &'a S,
     pyl: &'a Array1<f64>,
+    /// Weights of the subjects, see [Config::subject_weights](crate::routines::settings::Config::subject_weights)
+    weights: &'a Array1<f64>,
 }
 
 impl<'a, S, P> CostFunction for SppOptimizer<'a, S, P>
 where
-    S: Sigma + Sync,
+    S: Sigma + Sync + ?Sized,
     P: Predict<'static> + Sync + Clone,
 {
     type Param = Array1<f64>;
@@ -43,10 +45,10 @@ where
                 self.pyl.len()
             );
         }
-        let nsub = psi.nrows() as f64;
-        let mut sum = -nsub;
-        for (p_i, pyl_i) in psi.iter().zip(self.pyl.iter()) {
-            sum += nsub + p_i / pyl_i;
+        // D(θ) = Σ w_i (p_i(θ) / pyl_i - 1), which is zero at the support points of the optimal distribution
+        let mut sum = -self.weights.sum();
+        for ((p_i, pyl_i), w_i) in psi.iter().zip(self.pyl.iter()).zip(self.weights.iter()) {
+            sum += w_i * p_i / pyl_i;
         }
         Ok(-sum)
     }
@@ -54,7 +56,7 @@ where
 
 impl<'a, S, P> SppOptimizer<'a, S, P>
 where
-    S: Sigma + Sync,
+    S: Sigma + Sync + ?Sized,
     P: Predict<'static> + Sync + Clone,
 {
     pub fn new(
@@ -62,12 +64,14 @@ where
         scenarios: &'a Vec<Scenario>,
         sig: &'a S,
         pyl: &'a Array1<f64>,
+        weights: &'a Array1<f64>,
     ) -> Self {
         Self {
             engine,
             scenarios,
             sig,
             pyl,
+            weights,
         }
     }
     pub fn optimize_point(self, spp: Array1<f64>) -> Result<Array1<f64>, Error> {
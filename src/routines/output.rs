@@ -1,11 +1,146 @@
+use crate::algorithms::Algorithm;
 use crate::prelude::*;
+use chrono::{DateTime, Local};
+use convergence::StopReason;
 use csv::WriterBuilder;
-use datafile::Scenario;
+use datafile::{Event, Scenario};
 use ndarray::parallel::prelude::*;
 use ndarray::{Array, Array1, Array2, Axis};
 use predict::{post_predictions, sim_obs, Engine, Predict};
+use serde_derive::{Deserialize, Serialize};
 use settings::Settings;
+use sha2::{Digest, Sha256};
+use std::error;
 use std::fs::File;
+use std::path::{Path, PathBuf};
+use timing::CycleTiming;
+use warnings::Warning;
+
+/// Level of detail of the output files written at the end of a run, see [NPResult::output_files]
+///
+/// The `output_level` setting is parsed by [OutputLevel::from_name].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default, Deserialize, Serialize)]
+#[serde(try_from = "String", rename_all = "lowercase")]
+pub enum OutputLevel {
+    /// Only the cycle log, `cycles.csv`, with the objective function of each cycle
+    Minimal,
+    /// Also the support points, the posterior probabilities and individual estimates, and the population model
+    Standard,
+    /// Also the observations, predictions and diagnostics
+    #[default]
+    Full,
+}
+
+impl OutputLevel {
+    /// Parses the `output_level` setting, either "minimal", "standard" or "full" (case-insensitive)
+    pub fn from_name(name: &str) -> Result<Self, String> {
+        match name.to_lowercase().as_str() {
+            "minimal" => Ok(OutputLevel::Minimal),
+            "standard" => Ok(OutputLevel::Standard),
+            "full" => Ok(OutputLevel::Full),
+            _ => Err(format!(
+                "Output level not supported: {}, expected \"minimal\", \"standard\" or \"full\"",
+                name
+            )),
+        }
+    }
+}
+
+impl TryFrom<String> for OutputLevel {
+    type Error = String;
+
+    fn try_from(name: String) -> Result<Self, Self::Error> {
+        OutputLevel::from_name(&name)
+    }
+}
+
+/// A file written by [NPResult::write_outputs], see [NPResult::output_files]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFile {
+    Theta,
+    Posterior,
+    IndividualEstimates,
+    Obs,
+    Pred,
+    SupportPointPredictions,
+    Diagnostics,
+    EffectiveSupport,
+    Shrinkage,
+    Correlation,
+    ObjfContributions,
+    Meta,
+    Timing,
+    #[cfg(feature = "plots")]
+    ObjfPlot,
+    #[cfg(feature = "parquet")]
+    ThetaParquet,
+    #[cfg(feature = "parquet")]
+    PredParquet,
+    PopulationModel,
+    Manifest,
+}
+
+impl OutputFile {
+    /// Name of the file
+    pub fn name(&self) -> &'static str {
+        match self {
+            OutputFile::Theta => "theta.csv",
+            OutputFile::Posterior => "posterior.csv",
+            OutputFile::IndividualEstimates => "individual_estimates.csv",
+            OutputFile::Obs => "obs.csv",
+            OutputFile::Pred => "pred.csv",
+            OutputFile::SupportPointPredictions => "support_point_predictions.csv",
+            OutputFile::Diagnostics => "diagnostics.csv",
+            OutputFile::EffectiveSupport => "effective_support.csv",
+            OutputFile::Shrinkage => "shrinkage.csv",
+            OutputFile::Correlation => "correlation.csv",
+            OutputFile::ObjfContributions => "objf_contributions.csv",
+            OutputFile::Meta => "meta_rust.csv",
+            OutputFile::Timing => "timing.csv",
+            #[cfg(feature = "plots")]
+            OutputFile::ObjfPlot => "objf.svg",
+            #[cfg(feature = "parquet")]
+            OutputFile::ThetaParquet => "theta.parquet",
+            #[cfg(feature = "parquet")]
+            OutputFile::PredParquet => "pred.parquet",
+            OutputFile::PopulationModel => "population_model.json",
+            OutputFile::Manifest => "manifest.json",
+        }
+    }
+
+    /// Description of the contents of the file, for the log
+    fn description(&self) -> &'static str {
+        match self {
+            OutputFile::Theta => "final parameter distribution",
+            OutputFile::Posterior => "posterior parameter probabilities",
+            OutputFile::IndividualEstimates => "individual estimates",
+            OutputFile::Obs => "(expanded) observations",
+            OutputFile::Pred => "individual predictions",
+            OutputFile::SupportPointPredictions => "support point predictions",
+            OutputFile::Diagnostics => "diagnostics",
+            OutputFile::EffectiveSupport => "effective number of support points",
+            OutputFile::Shrinkage => "shrinkage",
+            OutputFile::Correlation => "parameter correlations",
+            OutputFile::ObjfContributions => "objective function contributions",
+            OutputFile::Meta => "run metadata",
+            OutputFile::Timing => "timings",
+            #[cfg(feature = "plots")]
+            OutputFile::ObjfPlot => "objective function plot",
+            #[cfg(feature = "parquet")]
+            OutputFile::ThetaParquet => "final parameter distribution as Parquet",
+            #[cfg(feature = "parquet")]
+            OutputFile::PredParquet => "individual predictions as Parquet",
+            OutputFile::PopulationModel => "population model",
+            OutputFile::Manifest => "manifest",
+        }
+    }
+}
+
+impl std::fmt::Display for OutputFile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
 
 /// Defines the result objects from an NPAG run
 /// An [NPResult] contains the necessary information to generate predictions and summary statistics
@@ -20,6 +155,18 @@ pub struct NPResult {
     pub converged: bool,
     pub par_names: Vec<String>,
     pub settings: Settings,
+    pub cycle_records: Vec<CycleRecord>,
+    pub timings: Vec<CycleTiming>,
+    /// Warnings raised during the run, see [Warning]
+    pub warnings: Vec<Warning>,
+    /// Time the run started, see [NPResult::with_start]
+    pub started: DateTime<Local>,
+    /// Time the run finished, i.e. the time the result was created
+    pub finished: DateTime<Local>,
+    /// Reason the run stopped, see [NPResult::stop_reason]
+    pub stop_reason: Option<StopReason>,
+    /// Hash of the data file when it was read for the fit, see [NPResult::with_data_hash]
+    pub data_hash: Option<String>,
 }
 
 impl NPResult {
@@ -37,6 +184,7 @@ impl NPResult {
         // TODO: Add support for fixed and constant parameters
 
         let par_names = settings.random.names();
+        let finished = Local::now();
 
         Self {
             scenarios,
@@ -48,19 +196,814 @@ impl NPResult {
             converged,
             par_names,
             settings,
+            cycle_records: Vec::new(),
+            timings: Vec::new(),
+            warnings: Vec::new(),
+            started: finished,
+            finished,
+            stop_reason: None,
+            data_hash: None,
+        }
+    }
+
+    /// Sets the reason the run stopped
+    pub fn with_stop_reason(mut self, stop_reason: Option<StopReason>) -> Self {
+        self.stop_reason = stop_reason;
+        self
+    }
+
+    /// Reason the run stopped, e.g. to distinguish convergence from reaching the maximum number of cycles
+    ///
+    /// This is `None` if the result was not created at the end of a run, e.g. if it was constructed with [NPResult::new].
+    pub fn stop_reason(&self) -> Option<StopReason> {
+        self.stop_reason
+    }
+
+    /// Sets the time the run started, which is otherwise the time the result was created
+    pub fn with_start(mut self, started: DateTime<Local>) -> Self {
+        self.started = started;
+        self
+    }
+
+    /// Sets the hash of the data file the subjects were read from, which is reported in the [RunManifest], see [hash_file]
+    ///
+    /// The hash is taken when the data is read, so the manifest describes the data which was fitted even if the file changes during the run.
+    pub fn with_data_hash(mut self, data_hash: Option<String>) -> Self {
+        self.data_hash = data_hash;
+        self
+    }
+
+    /// Attach the records of the cycles of the run, see [NPResult::cycle_log]
+    pub fn with_cycle_log(mut self, cycle_log: &CycleLog) -> Self {
+        self.cycle_records = cycle_log.cycles.iter().map(CycleRecord::from).collect();
+        self
+    }
+
+    /// Attach the time spent in each stage of the cycles of the run, see [timing::CycleTimer]
+    pub fn with_timings(mut self, timings: &[CycleTiming]) -> Self {
+        self.timings = timings.to_vec();
+        self
+    }
+
+    /// Attach the warnings raised during the run, see [Warning]
+    pub fn with_warnings(mut self, warnings: &[Warning]) -> Self {
+        self.warnings = warnings.to_vec();
+        self
+    }
+
+    /// Exports the final population model as JSON to `path`, see [PopulationModel]
+    pub fn export_population_model(&self, path: &str) -> Result<(), Box<dyn error::Error>> {
+        let model = PopulationModel::from(self);
+        let file = File::create(path)?;
+        serde_json::to_writer_pretty(file, &model)?;
+        Ok(())
+    }
+
+    /// Reproducibility metadata of the run, see [RunManifest]
+    ///
+    /// The hash of the data file is missing if it was not set with [NPResult::with_data_hash],
+    /// e.g. if the subjects were not read from the data file of the settings.
+    pub fn manifest(&self) -> RunManifest {
+        let settings = serde_json::to_value(&self.settings)
+            .map(|value| value.to_string())
+            .unwrap_or_default();
+        RunManifest {
+            npcore_version: env!("CARGO_PKG_VERSION").to_string(),
+            git_commit: option_env!("GIT_COMMIT_HASH").map(str::to_string),
+            settings_hash: sha256(settings.as_bytes()),
+            data_path: self.settings.paths.data.clone(),
+            data_hash: self.data_hash.clone(),
+            seed: self.settings.config.seed,
+            started: self.started.to_rfc3339(),
+            finished: self.finished.to_rfc3339(),
+            converged: self.converged,
+            stop_reason: self.stop_reason,
+            cycles: self.cycles,
+            objf: self.reported_objf(),
         }
     }
 
+    /// Writes the reproducibility metadata of the run as JSON to `path`, see [NPResult::manifest]
+    pub fn write_manifest(&self, path: &str) -> Result<(), Box<dyn error::Error>> {
+        let file = File::create(path)?;
+        serde_json::to_writer_pretty(file, &self.manifest())?;
+        Ok(())
+    }
+
+    /// The cycle log of the run as structured records, one per cycle, in the order they were run
+    pub fn cycle_log(&self) -> &[CycleRecord] {
+        &self.cycle_records
+    }
+
+    /// The subjects the model was fitted to, in the order of the rows of `psi`, e.g. to compute residuals without parsing the data again
+    pub fn scenarios(&self) -> &[Scenario] {
+        &self.scenarios
+    }
+
+    /// The settings of the run, with the error polynomial as estimated
+    pub fn settings(&self) -> &Settings {
+        &self.settings
+    }
+
+    /// Continues the run with NPAG for up to `extra_cycles` more cycles, starting from the final support points, weights and error model
+    ///
+    /// This is useful to refine a converged run, as the grid is again expanded with the initial resolution, and refined until NPAG converges anew.
+    /// The returned result includes the cycle log, timings and warnings of both runs. No output files are written, and no TUI is started.
+    /// Fails if the output equations of the settings do not match the parameters, see [Engine::with_parameters].
+    pub fn continue_run<S>(
+        self,
+        engine: Engine<S>,
+        extra_cycles: usize,
+    ) -> Result<NPResult, Box<dyn error::Error>>
+    where
+        S: Predict<'static> + std::marker::Sync + Clone,
+    {
+        self.continue_internal(engine, extra_cycles, None)
+    }
+
+    /// As [NPResult::continue_run], for a run with a user-defined error model, which must be supplied again as the result does not hold it
+    ///
+    /// See [Algorithm::set_sigma](crate::algorithms::Algorithm::set_sigma).
+    pub fn continue_run_with_sigma<S>(
+        self,
+        engine: Engine<S>,
+        extra_cycles: usize,
+        sigma: Box<dyn sigma::Sigma + Sync>,
+    ) -> Result<NPResult, Box<dyn error::Error>>
+    where
+        S: Predict<'static> + std::marker::Sync + Clone,
+    {
+        self.continue_internal(engine, extra_cycles, Some(sigma))
+    }
+
+    fn continue_internal<S>(
+        self,
+        engine: Engine<S>,
+        extra_cycles: usize,
+        sigma: Option<Box<dyn sigma::Sigma + Sync>>,
+    ) -> Result<NPResult, Box<dyn error::Error>>
+    where
+        S: Predict<'static> + std::marker::Sync + Clone,
+    {
+        let engine = engine
+            .configure(&self.settings)
+            .map_err(|err| format!("Unable to continue the run: {}", err))?;
+        let cycle_records = self.cycle_records.clone();
+        let timings = self.timings.clone();
+        let warnings = self.warnings.clone();
+        let started = self.started;
+        let data_hash = self.data_hash.clone();
+        let (tx, _rx) = tokio::sync::mpsc::unbounded_channel();
+        let mut npag = algorithms::npag::NPAG::from_result(engine, self, extra_cycles, tx);
+        if let Some(sigma) = sigma {
+            npag.set_sigma(sigma);
+        }
+        let mut continued = npag.run();
+        continued.cycle_records.splice(0..0, cycle_records);
+        continued.timings.splice(0..0, timings);
+        continued.warnings.splice(0..0, warnings);
+        continued.started = started;
+        continued.data_hash = data_hash;
+        Ok(continued)
+    }
+
+    /// Predicts the output of a subject at arbitrary times, using the population mean parameter vector
+    ///
+    /// The dosing regimen of the subject with the given `subject_id` is used, and the first output equation of the subject.
+    /// Predictions are returned in the same order as `times`. If a requested time coincides with a dose, the prediction includes that dose.
+    /// Times must be finite and not before the first event of the subject, while times after the last event are extrapolated.
+    pub fn predict_at<S>(
+        &self,
+        engine: &Engine<S>,
+        subject_id: &str,
+        times: &[f64],
+    ) -> Result<Vec<f64>, Box<dyn error::Error>>
+    where
+        S: Predict<'static> + std::marker::Sync + Clone,
+    {
+        let scenario = self
+            .scenarios
+            .iter()
+            .find(|scenario| scenario.id == subject_id)
+            .ok_or(format!("Subject {} not found", subject_id))?;
+        let events: Vec<Event> = scenario
+            .blocks
+            .iter()
+            .flat_map(|block| block.events.iter().cloned())
+            .collect();
+        let first_time = events.first().ok_or("Subject has no events")?.time;
+        for time in times {
+            if !time.is_finite() || *time < first_time {
+                return Err(format!(
+                    "Cannot predict subject {} at time {}, which is before the first event at time {}",
+                    subject_id, time, first_time
+                )
+                .into());
+            }
+        }
+        if times.is_empty() {
+            return Ok(vec![]);
+        }
+        let outeq = events.iter().find_map(|event| event.outeq).unwrap_or(1);
+
+        // Requested times are added in sorted order, as observations are predicted in order of time
+        let mut order: Vec<usize> = (0..times.len()).collect();
+        order.sort_by(|a, b| times[*a].partial_cmp(&times[*b]).unwrap());
+        let mut new_events: Vec<Event> = events
+            .iter()
+            .filter(|event| event.evid == 1)
+            .cloned()
+            .collect();
+        for &i in &order {
+            new_events.push(Event {
+                id: scenario.id.clone(),
+                evid: 0,
+                time: times[i],
+                dur: None,
+                dose: None,
+                addl: None,
+                ii: None,
+                input: None,
+                out: Some(-99.0),
+                interval: None,
+                outeq: Some(outeq),
+                _c0: None,
+                _c1: None,
+                _c2: None,
+                _c3: None,
+                covs: events.first().unwrap().covs.clone(),
+            });
+        }
+        new_events.sort_by(|a, b| a.cmp_by_id_then_time(b));
+        let new_scenario = Scenario::new(new_events)?;
+
+        let pop_mean = population_mean(&self.theta, &self.w);
+        let sorted_pred = engine.pred(new_scenario, pop_mean.to_vec());
+        let mut pred = vec![0.0; times.len()];
+        for (&i, y) in order.iter().zip(sorted_pred) {
+            pred[i] = y;
+        }
+        Ok(pred)
+    }
+
+    /// Predicts the observations of each subject with the typical value, i.e. the population mean parameter vector
+    ///
+    /// A single parameter vector, the weighted mean of the support points, is simulated, as in the `popMean` column of pred.csv.
+    /// This differs from the mean of the predictions of the mixture, i.e. the weighted mean of the predictions of each support point,
+    /// which is the expected prediction under the population model. The two are only equal for models which are linear in the parameters.
+    /// If `idelta` is positive, predictions are also made at intervals of `idelta`, up to `tad` after the last dose, see [Scenario::add_event_interval].
+    /// For each subject, in the order of [NPResult::scenarios], the pairs of time and prediction are returned in the order of time.
+    pub fn typical_value_predictions<S>(
+        &self,
+        engine: &Engine<S>,
+        idelta: f64,
+        tad: f64,
+    ) -> Vec<Vec<(f64, f64)>>
+    where
+        S: Predict<'static> + std::marker::Sync + Clone,
+    {
+        let scenarios: Vec<Scenario> = self
+            .scenarios
+            .iter()
+            .map(|scenario| {
+                if idelta > 0.0 {
+                    scenario.add_event_interval(idelta, tad)
+                } else {
+                    scenario.clone()
+                }
+            })
+            .collect();
+        let pop_mean = population_mean(&self.theta, &self.w);
+        let ndim = pop_mean.len();
+        let pred = sim_obs(
+            engine,
+            &scenarios,
+            &pop_mean.into_shape((1, ndim)).unwrap(),
+            false,
+        );
+        scenarios
+            .iter()
+            .enumerate()
+            .map(|(i, scenario)| {
+                scenario
+                    .obs_times
+                    .iter()
+                    .copied()
+                    .zip(pred[(i, 0)].iter().copied())
+                    .collect()
+            })
+            .collect()
+    }
+
+    /// Computes the error of the population mean predictions with respect to the observations of all subjects
+    ///
+    /// Missing observations, coded as -99, are ignored.
+    pub fn prediction_metrics<S>(&self, engine: &Engine<S>) -> PredictionMetrics
+    where
+        S: Predict<'static> + std::marker::Sync + Clone,
+    {
+        let pop_mean = population_mean(&self.theta, &self.w);
+        let ndim = pop_mean.len();
+        let pop_mean_pred = sim_obs(
+            engine,
+            &self.scenarios,
+            &pop_mean.into_shape((1, ndim)).unwrap(),
+            false,
+        );
+
+        let errors: Vec<f64> = self
+            .scenarios
+            .iter()
+            .enumerate()
+            .flat_map(|(i, scenario)| {
+                let pred = pop_mean_pred.get((i, 0)).unwrap();
+                scenario
+                    .obs
+                    .iter()
+                    .zip(pred.iter())
+                    .filter(|(obs, _)| **obs != -99.0)
+                    .map(|(obs, pred)| pred - obs)
+                    .collect::<Vec<f64>>()
+            })
+            .collect();
+        PredictionMetrics::from_errors(&errors)
+    }
+
+    /// Number of free parameters of the discrete population distribution, i.e. the coordinates of the support points and their weights, which sum to one
+    pub fn n_parameters(&self) -> usize {
+        (self.theta.nrows() * (self.theta.ncols() + 1)).saturating_sub(1)
+    }
+
+    /// The objective function, -2LL, as reported, i.e. without the normalization constants of the likelihood if `likelihood_constants` is false
+    ///
+    /// The fit itself always includes the constants, which are given by [prob::log_normalization], so the difference is -2 times their sum.
+    pub fn reported_objf(&self) -> f64 {
+        if self.settings.config.likelihood_constants {
+            self.objf
+        } else {
+            self.objf + 2.0 * prob::log_normalization(&self.scenarios, self.settings.error.df)
+        }
+    }
+
+    /// Akaike information criterion, `-2LL + 2k`, with `k` given by [NPResult::n_parameters] and -2LL by [NPResult::reported_objf]
+    pub fn aic(&self) -> f64 {
+        self.reported_objf() + 2.0 * self.n_parameters() as f64
+    }
+
+    /// Bayesian information criterion, `-2LL + k ln(n)`, with `k` given by [NPResult::n_parameters], `n` the number of observations, and -2LL by [NPResult::reported_objf]
+    ///
+    /// Missing observations, coded as -99, are not counted.
+    pub fn bic(&self) -> f64 {
+        let n_obs = self
+            .scenarios
+            .iter()
+            .flat_map(|scenario| scenario.obs.iter())
+            .filter(|obs| **obs != -99.0)
+            .count();
+        self.reported_objf() + self.n_parameters() as f64 * (n_obs as f64).ln()
+    }
+
+    /// Compares this result to `other`, e.g. a run of an alternative model on the same data
+    ///
+    /// Differences are given as `other - self`, so negative differences in the objective function or information criteria favour `other`.
+    /// Parameters are matched by name, and parameters which are not estimated in both runs are left out.
+    pub fn compare(&self, other: &NPResult) -> ComparisonReport {
+        let ranges = self.settings.random.names_and_ranges();
+        let other_ranges = other.settings.random.names_and_ranges();
+        let parameters = ranges
+            .iter()
+            .enumerate()
+            .filter_map(|(i, (name, (lo, hi)))| {
+                let (j, (_, (other_lo, other_hi))) = other_ranges
+                    .iter()
+                    .enumerate()
+                    .find(|(_, (other_name, _))| other_name == name)?;
+                let range = (lo.min(*other_lo), hi.max(*other_hi));
+                let marginal = self.theta.column(i);
+                let other_marginal = other.theta.column(j);
+                Some(ParameterComparison {
+                    name: name.clone(),
+                    mean: (
+                        marginal.dot(&self.w) / self.w.sum(),
+                        other_marginal.dot(&other.w) / other.w.sum(),
+                    ),
+                    overlap: histogram_overlap(
+                        (&marginal.to_owned(), &self.w),
+                        (&other_marginal.to_owned(), &other.w),
+                        range,
+                    ),
+                })
+            })
+            .collect();
+        ComparisonReport {
+            delta_objf: other.reported_objf() - self.reported_objf(),
+            delta_aic: other.aic() - self.aic(),
+            delta_bic: other.bic() - self.bic(),
+            nspp: (self.theta.nrows(), other.theta.nrows()),
+            parameters,
+        }
+    }
+
+    /// Contribution of each subject to the objective function, i.e. -2 times the log of its marginal likelihood, in the order of the subjects
+    ///
+    /// The contributions are not weighted. Without subject weights they sum to the objective function `objf`, while with subject weights,
+    /// see [Config::subject_weights](crate::routines::settings::Config::subject_weights), `objf` is their sum weighted by the subject weights.
+    /// Subjects with a large contribution may dominate the fit, e.g. as they have many observations.
+    pub fn objf_contributions(&self) -> Array1<f64> {
+        self.psi.dot(&self.w).mapv(|l| -2.0 * l.ln())
+    }
+
+    /// Writes the contribution of each subject to the objective function, see [NPResult::objf_contributions]
+    pub fn write_objf_contributions(&self) {
+        tracing::info!("Writing objective function contributions...");
+        if let Err(e) = self.write_objf_contributions_to("objf_contributions.csv") {
+            tracing::error!(
+                "Error while writing objective function contributions: {}",
+                e
+            );
+        }
+    }
+
+    /// Writes the contributions of the subjects to `path`, see [NPResult::write_objf_contributions]
+    pub(crate) fn write_objf_contributions_to(&self, path: &str) -> Result<(), csv::Error> {
+        let contributions = self.objf_contributions();
+        let precision = self.settings.config.output_precision;
+        let file = File::create(path)?;
+        let mut writer = WriterBuilder::new().has_headers(false).from_writer(file);
+        writer.write_record(["id", "nobs", "contribution"])?;
+        for (scenario, contribution) in self.scenarios.iter().zip(contributions.iter()) {
+            writer.write_record(&[
+                scenario.id.clone(),
+                scenario.obs.len().to_string(),
+                format_float(*contribution, precision),
+            ])?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Effective number of support points in the posterior of each subject, in the order of the subjects
+    ///
+    /// The count is the exponential of the entropy of the posterior probabilities of the support points, see [posterior].
+    /// It is one if the subject matches a single support point, and the number of support points if all are equally probable.
+    /// Subjects with a marginal likelihood of zero have an undefined posterior, and a count of NaN.
+    pub fn effective_support_per_subject(&self) -> Array1<f64> {
+        posterior(&self.psi, &self.w)
+            .outer_iter()
+            .map(|row| {
+                let entropy: f64 = row
+                    .iter()
+                    .filter(|&&p| p != 0.0)
+                    .map(|&p| -p * p.ln())
+                    .sum();
+                entropy.exp()
+            })
+            .collect()
+    }
+
+    /// Weighted Pearson correlation matrix of the parameters across the support points, using the weights of the population distribution
+    ///
+    /// The correlation is undefined for a parameter without variance, e.g. if there is only a single support point,
+    /// in which case its row and column, including the diagonal, are NaN.
+    pub fn parameter_correlation(&self) -> Array2<f64> {
+        let w = &self.w / self.w.sum();
+        let mean = self.theta.t().dot(&w);
+        let centered = &self.theta - &mean;
+        let weighted = &centered * &w.clone().insert_axis(Axis(1));
+        let cov = centered.t().dot(&weighted);
+        let sd = cov
+            .diag()
+            .mapv(|var| if var > 0.0 { var.sqrt() } else { f64::NAN });
+        let mut corr = Array2::from_shape_fn(cov.dim(), |(i, j)| cov[(i, j)] / (sd[i] * sd[j]));
+        for i in 0..corr.nrows() {
+            if sd[i].is_finite() {
+                corr[(i, i)] = 1.0;
+            }
+        }
+        corr
+    }
+
+    /// Shrinkage of the individual estimates of each parameter, i.e. one minus the ratio of the SD of the posterior means of the subjects to the SD of the population distribution
+    ///
+    /// Shrinkage near zero indicates individual estimates which are as dispersed as the population, and near one estimates which collapse towards the population mean,
+    /// e.g. as the subjects have too few observations to inform the parameter. The population SD is weighted by the weights of the support points,
+    /// and the SD of the posterior means is across the subjects, both with the number of values as denominator. Subjects with an undefined posterior are left out, see [posterior].
+    /// Shrinkage is NaN for a parameter without population variance.
+    pub fn shrinkage(&self) -> Array1<f64> {
+        let w = &self.w / self.w.sum();
+        let population_mean = self.theta.t().dot(&w);
+        let population_var = (&self.theta - &population_mean).mapv(|x| x * x).t().dot(&w);
+        let post_mean = posterior(&self.psi, &self.w).dot(&self.theta);
+        let defined: Vec<usize> = post_mean
+            .outer_iter()
+            .enumerate()
+            .filter(|(_, row)| row.iter().all(|x| x.is_finite()))
+            .map(|(i, _)| i)
+            .collect();
+        let post_mean = post_mean.select(Axis(0), &defined);
+        let individual_sd = post_mean.std_axis(Axis(0), 0.0);
+        Array1::from_shape_fn(population_var.len(), |i| {
+            if population_var[i] > 0.0 && !defined.is_empty() {
+                1.0 - individual_sd[i] / population_var[i].sqrt()
+            } else {
+                f64::NAN
+            }
+        })
+    }
+
+    /// Writes the posterior mean and median of the parameters of each subject to `path`, followed by the metadata of the subjects, see [Scenario::metadata]
+    pub fn write_individual_estimates(&self, path: &str) -> Result<(), Box<dyn error::Error>> {
+        let (mean, median) = posterior_mean_median(&self.theta, &self.psi, &self.w);
+        let precision = self.settings.config.output_precision;
+        let keys = metadata_keys(&self.scenarios);
+        let mut writer = WriterBuilder::new().has_headers(false).from_path(path)?;
+        let mut header = vec!["id".to_string()];
+        header.extend(self.par_names.iter().map(|name| format!("{}_mean", name)));
+        header.extend(self.par_names.iter().map(|name| format!("{}_median", name)));
+        header.extend(keys.iter().cloned());
+        writer.write_record(&header)?;
+        for (i, scenario) in self.scenarios.iter().enumerate() {
+            let mut record = vec![scenario.id.clone()];
+            record.extend(
+                mean.row(i)
+                    .iter()
+                    .map(|&value| format_float(value, precision)),
+            );
+            record.extend(
+                median
+                    .row(i)
+                    .iter()
+                    .map(|&value| format_float(value, precision)),
+            );
+            record.extend(metadata_values(scenario, &keys));
+            writer.write_record(&record)?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Writes the parameter correlation matrix, see [NPResult::parameter_correlation]
+    pub fn write_correlation(&self) {
+        tracing::info!("Writing parameter correlations...");
+        if let Err(e) = self.write_correlation_to("correlation.csv") {
+            tracing::error!("Error while writing parameter correlations: {}", e);
+        }
+    }
+
+    /// Writes the parameter correlation matrix to `path`, see [NPResult::write_correlation]
+    pub(crate) fn write_correlation_to(&self, path: &str) -> Result<(), csv::Error> {
+        let corr = self.parameter_correlation();
+        let precision = self.settings.config.output_precision;
+        let file = File::create(path)?;
+        let mut writer = WriterBuilder::new().has_headers(false).from_writer(file);
+        let mut header = vec!["parameter".to_string()];
+        header.extend(self.par_names.iter().cloned());
+        writer.write_record(&header)?;
+        for (name, row) in self.par_names.iter().zip(corr.outer_iter()) {
+            let mut record = vec![name.clone()];
+            record.extend(row.iter().map(|&value| format_float(value, precision)));
+            writer.write_record(&record)?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Writes the prediction metrics, see [NPResult::prediction_metrics], the effective number of support points of each subject, see [NPResult::effective_support_per_subject],
+    /// and the shrinkage of each parameter to `shrinkage.csv`, see [NPResult::shrinkage]
+    pub fn write_diagnostics<S>(&self, engine: &Engine<S>)
+    where
+        S: Predict<'static> + std::marker::Sync + Clone,
+    {
+        tracing::info!("Writing diagnostics...");
+        let result = self
+            .write_prediction_metrics_to(engine, "diagnostics.csv")
+            .and_then(|_| self.write_effective_support_to("effective_support.csv"))
+            .and_then(|_| self.write_shrinkage_to("shrinkage.csv"));
+        if let Err(e) = result {
+            tracing::error!("Error while writing diagnostics: {}", e);
+        }
+    }
+
+    /// Writes the prediction metrics to `path`, see [NPResult::prediction_metrics]
+    pub(crate) fn write_prediction_metrics_to<S>(
+        &self,
+        engine: &Engine<S>,
+        path: &str,
+    ) -> Result<(), csv::Error>
+    where
+        S: Predict<'static> + std::marker::Sync + Clone,
+    {
+        let metrics = self.prediction_metrics(engine);
+        let precision = self.settings.config.output_precision;
+        let file = File::create(path)?;
+        let mut writer = WriterBuilder::new().has_headers(false).from_writer(file);
+        writer.write_record(["n", "bias", "mae", "rmse"])?;
+        writer.write_record(&[
+            metrics.n.to_string(),
+            format_float(metrics.bias, precision),
+            format_float(metrics.mae, precision),
+            format_float(metrics.rmse, precision),
+        ])?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Writes the effective number of support points of each subject to `path`, see [NPResult::effective_support_per_subject]
+    pub(crate) fn write_effective_support_to(&self, path: &str) -> Result<(), csv::Error> {
+        let precision = self.settings.config.output_precision;
+        let file = File::create(path)?;
+        let mut writer = WriterBuilder::new().has_headers(false).from_writer(file);
+        writer.write_record(["id", "effective_support"])?;
+        for (scenario, count) in self
+            .scenarios
+            .iter()
+            .zip(self.effective_support_per_subject().iter())
+        {
+            writer.write_record(&[scenario.id.clone(), format_float(*count, precision)])?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Writes the shrinkage of each parameter to `path`, see [NPResult::shrinkage]
+    pub(crate) fn write_shrinkage_to(&self, path: &str) -> Result<(), csv::Error> {
+        let precision = self.settings.config.output_precision;
+        let file = File::create(path)?;
+        let mut writer = WriterBuilder::new().has_headers(false).from_writer(file);
+        writer.write_record(["parameter", "shrinkage"])?;
+        for (name, shrinkage) in self.par_names.iter().zip(self.shrinkage().iter()) {
+            writer.write_record(&[name.clone(), format_float(*shrinkage, precision)])?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Writes the prediction of each support point at the observation times of each subject to `path`, with the weight of the support point, e.g. for fan plots
+    ///
+    /// The columns are `id`, `point`, `weight`, `time`, `outeq` and `pred`, where `point` is the index of the support point as in `posterior.csv`,
+    /// so there is one row per subject, support point and observation. Predictions are scaled and labelled as in `pred.csv`, see [settings::OutputEquation].
+    pub fn write_support_point_predictions<S>(
+        &self,
+        engine: &Engine<S>,
+        path: &str,
+    ) -> Result<(), Box<dyn error::Error>>
+    where
+        S: Predict<'static> + std::marker::Sync + Clone,
+    {
+        let ypred = sim_obs(engine, &self.scenarios, &self.theta, false);
+        let precision = self.settings.config.output_precision;
+        let mut writer = WriterBuilder::new().has_headers(false).from_path(path)?;
+        let mut header = vec!["id", "point", "weight", "time", "outeq", "pred"];
+        if !self.settings.outputs.is_empty() {
+            header.extend(["name", "unit"]);
+        }
+        writer.write_record(&header)?;
+        for (i, scenario) in self.scenarios.iter().enumerate() {
+            let outeqs = scenario.obs_outeqs();
+            for (point, weight) in self.w.iter().enumerate() {
+                for ((time, pred), outeq) in scenario
+                    .obs_times
+                    .iter()
+                    .zip(&ypred[(i, point)])
+                    .zip(&outeqs)
+                {
+                    let (labels, scale) = output_labels(&self.settings, *outeq);
+                    let mut record = vec![
+                        scenario.id.clone(),
+                        point.to_string(),
+                        format_float(*weight, precision),
+                        format_float(*time, precision),
+                        outeq.to_string(),
+                        format_float(scale(*pred), precision),
+                    ];
+                    record.extend(labels);
+                    writer.write_record(&record)?;
+                }
+            }
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// The files written by [NPResult::write_outputs], in the order they are written, as given by the `output_level` in the settings, see [OutputLevel]
+    ///
+    /// The cycle log, `cycles.csv`, is written during the run, and is not included.
+    pub fn output_files(&self) -> Vec<OutputFile> {
+        let config = &self.settings.config;
+        let level = config.output_level;
+        let mut files = Vec::new();
+        if level >= OutputLevel::Standard {
+            files.extend([
+                OutputFile::Theta,
+                OutputFile::Posterior,
+                OutputFile::IndividualEstimates,
+            ]);
+        }
+        if level >= OutputLevel::Full {
+            files.extend([OutputFile::Obs, OutputFile::Pred]);
+            if config.support_point_predictions {
+                files.push(OutputFile::SupportPointPredictions);
+            }
+            files.extend([
+                OutputFile::Diagnostics,
+                OutputFile::EffectiveSupport,
+                OutputFile::Shrinkage,
+                OutputFile::Correlation,
+                OutputFile::ObjfContributions,
+            ]);
+        }
+        if level >= OutputLevel::Standard {
+            files.push(OutputFile::Meta);
+            if !self.timings.is_empty() {
+                files.push(OutputFile::Timing);
+            }
+        }
+        #[cfg(feature = "plots")]
+        if level >= OutputLevel::Full {
+            files.push(OutputFile::ObjfPlot);
+        }
+        // Parquet copies of theta.csv and pred.csv, at the same levels
+        #[cfg(feature = "parquet")]
+        {
+            if level >= OutputLevel::Standard {
+                files.push(OutputFile::ThetaParquet);
+            }
+            if level >= OutputLevel::Full {
+                files.push(OutputFile::PredParquet);
+            }
+        }
+        if level >= OutputLevel::Standard {
+            files.extend([OutputFile::PopulationModel, OutputFile::Manifest]);
+        }
+        files
+    }
+
+    /// Writes the output files given by [NPResult::output_files] to the working directory if `write` is true
     pub fn write_outputs<'a, S>(&self, write: bool, engine: &Engine<S>, idelta: f64, tad: f64)
     where
         S: Predict<'static> + std::marker::Sync + 'static + Clone + std::marker::Send,
     {
-        if write {
-            self.write_theta();
-            self.write_posterior();
-            self.write_obs();
-            self.write_pred(&engine, idelta, tad);
-            self.write_meta();
+        if !write {
+            return;
+        }
+        self.write_outputs_to(Path::new(""), engine, idelta, tad);
+    }
+
+    /// Writes the output files given by [NPResult::output_files] to the directory `dir`, see [NPResult::write_outputs]
+    ///
+    /// Errors are logged, and do not keep the other files from being written.
+    pub fn write_outputs_to<S>(&self, dir: &Path, engine: &Engine<S>, idelta: f64, tad: f64)
+    where
+        S: Predict<'static> + std::marker::Sync + 'static + Clone + std::marker::Send,
+    {
+        // The predictions are shared by pred.csv and pred.parquet
+        let mut predictions = None;
+        for file in self.output_files() {
+            tracing::info!("Writing {}...", file.description());
+            let path = dir.join(file.name());
+            let path = path.to_string_lossy();
+            let precision = self.settings.config.output_precision;
+            let result: Result<(), Box<dyn error::Error>> = match file {
+                OutputFile::Theta => self.write_theta_to(&path).map_err(Box::from),
+                OutputFile::Posterior => self.write_posterior_to(&path).map_err(Box::from),
+                OutputFile::IndividualEstimates => self.write_individual_estimates(&path),
+                OutputFile::Obs => self.write_obs_to(&path).map_err(Box::from),
+                OutputFile::Pred => predictions
+                    .get_or_insert_with(|| self.predictions(engine, idelta, tad))
+                    .write_csv(&path, precision)
+                    .map_err(Box::from),
+                OutputFile::SupportPointPredictions => {
+                    self.write_support_point_predictions(engine, &path)
+                }
+                OutputFile::Diagnostics => self
+                    .write_prediction_metrics_to(engine, &path)
+                    .map_err(Box::from),
+                OutputFile::EffectiveSupport => {
+                    self.write_effective_support_to(&path).map_err(Box::from)
+                }
+                OutputFile::Shrinkage => self.write_shrinkage_to(&path).map_err(Box::from),
+                OutputFile::Correlation => self.write_correlation_to(&path).map_err(Box::from),
+                OutputFile::ObjfContributions => {
+                    self.write_objf_contributions_to(&path).map_err(Box::from)
+                }
+                OutputFile::Meta => MetaWriter::create(&path)
+                    .map(|mut writer| writer.write(self.converged, self.cycles))
+                    .map_err(Box::from),
+                OutputFile::Timing => timing::write(&self.timings, &path),
+                #[cfg(feature = "plots")]
+                OutputFile::ObjfPlot => plot_objf(self.cycle_log(), &path),
+                #[cfg(feature = "parquet")]
+                OutputFile::ThetaParquet => self.write_theta_parquet(&path),
+                #[cfg(feature = "parquet")]
+                OutputFile::PredParquet => predictions
+                    .get_or_insert_with(|| self.predictions(engine, idelta, tad))
+                    .write_parquet(&path),
+                OutputFile::PopulationModel => self.export_population_model(&path),
+                OutputFile::Manifest => self.write_manifest(&path),
+            };
+            if let Err(e) = result {
+                tracing::error!("Error while writing {}: {}", file.description(), e);
+            }
         }
     }
 
@@ -70,239 +1013,749 @@ impl NPResult {
         meta_writer.write(self.converged, self.cycles);
     }
 
+    /// Indices of the support points reported in `theta.csv`, and the number and total weight of the support points omitted for being below `output_weight_threshold`
+    pub fn reported_support_points(&self) -> (Vec<usize>, usize, f64) {
+        let threshold = self.settings.config.output_weight_threshold.unwrap_or(0.0);
+        let (reported, omitted): (Vec<usize>, Vec<usize>) =
+            (0..self.w.len()).partition(|&i| self.w[i] >= threshold);
+        let omitted_weight = omitted.iter().map(|&i| self.w[i]).sum();
+        (reported, omitted.len(), omitted_weight)
+    }
+
     /// Writes theta, which containts the population support points and their associated probabilities
     /// Each row is one support point, the last column being probability
     pub fn write_theta(&self) {
         tracing::info!("Writing final parameter distribution...");
-        let result = (|| {
-            let theta: Array2<f64> = self.theta.clone();
-            let w: Array1<f64> = self.w.clone();
-
-            let file = File::create("theta.csv")?;
-            let mut writer = WriterBuilder::new().has_headers(true).from_writer(file);
-
-            // Create the headers
-            let mut theta_header = self.par_names.clone();
-            theta_header.push("prob".to_string());
-            writer.write_record(&theta_header)?;
-
-            // Write contents
-            for (theta_row, &w_val) in theta.outer_iter().zip(w.iter()) {
-                let mut row: Vec<String> = theta_row.iter().map(|&val| val.to_string()).collect();
-                row.push(w_val.to_string());
-                writer.write_record(&row)?;
-            }
-            writer.flush()
-        })();
-
-        if let Err(e) = result {
+        if let Err(e) = self.write_theta_to("theta.csv") {
             tracing::error!("Error while writing theta: {}", e);
         }
     }
 
+    /// Writes the support points with a weight of at least `output_weight_threshold` to `path`, see [NPResult::reported_support_points]
+    pub(crate) fn write_theta_to(&self, path: &str) -> Result<(), csv::Error> {
+        let precision = self.settings.config.output_precision;
+        let (reported, omitted, omitted_weight) = self.reported_support_points();
+        if omitted > 0 {
+            tracing::info!(
+                "Omitted {} support point(s) below the output weight threshold from {}, with a total weight of {}",
+                omitted,
+                path,
+                format_float(omitted_weight, precision)
+            );
+        }
+
+        write_support_points(
+            path,
+            &self.par_names,
+            &self.theta.select(Axis(0), &reported),
+            &self.w.select(Axis(0), &reported),
+            precision,
+        )
+    }
+
     /// Writes the posterior support points for each individual
     pub fn write_posterior(&self) {
         tracing::info!("Writing posterior parameter probabilities...");
-        let result = (|| {
-            let theta: Array2<f64> = self.theta.clone();
-            let w: Array1<f64> = self.w.clone();
-            let psi: Array2<f64> = self.psi.clone();
-            let par_names: Vec<String> = self.par_names.clone();
-            let scenarios = self.scenarios.clone();
-
-            let posterior = posterior(&psi, &w);
-
-            let file = File::create("posterior.csv")?;
-            let mut writer = WriterBuilder::new().has_headers(true).from_writer(file);
-
-            // Create the headers
-            writer.write_field("id")?;
-            writer.write_field("point")?;
-            for i in 0..theta.ncols() {
-                let param_name = par_names.get(i).unwrap();
-                writer.write_field(param_name)?;
-            }
-            writer.write_field("prob")?;
-            writer.write_record(None::<&[u8]>)?;
-
-            // Write contents
-            for (sub, row) in posterior.axis_iter(Axis(0)).enumerate() {
-                for (spp, elem) in row.axis_iter(Axis(0)).enumerate() {
-                    writer.write_field(&scenarios.get(sub).unwrap().id)?;
-                    writer.write_field(format!("{}", spp))?;
-                    for param in theta.row(spp) {
-                        writer.write_field(&format!("{param}"))?;
-                    }
-                    writer.write_field(&format!("{elem:.10}"))?;
-                    writer.write_record(None::<&[u8]>)?;
+        if let Err(e) = self.write_posterior_to("posterior.csv") {
+            tracing::error!("Error while writing posterior: {}", e);
+        }
+    }
+
+    /// Writes the posterior probabilities of the support points for each subject to `path`, see [NPResult::write_posterior]
+    pub(crate) fn write_posterior_to(&self, path: &str) -> Result<(), csv::Error> {
+        let theta: Array2<f64> = self.theta.clone();
+        let w: Array1<f64> = self.w.clone();
+        let psi: Array2<f64> = self.psi.clone();
+        let par_names: Vec<String> = self.par_names.clone();
+        let scenarios = self.scenarios.clone();
+        let precision = self.settings.config.output_precision;
+
+        let posterior = posterior(&psi, &w);
+
+        let file = File::create(path)?;
+        let mut writer = WriterBuilder::new().has_headers(true).from_writer(file);
+
+        // Create the headers
+        writer.write_field("id")?;
+        writer.write_field("point")?;
+        for i in 0..theta.ncols() {
+            let param_name = par_names.get(i).unwrap();
+            writer.write_field(param_name)?;
+        }
+        writer.write_field("prob")?;
+        writer.write_record(None::<&[u8]>)?;
+
+        // Write contents
+        for (sub, row) in posterior.axis_iter(Axis(0)).enumerate() {
+            for (spp, elem) in row.axis_iter(Axis(0)).enumerate() {
+                writer.write_field(&scenarios.get(sub).unwrap().id)?;
+                writer.write_field(format!("{}", spp))?;
+                for param in theta.row(spp) {
+                    writer.write_field(format_float(*param, precision))?;
+                }
+                match precision {
+                    Some(_) => writer.write_field(format_float(*elem.into_scalar(), precision))?,
+                    None => writer.write_field(format!("{elem:.10}"))?,
                 }
+                writer.write_record(None::<&[u8]>)?;
             }
-            writer.flush()
-        })();
-
-        if let Err(e) = result {
-            tracing::error!("Error while writing posterior: {}", e);
         }
+        writer.flush()?;
+        Ok(())
     }
 
     /// Write the observations, which is the reformatted input data
     pub fn write_obs(&self) {
         tracing::info!("Writing (expanded) observations...");
-        let result = (|| {
-            let scenarios = self.scenarios.clone();
-
-            let file = File::create("obs.csv")?;
-            let mut writer = WriterBuilder::new().has_headers(false).from_writer(file);
-
-            // Create the headers
-            writer.write_record(["id", "time", "obs", "outeq"])?;
-
-            // Write contents
-            for scenario in scenarios {
-                for (observation, time) in scenario.obs.iter().zip(&scenario.obs_times) {
-                    writer.write_record(&[
-                        scenario.id.to_string(),
-                        time.to_string(),
-                        observation.to_string(),
-                        "1".to_string(),
-                    ])?;
-                }
-            }
-            writer.flush()
-        })();
-
-        if let Err(e) = result {
+        if let Err(e) = self.write_obs_to("obs.csv") {
             tracing::error!("Error while writing observations: {}", e);
         }
     }
 
+    /// Writes the observations to `path`, scaled and labelled as given by the output equations in the settings, see [settings::OutputEquation]
+    pub(crate) fn write_obs_to(&self, path: &str) -> Result<(), csv::Error> {
+        let scenarios = self.scenarios.clone();
+        let precision = self.settings.config.output_precision;
+
+        let file = File::create(path)?;
+        let mut writer = WriterBuilder::new().has_headers(false).from_writer(file);
+
+        // Create the headers
+        let mut header = vec!["id", "time", "obs", "outeq"];
+        if !self.settings.outputs.is_empty() {
+            header.extend(["name", "unit"]);
+        }
+        writer.write_record(&header)?;
+
+        // Write contents
+        for scenario in scenarios {
+            for ((observation, time), outeq) in scenario
+                .obs
+                .iter()
+                .zip(&scenario.obs_times)
+                .zip(scenario.obs_outeqs())
+            {
+                let (labels, scale) = output_labels(&self.settings, outeq);
+                let mut record = vec![
+                    scenario.id.to_string(),
+                    format_float(*time, precision),
+                    format_float(scale(*observation), precision),
+                    outeq.to_string(),
+                ];
+                record.extend(labels.iter().cloned());
+                writer.write_record(&record)?;
+            }
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
     /// Writes the predictions
     pub fn write_pred<'a, S>(&self, engine: &Engine<S>, idelta: f64, tad: f64)
     where
         S: Predict<'static> + std::marker::Sync + std::marker::Send + 'static + Clone,
     {
         tracing::info!("Writing individual predictions...");
-        let result = (|| {
-            let mut scenarios = self.scenarios.clone();
-            // Add an event interval to each scenario
-            if idelta > 0.0 {
-                scenarios.iter_mut().for_each(|scenario| {
-                    *scenario = scenario.add_event_interval(idelta, tad);
+        if let Err(e) = self.write_pred_to(engine, idelta, tad, "pred.csv") {
+            tracing::error!("Error while writing predictions: {}", e);
+        }
+    }
+
+    /// Writes the predictions to `path`, see [NPResult::write_pred]
+    pub(crate) fn write_pred_to<S>(
+        &self,
+        engine: &Engine<S>,
+        idelta: f64,
+        tad: f64,
+        path: &str,
+    ) -> Result<(), csv::Error>
+    where
+        S: Predict<'static> + std::marker::Sync + std::marker::Send + 'static + Clone,
+    {
+        self.predictions(engine, idelta, tad)
+            .write_csv(path, self.settings.config.output_precision)
+    }
+
+    /// The population and posterior predictions at the observation times, and every `idelta` up to `tad` after the last event
+    fn predictions<S>(&self, engine: &Engine<S>, idelta: f64, tad: f64) -> Predictions
+    where
+        S: Predict<'static> + std::marker::Sync + std::marker::Send + 'static + Clone,
+    {
+        let mut scenarios = self.scenarios.clone();
+        // Add an event interval to each scenario
+        if idelta > 0.0 {
+            scenarios.iter_mut().for_each(|scenario| {
+                *scenario = scenario.add_event_interval(idelta, tad);
+            });
+        }
+
+        let theta: Array2<f64> = self.theta.clone();
+        let w: Array1<f64> = self.w.clone();
+        let psi: Array2<f64> = self.psi.clone();
+
+        let (pop_mean, pop_median) = population_mean_median(&theta, &w);
+        let (post_mean, post_median) = posterior_mean_median(&theta, &psi, &w);
+        let post_mean_pred = post_predictions(engine, post_mean, &scenarios).unwrap();
+        let post_median_pred = post_predictions(engine, post_median, &scenarios).unwrap();
+
+        let ndim = pop_mean.len();
+        let pop_mean_pred = sim_obs(
+            engine,
+            &scenarios,
+            &pop_mean.into_shape((1, ndim)).unwrap(),
+            false,
+        );
+        let pop_median_pred = sim_obs(
+            engine,
+            &scenarios,
+            &pop_median.into_shape((1, ndim)).unwrap(),
+            false,
+        );
+
+        let keys = metadata_keys(&scenarios);
+        let mut extra_columns = Vec::new();
+        if !self.settings.outputs.is_empty() {
+            extra_columns.extend(["name".to_string(), "unit".to_string()]);
+        }
+        extra_columns.extend(keys.iter().cloned());
+
+        let mut rows = Vec::new();
+        for (id, scenario) in scenarios.iter().enumerate() {
+            let time = scenario.obs_times.clone();
+            let pop_mp = pop_mean_pred.get((id, 0)).unwrap().to_owned();
+            let pop_medp = pop_median_pred.get((id, 0)).unwrap().to_owned();
+            let post_mp = post_mean_pred.get(id).unwrap().to_owned();
+            let post_mdp = post_median_pred.get(id).unwrap().to_owned();
+            for (((((pop_mp_i, pop_mdp_i), post_mp_i), post_medp_i), t), outeq) in pop_mp
+                .into_iter()
+                .zip(pop_medp)
+                .zip(post_mp)
+                .zip(post_mdp)
+                .zip(time)
+                .zip(scenario.obs_outeqs())
+            {
+                let (mut extra, scale) = output_labels(&self.settings, outeq);
+                extra.extend(metadata_values(scenario, &keys));
+                rows.push(PredictionRow {
+                    id: scenario.id.to_string(),
+                    time: t,
+                    outeq,
+                    values: [
+                        scale(pop_mp_i),
+                        scale(pop_mdp_i),
+                        scale(post_mp_i),
+                        scale(post_medp_i),
+                    ],
+                    extra,
                 });
             }
+        }
+        Predictions {
+            extra_columns,
+            rows,
+        }
+    }
 
-            let theta: Array2<f64> = self.theta.clone();
-            let w: Array1<f64> = self.w.clone();
-            let psi: Array2<f64> = self.psi.clone();
-
-            let (pop_mean, pop_median) = population_mean_median(&theta, &w);
-            let (post_mean, post_median) = posterior_mean_median(&theta, &psi, &w);
-            let post_mean_pred = post_predictions(engine, post_mean, &scenarios).unwrap();
-            let post_median_pred = post_predictions(engine, post_median, &scenarios).unwrap();
-
-            let ndim = pop_mean.len();
-            let pop_mean_pred = sim_obs(
-                engine,
-                &scenarios,
-                &pop_mean.into_shape((1, ndim)).unwrap(),
-                false,
-            );
-            let pop_median_pred = sim_obs(
-                engine,
-                &scenarios,
-                &pop_median.into_shape((1, ndim)).unwrap(),
-                false,
-            );
+    /// Writes the reported support points to `path` as Parquet, with the columns of `theta.csv`, see [NPResult::write_theta_to]
+    ///
+    /// The values are written at full precision, regardless of `output_precision`.
+    #[cfg(feature = "parquet")]
+    pub fn write_theta_parquet(&self, path: &str) -> Result<(), Box<dyn error::Error>> {
+        use arrow_array::{ArrayRef, Float64Array};
+        use std::sync::Arc;
+
+        let (reported, _, _) = self.reported_support_points();
+        let theta = self.theta.select(Axis(0), &reported);
+        let mut columns: Vec<(String, ArrayRef)> = self
+            .par_names
+            .iter()
+            .zip(theta.columns())
+            .map(|(name, column)| {
+                let array: ArrayRef = Arc::new(Float64Array::from(column.to_vec()));
+                (name.clone(), array)
+            })
+            .collect();
+        let prob = self.w.select(Axis(0), &reported).to_vec();
+        columns.push(("prob".to_string(), Arc::new(Float64Array::from(prob))));
+        write_parquet(path, columns)
+    }
 
-            let file = File::create("pred.csv")?;
-            let mut writer = WriterBuilder::new().has_headers(false).from_writer(file);
-
-            // Create the headers
-            writer.write_record([
-                "id",
-                "time",
-                "outeq",
-                "popMean",
-                "popMedian",
-                "postMean",
-                "postMedian",
-            ])?;
+    /// Writes the predictions to `path` as Parquet, with the columns of `pred.csv`, see [NPResult::write_pred]
+    ///
+    /// `id`, the output labels and the metadata are strings, `outeq` an unsigned integer and the times and predictions are written at full precision, regardless of `output_precision`.
+    #[cfg(feature = "parquet")]
+    pub fn write_pred_parquet<S>(
+        &self,
+        engine: &Engine<S>,
+        idelta: f64,
+        tad: f64,
+        path: &str,
+    ) -> Result<(), Box<dyn error::Error>>
+    where
+        S: Predict<'static> + std::marker::Sync + std::marker::Send + 'static + Clone,
+    {
+        self.predictions(engine, idelta, tad).write_parquet(path)
+    }
+}
 
-            // Write contents
-            for (id, scenario) in scenarios.iter().enumerate() {
-                let time = scenario.obs_times.clone();
-                let pop_mp = pop_mean_pred.get((id, 0)).unwrap().to_owned();
-                let pop_medp = pop_median_pred.get((id, 0)).unwrap().to_owned();
-                let post_mp = post_mean_pred.get(id).unwrap().to_owned();
-                let post_mdp = post_median_pred.get(id).unwrap().to_owned();
-                for ((((pop_mp_i, pop_mdp_i), post_mp_i), post_medp_i), t) in pop_mp
-                    .into_iter()
-                    .zip(pop_medp)
-                    .zip(post_mp)
-                    .zip(post_mdp)
-                    .zip(time)
-                {
-                    writer
-                        .write_record(&[
-                            scenarios.get(id).unwrap().id.to_string(),
-                            t.to_string(),
-                            "1".to_string(),
-                            pop_mp_i.to_string(),
-                            pop_mdp_i.to_string(),
-                            post_mp_i.to_string(),
-                            post_medp_i.to_string(),
-                        ])
-                        .unwrap();
-                }
-            }
-            writer.flush()
-        })();
+/// The leading columns of `pred.csv`, followed by the output labels and metadata, see [NPResult::write_pred]
+const PREDICTION_COLUMNS: [&str; 7] = [
+    "id",
+    "time",
+    "outeq",
+    "popMean",
+    "popMedian",
+    "postMean",
+    "postMedian",
+];
+
+/// The rows of `pred.csv`, shared by the CSV and Parquet writers
+struct Predictions {
+    /// Names of the output label and metadata columns, after [PREDICTION_COLUMNS]
+    extra_columns: Vec<String>,
+    rows: Vec<PredictionRow>,
+}
 
-        if let Err(e) = result {
-            tracing::error!("Error while writing predictions: {}", e);
+struct PredictionRow {
+    id: String,
+    time: f64,
+    outeq: usize,
+    /// Population mean and median, and posterior mean and median predictions
+    values: [f64; 4],
+    extra: Vec<String>,
+}
+
+impl Predictions {
+    /// Writes the predictions to `path` as `pred.csv`, see [NPResult::write_pred]
+    fn write_csv(&self, path: &str, precision: Option<usize>) -> Result<(), csv::Error> {
+        let file = File::create(path)?;
+        let mut writer = WriterBuilder::new().has_headers(false).from_writer(file);
+
+        // Create the headers
+        let mut header: Vec<String> = PREDICTION_COLUMNS.iter().map(|s| s.to_string()).collect();
+        header.extend(self.extra_columns.iter().cloned());
+        writer.write_record(&header)?;
+
+        // Write contents
+        for row in &self.rows {
+            let mut record = vec![
+                row.id.clone(),
+                format_float(row.time, precision),
+                row.outeq.to_string(),
+            ];
+            record.extend(row.values.iter().map(|v| format_float(*v, precision)));
+            record.extend(row.extra.iter().cloned());
+            writer.write_record(&record)?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Writes the predictions to `path` as Parquet, see [NPResult::write_pred_parquet]
+    #[cfg(feature = "parquet")]
+    fn write_parquet(&self, path: &str) -> Result<(), Box<dyn error::Error>> {
+        use arrow_array::{ArrayRef, Float64Array, StringArray, UInt64Array};
+        use std::sync::Arc;
+
+        let rows = &self.rows;
+        let mut columns: Vec<(String, ArrayRef)> = vec![
+            (
+                "id".to_string(),
+                Arc::new(StringArray::from_iter_values(rows.iter().map(|r| &r.id))),
+            ),
+            (
+                "time".to_string(),
+                Arc::new(Float64Array::from_iter_values(rows.iter().map(|r| r.time))),
+            ),
+            (
+                "outeq".to_string(),
+                Arc::new(UInt64Array::from_iter_values(
+                    rows.iter().map(|r| r.outeq as u64),
+                )),
+            ),
+        ];
+        for (i, name) in PREDICTION_COLUMNS[3..].iter().enumerate() {
+            let values = Float64Array::from_iter_values(rows.iter().map(|r| r.values[i]));
+            columns.push((name.to_string(), Arc::new(values)));
+        }
+        for (i, name) in self.extra_columns.iter().enumerate() {
+            let values = StringArray::from_iter_values(rows.iter().map(|r| &r.extra[i]));
+            columns.push((name.clone(), Arc::new(values)));
         }
+        write_parquet(path, columns)
     }
 }
+
+/// Writes `columns` as a single record batch to the Parquet file `path`
+#[cfg(feature = "parquet")]
+fn write_parquet(
+    path: &str,
+    columns: Vec<(String, arrow_array::ArrayRef)>,
+) -> Result<(), Box<dyn error::Error>> {
+    let batch = arrow_array::RecordBatch::try_from_iter(columns)?;
+    let file = File::create(path)?;
+    let mut writer = parquet::arrow::ArrowWriter::try_new(file, batch.schema(), None)?;
+    writer.write(&batch)?;
+    writer.close()?;
+    Ok(())
+}
+
+/// The final population model, with everything needed to simulate from it
+///
+/// The model is exported as JSON by [NPResult::export_population_model], with the following fields:
+/// - `parameters`: names of the random parameters, in the order of the columns of the support points
+/// - `support_points`: one array of parameter values per support point
+/// - `weights`: the probability of each support point
+/// - `error`: the error model, as in the `error` section of the settings, where `value` is the final gamma or lambda
+/// - `fixed` and `constant`: the fixed and constant parameters of the settings, if any
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PopulationModel {
+    pub parameters: Vec<String>,
+    pub support_points: Vec<Vec<f64>>,
+    pub weights: Vec<f64>,
+    pub error: settings::Error,
+    pub fixed: Option<settings::Fixed>,
+    pub constant: Option<settings::Constant>,
+}
+
+/// Reproducibility metadata of a run, written as JSON by [NPResult::write_manifest] to audit or reproduce the run
+///
+/// The hashes are hexadecimal SHA-256 digests. The settings are hashed as JSON with sorted keys, and the data file as its raw bytes when it was read for the fit.
+/// The git commit is that of the source NPcore was built from, if it was built from a git checkout.
+/// The timestamps are in RFC 3339 format, and `objf` is the reported objective function, see [NPResult::reported_objf].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RunManifest {
+    pub npcore_version: String,
+    pub git_commit: Option<String>,
+    pub settings_hash: String,
+    pub data_path: String,
+    pub data_hash: Option<String>,
+    pub seed: usize,
+    pub started: String,
+    pub finished: String,
+    pub converged: bool,
+    /// Reason the run stopped, see [NPResult::stop_reason]
+    pub stop_reason: Option<StopReason>,
+    pub cycles: usize,
+    pub objf: f64,
+}
+
+/// Hexadecimal SHA-256 digest of the file at `path`, or `None` if it cannot be read, see [NPResult::with_data_hash]
+pub fn hash_file(path: &str) -> Option<String> {
+    std::fs::read(path).ok().map(|data| sha256(&data))
+}
+
+/// Hexadecimal SHA-256 digest of `data`
+fn sha256(data: &[u8]) -> String {
+    Sha256::digest(data)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+impl PopulationModel {
+    /// Reads a population model exported by [NPResult::export_population_model]
+    pub fn read(path: &str) -> Result<Self, Box<dyn error::Error>> {
+        let file = File::open(path)?;
+        let model: PopulationModel = serde_json::from_reader(file)?;
+        if model.support_points.len() != model.weights.len() {
+            return Err(format!(
+                "The population model has {} support points, but {} weights",
+                model.support_points.len(),
+                model.weights.len()
+            )
+            .into());
+        }
+        if let Some(point) = model
+            .support_points
+            .iter()
+            .find(|point| point.len() != model.parameters.len())
+        {
+            return Err(format!(
+                "Support point {:?} does not match the {} parameters of the population model",
+                point,
+                model.parameters.len()
+            )
+            .into());
+        }
+        Ok(model)
+    }
+
+    /// The support points, with one row per support point
+    pub fn theta(&self) -> Array2<f64> {
+        let values: Vec<f64> = self.support_points.iter().flatten().copied().collect();
+        Array2::from_shape_vec((self.support_points.len(), self.parameters.len()), values).unwrap()
+    }
+
+    /// The probabilities of the support points
+    pub fn weights(&self) -> Array1<f64> {
+        Array1::from(self.weights.clone())
+    }
+}
+
+impl From<&NPResult> for PopulationModel {
+    fn from(result: &NPResult) -> Self {
+        let mut error = result.settings.error.clone();
+        if let Some(last) = result.cycle_log().last() {
+            error.value = last.gamlam;
+        }
+        Self {
+            parameters: result.par_names.clone(),
+            support_points: result
+                .theta
+                .rows()
+                .into_iter()
+                .map(|row| row.to_vec())
+                .collect(),
+            weights: result.w.to_vec(),
+            error,
+            fixed: result.settings.fixed.clone(),
+            constant: result.settings.constant.clone(),
+        }
+    }
+}
+
+/// Summary of the prediction errors (prediction - observation)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PredictionMetrics {
+    /// Number of observations
+    pub n: usize,
+    /// Mean error
+    pub bias: f64,
+    /// Mean absolute error
+    pub mae: f64,
+    /// Root mean squared error
+    pub rmse: f64,
+}
+
+impl PredictionMetrics {
+    pub fn from_errors(errors: &[f64]) -> Self {
+        let n = errors.len();
+        Self {
+            n,
+            bias: errors.iter().sum::<f64>() / n as f64,
+            mae: errors.iter().map(|e| e.abs()).sum::<f64>() / n as f64,
+            rmse: (errors.iter().map(|e| e.powi(2)).sum::<f64>() / n as f64).sqrt(),
+        }
+    }
+}
+
+/// Number of bins used for the marginal distributions in [ParameterComparison::overlap]
+const OVERLAP_BINS: usize = 20;
+
+/// Comparison of two results, see [NPResult::compare]
+#[derive(Debug, Clone, PartialEq)]
+pub struct ComparisonReport {
+    /// Difference in the objective function value, -2LL
+    pub delta_objf: f64,
+    /// Difference in the Akaike information criterion, see [NPResult::aic]
+    pub delta_aic: f64,
+    /// Difference in the Bayesian information criterion, see [NPResult::bic]
+    pub delta_bic: f64,
+    /// Number of support points of both results
+    pub nspp: (usize, usize),
+    /// Comparison of the marginal distributions of the parameters estimated in both results
+    pub parameters: Vec<ParameterComparison>,
+}
+
+/// Comparison of the marginal distribution of a parameter in two results
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParameterComparison {
+    pub name: String,
+    /// Weighted population mean in both results
+    pub mean: (f64, f64),
+    /// Overlapping probability mass of the marginal distributions, from 0 (disjoint) to 1 (identical)
+    ///
+    /// The marginal distributions are binned into equally wide bins across the union of the parameter ranges of both results.
+    pub overlap: f64,
+}
+
+impl ComparisonReport {
+    /// A short text summary of the comparison
+    pub fn summary(&self) -> String {
+        let mut summary = format!(
+            "Change in -2LL: {:+.4}, AIC: {:+.4}, BIC: {:+.4}\nSupport points: {} -> {}",
+            self.delta_objf, self.delta_aic, self.delta_bic, self.nspp.0, self.nspp.1
+        );
+        for parameter in &self.parameters {
+            summary.push_str(&format!(
+                "\n{}: mean {:.4} -> {:.4}, overlap {:.2}",
+                parameter.name, parameter.mean.0, parameter.mean.1, parameter.overlap
+            ));
+        }
+        summary
+    }
+}
+
+/// Overlap of two weighted, discrete distributions, binned into [OVERLAP_BINS] bins across `range`
+fn histogram_overlap(
+    first: (&Array1<f64>, &Array1<f64>),
+    second: (&Array1<f64>, &Array1<f64>),
+    range: (f64, f64),
+) -> f64 {
+    let histogram = |(values, weights): (&Array1<f64>, &Array1<f64>)| {
+        let mut bins = [0.0; OVERLAP_BINS];
+        let width = (range.1 - range.0) / OVERLAP_BINS as f64;
+        for (value, weight) in values.iter().zip(weights.iter()) {
+            let bin = if width > 0.0 {
+                (((value - range.0) / width).floor().max(0.0) as usize).min(OVERLAP_BINS - 1)
+            } else {
+                0
+            };
+            bins[bin] += weight / weights.sum();
+        }
+        bins
+    };
+    histogram(first)
+        .iter()
+        .zip(histogram(second).iter())
+        .map(|(a, b)| a.min(*b))
+        .sum()
+}
+
 #[derive(Debug)]
 pub struct CycleLog {
     pub cycles: Vec<NPCycle>,
-    cycle_writer: CycleWriter,
+    par_names: Vec<String>,
+    precision: Option<usize>,
+    error: Option<settings::Error>,
+    snapshots: Option<(usize, PathBuf)>,
+    cycle_writer: Option<CycleWriter>,
 }
 impl CycleLog {
-    pub fn new(par_names: &[String]) -> Self {
-        let cycle_writer = CycleWriter::new("cycles.csv", par_names.to_vec());
+    pub fn new(par_names: &[String], precision: Option<usize>) -> Self {
         Self {
             cycles: Vec::new(),
-            cycle_writer,
+            par_names: par_names.to_vec(),
+            precision,
+            error: None,
+            snapshots: None,
+            cycle_writer: None,
         }
     }
+
+    /// Also writes the support points and weights of every `interval`-th cycle to `dir/theta_<cycle>.csv`, in the format of `theta.csv`
+    ///
+    /// The directory is created when the first snapshot is written. Snapshots are written regardless of the `write_ouput` argument of [CycleLog::push_and_write].
+    pub fn with_snapshots(mut self, interval: usize, dir: &Path) -> Self {
+        self.snapshots = Some((interval, dir.to_path_buf()));
+        self
+    }
+
+    /// Also writes the magnitude of `error` to cycles.csv, see [CycleWriter::new]
+    pub fn with_error_magnitude(mut self, error: &settings::Error) -> Self {
+        self.error = Some(error.clone());
+        self
+    }
+    /// Stores the cycle, and writes it to cycles.csv if `write_ouput` is true
+    ///
+    /// The file is created when the first cycle is written, so no file is created for runs without output.
     pub fn push_and_write(&mut self, npcycle: NPCycle, write_ouput: bool) {
         if write_ouput {
-            self.cycle_writer
-                .write(npcycle.cycle, npcycle.objf, npcycle.gamlam, &npcycle.theta);
-            self.cycle_writer.flush();
+            let cycle_writer = self.cycle_writer.get_or_insert_with(|| {
+                CycleWriter::new(
+                    "cycles.csv",
+                    self.par_names.clone(),
+                    self.precision,
+                    self.error.clone(),
+                )
+            });
+            cycle_writer.write(
+                npcycle.cycle,
+                npcycle.objf,
+                npcycle.gamlam,
+                npcycle.poly,
+                npcycle.added,
+                npcycle.removed,
+                &npcycle.theta,
+            );
+            cycle_writer.flush();
+        }
+        if let Some((interval, dir)) = &self.snapshots {
+            if npcycle.cycle.is_multiple_of(*interval) {
+                let path = dir.join(format!("theta_{}.csv", npcycle.cycle));
+                let result = std::fs::create_dir_all(dir)
+                    .map_err(csv::Error::from)
+                    .and_then(|_| {
+                        write_support_points(
+                            &path.to_string_lossy(),
+                            &self.par_names,
+                            &npcycle.theta,
+                            &npcycle.weights,
+                            self.precision,
+                        )
+                    });
+                if let Err(e) = result {
+                    tracing::error!("Error while writing snapshot {}: {}", path.display(), e);
+                }
+            }
         }
         self.cycles.push(npcycle);
     }
 }
 
+/// Writes support points and their weights to `path`, with one column per parameter and the weights in the column `prob`, as in `theta.csv`
+pub fn write_support_points(
+    path: &str,
+    par_names: &[String],
+    theta: &Array2<f64>,
+    w: &Array1<f64>,
+    precision: Option<usize>,
+) -> Result<(), csv::Error> {
+    let file = File::create(path)?;
+    let mut writer = WriterBuilder::new().has_headers(true).from_writer(file);
+
+    // Create the headers
+    let mut theta_header = par_names.to_vec();
+    theta_header.push("prob".to_string());
+    writer.write_record(&theta_header)?;
+
+    // Write contents
+    for (row, weight) in theta.rows().into_iter().zip(w.iter()) {
+        let mut row: Vec<String> = row
+            .iter()
+            .map(|&val| format_float(val, precision))
+            .collect();
+        row.push(format_float(*weight, precision));
+        writer.write_record(&row)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
 /// Defines the result objects from a run
 /// An [NPResult] contains the necessary information to generate predictions and summary statistics
 /// It holds the following information:
 /// - `cycle`: The cycle number
-/// - `objf`: The objective function value
+/// - `objf`: The objective function value, -2LL
 /// - `gamlam`: The assay noise parameter, either gamma or lambda
 /// - `theta`: The support points and their associated probabilities
+/// - `weights`: The current probability of each support point, in the order of the rows of `theta`
 /// - `nspp`: The number of support points
-/// - `delta_objf`: The change in objective function value from last cycle
-#[derive(Debug, Clone)]
+/// - `delta_objf`: The absolute change in objective function value (-2LL) from last cycle
+///
+/// Non-finite values, e.g. the objective function of a failed cycle, are serialized as strings, as JSON has no representation of them, see [non_finite].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct NPCycle {
     pub cycle: usize,
+    #[serde(with = "non_finite")]
     pub objf: f64,
+    #[serde(with = "non_finite")]
     pub gamlam: f64,
+    #[serde(with = "non_finite::array2")]
     pub theta: Array2<f64>,
+    #[serde(with = "non_finite::array1")]
+    pub weights: Array1<f64>,
     pub nspp: usize,
+    /// The number of support points added by the expansion of the grid before this cycle
+    pub added: usize,
+    /// The number of support points removed by the condensation in this cycle
+    pub removed: usize,
+    #[serde(with = "non_finite")]
     pub delta_objf: f64,
+    /// The error polynomial of the cycle, which NPAG may optimize, see `settings::Error::optimize_poly`, or `None` if it is that of the settings
+    #[serde(default)]
+    pub poly: Option<(f64, f64, f64, f64)>,
 }
 impl NPCycle {
     pub fn new() -> Self {
@@ -311,8 +1764,12 @@ impl NPCycle {
             objf: 0.0,
             gamlam: 0.0,
             theta: Array2::default((0, 0)),
+            weights: Array1::default(0),
             nspp: 0,
+            added: 0,
+            removed: 0,
             delta_objf: 0.0,
+            poly: None,
         }
     }
 }
@@ -322,14 +1779,169 @@ impl Default for NPCycle {
     }
 }
 
+/// Serialization of floats which may not be finite, as numbers if finite, and otherwise as the strings `NaN`, `inf` and `-inf`
+///
+/// JSON has no representation of non-finite numbers, which `serde_json` writes as `null`, and can not read back as floats.
+mod non_finite {
+    use ndarray::{Array1, Array2};
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    #[derive(Serialize, Deserialize)]
+    #[serde(untagged)]
+    enum Float {
+        Finite(f64),
+        NonFinite(String),
+    }
+
+    impl From<f64> for Float {
+        fn from(value: f64) -> Self {
+            match value.is_finite() {
+                true => Float::Finite(value),
+                false => Float::NonFinite(value.to_string()),
+            }
+        }
+    }
+
+    impl Float {
+        fn value<E: serde::de::Error>(self) -> Result<f64, E> {
+            match self {
+                Float::Finite(value) => Ok(value),
+                Float::NonFinite(text) => text
+                    .parse()
+                    .map_err(|_| E::custom(format!("invalid float {}", text))),
+            }
+        }
+    }
+
+    pub fn serialize<S: Serializer>(value: &f64, serializer: S) -> Result<S::Ok, S::Error> {
+        Float::from(*value).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<f64, D::Error> {
+        Float::deserialize(deserializer)?.value()
+    }
+
+    /// The shape and the elements in row-major order of an array
+    #[derive(Serialize, Deserialize)]
+    struct Elements {
+        dim: Vec<usize>,
+        data: Vec<Float>,
+    }
+
+    impl Elements {
+        fn new<'a>(dim: &[usize], values: impl Iterator<Item = &'a f64>) -> Self {
+            Self {
+                dim: dim.to_vec(),
+                data: values.map(|value| Float::from(*value)).collect(),
+            }
+        }
+
+        fn values<E: serde::de::Error>(self) -> Result<(Vec<usize>, Vec<f64>), E> {
+            let values = self
+                .data
+                .into_iter()
+                .map(Float::value)
+                .collect::<Result<Vec<f64>, E>>()?;
+            Ok((self.dim, values))
+        }
+    }
+
+    pub mod array1 {
+        use super::*;
+
+        pub fn serialize<S: Serializer>(
+            value: &Array1<f64>,
+            serializer: S,
+        ) -> Result<S::Ok, S::Error> {
+            Elements::new(value.shape(), value.iter()).serialize(serializer)
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(
+            deserializer: D,
+        ) -> Result<Array1<f64>, D::Error> {
+            let (_, values) = Elements::deserialize(deserializer)?.values()?;
+            Ok(Array1::from(values))
+        }
+    }
+
+    pub mod array2 {
+        use super::*;
+
+        pub fn serialize<S: Serializer>(
+            value: &Array2<f64>,
+            serializer: S,
+        ) -> Result<S::Ok, S::Error> {
+            Elements::new(value.shape(), value.iter()).serialize(serializer)
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(
+            deserializer: D,
+        ) -> Result<Array2<f64>, D::Error> {
+            let (dim, values) = Elements::deserialize(deserializer)?.values()?;
+            match dim[..] {
+                [rows, cols] => {
+                    Array2::from_shape_vec((rows, cols), values).map_err(serde::de::Error::custom)
+                }
+                _ => Err(serde::de::Error::custom(format!(
+                    "expected 2 dimensions, found {}",
+                    dim.len()
+                ))),
+            }
+        }
+    }
+}
+
+/// Summary of a single cycle, see [NPResult::cycle_log]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct CycleRecord {
+    pub cycle: usize,
+    /// The objective function value, -2 times the log-likelihood
+    pub objf: f64,
+    /// The change in log-likelihood from the last cycle
+    pub delta_objf: f64,
+    /// The number of support points
+    pub nspp: usize,
+    /// The number of support points added by the expansion of the grid before the cycle
+    pub added: usize,
+    /// The number of support points removed by the condensation in the cycle
+    pub removed: usize,
+    /// The assay noise parameter, either gamma or lambda
+    pub gamlam: f64,
+}
+
+impl From<&NPCycle> for CycleRecord {
+    fn from(cycle: &NPCycle) -> Self {
+        Self {
+            cycle: cycle.cycle,
+            objf: cycle.objf,
+            delta_objf: cycle.delta_objf,
+            nspp: cycle.nspp,
+            added: cycle.added,
+            removed: cycle.removed,
+            gamlam: cycle.gamlam,
+        }
+    }
+}
+
 // Cycles
 #[derive(Debug)]
 pub struct CycleWriter {
     writer: csv::Writer<File>,
+    precision: Option<usize>,
+    error: Option<settings::Error>,
 }
 
 impl CycleWriter {
-    pub fn new(file_path: &str, parameter_names: Vec<String>) -> CycleWriter {
+    /// Creates `file_path` and writes the headers
+    ///
+    /// If `error` is provided, gamma or lambda is also written as an additive SD and a CV%, in the columns `error.sd` and `error.cv`,
+    /// see [ErrorPoly::magnitude](crate::routines::evaluation::sigma::ErrorPoly::magnitude).
+    pub fn new(
+        file_path: &str,
+        parameter_names: Vec<String>,
+        precision: Option<usize>,
+        error: Option<settings::Error>,
+    ) -> CycleWriter {
         let file = File::create(file_path).unwrap();
         let mut writer = WriterBuilder::new().has_headers(false).from_writer(file);
 
@@ -337,7 +1949,13 @@ impl CycleWriter {
         writer.write_field("cycle").unwrap();
         writer.write_field("neg2ll").unwrap();
         writer.write_field("gamlam").unwrap();
+        if error.is_some() {
+            writer.write_field("error.sd").unwrap();
+            writer.write_field("error.cv").unwrap();
+        }
         writer.write_field("nspp").unwrap();
+        writer.write_field("added").unwrap();
+        writer.write_field("removed").unwrap();
 
         for param_name in &parameter_names {
             writer.write_field(format!("{}.mean", param_name)).unwrap();
@@ -349,32 +1967,67 @@ impl CycleWriter {
 
         writer.write_record(None::<&[u8]>).unwrap();
 
-        CycleWriter { writer }
+        CycleWriter {
+            writer,
+            precision,
+            error,
+        }
     }
 
-    pub fn write(&mut self, cycle: usize, objf: f64, gamma: f64, theta: &Array2<f64>) {
+    /// Writes a cycle, with the number of support points `added` by the expansion of the grid and `removed` by the condensation
+    ///
+    /// The magnitude of the error is computed with the error polynomial `poly` of the cycle, or that of the settings if `None`.
+    #[allow(clippy::too_many_arguments)]
+    pub fn write(
+        &mut self,
+        cycle: usize,
+        objf: f64,
+        gamma: f64,
+        poly: Option<(f64, f64, f64, f64)>,
+        added: usize,
+        removed: usize,
+        theta: &Array2<f64>,
+    ) {
         self.writer.write_field(format!("{}", cycle)).unwrap();
-        self.writer.write_field(format!("{}", objf)).unwrap();
-        self.writer.write_field(format!("{}", gamma)).unwrap();
+        self.writer
+            .write_field(format_float(objf, self.precision))
+            .unwrap();
+        self.writer
+            .write_field(format_float(gamma, self.precision))
+            .unwrap();
+        if let Some(error) = &self.error {
+            let magnitude = error.magnitude(poly.unwrap_or(error.poly), gamma).unwrap();
+            self.writer
+                .write_field(format_float(magnitude.sd, self.precision))
+                .unwrap();
+            self.writer
+                .write_field(format_float(magnitude.cv, self.precision))
+                .unwrap();
+        }
         self.writer
             .write_field(format!("{}", theta.nrows()))
             .unwrap();
+        self.writer.write_field(format!("{}", added)).unwrap();
+        self.writer.write_field(format!("{}", removed)).unwrap();
 
         for param in theta.axis_iter(Axis(1)) {
             self.writer
-                .write_field(format!("{}", param.mean().unwrap()))
+                .write_field(format_float(param.mean().unwrap(), self.precision))
                 .unwrap();
         }
 
         for param in theta.axis_iter(Axis(1)) {
             self.writer
-                .write_field(format!("{}", median(param.to_owned().to_vec())))
+                .write_field(format_float(
+                    median(param.to_owned().to_vec()),
+                    self.precision,
+                ))
                 .unwrap();
         }
 
         for param in theta.axis_iter(Axis(1)) {
             self.writer
-                .write_field(format!("{}", param.std(1.)))
+                .write_field(format_float(param.std(1.), self.precision))
                 .unwrap();
         }
 
@@ -394,16 +2047,21 @@ pub struct MetaWriter {
 
 impl MetaWriter {
     pub fn new() -> MetaWriter {
-        let meta_file = File::create("meta_rust.csv").unwrap();
+        MetaWriter::create("meta_rust.csv").unwrap()
+    }
+
+    /// Creates the metadata file at `path`, with its header
+    pub fn create(path: &str) -> Result<MetaWriter, csv::Error> {
+        let meta_file = File::create(path)?;
         let mut meta_writer = WriterBuilder::new()
             .has_headers(false)
             .from_writer(meta_file);
-        meta_writer.write_field("converged").unwrap();
-        meta_writer.write_field("ncycles").unwrap();
-        meta_writer.write_record(None::<&[u8]>).unwrap();
-        MetaWriter {
+        meta_writer.write_field("converged")?;
+        meta_writer.write_field("ncycles")?;
+        meta_writer.write_record(None::<&[u8]>)?;
+        Ok(MetaWriter {
             writer: meta_writer,
-        }
+        })
     }
 
     pub fn write(&mut self, converged: bool, cycle: usize) {
@@ -418,6 +2076,58 @@ impl MetaWriter {
     }
 }
 
+/// Renders the objective function (-2LL) of each cycle to an SVG image at `path`
+///
+/// Only available with the `plots` feature. The chart shows the trajectory on a grid, without text, as no fonts are bundled.
+#[cfg(feature = "plots")]
+pub fn plot_objf(records: &[CycleRecord], path: &str) -> Result<(), Box<dyn error::Error>> {
+    use plotters::prelude::*;
+
+    let points: Vec<(f64, f64)> = records
+        .iter()
+        .filter(|record| record.objf.is_finite())
+        .map(|record| (record.cycle as f64, record.objf))
+        .collect();
+    if points.is_empty() {
+        return Err("No cycles with a finite objective function to plot".into());
+    }
+    let (min, max) = points
+        .iter()
+        .fold((f64::INFINITY, f64::NEG_INFINITY), |(min, max), (_, y)| {
+            (min.min(*y), max.max(*y))
+        });
+    let margin = ((max - min) * 0.05).max(1e-6);
+    let cycles = points.last().unwrap().0.max(2.0);
+
+    let root = SVGBackend::new(path, (800, 600)).into_drawing_area();
+    root.fill(&WHITE)?;
+    let mut chart = ChartBuilder::on(&root)
+        .margin(20)
+        .build_cartesian_2d(1.0..cycles, (min - margin)..(max + margin))?;
+    chart
+        .configure_mesh()
+        .x_desc("Cycle")
+        .y_desc("-2LL")
+        .draw()?;
+    chart.draw_series(LineSeries::new(points, &BLUE))?;
+    root.present()?;
+    Ok(())
+}
+
+/// Formats a floating point value for the output files
+///
+/// With a `precision`, the value is rounded to that number of significant digits, and written without trailing zeros, e.g. 0.123456 becomes `0.123` with three digits.
+/// Without a `precision`, or for non-finite values, the default formatting is used.
+pub fn format_float(value: f64, precision: Option<usize>) -> String {
+    match precision {
+        Some(digits) if value.is_finite() => {
+            let rounded: f64 = format!("{:.*e}", digits.max(1) - 1, value).parse().unwrap();
+            rounded.to_string()
+        }
+        _ => value.to_string(),
+    }
+}
+
 pub fn posterior(psi: &Array2<f64>, w: &Array1<f64>) -> Array2<f64> {
     let py = psi.dot(w);
     let mut post: Array2<f64> = Array2::zeros((psi.nrows(), psi.ncols()));
@@ -448,6 +2158,11 @@ pub fn median(data: Vec<f64>) -> f64 {
     }
 }
 
+/// Computes the weighted mean of each parameter
+pub fn population_mean(theta: &Array2<f64>, w: &Array1<f64>) -> Array1<f64> {
+    theta.t().dot(w)
+}
+
 pub fn population_mean_median(theta: &Array2<f64>, w: &Array1<f64>) -> (Array1<f64>, Array1<f64>) {
     let mut mean = Array1::zeros(theta.ncols());
     let mut median = Array1::zeros(theta.ncols());
@@ -479,6 +2194,12 @@ pub fn population_mean_median(theta: &Array2<f64>, w: &Array1<f64>) -> (Array1<f
             }
         }
 
+        // If the first support point holds more than half of the weight, it is the median
+        if widx == 0 {
+            *mdn = tup[0].0;
+            continue;
+        }
+
         let acc2 = wacc.pop().unwrap();
         let acc1 = wacc.pop().unwrap();
         let par2 = tup.get(widx).unwrap().0;
@@ -491,6 +2212,52 @@ pub fn population_mean_median(theta: &Array2<f64>, w: &Array1<f64>) -> (Array1<f
     (mean, median)
 }
 
+/// The `name` and `unit` columns and the scaling of output equation `outeq`, see [settings::OutputEquation]
+///
+/// The columns are empty if no output equations are given in the settings, and missing observations (-99) are not scaled.
+fn output_labels(settings: &Settings, outeq: usize) -> (Vec<String>, impl Fn(f64) -> f64) {
+    let output = settings.output_equation(outeq);
+    let labels = if settings.outputs.is_empty() {
+        vec![]
+    } else {
+        vec![
+            output
+                .and_then(|output| output.name.clone())
+                .unwrap_or_default(),
+            output
+                .and_then(|output| output.unit.clone())
+                .unwrap_or_default(),
+        ]
+    };
+    let factor = output.map_or(1.0, |output| output.scale);
+    let scale = move |value: f64| {
+        if value == -99.0 {
+            value
+        } else {
+            value * factor
+        }
+    };
+    (labels, scale)
+}
+
+/// Sorted names of the metadata of the subjects, see [Scenario::metadata]
+fn metadata_keys(scenarios: &[Scenario]) -> Vec<String> {
+    let mut keys: Vec<String> = scenarios
+        .iter()
+        .flat_map(|scenario| scenario.metadata.keys().cloned())
+        .collect();
+    keys.sort();
+    keys.dedup();
+    keys
+}
+
+/// Metadata of `scenario` for each of `keys`, empty if the subject has no value
+fn metadata_values(scenario: &Scenario, keys: &[String]) -> Vec<String> {
+    keys.iter()
+        .map(|key| scenario.metadata.get(key).cloned().unwrap_or_default())
+        .collect()
+}
+
 pub fn posterior_mean_median(
     theta: &Array2<f64>,
     psi: &Array2<f64>,
@@ -549,6 +2316,12 @@ pub fn posterior_mean_median(
                 }
             }
 
+            // If the first support point holds more than half of the probability, it is the median
+            if widx == 0 {
+                post_median.push(tup[0].0);
+                continue;
+            }
+
             let acc2 = wacc.pop().unwrap();
             let acc1 = wacc.pop().unwrap();
             let par2 = tup.get(widx).unwrap().0;
@@ -1,6 +1,14 @@
 #![allow(dead_code)]
 
+use crate::routines::datafile::DoseRowObservations;
+use crate::routines::evaluation::convergence::Combine;
+use crate::routines::evaluation::sigma::{ErrorMagnitude, ErrorPoly, ErrorType, Sigma, Transform};
+use crate::routines::initialization::Sampler;
+use crate::routines::output::OutputLevel;
+use crate::routines::simulation::parametric::{correlation_factor, sample_correlated, Marginal};
+use crate::routines::simulation::predict::VolumeOutput;
 use config::Config as eConfig;
+use ndarray::{Array1, Array2};
 use serde::Deserialize;
 use serde_derive::Serialize;
 use serde_json;
@@ -16,6 +24,35 @@ pub struct Settings {
     pub fixed: Option<Fixed>,
     pub constant: Option<Constant>,
     pub error: Error,
+    /// Labels and scaling of the output equations in the output files, given as `[[outputs]]` tables, see [OutputEquation]
+    #[serde(default)]
+    pub outputs: Vec<OutputEquation>,
+    /// Parametric distribution to simulate from in place of the support points of the prior, see [Simulation]
+    pub simulation: Option<Simulation>,
+}
+
+impl Settings {
+    /// The labels and scaling of output equation `outeq`, if given
+    pub fn output_equation(&self, outeq: usize) -> Option<&OutputEquation> {
+        self.outputs.iter().find(|output| output.outeq == outeq)
+    }
+
+    /// Output equations with a volume parameter given by name in the `outputs`, see [Engine::with_volume_outputs](crate::routines::simulation::predict::Engine::with_volume_outputs)
+    ///
+    /// The concentration is computed from the amount reported by the model, see `Predict::amounts`, so the model does not divide by the volume,
+    /// and the position of the volume among the (alphabetically sorted) random parameters does not matter.
+    pub fn volume_outputs(&self) -> Vec<VolumeOutput> {
+        self.outputs
+            .iter()
+            .filter_map(|output| {
+                output.volume.as_ref().map(|volume| VolumeOutput {
+                    outeq: output.outeq,
+                    compartment: output.compartment,
+                    volume: volume.clone(),
+                })
+            })
+            .collect()
+    }
 }
 
 /// This struct contains the paths to the data, log and prior files.
@@ -28,6 +65,8 @@ pub struct Paths {
     pub log: Option<String>,
     /// If provided, NPcore will use this prior instead of a "uniform" prior, see `sobol::generate` for details.
     pub prior: Option<String>,
+    /// If provided, the support points in this CSV file are added to the initial grid, see `initialization::seed_points`
+    pub seed_points: Option<String>,
 }
 
 /// General configuration settings
@@ -40,19 +79,277 @@ pub struct Config {
     pub seed: usize,
     #[serde(default = "default_10k")]
     pub init_points: usize,
+    /// Samplers of the initial grid for individual random parameters, by name, e.g. to start a lag time concentrated near a value, see `initialization::generate_mixed`
+    ///
+    /// Parameters without a sampler are covered uniformly by a Sobol sequence.
+    pub init_samplers: Option<HashMap<String, Sampler>>,
+    /// If true, naive individual estimates from a weighted least squares fit are added to the initial grid, see `initialization::seed_from_wls`
+    #[serde(default = "default_false")]
+    pub wls_seed: bool,
     #[serde(default = "default_false")]
     pub tui: bool,
+    /// Number of support points with the highest weights listed in the parameters tab of the TUI
+    #[serde(default = "default_tui_support_points")]
+    pub tui_support_points: usize,
     #[serde(default = "default_true")]
     pub output: bool,
+    /// Level of detail of the output files written when output is enabled, either "minimal", "standard" or "full", see `output::OutputLevel`
+    #[serde(default)]
+    pub output_level: OutputLevel,
     #[serde(default = "default_true")]
     pub cache: bool,
+    /// If true, the cache is also used in the first cycle, see `algorithms::npag::NPAG::run`
+    #[serde(default = "default_false")]
+    pub cache_first_cycle: bool,
+    /// If provided, subjects are simulated in batches of this size to limit memory usage, see `prob::simulate_psi`
+    pub subject_batch_size: Option<usize>,
+    /// If provided, the adaptive grid of NPAG is not expanded beyond this number of support points to limit memory usage, see `adaptative_grid::limit`
+    ///
+    /// Other engines do not use it, so it is rejected for them.
+    pub max_grid_size: Option<usize>,
+    /// If provided, NPAG only expands the grid along this number of parameters with the largest weighted variance, rather than along every parameter, see `adaptative_grid::sensitive_dimensions`
+    pub expansion_dimensions: Option<usize>,
+    /// If true, ODE solver statistics reported through `simulation::stats::record` are logged for each cycle
+    #[serde(default = "default_false")]
+    pub solver_stats: bool,
+    /// If true, NPAG refines the grid resolution `eps` faster when the objective function converges within few cycles, and slower when it takes many, see `npag::NPAG::eps_factor`
+    ///
+    /// By default, `eps` is halved each time the objective function converges.
+    #[serde(default = "default_false")]
+    pub adaptive_eps: bool,
+    /// If true, the support points of the second cycle are simulated twice, and an error is logged if the predictions differ, see `predict::predictions_are_deterministic`
+    #[serde(default = "default_false")]
+    pub check_determinism: bool,
+    /// If true, each random parameter is perturbed before the fit, and a warning is issued for parameters which do not affect any prediction, see `predict::Engine::insensitive_parameters`
+    ///
+    /// This simulates every subject twice per parameter, and is off by default. A dry run (`--dry-run`) always performs the check.
+    #[serde(default = "default_false")]
+    pub check_parameters: bool,
+    /// If true, NPAG measures the time spent in each stage of a cycle, which is logged at the end of the run and written to `timing.csv`, see `timing::CycleTimer`
+    #[serde(default = "default_false")]
+    pub timing: bool,
+    /// Number of consecutive cycles the change in objective function must be within tolerance before NPAG refines the grid or converges, see `convergence::StopCriterion`
+    #[serde(default = "default_one")]
+    pub convergence_cycles: usize,
+    /// If provided, NPAG also considers the largest relative change in the weighted mean and variance of the parameters between cycles, see `convergence::StopCriterion::with_distribution`
+    ///
+    /// Other engines do not use it, so it is rejected for them.
+    pub distribution_tolerance: Option<f64>,
+    /// How the distribution criterion is combined with the objective function criterion, either "and" or "or", see `convergence::Combine`
+    #[serde(default = "default_distribution_combine")]
+    pub distribution_combine: String,
+    /// If provided, a run is stopped after the first cycle which ends after this number of seconds, see `convergence::check_stop`
+    pub time_limit: Option<f64>,
+    /// Maximum number of steps of a single integration, after which the prediction fails and the support point is penalized, see `simulation::stats::max_steps`
+    ///
+    /// This only takes effect if the model passes `simulation::stats::max_steps` to its solver, and sets its state to NaN when the integration fails, as in `examples/bimodal_ke`.
+    #[serde(default = "default_max_steps")]
+    pub max_steps: usize,
+    /// If provided, finite predictions below this value are raised to it before computing the likelihood, e.g. 0 to remove tiny negative concentrations caused by numerical error, see `predict::Engine::with_clamp`
+    pub prediction_floor: Option<f64>,
+    /// If provided, finite predictions above this value are lowered to it before computing the likelihood
+    pub prediction_ceiling: Option<f64>,
+    /// Convergence tolerance of the interior point method, see `ipm::burke_with`
+    #[serde(default = "default_ipm_tol")]
+    pub ipm_tol: f64,
+    /// Maximum number of iterations of the interior point method, after which the run fails with an error, see `ipm::burke_with`
+    #[serde(default = "default_ipm_max_iter")]
+    pub ipm_max_iter: usize,
+    /// If true, the error model is also reported as an additive SD and a proportional CV% in the TUI and in `cycles.csv`, see `sigma::ErrorPoly::magnitude`
+    #[serde(default = "default_false")]
+    pub error_magnitude: bool,
     #[serde(default = "default_idelta")]
     pub idelta: f64,
     #[serde(default = "default_log_level")]
     pub log_level: String,
+    /// IDs of subjects which are removed from the data before fitting with `entrypoints::start`
     pub exclude: Option<Vec<String>>,
+    /// If provided, the subjects are shuffled with this seed before fitting with `entrypoints::start`, to detect a dependence of the results on the order of the subjects, see `datafile::shuffle`
+    pub shuffle_seed: Option<u64>,
+    /// If provided, only observations within these time windows, given as inclusive ranges `[lower, upper]`, are fitted, see `datafile::filter_observation_windows`
+    ///
+    /// Doses are not affected. This applies to all subjects without windows in `subject_observation_windows`.
+    pub observation_windows: Option<Vec<(f64, f64)>>,
+    /// Time windows of the observations which are fitted for individual subjects, by ID, in place of `observation_windows`
+    pub subject_observation_windows: Option<HashMap<String, Vec<(f64, f64)>>>,
+    /// Weights of the log-likelihoods of individual subjects, by ID, in the population objective function, e.g. to weight studies by reliability in a pooled analysis, see `ipm::burke_weighted`
+    ///
+    /// Subjects without a weight have a weight of 1, so that a subject with a weight of 2 counts as two copies of the subject.
+    /// The objective function is then the weighted sum of the log-likelihoods of the subjects, which is not the likelihood of the data,
+    /// so it can not be compared to that of a fit with other weights, and the information criteria are not meaningful.
+    pub subject_weights: Option<HashMap<String, f64>>,
+    /// Handling of observations on dose rows of the data, either "ignore" or "split", see `datafile::DoseRowObservations`
+    #[serde(default = "default_dose_row_observations")]
+    pub dose_row_observations: String,
+    /// Time after the last dose to predict until, if later than the last event of a subject, see `datafile::Scenario::add_event_interval`
     #[serde(default = "default_tad")]
     pub tad: f64,
+    /// If provided, `entrypoints::simulate` predicts all subjects at these times instead of at their observations expanded by `idelta` and `tad`, see `datafile::Scenario::with_prediction_times`
+    pub prediction_times: Option<Vec<f64>>,
+    /// If true, the prediction of each support point at the observation times of each subject is also written to `support_point_predictions.csv` when output is enabled, see `output::NPResult::write_support_point_predictions`
+    #[serde(default = "default_false")]
+    pub support_point_predictions: bool,
+    /// If true, `entrypoints::simulate` also writes the amount in each compartment to `amounts.csv`, see `predict::Engine::simulate_amounts`
+    #[serde(default = "default_false")]
+    pub amounts: bool,
+    /// If false, the reported objective function excludes the normalization constant 1/(2π)<sup>0.5</sup> of the likelihood of each observation, see `output::NPResult::reported_objf`
+    ///
+    /// This only affects the final objective function and the information criteria, to compare them with tools using the other convention. The fit is not affected.
+    #[serde(default = "default_true")]
+    pub likelihood_constants: bool,
+    /// If provided, floating point values in the output files are written with this number of significant digits, see `output::format_float`
+    pub output_precision: Option<usize>,
+    /// If provided, the support points and weights of every `snapshot_interval`-th cycle are written to `snapshots/theta_<cycle>.csv` when output is enabled, see `output::CycleLog::with_snapshots`
+    pub snapshot_interval: Option<usize>,
+    /// If provided, the messages sent to the TUI are recorded to this file, to be replayed without running the algorithm, see `tui::replay`
+    pub record_messages: Option<String>,
+    /// If provided, support points with a lower weight are omitted from `theta.csv`, see `output::NPResult::reported_support_points`
+    ///
+    /// The result itself, and all other output files, keep all support points.
+    pub output_weight_threshold: Option<f64>,
+    /// Support points which are never removed during condensation, see `condensation::frozen`
+    ///
+    /// Each point is given in the order of the (alphabetically sorted) random parameters.
+    pub frozen: Option<Vec<Vec<f64>>>,
+}
+
+impl Config {
+    /// Weight of the log-likelihood of subject `id` in the population objective function, see [Config::subject_weights]
+    pub fn subject_weight(&self, id: &str) -> f64 {
+        self.subject_weights
+            .as_ref()
+            .and_then(|weights| weights.get(id))
+            .copied()
+            .unwrap_or(1.0)
+    }
+
+    /// Validate the configuration against the random parameters
+    pub fn validate(&self, random: &Random) -> Result<(), String> {
+        if self.output_precision == Some(0) {
+            return Err("Output precision must be at least one significant digit".to_string());
+        }
+        if let Some(times) = &self.prediction_times {
+            if times.iter().any(|time| !(*time >= 0.0 && time.is_finite())) {
+                return Err(format!(
+                    "Prediction times must be non-negative, got {:?}",
+                    times
+                ));
+            }
+            if times.windows(2).any(|pair| pair[0] >= pair[1]) {
+                return Err(format!(
+                    "Prediction times must be sorted in increasing order, without duplicates, got {:?}",
+                    times
+                ));
+            }
+        }
+        DoseRowObservations::from_name(&self.dose_row_observations)?;
+        Combine::from_name(&self.distribution_combine)?;
+        if let Some(tolerance) = self.distribution_tolerance {
+            if !(tolerance > 0.0 && tolerance.is_finite()) {
+                return Err(format!(
+                    "Distribution tolerance must be positive, got {}",
+                    tolerance
+                ));
+            }
+            if self.engine != "NPAG" {
+                return Err(format!(
+                    "The distribution tolerance is only used by NPAG, not by {}",
+                    self.engine
+                ));
+            }
+        }
+        if self.max_grid_size.is_some() && self.engine != "NPAG" {
+            return Err(format!(
+                "The maximum grid size is only used by NPAG, not by {}",
+                self.engine
+            ));
+        }
+        if let Some(limit) = self.time_limit {
+            if !(limit > 0.0 && limit.is_finite()) {
+                return Err(format!("Time limit must be positive, got {}", limit));
+            }
+        }
+        for (id, weight) in self.subject_weights.iter().flatten() {
+            if weight.is_nan() || *weight <= 0.0 || weight.is_infinite() {
+                return Err(format!(
+                    "The weight of subject {} must be positive, got {}",
+                    id, weight
+                ));
+            }
+        }
+        let windows = self
+            .observation_windows
+            .iter()
+            .chain(
+                self.subject_observation_windows
+                    .iter()
+                    .flat_map(|w| w.values()),
+            )
+            .flatten();
+        for &(lower, upper) in windows {
+            if lower.is_nan() || upper.is_nan() || lower > upper {
+                return Err(format!(
+                    "Observation windows must have a lower bound below the upper bound, got [{}, {}]",
+                    lower, upper
+                ));
+            }
+        }
+        if self.expansion_dimensions == Some(0) {
+            return Err("Expansion dimensions must be at least one parameter".to_string());
+        }
+        if self.snapshot_interval == Some(0) {
+            return Err("Snapshot interval must be at least one cycle".to_string());
+        }
+        if let Some(threshold) = self.output_weight_threshold {
+            if !(0.0..1.0).contains(&threshold) {
+                return Err(format!(
+                    "Output weight threshold must be at least 0 and less than 1, got {}",
+                    threshold
+                ));
+            }
+        }
+        if let (Some(floor), Some(ceiling)) = (self.prediction_floor, self.prediction_ceiling) {
+            if floor >= ceiling {
+                return Err(format!(
+                    "Prediction floor must be below the prediction ceiling, got {} and {}",
+                    floor, ceiling
+                ));
+            }
+        }
+        if !(self.ipm_tol > 0.0 && self.ipm_tol.is_finite()) {
+            return Err(format!(
+                "IPM tolerance must be positive, got {}",
+                self.ipm_tol
+            ));
+        }
+        if self.ipm_max_iter == 0 {
+            return Err("IPM maximum number of iterations must be at least one".to_string());
+        }
+        for (name, sampler) in self.init_samplers.iter().flatten() {
+            let range = random.get(name).ok_or_else(|| {
+                format!(
+                    "Initial sampler given for {}, which is not a random parameter",
+                    name
+                )
+            })?;
+            sampler
+                .validate(*range)
+                .map_err(|e| format!("Initial sampler of {}: {}", name, e))?;
+        }
+        if let Some(frozen) = &self.frozen {
+            for point in frozen {
+                if point.len() != random.parameters.len() {
+                    return Err(format!(
+                        "Frozen support point {:?} has {} coordinates, but there are {} random parameters",
+                        point,
+                        point.len(),
+                        random.parameters.len()
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
 }
 
 /// Random parameters to be estimated
@@ -107,12 +404,23 @@ impl Random {
             .collect()
     }
 
+    /// Names and values of the random parameters with equal lower and upper bounds, which are held constant at that value
+    pub fn degenerate(&self) -> Vec<(String, f64)> {
+        self.names_and_ranges()
+            .into_iter()
+            .filter(|(_, (lower, upper))| lower == upper)
+            .map(|(name, (lower, _))| (name, lower))
+            .collect()
+    }
+
     /// Validate the boundaries of the random parameters
+    ///
+    /// Equal bounds are allowed, and hold the parameter constant, see [Random::degenerate].
     pub fn validate(&self) -> Result<(), String> {
         for (key, &(lower, upper)) in &self.parameters {
-            if lower >= upper {
+            if lower > upper {
                 return Err(format!(
-                    "In key '{}', lower bound ({}) is not less than upper bound ({})",
+                    "In key '{}', lower bound ({}) is greater than upper bound ({})",
                     key, lower, upper
                 ));
             }
@@ -142,6 +450,25 @@ pub struct Error {
     pub value: f64,
     pub class: String,
     pub poly: (f64, f64, f64, f64),
+    /// If provided, the proportional error is relative to this reference concentration rather than to each observation, see [ErrorPoly](crate::routines::evaluation::sigma::ErrorPoly)
+    pub reference: Option<f64>,
+    /// If provided, the residuals follow a Student-t distribution with these degrees of freedom rather than a normal distribution, see [student_t_likelihood](crate::routines::evaluation::prob::student_t_likelihood)
+    ///
+    /// The heavier tails downweight outlying observations, e.g. 4 for a robust fit, but this changes the objective function,
+    /// which is then not comparable to that of a fit with normally distributed residuals.
+    pub df: Option<f64>,
+    /// If provided, the observations and predictions are transformed before the likelihood is computed, either "identity", "log", "sqrt" or "boxcox(lambda)", see [Transform]
+    ///
+    /// The error model then gives the standard deviation of the transformed observations, e.g. the additive class with a constant polynomial for a log transformation.
+    /// The objective function is not comparable between transformations. Simulated observations draw their residuals on the transformed scale as well.
+    pub transform: Option<String>,
+    /// If true, NPAG optimizes the non-zero coefficients of `poly` instead of `value`, which is then held fixed, e.g. both the additive and proportional components of a combined error model
+    ///
+    /// With the proportional class, `value = 1.0` and `poly = [SD, CV, 0.0, 0.0]`, the error is SD + CV y.
+    /// With the additive class and `poly = [0.0, CV, 0.0, 0.0]`, the error is (CV<sup>2</sup> y<sup>2</sup> + lambda<sup>2</sup>)<sup>0.5</sup>, where lambda is the fixed additive SD.
+    /// The optimized coefficients are part of the result, e.g. the exported population model.
+    #[serde(default)]
+    pub optimize_poly: bool,
 }
 
 impl Error {
@@ -152,25 +479,224 @@ impl Error {
                 self.value
             ));
         }
+        let error_type = ErrorType::from_class(&self.class)?;
+        if let Some(transform) = &self.transform {
+            Transform::from_name(transform)?;
+        }
+        if let Some(df) = self.df {
+            if df.is_nan() || df <= 0.0 || df.is_infinite() {
+                return Err(format!(
+                    "The degrees of freedom of the residual distribution must be positive, got {}",
+                    df
+                ));
+            }
+        }
+        if let Some(reference) = self.reference {
+            if !matches!(error_type, ErrorType::Prop) {
+                return Err(format!(
+                    "A reference concentration is only used by the proportional error class, but the class is {}",
+                    self.class
+                ));
+            }
+            if !(reference > 0.0 && reference.is_finite()) {
+                return Err(format!(
+                    "The reference concentration must be positive, got {}",
+                    reference
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// The transformation of the observations and predictions, see [Error::transform](Error#structfield.transform)
+    ///
+    /// Invalid transformations are rejected by [Error::validate], and are otherwise treated as the identity.
+    pub fn transform(&self) -> Transform {
+        self.transform
+            .as_deref()
+            .and_then(|name| Transform::from_name(name).ok())
+            .unwrap_or_default()
+    }
+
+    /// Validate that each of the observations is within the domain of the transformation, and that the error model yields a positive standard deviation for it
+    ///
+    /// The standard deviation is given by [ErrorPoly](crate::routines::evaluation::sigma::ErrorPoly) of the transformed observation, with `value` as gamma or lambda.
+    /// Missing observations, coded as -99, are ignored.
+    pub fn validate_observations(&self, observations: &[f64]) -> Result<(), String> {
+        let error_type = ErrorType::from_class(&self.class)?;
+        let transform = self.transform();
+        let poly = ErrorPoly::new(self.poly, self.value, &error_type)
+            .with_reference(self.reference)
+            .with_df(self.df)
+            .with_transform(transform);
+        for &obs in observations.iter().filter(|&&obs| obs != -99.0) {
+            let transformed = transform
+                .apply(obs)
+                .map_err(|e| format!("Invalid observation: {}", e))?;
+            let sigma = poly.sigma(&Array1::from_elem(1, transformed))[0];
+            if !(sigma > 0.0 && sigma.is_finite()) {
+                return Err(format!(
+                    "The {} error model with polynomial {:?} and value {} yields a standard deviation of {} for the observation {}",
+                    self.class, self.poly, self.value, sigma, obs
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// The magnitude of the error model in interpretable units, with the polynomial `poly` and `value` as gamma or lambda, e.g. as estimated in a cycle,
+    /// see [ErrorPoly::magnitude](crate::routines::evaluation::sigma::ErrorPoly::magnitude)
+    pub fn magnitude(
+        &self,
+        poly: (f64, f64, f64, f64),
+        value: f64,
+    ) -> Result<ErrorMagnitude, String> {
+        let e_type = ErrorType::from_class(&self.class)?;
+        let poly = ErrorPoly::new(poly, value, &e_type)
+            .with_reference(self.reference)
+            .with_df(self.df)
+            .with_transform(self.transform());
+        Ok(poly.magnitude())
+    }
+}
+
+/// Labels and scaling of an output equation, which only affect the output files and not the fit
+///
+/// Predictions and observations of the output equation are multiplied by `scale` when written, e.g. 1000 to convert from mg/L to ng/mL.
+#[derive(Debug, Deserialize, Clone, Serialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct OutputEquation {
+    /// Number of the output equation, as in the `OUTEQ` column of the data
+    pub outeq: usize,
+    pub name: Option<String>,
+    /// Unit of the scaled predictions and observations, e.g. `ng/mL`
+    pub unit: Option<String>,
+    #[serde(default = "default_scale")]
+    pub scale: f64,
+    /// If provided, the output equation is the amount in `compartment` divided by the random parameter of this name, rather than given by the model, see [Settings::volume_outputs]
+    pub volume: Option<String>,
+    /// Compartment of the amount divided by `volume`, 0-indexed as in `Predict::amounts`
+    #[serde(default)]
+    pub compartment: usize,
+}
+
+impl OutputEquation {
+    /// Validate that the output equations are unique, and that the scale factors are positive
+    pub fn validate(outputs: &[OutputEquation]) -> Result<(), String> {
+        for (i, output) in outputs.iter().enumerate() {
+            if outputs[..i].iter().any(|other| other.outeq == output.outeq) {
+                return Err(format!(
+                    "Output equation {} is given more than once",
+                    output.outeq
+                ));
+            }
+            if !(output.scale > 0.0 && output.scale.is_finite()) {
+                return Err(format!(
+                    "The scale of output equation {} must be positive, got {}",
+                    output.outeq, output.scale
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Parametric summary of the random parameters, which `entrypoints::simulate` samples in place of the support points of the prior
+///
+/// This is a simulation-side feature for prospective simulation, e.g. from a published parametric model, and is not used when fitting.
+/// Each random parameter needs a marginal distribution, see [Marginal], and the parameters may be correlated, see `parametric::sample_correlated`.
+/// The samples are restricted to the ranges of the random parameters.
+#[derive(Debug, Deserialize, Clone, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Simulation {
+    /// Number of parameter vectors to sample
+    pub n_samples: usize,
+    /// Marginal distribution of each random parameter, by name
+    pub marginals: HashMap<String, Marginal>,
+    /// Correlation matrix of the parameters on the normal scale, in the order of the (alphabetically sorted) random parameters, uncorrelated if not provided
+    pub correlation: Option<Vec<Vec<f64>>>,
+}
+
+impl Simulation {
+    /// The marginal distributions in the order of the random parameters
+    pub fn ordered_marginals(&self, random: &Random) -> Result<Vec<Marginal>, String> {
+        random
+            .names()
+            .iter()
+            .map(|name| {
+                self.marginals.get(name).copied().ok_or_else(|| {
+                    format!("The random parameter {} has no marginal distribution", name)
+                })
+            })
+            .collect()
+    }
+
+    /// The correlation matrix for `n` parameters, the identity matrix if not provided
+    pub fn correlation_matrix(&self, n: usize) -> Result<Array2<f64>, String> {
+        match &self.correlation {
+            None => Ok(Array2::eye(n)),
+            Some(rows) => {
+                if rows.len() != n || rows.iter().any(|row| row.len() != n) {
+                    return Err(format!(
+                        "The correlation matrix must have {} rows and columns, one for each random parameter",
+                        n
+                    ));
+                }
+                Ok(Array2::from_shape_fn((n, n), |(i, j)| rows[i][j]))
+            }
+        }
+    }
+
+    /// Samples the parameter vectors, one row per vector, reproducibly for a given `seed`
+    ///
+    /// The distribution is truncated to the ranges of the random parameters, see [sample_correlated].
+    pub fn sample(&self, random: &Random, seed: usize) -> Result<Array2<f64>, String> {
+        let marginals = self.ordered_marginals(random)?;
+        let correlation = self.correlation_matrix(marginals.len())?;
+        sample_correlated(
+            &marginals,
+            &correlation,
+            &random.ranges(),
+            self.n_samples,
+            seed,
+        )
+    }
+
+    /// Validate the marginal distributions and the correlation matrix against the random parameters
+    pub fn validate(&self, random: &Random) -> Result<(), String> {
+        if self.n_samples == 0 {
+            return Err("The number of samples to simulate must be at least one".to_string());
+        }
+        let names = random.names();
+        if let Some(name) = self.marginals.keys().find(|name| !names.contains(name)) {
+            return Err(format!(
+                "The marginal distribution of {} does not match a random parameter",
+                name
+            ));
+        }
+        let marginals = self.ordered_marginals(random)?;
+        for marginal in &marginals {
+            marginal.validate()?;
+        }
+        correlation_factor(&self.correlation_matrix(marginals.len())?)?;
         Ok(())
     }
 }
 
+/// Prefix of environment variables which override entries in the configuration file
+const ENV_PREFIX: &str = "NPCORE_";
+
+/// Sections of the configuration file, which may be overridden by environment variables
+const SECTIONS: [&str; 6] = ["paths", "config", "random", "fixed", "constant", "error"];
+
 /// Parses the settings from a TOML configuration file
 ///
 /// This function parses the settings from a TOML configuration file. The settings are validated, and a copy of the settings is written to file.
 ///
-/// Entries in the TOML file may be overridden by environment variables. The environment variables must be prefixed with `NPCORE_`, and the TOML entry must be in uppercase. For example, the TUI may be disabled by setting the environment variable `NPCORE_TUI=false`.
+/// Entries in the TOML file may be overridden by environment variables named `NPCORE_<SECTION>_<KEY>`, see [env_overrides] for details.
+/// For example, the TUI may be disabled by setting the environment variable `NPCORE_CONFIG_TUI=false`, and the maximum number of cycles by `NPCORE_CONFIG_CYCLES=500`.
 pub fn read_settings(path: String) -> Result<Settings, config::ConfigError> {
-    let settings_path = path;
-
-    let parsed = eConfig::builder()
-        .add_source(config::File::with_name(&settings_path).format(config::FileFormat::Toml))
-        .add_source(config::Environment::with_prefix("NPCORE").separator("_"))
-        .build()?;
-
-    // Deserialize settings to the Settings struct
-    let settings: Settings = parsed.try_deserialize()?;
+    let settings = parse_settings(&path, std::env::vars())?;
 
     // Validate entries
     settings
@@ -181,6 +707,16 @@ pub fn read_settings(path: String) -> Result<Settings, config::ConfigError> {
         .error
         .validate()
         .map_err(config::ConfigError::Message)?;
+    settings
+        .config
+        .validate(&settings.random)
+        .map_err(config::ConfigError::Message)?;
+    OutputEquation::validate(&settings.outputs).map_err(config::ConfigError::Message)?;
+    if let Some(simulation) = &settings.simulation {
+        simulation
+            .validate(&settings.random)
+            .map_err(config::ConfigError::Message)?;
+    }
 
     // Write a copy of the settings to file if output is enabled
     if settings.config.output {
@@ -190,6 +726,97 @@ pub fn read_settings(path: String) -> Result<Settings, config::ConfigError> {
     Ok(settings) // Return the settings wrapped in Ok
 }
 
+/// Parses the settings from a TOML configuration file, overridden by the given environment variables
+pub(crate) fn parse_settings<I>(path: &str, vars: I) -> Result<Settings, config::ConfigError>
+where
+    I: IntoIterator<Item = (String, String)>,
+{
+    let file = eConfig::builder()
+        .add_source(config::File::with_name(path).format(config::FileFormat::Toml))
+        .build()?;
+
+    let mut builder = eConfig::builder().add_source(file.clone());
+    for (key, value) in env_overrides(&file, vars) {
+        builder = builder.set_override(key, value)?;
+    }
+
+    // Deserialize settings to the Settings struct
+    builder.build()?.try_deserialize()
+}
+
+/// Maps environment variables to overrides of entries in the configuration file
+///
+/// The variables must be named `NPCORE_<SECTION>_<KEY>`, where `<SECTION>` is one of the sections of the configuration file, e.g. `CONFIG`.
+/// As only the first underscore after the section separates it from the key, keys may contain underscores, e.g. `NPCORE_CONFIG_INIT_POINTS`.
+/// Variable names are case-insensitive, and keys in the `random`, `fixed` and `constant` sections are matched to the parameter names in the configuration file,
+/// such that `NPCORE_RANDOM_KE=0.1,2.0` overrides the range of a parameter named `Ke`. Parameters not present in the file are added with a lowercase name.
+///
+/// Variables with the prefix which do not name a section, e.g. `NPCORE_LOG`, are ignored with a warning, while unknown keys in a section are rejected
+/// when the settings are deserialized.
+///
+/// Comma-separated lists of numbers are parsed as lists, e.g. for parameter ranges or the error polynomial.
+/// Other values are parsed as integers, floats or booleans if possible, and are otherwise kept as strings, e.g. a path containing a comma.
+/// Nested values, such as `config.frozen`, can not be overridden.
+fn env_overrides<I>(file: &eConfig, vars: I) -> Vec<(String, config::Value)>
+where
+    I: IntoIterator<Item = (String, String)>,
+{
+    let mut overrides = Vec::new();
+    for (var, value) in vars {
+        let name = match var.strip_prefix(ENV_PREFIX) {
+            Some(name) => name.to_lowercase(),
+            None => continue,
+        };
+        let (section, key) = match SECTIONS.iter().find_map(|section| {
+            name.strip_prefix(section)
+                .and_then(|rest| rest.strip_prefix('_'))
+                .filter(|key| !key.is_empty())
+                .map(|key| (*section, key.to_string()))
+        }) {
+            Some(entry) => entry,
+            None => {
+                // The settings are read before the logger is set up, so the warning is printed
+                eprintln!(
+                    "Ignoring environment variable {}, which is not named {}<SECTION>_<KEY>, where <SECTION> is one of {:?}",
+                    var, ENV_PREFIX, SECTIONS
+                );
+                continue;
+            }
+        };
+
+        // Parameter names are case-sensitive, so match them to the names in the file
+        let key = match file.get_table(section) {
+            Ok(table) => table
+                .into_keys()
+                .find(|k| k.to_lowercase() == key)
+                .unwrap_or(key),
+            Err(_) => key,
+        };
+
+        let list = match value.contains(',') {
+            true => value
+                .split(',')
+                .map(|v| v.trim().parse::<f64>())
+                .collect::<Result<Vec<f64>, _>>()
+                .ok(),
+            false => None,
+        };
+        let value = if let Some(list) = list {
+            config::Value::from(list)
+        } else if let Ok(int) = value.parse::<i64>() {
+            config::Value::from(int)
+        } else if let Ok(float) = value.parse::<f64>() {
+            config::Value::from(float)
+        } else if let Ok(boolean) = value.parse::<bool>() {
+            config::Value::from(boolean)
+        } else {
+            config::Value::from(value)
+        };
+        overrides.push((format!("{}.{}", section, key), value));
+    }
+    overrides
+}
+
 /// Writes a copy of the parsed settings to file
 ///
 /// This function writes a copy of the parsed settings to file. The file is written to the current working directory, and is named `settings.json`.
@@ -214,10 +841,30 @@ fn default_false() -> bool {
     false
 }
 
+fn default_tui_support_points() -> usize {
+    10
+}
+
+fn default_max_steps() -> usize {
+    crate::routines::simulation::stats::DEFAULT_MAX_STEPS as usize
+}
+
+fn default_one() -> usize {
+    1
+}
+
 fn default_log_level() -> String {
     "info".to_string()
 }
 
+fn default_distribution_combine() -> String {
+    "and".to_string()
+}
+
+fn default_dose_row_observations() -> String {
+    "ignore".to_string()
+}
+
 fn default_seed() -> usize {
     347
 }
@@ -226,6 +873,18 @@ fn default_idelta() -> f64 {
     0.12
 }
 
+fn default_ipm_tol() -> f64 {
+    crate::routines::evaluation::ipm::DEFAULT_TOL
+}
+
+fn default_ipm_max_iter() -> usize {
+    crate::routines::evaluation::ipm::DEFAULT_MAX_ITER
+}
+
+fn default_scale() -> f64 {
+    1.0
+}
+
 fn default_tad() -> f64 {
     0.0
 }
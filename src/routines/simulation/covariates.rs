@@ -0,0 +1,76 @@
+use crate::routines::datafile::CovLine;
+use std::collections::HashMap;
+
+/// The functional form of a covariate effect, see [CovariateEffect]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EffectType {
+    /// `value * (cov / reference)^exponent`, e.g. allometric scaling by weight
+    Power { exponent: f64 },
+    /// `value * (1 + slope * (cov - reference))`
+    Linear { slope: f64 },
+    /// `value * exp(coefficient * (cov - reference))`
+    Exponential { coefficient: f64 },
+}
+
+/// Effect of a covariate on a parameter, relative to a reference value of the covariate
+///
+/// At the reference value, the parameter is unchanged, e.g. a typical clearance scaled by weight
+///
+/// ```ignore
+/// let weight = CovariateEffect::power("WT", 70.0, 0.75);
+/// let cl = weight.apply_at(cl, system.cov.as_ref().unwrap(), t).unwrap();
+/// ```
+///
+/// gives `CL = CL_pop * (WT/70)^0.75`, with the weight interpolated at time `t`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CovariateEffect {
+    /// Name of the covariate, as in the header of the data file
+    pub covariate: String,
+    pub reference: f64,
+    pub effect: EffectType,
+}
+
+impl CovariateEffect {
+    pub fn power(covariate: &str, reference: f64, exponent: f64) -> Self {
+        Self {
+            covariate: covariate.to_string(),
+            reference,
+            effect: EffectType::Power { exponent },
+        }
+    }
+
+    pub fn linear(covariate: &str, reference: f64, slope: f64) -> Self {
+        Self {
+            covariate: covariate.to_string(),
+            reference,
+            effect: EffectType::Linear { slope },
+        }
+    }
+
+    pub fn exponential(covariate: &str, reference: f64, coefficient: f64) -> Self {
+        Self {
+            covariate: covariate.to_string(),
+            reference,
+            effect: EffectType::Exponential { coefficient },
+        }
+    }
+
+    /// Scales the parameter `value` for the covariate value `cov`
+    pub fn apply(&self, value: f64, cov: f64) -> f64 {
+        match self.effect {
+            EffectType::Power { exponent } => value * (cov / self.reference).powf(exponent),
+            EffectType::Linear { slope } => value * (1.0 + slope * (cov - self.reference)),
+            EffectType::Exponential { coefficient } => {
+                value * (coefficient * (cov - self.reference)).exp()
+            }
+        }
+    }
+
+    /// Scales the parameter `value` for the covariate interpolated at `time`, as passed to [Predict::add_covs](super::predict::Predict::add_covs)
+    ///
+    /// Returns `None` if the covariate is not present, e.g. as it is missing for the subject.
+    pub fn apply_at(&self, value: f64, covs: &HashMap<String, CovLine>, time: f64) -> Option<f64> {
+        covs.get(&self.covariate)
+            .map(|line| self.apply(value, line.interp(time)))
+    }
+}
@@ -0,0 +1,144 @@
+use linfa_linalg::cholesky::Cholesky;
+use ndarray::{Array1, Array2};
+use rand::{rngs::StdRng, SeedableRng};
+use rand_distr::{Distribution, StandardNormal};
+use serde_derive::{Deserialize, Serialize};
+
+/// Marginal distribution of a random parameter, for simulation from a parametric summary, see [sample_correlated]
+///
+/// Given in the settings as e.g. `ke = { kind = "lognormal", mu = -2.3, sigma = 0.3 }` in `[simulation.marginals]`.
+#[derive(Debug, Deserialize, Clone, Copy, Serialize, PartialEq)]
+#[serde(tag = "kind", rename_all = "lowercase", deny_unknown_fields)]
+pub enum Marginal {
+    /// Normal distribution with mean `mean` and standard deviation `sd`
+    Normal { mean: f64, sd: f64 },
+    /// Log-normal distribution, i.e. the logarithm of the parameter is normal with mean `mu` and standard deviation `sigma`
+    Lognormal { mu: f64, sigma: f64 },
+}
+
+impl Marginal {
+    /// Validate that the standard deviation is positive and the location finite
+    pub fn validate(&self) -> Result<(), String> {
+        let (location, scale) = match *self {
+            Marginal::Normal { mean, sd } => (mean, sd),
+            Marginal::Lognormal { mu, sigma } => (mu, sigma),
+        };
+        if !(location.is_finite() && scale > 0.0 && scale.is_finite()) {
+            return Err(format!(
+                "Marginal distributions must have a finite location and a positive scale, got {:?}",
+                self
+            ));
+        }
+        Ok(())
+    }
+
+    /// The parameter value for the standard normal variate `z`
+    fn value(&self, z: f64) -> f64 {
+        match *self {
+            Marginal::Normal { mean, sd } => mean + sd * z,
+            Marginal::Lognormal { mu, sigma } => (mu + sigma * z).exp(),
+        }
+    }
+}
+
+/// Lower Cholesky factor of a correlation matrix, validating that it is a correlation matrix
+///
+/// The matrix must be square, symmetric and positive definite, with ones on the diagonal and off-diagonal elements in [-1, 1].
+pub fn correlation_factor(correlation: &Array2<f64>) -> Result<Array2<f64>, String> {
+    let n = correlation.nrows();
+    if correlation.ncols() != n {
+        return Err(format!(
+            "The correlation matrix must be square, got {} rows and {} columns",
+            n,
+            correlation.ncols()
+        ));
+    }
+    for i in 0..n {
+        if (correlation[(i, i)] - 1.0).abs() > 1e-12 {
+            return Err(format!(
+                "The diagonal of the correlation matrix must be one, got {} in row {}",
+                correlation[(i, i)],
+                i + 1
+            ));
+        }
+        for j in 0..i {
+            let value = correlation[(i, j)];
+            if !(-1.0..=1.0).contains(&value) || (value - correlation[(j, i)]).abs() > 1e-12 {
+                return Err(format!(
+                    "The correlation matrix must be symmetric with elements in [-1, 1], got {} and {} in rows {} and {}",
+                    value,
+                    correlation[(j, i)],
+                    i + 1,
+                    j + 1
+                ));
+            }
+        }
+    }
+    correlation
+        .cholesky()
+        .ok()
+        .filter(|factor| factor.iter().all(|x| x.is_finite()))
+        .ok_or_else(|| "The correlation matrix must be positive definite".to_string())
+}
+
+/// Maximum number of draws per requested sample of [sample_correlated], after which the ranges are deemed too unlikely under the marginal distributions
+const MAX_DRAWS_PER_SAMPLE: usize = 1000;
+
+/// Samples `n` parameter vectors with the given marginal distributions and correlation matrix, one row per vector
+///
+/// Correlated standard normal variates are drawn with the Cholesky factor of `correlation`, see [correlation_factor],
+/// and transformed by each marginal distribution, i.e. the correlations apply on the normal scale (a Gaussian copula).
+/// For log-normal marginals, these are the correlations of the logarithms of the parameters.
+///
+/// Vectors with a parameter outside its range in `ranges`, e.g. a negative volume from a normal marginal, are rejected and drawn again,
+/// i.e. the distribution is truncated to the ranges. Fails if too few draws fall within the ranges, see [MAX_DRAWS_PER_SAMPLE].
+/// The samples are reproducible for a given `seed`.
+pub fn sample_correlated(
+    marginals: &[Marginal],
+    correlation: &Array2<f64>,
+    ranges: &[(f64, f64)],
+    n: usize,
+    seed: usize,
+) -> Result<Array2<f64>, String> {
+    if correlation.nrows() != marginals.len() || ranges.len() != marginals.len() {
+        return Err(format!(
+            "The correlation matrix has {} rows and there are {} ranges, but there are {} marginal distributions",
+            correlation.nrows(),
+            ranges.len(),
+            marginals.len()
+        ));
+    }
+    for marginal in marginals {
+        marginal.validate()?;
+    }
+    let factor = correlation_factor(correlation)?;
+    let mut rng = StdRng::seed_from_u64(seed as u64);
+    let mut samples = Array2::zeros((n, marginals.len()));
+    let mut draws = 0;
+    for mut row in samples.rows_mut() {
+        loop {
+            if draws >= n.saturating_mul(MAX_DRAWS_PER_SAMPLE) {
+                return Err(format!(
+                    "Fewer than one in {} samples of the marginal distributions are within the ranges of the random parameters",
+                    MAX_DRAWS_PER_SAMPLE
+                ));
+            }
+            draws += 1;
+            let z: Array1<f64> = (0..marginals.len())
+                .map(|_| StandardNormal.sample(&mut rng))
+                .collect();
+            let correlated = factor.dot(&z);
+            for ((value, marginal), z) in row.iter_mut().zip(marginals).zip(correlated) {
+                *value = marginal.value(z);
+            }
+            if row
+                .iter()
+                .zip(ranges)
+                .all(|(value, (lower, upper))| (*lower..=*upper).contains(value))
+            {
+                break;
+            }
+        }
+    }
+    Ok(samples)
+}
@@ -1,6 +1,9 @@
 use crate::routines::datafile::CovLine;
+use crate::routines::datafile::Event;
 use crate::routines::datafile::Infusion;
 use crate::routines::datafile::Scenario;
+use crate::routines::settings::Settings;
+use crate::routines::simulation::stats;
 use dashmap::mapref::entry::Entry;
 use dashmap::DashMap;
 use lazy_static::lazy_static;
@@ -11,10 +14,18 @@ use ndarray::{Array, Array2, Axis};
 use std::collections::HashMap;
 use std::error;
 use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 
 /// Number of support points to cache for each scenario
 const CACHE_SIZE: usize = 1000;
 
+/// Relative tolerance when comparing cached and simulated predictions, see [cache_is_consistent]
+const CACHE_TOLERANCE: f64 = 1e-10;
+
+/// Relative tolerance within which perturbed predictions are considered unchanged, see [Engine::insensitive_parameters]
+pub const INSENSITIVITY_TOLERANCE: f64 = 1e-9;
+
 #[derive(Debug, Clone)]
 pub struct Model {
     params: HashMap<String, f64>,
@@ -37,12 +48,68 @@ pub trait Predict<'a> {
     type State;
     fn initial_system(&self, params: &Vec<f64>, scenario: Scenario) -> (Self::Model, Scenario);
     fn initial_state(&self) -> Self::State;
+    /// Amounts present in each compartment before the first event, as `(compartment, amount)` pairs
+    ///
+    /// Compartments are 0-indexed. The amounts are added to the state returned by [Predict::initial_state] using [Predict::add_dose],
+    /// and as `system` is already initialized, they may depend on (estimated) parameters, e.g. an endogenous baseline.
+    /// Defaults to an empty system, i.e. all compartments start at the initial state.
+    fn initial_amounts(&self, _system: &Self::Model) -> Vec<(usize, f64)> {
+        vec![]
+    }
+    /// Number of compartments which can receive doses or infusions, used to validate the `INPUT` of the data
+    ///
+    /// Defaults to `None`, in which case the inputs are not validated.
+    fn n_compartments(&self) -> Option<usize> {
+        None
+    }
+    /// Names of the covariates read by the model, which are checked against the data before a run
+    ///
+    /// Defaults to an empty list, in which case only the covariates present in the data are summarized.
+    fn covariates(&self) -> Vec<String> {
+        vec![]
+    }
     fn add_covs(&self, system: &mut Self::Model, cov: Option<HashMap<String, CovLine>>);
     fn add_infusion(&self, system: &mut Self::Model, infusion: Infusion);
     fn add_dose(&self, state: &mut Self::State, dose: f64, compartment: usize);
     fn get_output(&self, time: f64, state: &Self::State, system: &Self::Model, outeq: usize)
         -> f64;
     fn state_step(&self, state: &mut Self::State, system: &Self::Model, time: f64, next_time: f64);
+    /// Amounts in each compartment of a state, used by [Engine::simulate_amounts]
+    ///
+    /// Defaults to an empty vector, i.e. the amounts are not reported.
+    fn amounts(&self, _state: &Self::State) -> Vec<f64> {
+        vec![]
+    }
+    /// Output equations given by the amount in a compartment divided by a volume parameter, which is referenced by name
+    ///
+    /// The amount is read with [Predict::amounts], and the volume from the support point by the name of the random parameter, see [Engine::with_parameters].
+    /// The output thus does not depend on the position of the volume among the (alphabetically sorted) parameters, and [Predict::get_output] is not called for these output equations.
+    /// Defaults to none, i.e. all outputs are given by [Predict::get_output].
+    fn volume_outputs(&self) -> Vec<VolumeOutput> {
+        vec![]
+    }
+    /// Alternative configuration of the model, used to retry predictions which are not finite, e.g. when the integration fails
+    ///
+    /// This could be a smaller initial step size, a larger maximum number of steps, or a fixed-step solver such as `Rk4`.
+    /// For the retry to trigger, [Predict::state_step] must produce a non-finite state when the integration fails, rather than panic.
+    /// Defaults to `None`, i.e. predictions are not retried.
+    fn fallback(&self) -> Option<Self>
+    where
+        Self: Sized,
+    {
+        None
+    }
+}
+
+/// An output equation given by the amount in a compartment divided by a volume parameter, see [Predict::volume_outputs]
+#[derive(Clone, Debug, PartialEq)]
+pub struct VolumeOutput {
+    /// Output equation, as in the data file
+    pub outeq: usize,
+    /// Compartment, 0-indexed as in [Predict::amounts]
+    pub compartment: usize,
+    /// Name of the random parameter holding the volume
+    pub volume: String,
 }
 
 #[derive(Clone, Debug)]
@@ -51,6 +118,16 @@ where
     S: Predict<'static> + Clone,
 {
     ode: S,
+    fallback: Option<S>,
+    retries: Arc<stats::RetryCounter>,
+    max_steps: u32,
+    /// Lower and upper bound of the predictions, see [Engine::with_clamp]
+    clamp: (Option<f64>, Option<f64>),
+    clamped: Arc<AtomicUsize>,
+    /// Number of predictions which remained non-finite, see [Engine::take_non_finite]
+    non_finite: Arc<AtomicUsize>,
+    /// Volume outputs of the model, with the index of the volume among the parameters once known, see [Engine::with_parameters]
+    volumes: Vec<(VolumeOutput, Option<usize>)>,
 }
 
 impl<S> Engine<S>
@@ -58,20 +135,335 @@ where
     S: Predict<'static> + Clone,
 {
     pub fn new(ode: S) -> Self {
-        Self { ode }
+        let fallback = ode.fallback();
+        let volumes = ode
+            .volume_outputs()
+            .into_iter()
+            .map(|output| (output, None))
+            .collect();
+        Self {
+            ode,
+            fallback,
+            retries: Arc::default(),
+            max_steps: stats::DEFAULT_MAX_STEPS,
+            clamp: (None, None),
+            clamped: Arc::default(),
+            non_finite: Arc::default(),
+            volumes,
+        }
     }
+    /// Adds output equations given by the amount in a compartment divided by a volume parameter, e.g. from the `outputs` of the settings, see [Settings::volume_outputs](crate::routines::settings::Settings::volume_outputs)
+    ///
+    /// These replace the volume output of the model, see [Predict::volume_outputs], or its [Predict::get_output], for the same output equation.
+    /// The volumes are found by name when the names of the parameters are set, so this must precede [Engine::with_parameters].
+    pub fn with_volume_outputs(mut self, outputs: Vec<VolumeOutput>) -> Self {
+        for output in outputs {
+            self.volumes
+                .retain(|(existing, _)| existing.outeq != output.outeq);
+            self.volumes.push((output, None));
+        }
+        self
+    }
+    /// Sets the names of the parameters, in the order of the support points, used to find the volumes of [Predict::volume_outputs]
+    ///
+    /// Fails if a volume is not one of the parameters, or its compartment is not reported by [Predict::amounts].
+    pub fn with_parameters(mut self, names: &[String]) -> Result<Self, String> {
+        let n_compartments = self.ode.amounts(&self.ode.initial_state()).len();
+        for (output, index) in &mut self.volumes {
+            *index = Some(
+                names
+                    .iter()
+                    .position(|name| *name == output.volume)
+                    .ok_or_else(|| {
+                        format!(
+                            "The volume {} of output equation {} is not one of the parameters {:?}",
+                            output.volume, output.outeq, names
+                        )
+                    })?,
+            );
+            if output.compartment >= n_compartments {
+                return Err(format!(
+                    "Output equation {} reads compartment {}, but the model reports the amounts of {} compartment(s)",
+                    output.outeq, output.compartment, n_compartments
+                ));
+            }
+        }
+        Ok(self)
+    }
+    /// Configures the engine as given by the settings: the volume outputs and names of the parameters, see [Engine::with_parameters],
+    /// the maximum number of steps, see [Engine::with_max_steps], and the bounds of the predictions, see [Engine::with_clamp]
+    ///
+    /// Volume outputs of earlier settings are kept unless replaced, so settings are applied to the engine of the model, not to an engine configured before.
+    pub fn configure(self, settings: &Settings) -> Result<Self, String> {
+        Ok(self
+            .with_volume_outputs(settings.volume_outputs())
+            .with_parameters(&settings.random.names())?
+            .with_max_steps(settings.config.max_steps)
+            .with_clamp(
+                settings.config.prediction_floor,
+                settings.config.prediction_ceiling,
+            ))
+    }
+    /// Sets the maximum number of steps of a single integration, see [stats::max_steps]
+    pub fn with_max_steps(mut self, max_steps: usize) -> Self {
+        self.max_steps = max_steps.min(u32::MAX as usize) as u32;
+        self
+    }
+    /// Clamps finite predictions to at least `floor` and at most `ceiling`, e.g. to keep tiny negative concentrations caused by numerical error out of the likelihood
+    ///
+    /// Clamped predictions are counted, see [Engine::take_clamped].
+    pub fn with_clamp(mut self, floor: Option<f64>, ceiling: Option<f64>) -> Self {
+        self.clamp = (floor, ceiling);
+        self
+    }
+    /// Validates that the doses and infusions of each scenario are consistent with the compartments of the model
+    ///
+    /// Inputs are 1-indexed, and must not exceed [Predict::n_compartments]. A subject may dose into several compartments, e.g. oral and intravenous co-administration.
+    ///
+    /// Also fails if the volume of an output equation is not yet found among the parameters, see [Engine::with_parameters].
+    pub fn validate(&self, scenarios: &[Scenario]) -> Result<(), String> {
+        if let Some((output, _)) = self.volumes.iter().find(|(_, index)| index.is_none()) {
+            return Err(format!(
+                "The volume {} of output equation {} is referenced by name, but the parameter names are not known, see Engine::with_parameters",
+                output.volume, output.outeq
+            ));
+        }
+        for scenario in scenarios {
+            let doses = scenario
+                .blocks
+                .iter()
+                .flat_map(|block| block.events.iter())
+                .filter(|event| event.evid == 1);
+            for event in doses {
+                let input = event.input.unwrap_or(0);
+                let valid = match self.ode.n_compartments() {
+                    Some(n) => input >= 1 && input <= n,
+                    None => input >= 1,
+                };
+                if !valid {
+                    return Err(format!(
+                        "Subject {} has a dose at time {} into input {}, but the model has {} compartment(s)",
+                        scenario.id,
+                        event.time,
+                        input,
+                        self.ode
+                            .n_compartments()
+                            .map_or("an unknown number of".to_string(), |n| n.to_string())
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Names of the covariates read by the model, see [Predict::covariates]
+    pub fn covariates(&self) -> Vec<String> {
+        self.ode.covariates()
+    }
+
+    /// Screens for parameters which do not influence the predictions
+    ///
+    /// Starting from the center of the parameter space, each parameter is perturbed towards both ends of its range while the others are kept fixed.
+    /// Parameters whose perturbation never changes any prediction, for any of the scenarios, are likely not read by the model and are returned by index.
+    /// Predictions are compared with the relative tolerance [INSENSITIVITY_TOLERANCE], so that rounding in the solver does not hide an unused parameter.
+    /// Parameters with equal bounds can not be perturbed, and are never returned.
+    pub fn insensitive_parameters(
+        &self,
+        scenarios: &[Scenario],
+        ranges: &[(f64, f64)],
+    ) -> Vec<usize> {
+        let center: Vec<f64> = ranges.iter().map(|(lo, hi)| (lo + hi) / 2.0).collect();
+        let predict_all = |params: &Vec<f64>| -> Vec<Vec<f64>> {
+            scenarios
+                .iter()
+                .map(|scenario| self.pred(scenario.clone(), params.clone()))
+                .collect()
+        };
+        let reference = predict_all(&center);
+        (0..ranges.len())
+            .filter(|&j| {
+                let (lo, hi) = ranges[j];
+                lo < hi
+                    && [lo + 0.1 * (hi - lo), hi - 0.1 * (hi - lo)]
+                        .iter()
+                        .all(|&value| {
+                            let mut params = center.clone();
+                            params[j] = value;
+                            predict_all(&params)
+                                .iter()
+                                .zip(reference.iter())
+                                .all(|(a, b)| {
+                                    a.len() == b.len()
+                                        && a.iter().zip(b.iter()).all(|(x, y)| {
+                                            x == y
+                                                || (x.is_nan() && y.is_nan())
+                                                || (x - y).abs()
+                                                    <= INSENSITIVITY_TOLERANCE
+                                                        * x.abs().max(y.abs())
+                                        })
+                                })
+                        })
+            })
+            .collect()
+    }
+
+    /// Predicts the observations of a scenario for a support point
+    ///
+    /// The observations of all output equations are predicted from a single integration of the model, in the order of `obs`.
+    /// If any prediction is not finite, and the model provides a [Predict::fallback], the scenario is simulated again with the fallback.
+    /// Retries are counted, see [Engine::take_retries].
+    /// Predictions which remain non-finite, e.g. because an integration reached the maximum number of steps, are counted, see [Engine::take_non_finite],
+    /// and give the support point a likelihood of zero for the subject, see [prob::calculate_psi](crate::routines::evaluation::prob::calculate_psi).
+    /// Finite predictions are clamped if a floor or ceiling is set, see [Engine::with_clamp].
     pub fn pred(&self, scenario: Scenario, params: Vec<f64>) -> Vec<f64> {
-        let (mut system, scenario) = self.ode.initial_system(&params, scenario.clone());
+        let mut yout = self.pred_retried(scenario, params);
+        if let (None, None) = self.clamp {
+            return yout;
+        }
+        let (floor, ceiling) = self.clamp;
+        let mut clamped = 0;
+        for y in yout.iter_mut().filter(|y| y.is_finite()) {
+            let bounded = y
+                .max(floor.unwrap_or(f64::NEG_INFINITY))
+                .min(ceiling.unwrap_or(f64::INFINITY));
+            if bounded != *y {
+                *y = bounded;
+                clamped += 1;
+            }
+        }
+        if clamped > 0 {
+            self.clamped.fetch_add(clamped, Ordering::Relaxed);
+        }
+        yout
+    }
+
+    /// Predicts the observations of a scenario, retrying with the [Predict::fallback] of the model if any prediction is not finite, see [Engine::pred]
+    fn pred_retried(&self, scenario: Scenario, params: Vec<f64>) -> Vec<f64> {
+        stats::set_max_steps(self.max_steps);
+        let mut yout = self.simulate_obs(&self.ode, &scenario, &params);
+        if yout.iter().all(|y| y.is_finite()) {
+            return yout;
+        }
+        if let Some(fallback) = &self.fallback {
+            yout = self.simulate_obs(fallback, &scenario, &params);
+            let rescued = yout.iter().all(|y| y.is_finite());
+            self.retries.record(rescued);
+            if rescued {
+                return yout;
+            }
+        }
+        self.non_finite.fetch_add(1, Ordering::Relaxed);
+        tracing::debug!(
+            "Non-finite prediction for subject {} with support point {:?}, e.g. as an integration exceeded {} steps",
+            scenario.id,
+            params,
+            self.max_steps
+        );
+        yout
+    }
+
+    /// Returns the number of predictions retried with the [Predict::fallback] of the model so far, and resets the count
+    pub fn take_retries(&self) -> stats::Retries {
+        self.retries.take()
+    }
+
+    /// Returns the number of predictions clamped so far, see [Engine::with_clamp], and resets the count
+    pub fn take_clamped(&self) -> usize {
+        self.clamped.swap(0, Ordering::Relaxed)
+    }
+
+    /// Returns the number of predictions which remained non-finite so far, see [Engine::pred], and resets the count
+    pub fn take_non_finite(&self) -> usize {
+        self.non_finite.swap(0, Ordering::Relaxed)
+    }
+
+    /// Predicts the amounts in each compartment of the model at the observations of a scenario, see [Predict::amounts]
+    ///
+    /// If `idelta` is positive, the scenario is first expanded with predictions at that interval, see [Scenario::add_event_interval].
+    /// Returns `(time, amounts)` pairs in order of time, with one pair per time even if several output equations are observed.
+    pub fn simulate_amounts(
+        &self,
+        scenario: &Scenario,
+        params: &Vec<f64>,
+        idelta: f64,
+        tad: f64,
+    ) -> Vec<(f64, Vec<f64>)> {
+        let scenario = if idelta > 0.0 {
+            scenario.add_event_interval(idelta, tad)
+        } else {
+            scenario.clone()
+        };
+        stats::set_max_steps(self.max_steps);
+        let mut trajectory: Vec<(f64, Vec<f64>)> = vec![];
+        Self::simulate(&self.ode, &scenario, params, |event, x, _system| {
+            if trajectory
+                .last()
+                .is_none_or(|(time, _)| *time != event.time)
+            {
+                trajectory.push((event.time, self.ode.amounts(x)));
+            }
+        });
+        trajectory
+    }
+
+    fn simulate_obs(&self, ode: &S, scenario: &Scenario, params: &Vec<f64>) -> Vec<f64> {
         let mut yout = vec![];
-        let mut x = self.ode.initial_state();
+        Self::simulate(ode, scenario, params, |event, x, system| {
+            yout.push(self.output(ode, event.time, x, system, event.outeq.unwrap(), params))
+        });
+        yout
+    }
+
+    /// Output equation `outeq` of a state, given by [Predict::volume_outputs] or [Predict::get_output]
+    ///
+    /// A volume output whose volume is not yet found among the parameters is not finite, as a failed prediction, see [Engine::validate].
+    fn output(
+        &self,
+        ode: &S,
+        time: f64,
+        x: &S::State,
+        system: &S::Model,
+        outeq: usize,
+        params: &[f64],
+    ) -> f64 {
+        match self
+            .volumes
+            .iter()
+            .find(|(output, _)| output.outeq == outeq)
+        {
+            Some((output, Some(index))) => ode.amounts(x)[output.compartment] / params[*index],
+            Some((_, None)) => f64::NAN,
+            None => ode.get_output(time, x, system, outeq),
+        }
+    }
+
+    /// Simulates a scenario, calling `observe` with the state and system at each observation
+    fn simulate<F>(ode: &S, scenario: &Scenario, params: &Vec<f64>, mut observe: F)
+    where
+        F: FnMut(&Event, &S::State, &S::Model),
+    {
+        let (mut system, scenario) = ode.initial_system(params, scenario.clone());
+        let mut x = ode.initial_state();
+        for (compartment, amount) in ode.initial_amounts(&system) {
+            ode.add_dose(&mut x, amount, compartment);
+        }
+        // The infusion rate is discontinuous at the end of an infusion, so integration is restarted there rather than stepped across
+        let mut infusion_ends: Vec<f64> = scenario
+            .blocks
+            .iter()
+            .flat_map(|block| block.events.iter())
+            .filter(|event| event.evid == 1 && event.dur.unwrap_or(0.0) > 0.0)
+            .map(|event| event.time + event.dur.unwrap())
+            .collect();
+        infusion_ends.sort_by(f64::total_cmp);
         let mut index: usize = 0;
         for block in scenario.blocks {
-            self.ode.add_covs(&mut system, Some(block.covs));
+            ode.add_covs(&mut system, Some(block.covs));
             for event in &block.events {
                 if event.evid == 1 {
                     if event.dur.unwrap_or(0.0) > 0.0 {
                         //infusion
-                        self.ode.add_infusion(
+                        ode.add_infusion(
                             &mut system,
                             Infusion {
                                 time: event.time,
@@ -82,24 +474,27 @@ where
                         );
                     } else {
                         //     //dose
-                        self.ode
-                            .add_dose(&mut x, event.dose.unwrap(), event.input.unwrap() - 1);
+                        ode.add_dose(&mut x, event.dose.unwrap(), event.input.unwrap() - 1);
                     }
                 } else if event.evid == 0 {
                     //obs
-                    yout.push(
-                        self.ode
-                            .get_output(event.time, &x, &system, event.outeq.unwrap()),
-                    )
+                    observe(event, &x, &system)
                 }
                 if let Some(next_time) = scenario.times.get(index + 1) {
                     // TODO: use the last dx as the initial one for the next simulation.
-                    self.ode.state_step(&mut x, &system, event.time, *next_time);
+                    let mut time = event.time;
+                    for &end in infusion_ends
+                        .iter()
+                        .filter(|&&end| end > event.time && end < *next_time)
+                    {
+                        ode.state_step(&mut x, &system, time, end);
+                        time = end;
+                    }
+                    ode.state_step(&mut x, &system, time, *next_time);
                 }
                 index += 1;
             }
         }
-        yout
     }
 }
 
@@ -150,6 +545,63 @@ pub fn get_ypred<S: Predict<'static> + Sync + Clone>(
     }
 }
 
+/// Removes all cached predictions
+pub fn clear_cache() {
+    YPRED_CACHE.clear();
+}
+
+/// Number of cached predictions, used to confirm the state of the cache, e.g. in `benches/cache.rs`
+pub fn cache_len() -> usize {
+    YPRED_CACHE.len()
+}
+
+/// Verifies that cached predictions agree with freshly simulated ones
+///
+/// The cache is keyed by the index of the scenario and the support point, not by the content of the scenario.
+/// Stale entries, e.g. from an earlier run in the same process with different data, would therefore silently corrupt the predictions.
+/// This simulates `support_points` both with and without the cache, and returns false if any prediction differs beyond a small relative tolerance.
+pub fn cache_is_consistent<S>(
+    sim_eng: &Engine<S>,
+    scenarios: &Vec<Scenario>,
+    support_points: &Array2<f64>,
+) -> bool
+where
+    S: Predict<'static> + Sync + Clone,
+{
+    let cached = sim_obs(sim_eng, scenarios, support_points, true);
+    let simulated = sim_obs(sim_eng, scenarios, support_points, false);
+    cached.iter().zip(simulated.iter()).all(|(a, b)| {
+        a.len() == b.len()
+            && a.iter().zip(b.iter()).all(|(x, y)| {
+                (x.is_nan() && y.is_nan())
+                    || (x - y).abs() <= CACHE_TOLERANCE * x.abs().max(y.abs()).max(1.0)
+            })
+    })
+}
+
+/// Verifies that simulating the same support points twice gives identical predictions
+///
+/// Predictions must be deterministic, as the cache and the comparison of objective functions between cycles rely on it.
+/// Differences point to state which is not reset between simulations, e.g. uninitialized solver state, or to data races between threads.
+/// The cache is bypassed, and predictions are compared exactly, where non-finite predictions agree with themselves.
+pub fn predictions_are_deterministic<S>(
+    sim_eng: &Engine<S>,
+    scenarios: &Vec<Scenario>,
+    support_points: &Array2<f64>,
+) -> bool
+where
+    S: Predict<'static> + Sync + Clone,
+{
+    let first = sim_obs(sim_eng, scenarios, support_points, false);
+    let second = sim_obs(sim_eng, scenarios, support_points, false);
+    first.iter().zip(second.iter()).all(|(a, b)| {
+        a.len() == b.len()
+            && a.iter()
+                .zip(b.iter())
+                .all(|(x, y)| x.to_bits() == y.to_bits() || (x.is_nan() && y.is_nan()))
+    })
+}
+
 /// Simulate observations for multiple scenarios and support points.
 ///
 /// This function performs simulation of observations for multiple scenarios and support points
@@ -187,6 +639,22 @@ pub fn sim_obs<S>(
     support_points: &Array2<f64>,
     cache: bool,
 ) -> Array2<Array1<f64>>
+where
+    S: Predict<'static> + Sync + Clone,
+{
+    sim_obs_offset(sim_eng, scenarios, support_points, cache, 0)
+}
+
+/// Simulate observations for a batch of scenarios, starting at index `offset` among all scenarios
+///
+/// The index is used to identify the scenario in the cache, and must therefore be the same as when simulating all scenarios at once.
+pub(crate) fn sim_obs_offset<S>(
+    sim_eng: &Engine<S>,
+    scenarios: &[Scenario],
+    support_points: &Array2<f64>,
+    cache: bool,
+    offset: usize,
+) -> Array2<Array1<f64>>
 where
     S: Predict<'static> + Sync + Clone,
 {
@@ -201,11 +669,12 @@ where
                 .enumerate()
                 .for_each(|(j, mut element)| {
                     let scenario = scenarios.get(i).unwrap();
+                    stats::set_subject(offset + i);
                     let ypred = get_ypred(
                         sim_eng,
                         scenario.clone(),
                         support_points.row(j).to_vec(),
-                        i,
+                        offset + i,
                         cache,
                     );
                     element.fill(ypred);
@@ -0,0 +1,232 @@
+use crate::routines::datafile::Scenario;
+use dashmap::DashMap;
+use lazy_static::lazy_static;
+use std::cell::Cell;
+use std::ops::AddAssign;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+/// Statistics of the ODE integration, summed over all integrations for a subject
+///
+/// Predictions retrieved from the cache do not contribute, as no integration is performed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SolverStats {
+    /// Number of steps attempted, i.e. accepted and rejected steps
+    pub steps: u64,
+    pub accepted: u64,
+    pub rejected: u64,
+    /// Number of evaluations of the system of ODEs
+    pub evaluations: u64,
+}
+
+impl From<&ode_solvers::dop_shared::Stats> for SolverStats {
+    fn from(stats: &ode_solvers::dop_shared::Stats) -> Self {
+        Self {
+            steps: (stats.accepted_steps + stats.rejected_steps) as u64,
+            accepted: stats.accepted_steps as u64,
+            rejected: stats.rejected_steps as u64,
+            evaluations: stats.num_eval as u64,
+        }
+    }
+}
+
+impl AddAssign for SolverStats {
+    fn add_assign(&mut self, other: Self) {
+        self.steps += other.steps;
+        self.accepted += other.accepted;
+        self.rejected += other.rejected;
+        self.evaluations += other.evaluations;
+    }
+}
+
+/// Number of predictions retried with the fallback of the model, see [Predict::fallback](super::predict::Predict::fallback)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Retries {
+    pub attempted: usize,
+    /// Number of retries which produced finite predictions
+    pub rescued: usize,
+}
+
+/// Counter of retries, shared between clones of an [Engine](super::predict::Engine)
+///
+/// Retries are always counted, regardless of whether the collection of solver statistics is enabled.
+#[derive(Debug, Default)]
+pub(crate) struct RetryCounter {
+    attempted: AtomicUsize,
+    rescued: AtomicUsize,
+}
+
+impl RetryCounter {
+    pub(crate) fn record(&self, rescued: bool) {
+        self.attempted.fetch_add(1, Ordering::Relaxed);
+        if rescued {
+            self.rescued.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Returns the number of retries so far, and resets the count
+    pub(crate) fn take(&self) -> Retries {
+        Retries {
+            attempted: self.attempted.swap(0, Ordering::Relaxed),
+            rescued: self.rescued.swap(0, Ordering::Relaxed),
+        }
+    }
+}
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Default maximum number of steps of a single integration, equal to the default of the adaptive solvers in `ode_solvers`
+pub const DEFAULT_MAX_STEPS: u32 = 100_000;
+
+lazy_static! {
+    static ref SOLVER_STATS: DashMap<usize, SolverStats> = DashMap::new();
+}
+
+thread_local! {
+    static SUBJECT: Cell<usize> = const { Cell::new(0) };
+    static MAX_STEPS: Cell<u32> = const { Cell::new(DEFAULT_MAX_STEPS) };
+}
+
+/// Enables or disables the collection of solver statistics, mapped to the `solver_stats`-setting in the configuration file
+pub fn enable(enabled: bool) {
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Records the statistics of an integration, attributing them to the subject currently being simulated
+///
+/// This is meant to be called from [Predict::state_step](super::predict::Predict::state_step), e.g.
+///
+/// ```ignore
+/// if let Ok(stats) = stepper.integrate() {
+///     npcore::prelude::simulation::stats::record(&stats);
+/// }
+/// ```
+///
+/// Does nothing unless the collection is enabled.
+pub fn record(stats: &ode_solvers::dop_shared::Stats) {
+    if !is_enabled() {
+        return;
+    }
+    let subject = SUBJECT.with(|s| s.get());
+    *SOLVER_STATS.entry(subject).or_default() += SolverStats::from(stats);
+}
+
+/// Sets the subject which subsequent calls to [record] on this thread are attributed to
+pub(crate) fn set_subject(index: usize) {
+    SUBJECT.with(|s| s.set(index));
+}
+
+/// Maximum number of steps of a single integration, mapped to the `max_steps`-setting in the configuration file
+///
+/// This is meant to be passed as `n_max` to the adaptive solvers in [Predict::state_step](super::predict::Predict::state_step), e.g.
+///
+/// ```ignore
+/// let mut stepper = Dopri5::from_param(
+///     system, time, next_time, 1e-3, state, rtol, atol, 0.9, 0.04, 0.2, 10.0, dx_max, 0.0,
+///     npcore::prelude::simulation::stats::max_steps(),
+///     1000,
+///     OutputType::Sparse,
+/// );
+/// ```
+///
+/// An integration which reaches the maximum should produce a non-finite state, which penalizes the support point, see [Engine::pred](super::predict::Engine::pred).
+/// The solvers of `ode_solvers` return an error in that case, but keep the last partial state, so the model should set the state to NaN itself.
+pub fn max_steps() -> u32 {
+    MAX_STEPS.with(|m| m.get())
+}
+
+/// Sets the maximum number of steps which subsequent calls to [max_steps] on this thread return
+pub(crate) fn set_max_steps(max_steps: u32) {
+    MAX_STEPS.with(|m| m.set(max_steps));
+}
+
+/// Returns the statistics collected so far, indexed by subject, and resets the collection
+pub fn take() -> Vec<(usize, SolverStats)> {
+    let mut stats: Vec<(usize, SolverStats)> = SOLVER_STATS
+        .iter()
+        .map(|e| (*e.key(), *e.value()))
+        .collect();
+    SOLVER_STATS.clear();
+    stats.sort_by_key(|(index, _)| *index);
+    stats
+}
+
+/// Logs the retried, clamped and non-finite predictions of a cycle as warnings, and the solver statistics at `debug` level, and resets the collection
+pub fn log_cycle(scenarios: &[Scenario], retries: Retries, clamped: usize, non_finite: usize) {
+    if retries.attempted > 0 {
+        tracing::warn!(
+            "{} prediction(s) were retried with the fallback solver, of which {} succeeded",
+            retries.attempted,
+            retries.rescued
+        );
+    }
+    if clamped > 0 {
+        tracing::warn!(
+            "{} prediction(s) were clamped to the prediction floor or ceiling",
+            clamped
+        );
+    }
+    if non_finite > 0 {
+        tracing::warn!(
+            "{} prediction(s) were not finite, e.g. as an integration exceeded the maximum number of steps, and were given a likelihood of zero",
+            non_finite
+        );
+    }
+    if !is_enabled() {
+        return;
+    }
+    let mut total = SolverStats::default();
+    for (index, stats) in take() {
+        let id = scenarios.get(index).map_or("?", |s| s.id.as_str());
+        tracing::debug!(
+            "Solver statistics for subject {}: {} steps ({} accepted, {} rejected), {} evaluations",
+            id,
+            stats.steps,
+            stats.accepted,
+            stats.rejected,
+            stats.evaluations
+        );
+        total += stats;
+    }
+    tracing::debug!(
+        "Solver statistics for cycle: {} steps ({} accepted, {} rejected), {} evaluations",
+        total.steps,
+        total.accepted,
+        total.rejected,
+        total.evaluations
+    );
+}
+
+/// Builder of a thread pool with `threads` threads, as used for the simulations with the `--threads` option of `entrypoints::run_cli`
+pub fn thread_pool(threads: usize) -> rayon::ThreadPoolBuilder {
+    rayon::ThreadPoolBuilder::new().num_threads(threads)
+}
+
+/// Fraction of the thread slots used when `work_items` simulations are spread over `threads` threads in waves of `threads` items
+///
+/// The simulations of a cycle, one for each subject and support point, are only fully parallel if their number is a multiple of the number of threads,
+/// and with fewer simulations than threads, some threads are idle. Assumes that all simulations take the same time.
+pub fn parallel_efficiency(work_items: usize, threads: usize) -> f64 {
+    if work_items == 0 || threads == 0 {
+        return 0.0;
+    }
+    let waves = work_items.div_ceil(threads);
+    work_items as f64 / (waves * threads) as f64
+}
+
+/// Logs the number of simulations of a cycle, i.e. the subjects times the support points, and their parallel efficiency on the current thread pool, see [parallel_efficiency]
+pub fn log_parallelism(n_subjects: usize, n_points: usize) {
+    let work_items = n_subjects * n_points;
+    let threads = rayon::current_num_threads();
+    tracing::debug!(
+        "Simulated {} subjects x {} support points = {} work items on {} threads, parallel efficiency {:.1}%",
+        n_subjects,
+        n_points,
+        work_items,
+        threads,
+        100.0 * parallel_efficiency(work_items, threads)
+    );
+}
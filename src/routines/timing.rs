@@ -0,0 +1,187 @@
+use csv::WriterBuilder;
+use std::error;
+use std::time::{Duration, Instant};
+
+/// Stages of a cycle which are timed separately, see [CycleTimer]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    /// Simulation of the predictions, i.e. `sim_obs`
+    Simulation,
+    /// Calculation of the likelihoods from the predictions, i.e. `prob::calculate_psi`
+    Likelihood,
+    /// Estimation of the weights, i.e. `ipm::burke`
+    Ipm,
+    /// Rank-revealing factorization, i.e. `qr::calculate_r`
+    Qr,
+    /// Expansion of the grid, i.e. `adaptative_grid`
+    Expansion,
+}
+
+const STAGES: [Stage; 5] = [
+    Stage::Simulation,
+    Stage::Likelihood,
+    Stage::Ipm,
+    Stage::Qr,
+    Stage::Expansion,
+];
+
+/// Time spent in each [Stage] of a cycle
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct CycleTiming {
+    pub cycle: usize,
+    pub simulation: Duration,
+    pub likelihood: Duration,
+    pub ipm: Duration,
+    pub qr: Duration,
+    pub expansion: Duration,
+    /// Duration of the whole cycle, including the time not attributed to any of the stages
+    pub total: Duration,
+}
+
+impl CycleTiming {
+    fn stage_mut(&mut self, stage: Stage) -> &mut Duration {
+        match stage {
+            Stage::Simulation => &mut self.simulation,
+            Stage::Likelihood => &mut self.likelihood,
+            Stage::Ipm => &mut self.ipm,
+            Stage::Qr => &mut self.qr,
+            Stage::Expansion => &mut self.expansion,
+        }
+    }
+
+    /// Time spent in `stage`
+    pub fn stage(&self, stage: Stage) -> Duration {
+        match stage {
+            Stage::Simulation => self.simulation,
+            Stage::Likelihood => self.likelihood,
+            Stage::Ipm => self.ipm,
+            Stage::Qr => self.qr,
+            Stage::Expansion => self.expansion,
+        }
+    }
+
+    /// Sum of the time spent in the stages, which is at most [CycleTiming::total]
+    pub fn stages(&self) -> Duration {
+        STAGES.iter().map(|stage| self.stage(*stage)).sum()
+    }
+}
+
+/// Measures the time spent in each [Stage] of the cycles of an algorithm, mapped to the `timing`-setting in the configuration file
+///
+/// When disabled, nothing is measured or recorded, and the timed closures are simply called.
+#[derive(Debug, Clone)]
+pub struct CycleTimer {
+    enabled: bool,
+    current: Option<(Instant, CycleTiming)>,
+    records: Vec<CycleTiming>,
+}
+
+impl CycleTimer {
+    pub fn new(enabled: bool) -> Self {
+        Self {
+            enabled,
+            current: None,
+            records: Vec::new(),
+        }
+    }
+
+    /// Starts timing a new cycle, finishing the previous one
+    pub fn start_cycle(&mut self, cycle: usize) {
+        if !self.enabled {
+            return;
+        }
+        self.finish();
+        self.current = Some((
+            Instant::now(),
+            CycleTiming {
+                cycle,
+                ..Default::default()
+            },
+        ));
+    }
+
+    /// Finishes timing the current cycle, if any
+    pub fn finish(&mut self) {
+        if let Some((start, mut timing)) = self.current.take() {
+            timing.total = start.elapsed();
+            self.records.push(timing);
+        }
+    }
+
+    /// Calls `f`, attributing the time spent to `stage` of the current cycle
+    pub fn time<T>(&mut self, stage: Stage, f: impl FnOnce() -> T) -> T {
+        if !self.enabled {
+            return f();
+        }
+        let start = Instant::now();
+        let result = f();
+        self.add(stage, start.elapsed());
+        result
+    }
+
+    /// Attributes `duration` to `stage` of the current cycle, e.g. for stages which are timed by the callee
+    pub fn add(&mut self, stage: Stage, duration: Duration) {
+        if let Some((_, timing)) = self.current.as_mut() {
+            *timing.stage_mut(stage) += duration;
+        }
+    }
+
+    /// The timings of the finished cycles, in the order they were run
+    pub fn records(&self) -> &[CycleTiming] {
+        &self.records
+    }
+}
+
+/// Logs the total time spent in each stage across `records`, and its share of the total run time
+pub fn log_summary(records: &[CycleTiming]) {
+    if records.is_empty() {
+        return;
+    }
+    let mut total = CycleTiming::default();
+    for record in records {
+        for stage in STAGES {
+            *total.stage_mut(stage) += record.stage(stage);
+        }
+        total.total += record.total;
+    }
+    let share = |duration: Duration| {
+        100.0 * duration.as_secs_f64() / total.total.as_secs_f64().max(f64::MIN_POSITIVE)
+    };
+    tracing::info!(
+        "Time spent in {} cycle(s): {:.3} s, of which simulation {:.1}%, likelihood {:.1}%, IPM {:.1}%, QR {:.1}%, grid expansion {:.1}%",
+        records.len(),
+        total.total.as_secs_f64(),
+        share(total.simulation),
+        share(total.likelihood),
+        share(total.ipm),
+        share(total.qr),
+        share(total.expansion)
+    );
+}
+
+/// Writes the timings to `path` as CSV, with one row per cycle and durations in seconds
+pub fn write(records: &[CycleTiming], path: &str) -> Result<(), Box<dyn error::Error>> {
+    let mut writer = WriterBuilder::new().has_headers(false).from_path(path)?;
+    writer.write_record([
+        "cycle",
+        "simulation",
+        "likelihood",
+        "ipm",
+        "qr",
+        "expansion",
+        "total",
+    ])?;
+    for record in records {
+        writer.write_record(&[
+            record.cycle.to_string(),
+            record.simulation.as_secs_f64().to_string(),
+            record.likelihood.as_secs_f64().to_string(),
+            record.ipm.as_secs_f64().to_string(),
+            record.qr.as_secs_f64().to_string(),
+            record.expansion.as_secs_f64().to_string(),
+            record.total.as_secs_f64().to_string(),
+        ])?;
+    }
+    writer.flush()?;
+    Ok(())
+}
@@ -0,0 +1,122 @@
+use std::fmt::{self, Display};
+
+use ndarray::Array1;
+use serde_derive::Serialize;
+
+use crate::routines::datafile::Scenario;
+use crate::routines::evaluation::sigma::Sigma;
+
+/// Standard deviations of the error model below this value are considered to be zero, see [check_sigma]
+pub const MIN_SIGMA: f64 = 1e-8;
+
+/// What a [Warning] concerns
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Category {
+    /// Subjects removed from, or issues with, the data
+    Data,
+    /// Random parameters which are held constant or may be unused by the model
+    Parameters,
+    /// Support points dropped by the rank-revealing factorization
+    Condensation,
+    /// Support points discarded to limit the size of the grid
+    Grid,
+    /// An objective function which moved in the wrong direction
+    Objective,
+    /// An error model with standard deviations near zero
+    Sigma,
+    /// Cached predictions which disagree with simulated predictions
+    Cache,
+    /// Predictions which differ between repeated simulations, see `predict::predictions_are_deterministic`
+    Determinism,
+    /// Runs which ended without converging
+    Convergence,
+    /// A projected memory use of the initial grid above `prob::MEMORY_WARNING`
+    Memory,
+}
+
+impl Display for Category {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let str = match self {
+            Category::Data => "data",
+            Category::Parameters => "parameters",
+            Category::Condensation => "condensation",
+            Category::Grid => "grid",
+            Category::Objective => "objective",
+            Category::Sigma => "sigma",
+            Category::Cache => "cache",
+            Category::Determinism => "determinism",
+            Category::Convergence => "convergence",
+            Category::Memory => "memory",
+        };
+        write!(f, "{}", str)
+    }
+}
+
+/// A warning raised during a run, which is also logged, and returned in [NPResult::warnings](crate::routines::output::NPResult::warnings)
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Warning {
+    pub category: Category,
+    /// Cycle the warning was raised in, if raised by a cycle of the algorithm
+    pub cycle: Option<usize>,
+    pub message: String,
+}
+
+impl Warning {
+    pub fn new(category: Category, message: String) -> Self {
+        Self {
+            category,
+            cycle: None,
+            message,
+        }
+    }
+
+    /// Sets the cycle the warning was raised in
+    pub fn in_cycle(mut self, cycle: usize) -> Self {
+        self.cycle = Some(cycle);
+        self
+    }
+}
+
+impl Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.cycle {
+            Some(cycle) => write!(f, "[{}] cycle {}: {}", self.category, cycle, self.message),
+            None => write!(f, "[{}] {}", self.category, self.message),
+        }
+    }
+}
+
+/// Returns a warning if the error model gives a standard deviation below [MIN_SIGMA] for any of the observations
+///
+/// Such observations dominate the likelihood, as their error is (nearly) zero. Missing observations are not checked.
+pub fn check_sigma<S>(scenarios: &[Scenario], sigma: &S) -> Option<Warning>
+where
+    S: Sigma + ?Sized,
+{
+    let mut near_zero = 0;
+    let mut total = 0;
+    for scenario in scenarios {
+        let obs: Array1<f64> = scenario
+            .obs
+            .iter()
+            .copied()
+            .filter(|&obs| obs != -99.0)
+            .collect();
+        total += obs.len();
+        near_zero += sigma
+            .sigma(&obs)
+            .iter()
+            .filter(|&&sd| sd < MIN_SIGMA)
+            .count();
+    }
+    (near_zero > 0).then(|| {
+        Warning::new(
+            Category::Sigma,
+            format!(
+                "The error model gives a standard deviation below {} for {} of {} observations",
+                MIN_SIGMA, near_zero, total
+            ),
+        )
+    })
+}
@@ -0,0 +1,216 @@
+use crate::algorithms::initialize_algorithm;
+use crate::entrypoints::read_data_from;
+use crate::routines::datafile::{self, Scenario};
+use crate::routines::evaluation::convergence::StopReason;
+use crate::routines::output::{NPResult, PopulationModel};
+use crate::routines::settings::{read_settings, Settings};
+use crate::routines::simulation::predict::{self, Engine, Predict};
+use eyre::Result;
+use serde_derive::Deserialize;
+use serde_json::{json, Value};
+use std::io::{BufRead, Write};
+use std::panic::AssertUnwindSafe;
+use tokio::sync::mpsc;
+
+/// A command read by [serve], with the name of the command in the `command` field
+#[derive(Debug, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case", deny_unknown_fields)]
+enum Command {
+    /// Reads the data file at `path` as configured in the settings, see [read_data_from]
+    LoadData { path: String },
+    /// Reads and validates the settings file at `path`, see [read_settings], and reads the loaded data file again as configured
+    SetSettings { path: String },
+    /// Fits the loaded data with the current settings, replacing the result of the last fit, which is discarded if the fit fails
+    Fit,
+    /// Returns the population model of the last fit, see [PopulationModel]
+    GetResult,
+    /// Predicts the observations of the loaded subjects, or only subject `id`, for the given support point
+    Simulate {
+        support_point: Vec<f64>,
+        id: Option<String>,
+    },
+    /// Stops the server
+    Exit,
+}
+
+/// State of a [serve] session
+struct Session<S>
+where
+    S: Predict<'static> + std::marker::Sync + std::marker::Send + 'static + Clone,
+{
+    /// Engine of the model, which is configured by the settings into `engine`
+    model: Engine<S>,
+    engine: Engine<S>,
+    settings: Option<Settings>,
+    /// Path of the data file of [Command::LoadData], which is read again if the settings change
+    data_path: Option<String>,
+    scenarios: Option<Vec<Scenario>>,
+    result: Option<NPResult>,
+}
+
+impl<S> Session<S>
+where
+    S: Predict<'static> + std::marker::Sync + std::marker::Send + 'static + Clone,
+{
+    /// Reads the data file at `path`, handling observations on dose rows and observation windows as in the settings, if any
+    fn read_data(&self, path: &str) -> Result<Vec<Scenario>> {
+        let scenarios = match &self.settings {
+            Some(settings) => read_data_from(path, settings)?,
+            None => datafile::parse(path)?,
+        };
+        datafile::check_scenarios(&scenarios).map_err(|e| eyre::eyre!(e))?;
+        Ok(scenarios)
+    }
+
+    fn handle(&mut self, command: Command) -> Result<Value> {
+        match command {
+            Command::LoadData { path } => {
+                let scenarios = self.read_data(&path)?;
+                let response = json!({
+                    "subjects": scenarios.len(),
+                    "observations": scenarios.iter().map(|s| s.obs.len()).sum::<usize>(),
+                });
+                self.data_path = Some(path);
+                self.scenarios = Some(scenarios);
+                Ok(response)
+            }
+            Command::SetSettings { path } => {
+                let mut settings = read_settings(path)?;
+                // Standard output is reserved for the responses
+                settings.config.tui = false;
+                // Configured from the engine of the model, so volume outputs of earlier settings do not persist
+                self.engine = self
+                    .model
+                    .clone()
+                    .configure(&settings)
+                    .map_err(|e| eyre::eyre!(e))?;
+                let response = json!({ "parameters": settings.random.names() });
+                self.settings = Some(settings);
+                if let Some(path) = &self.data_path {
+                    self.scenarios = Some(self.read_data(path)?);
+                }
+                Ok(response)
+            }
+            Command::Fit => {
+                let settings = self
+                    .settings
+                    .clone()
+                    .ok_or_else(|| eyre::eyre!("No settings, use set_settings first"))?;
+                let scenarios = self
+                    .scenarios
+                    .clone()
+                    .ok_or_else(|| eyre::eyre!("No data, use load_data first"))?;
+                self.result = None;
+                // The cache is keyed by the subject IDs, which may refer to other subjects after loading other data
+                predict::clear_cache();
+                let (tx, _rx) = mpsc::unbounded_channel();
+                let mut algorithm =
+                    initialize_algorithm(self.engine.clone(), settings, scenarios, tx)?;
+                // A panic in the fit must not stop the server
+                let result = std::panic::catch_unwind(AssertUnwindSafe(|| algorithm.fit()))
+                    .map_err(|panic| {
+                        let message = panic
+                            .downcast_ref::<&str>()
+                            .map(|message| message.to_string())
+                            .or_else(|| panic.downcast_ref::<String>().cloned())
+                            .unwrap_or_default();
+                        eyre::eyre!("The fit panicked: {}", message)
+                    })?;
+                if result.stop_reason() == Some(StopReason::Error) {
+                    let message = result
+                        .warnings
+                        .last()
+                        .map(|warning| warning.message.clone())
+                        .unwrap_or_else(|| StopReason::Error.to_string());
+                    eyre::bail!("The fit failed: {}", message);
+                }
+                let response = json!({
+                    "objf": result.objf,
+                    "converged": result.converged,
+                    "cycles": result.cycles,
+                    "nspp": result.theta.nrows(),
+                });
+                self.result = Some(result);
+                Ok(response)
+            }
+            Command::GetResult => {
+                let result = self
+                    .result
+                    .as_ref()
+                    .ok_or_else(|| eyre::eyre!("No result, use fit first"))?;
+                Ok(serde_json::to_value(PopulationModel::from(result))?)
+            }
+            Command::Simulate { support_point, id } => {
+                let scenarios = self
+                    .scenarios
+                    .as_ref()
+                    .ok_or_else(|| eyre::eyre!("No data, use load_data first"))?;
+                let selected: Vec<&Scenario> = scenarios
+                    .iter()
+                    .filter(|scenario| id.as_ref().is_none_or(|id| &scenario.id == id))
+                    .collect();
+                if let (Some(id), true) = (&id, selected.is_empty()) {
+                    eyre::bail!("Subject {} is not in the data", id);
+                }
+                let predictions: Vec<Value> = selected
+                    .into_iter()
+                    .map(|scenario| {
+                        json!({
+                            "id": scenario.id,
+                            "times": scenario.obs_times,
+                            "predictions": self.engine.pred(scenario.clone(), support_point.clone()),
+                        })
+                    })
+                    .collect();
+                Ok(Value::Array(predictions))
+            }
+            Command::Exit => Ok(Value::Null),
+        }
+    }
+}
+
+/// Runs NPcore as a server, reading one JSON command per line from `input` and writing one JSON response per line to `output`
+///
+/// Commands are objects with the name of the command in the `command` field, and its arguments in the other fields:
+/// - `{"command": "set_settings", "path": "config.toml"}` reads the settings, with the TUI disabled
+/// - `{"command": "load_data", "path": "data.csv"}` reads the data, as configured in the settings if they are set, see [read_data_from]
+/// - `{"command": "fit"}` fits the data, and returns the objective function, convergence, number of cycles and number of support points, or fails if the run fails
+/// - `{"command": "get_result"}` returns the population model of the last fit, as written by [NPResult::export_population_model]
+/// - `{"command": "simulate", "support_point": [0.1, 10.0], "id": "1"}` returns the predictions at the observation times, for all subjects if `id` is omitted
+/// - `{"command": "exit"}` stops the server, as does the end of `input`
+///
+/// Responses are `{"ok": true, "result": ...}`, or `{"ok": false, "error": "..."}` if the command failed, in which case the server keeps running.
+/// Logging is not set up, as standard output is usually reserved for the responses.
+pub fn serve<S, R, W>(engine: Engine<S>, input: R, mut output: W) -> Result<()>
+where
+    S: Predict<'static> + std::marker::Sync + std::marker::Send + 'static + Clone,
+    R: BufRead,
+    W: Write,
+{
+    let mut session = Session {
+        model: engine.clone(),
+        engine,
+        settings: None,
+        data_path: None,
+        scenarios: None,
+        result: None,
+    };
+    for line in input.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let command: Result<Command> = serde_json::from_str(&line).map_err(|e| e.into());
+        let exit = matches!(command, Ok(Command::Exit));
+        let response = match command.and_then(|command| session.handle(command)) {
+            Ok(result) => json!({ "ok": true, "result": result }),
+            Err(e) => json!({ "ok": false, "error": e.to_string() }),
+        };
+        writeln!(output, "{}", response)?;
+        output.flush()?;
+        if exit {
+            break;
+        }
+    }
+    Ok(())
+}
@@ -1,5 +1,114 @@
 #[cfg(test)]
 use crate::prelude::*;
+#[cfg(test)]
+use datafile::{CovLine, Event, Infusion, Scenario};
+#[cfg(test)]
+use predict::{Engine, Predict};
+#[cfg(test)]
+use std::collections::HashMap;
+
+/// Analytical one-compartment model with first-order elimination, used for testing
+///
+/// The parameters are `ke`, `v` and, optionally, the amount in the compartment at time zero.
+#[cfg(test)]
+#[derive(Debug, Clone)]
+struct OneCompartment {}
+
+#[cfg(test)]
+#[derive(Debug, Clone)]
+struct OneCompartmentSystem {
+    ke: f64,
+    v: f64,
+    a0: f64,
+}
+
+#[cfg(test)]
+impl<'a> Predict<'a> for OneCompartment {
+    type Model = OneCompartmentSystem;
+    type State = f64;
+    fn initial_system(&self, params: &Vec<f64>, scenario: Scenario) -> (Self::Model, Scenario) {
+        let system = OneCompartmentSystem {
+            ke: params[0],
+            v: params[1],
+            a0: params.get(2).copied().unwrap_or(0.0),
+        };
+        (system, scenario)
+    }
+    fn initial_state(&self) -> Self::State {
+        0.0
+    }
+    fn initial_amounts(&self, system: &Self::Model) -> Vec<(usize, f64)> {
+        vec![(0, system.a0)]
+    }
+    fn add_covs(&self, _system: &mut Self::Model, _cov: Option<HashMap<String, CovLine>>) {}
+    fn add_infusion(&self, _system: &mut Self::Model, _infusion: Infusion) {}
+    fn add_dose(&self, state: &mut Self::State, dose: f64, _compartment: usize) {
+        *state += dose;
+    }
+    fn get_output(
+        &self,
+        _time: f64,
+        state: &Self::State,
+        system: &Self::Model,
+        _outeq: usize,
+    ) -> f64 {
+        state / system.v
+    }
+    fn state_step(&self, state: &mut Self::State, system: &Self::Model, time: f64, next_time: f64) {
+        *state *= (-system.ke * (next_time - time)).exp();
+    }
+}
+
+/// Settings from `src/tests/config.toml`, with the given random parameters
+#[cfg(test)]
+fn test_settings(random: &[(&str, (f64, f64))]) -> settings::Settings {
+    let config = std::fs::read_to_string("src/tests/config.toml").unwrap();
+    let mut settings: settings::Settings = toml::from_str(&config).unwrap();
+    settings.random.parameters = random
+        .iter()
+        .map(|(name, range)| (name.to_string(), *range))
+        .collect();
+    settings
+}
+
+/// Creates a scenario with bolus doses into the first compartment, given as `(time, amount)`, and observations at `obs_times`
+#[cfg(test)]
+fn test_scenario(id: &str, doses: &[(f64, f64)], obs_times: &[f64]) -> Scenario {
+    let event = |evid: isize, time: f64| Event {
+        id: id.to_string(),
+        evid,
+        time,
+        dur: None,
+        dose: None,
+        addl: None,
+        ii: None,
+        input: None,
+        out: None,
+        interval: None,
+        outeq: None,
+        _c0: None,
+        _c1: None,
+        _c2: None,
+        _c3: None,
+        covs: HashMap::new(),
+    };
+    let mut events: Vec<Event> = doses
+        .iter()
+        .map(|&(time, amount)| Event {
+            dose: Some(amount),
+            input: Some(1),
+            ..event(1, time)
+        })
+        .chain(obs_times.iter().map(|&time| Event {
+            out: Some(0.0),
+            interval: None,
+            outeq: Some(1),
+            ..event(0, time)
+        }))
+        .collect();
+    events.sort_by(|a, b| a.cmp_by_id_then_time(b));
+    Scenario::new(events).unwrap()
+}
 
 #[test]
 fn basic_sobol() {
@@ -46,3 +155,5104 @@ fn read_test_datafile() {
         //TODO: Uncomment this
     }
 }
+
+#[test]
+fn initial_amounts_set_baseline() {
+    let engine = Engine::new(OneCompartment {});
+    let scenario = test_scenario("1", &[], &[0.0, 1.0, 2.0]);
+    let (ke, v, a0) = (0.5, 10.0, 100.0);
+
+    let pred = engine.pred(scenario.clone(), vec![ke, v, a0]);
+    assert_eq!(pred[0], a0 / v);
+    for (y, t) in pred.iter().zip(&scenario.obs_times) {
+        assert!((y - a0 / v * (-ke * t).exp()).abs() < 1e-12);
+    }
+
+    // Without a baseline, the compartment starts empty
+    let pred = engine.pred(scenario, vec![ke, v]);
+    assert!(pred.iter().all(|y| *y == 0.0));
+}
+
+#[test]
+fn eta_formatting() {
+    use crate::tui::components::format_eta;
+    use std::time::Duration;
+
+    // No cycles completed yet
+    assert_eq!(
+        format_eta(0, 100, Duration::from_secs(5), false),
+        "Estimating..."
+    );
+    // 2 s per cycle, 90 cycles left
+    assert_eq!(format_eta(10, 100, Duration::from_secs(20), false), "3m");
+    // 0.5 s per cycle, 40 cycles left
+    assert_eq!(format_eta(10, 50, Duration::from_secs(5), false), "20s");
+    // 1 min per cycle, 100 cycles left
+    assert_eq!(format_eta(2, 102, Duration::from_secs(120), false), "1h");
+    // Maximum reached or run finished
+    assert_eq!(format_eta(100, 100, Duration::from_secs(20), false), "0s");
+    assert_eq!(format_eta(10, 100, Duration::from_secs(20), true), "0s");
+}
+
+#[test]
+fn wls_seeds_near_known_parameters() {
+    let engine = Engine::new(OneCompartment {});
+    let known = [(0.1, 20.0), (0.3, 10.0), (0.05, 40.0)];
+    let obs_times = [0.5, 1.0, 2.0, 4.0, 8.0, 12.0];
+    let scenarios: Vec<Scenario> = known
+        .iter()
+        .enumerate()
+        .map(|(i, &(ke, v))| {
+            let mut scenario = test_scenario(&i.to_string(), &[(0.0, 100.0)], &obs_times);
+            scenario.obs = engine.pred(scenario.clone(), vec![ke, v]);
+            scenario
+        })
+        .collect();
+    let ranges = vec![(0.01, 1.0), (1.0, 50.0)];
+
+    let seeds = initialization::seed_from_wls(&engine, &scenarios, &ranges);
+    assert_eq!(seeds.nrows(), known.len());
+    for (seed, (ke, v)) in seeds.rows().into_iter().zip(known) {
+        for (val, (min, max)) in seed.iter().zip(&ranges) {
+            assert!(val >= min && val <= max);
+        }
+        assert!((seed[0] - ke).abs() / ke < 0.05);
+        assert!((seed[1] - v).abs() / v < 0.05);
+    }
+}
+
+#[test]
+fn frozen_point_survives_condensation() {
+    use condensation::frozen::FrozenPoints;
+    use ndarray::{array, Axis};
+
+    let frozen = FrozenPoints::new(vec![vec![0.5, 50.0]]);
+    let mut theta = array![[0.1, 10.0], [0.2, 20.0]];
+    frozen.insert_missing(&mut theta);
+    assert_eq!(theta.nrows(), 3);
+
+    // The frozen point has a negligible weight, and would be dropped by the lambda filter
+    let lambda = array![0.5, 0.5, 1e-12];
+    let max_lambda = lambda.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+    let mut keep: Vec<usize> = (0..lambda.len())
+        .filter(|&i| lambda[i] > max_lambda / 1000.0)
+        .collect();
+    assert_eq!(keep, vec![0, 1]);
+
+    frozen.protect(&theta, &mut keep);
+    let theta = theta.select(Axis(0), &keep);
+    assert_eq!(theta.nrows(), 3);
+    assert!(frozen.contains(theta.row(2)));
+
+    // Grid expansion does not move existing points
+    let ranges = vec![(0.0, 1.0), (0.0, 100.0)];
+    let mut expanded = theta.clone();
+    adaptative_grid::adaptative_grid(&mut expanded, 0.1, &ranges, 1e-4);
+    frozen.insert_missing(&mut expanded);
+    assert!(expanded.rows().into_iter().any(|spp| frozen.contains(spp)));
+}
+
+#[test]
+fn expansion_concentrates_in_high_variance_dimensions() {
+    use adaptative_grid::{adaptative_grid, adaptative_grid_in, sensitive_dimensions};
+    use ndarray::array;
+
+    // The first parameter is spread over its range, while the second and third are well determined
+    let ranges = vec![(0.0, 1.0), (0.0, 100.0), (0.0, 10.0)];
+    let theta = array![[0.1, 50.0, 5.0], [0.5, 51.0, 5.0], [0.9, 49.0, 5.1]];
+    let w = array![0.3, 0.4, 0.3];
+    assert_eq!(sensitive_dimensions(&theta, &w, &ranges, 1), vec![0]);
+    assert_eq!(sensitive_dimensions(&theta, &w, &ranges, 2), vec![0, 1]);
+    assert_eq!(sensitive_dimensions(&theta, &w, &ranges, 5), vec![0, 1, 2]);
+
+    let mut expanded = theta.clone();
+    adaptative_grid_in(&mut expanded, 0.05, &ranges, 1e-4, &[0]);
+    let mut uniform = theta.clone();
+    adaptative_grid(&mut uniform, 0.05, &ranges, 1e-4);
+    assert_eq!(expanded.nrows(), 3 + 6);
+    assert_eq!(uniform.nrows(), 3 + 3 * 6);
+    // Every new support point differs from an existing one in the first parameter only
+    for new in expanded.rows().into_iter().skip(3) {
+        assert!(theta
+            .rows()
+            .into_iter()
+            .any(|old| old[1] == new[1] && old[2] == new[2] && old[0] != new[0]));
+    }
+
+    let (engine, mut settings, scenarios) = short_fit_setup(3);
+    settings.config.expansion_dimensions = Some(1);
+    let (tx, _rx) = tokio::sync::mpsc::unbounded_channel();
+    let result = algorithms::initialize_algorithm(engine, settings, scenarios, tx)
+        .unwrap()
+        .fit();
+    assert!(result.objf.is_finite());
+    // Each support point is expanded along a single parameter, into at most two new support points
+    assert!(result
+        .cycle_log()
+        .windows(2)
+        .all(|pair| pair[1].added <= 2 * pair[0].nspp));
+}
+
+#[test]
+fn grid_is_limited_to_max_grid_size() {
+    let ranges = vec![(0.0, 1.0), (0.0, 100.0)];
+    let mut theta = ndarray::array![[0.5, 50.0], [0.2, 20.0], [0.8, 80.0]];
+    let mut eps = 0.2;
+    for _ in 0..5 {
+        let existing = theta.clone();
+        adaptative_grid::adaptative_grid(&mut theta, eps, &ranges, 1e-4);
+        let expanded = theta.nrows();
+        assert!(expanded > 10);
+        assert_eq!(
+            adaptative_grid::limit(&mut theta, existing.nrows(), 10, 42),
+            expanded - 10
+        );
+        assert_eq!(theta.nrows(), 10);
+        assert_eq!(theta.slice(ndarray::s![..existing.nrows(), ..]), existing);
+        eps /= 2.0;
+    }
+
+    // Existing support points are kept, even if they exceed the limit
+    let existing = theta.nrows();
+    adaptative_grid::adaptative_grid(&mut theta, eps, &ranges, 1e-4);
+    adaptative_grid::limit(&mut theta, existing, 5, 42);
+    assert_eq!(theta.nrows(), existing);
+
+    // The kept candidates are subsampled uniformly, reproducibly for a given seed
+    let existing = ndarray::array![[0.5, 50.0], [0.25, 25.0]];
+    let candidates =
+        ndarray::Array2::from_shape_fn((100, 2), |(i, j)| (i as f64 + 0.5) / 100.0 * ranges[j].1);
+    let grid = ndarray::concatenate![ndarray::Axis(0), existing, candidates];
+    let limited = |seed| {
+        let mut theta = grid.clone();
+        adaptative_grid::limit(&mut theta, existing.nrows(), 12, seed);
+        theta
+    };
+    let theta = limited(42);
+    assert_eq!(theta.slice(ndarray::s![..existing.nrows(), ..]), existing);
+    let kept = theta.slice(ndarray::s![existing.nrows().., 0]);
+    assert!(kept
+        .iter()
+        .all(|x| candidates.column(0).iter().any(|y| y == x)));
+    assert!(kept.iter().any(|&x| x < 0.3) && kept.iter().any(|&x| x > 0.7));
+    assert_eq!(limited(42), theta);
+    assert_ne!(limited(43), theta);
+
+    // After the initial grid, the expanded grid of each cycle stays within the maximum
+    let (engine, mut settings, scenarios) = short_fit_setup(5);
+    settings.config.max_grid_size = Some(8);
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    let result = algorithms::initialize_algorithm(engine, settings, scenarios, tx)
+        .unwrap()
+        .fit();
+    assert!(result.objf.is_finite());
+    let mut cycles = 0;
+    while let Ok(msg) = rx.try_recv() {
+        if let Comm::NPCycle(cycle) = msg {
+            assert!(cycle.nspp <= 8);
+            if cycle.cycle > 1 {
+                assert!(cycle.nspp + cycle.removed <= 8);
+            }
+            cycles += 1;
+        }
+    }
+    assert_eq!(cycles, result.cycles);
+
+    // If the condensed grid already reaches the maximum, the expansion is skipped
+    let (engine, mut settings, scenarios) = short_fit_setup(3);
+    settings.config.max_grid_size = Some(1);
+    let (tx, _rx) = tokio::sync::mpsc::unbounded_channel();
+    let result = algorithms::initialize_algorithm(engine, settings, scenarios, tx)
+        .unwrap()
+        .fit();
+    assert!(result.cycle_log()[1..].iter().all(|cycle| cycle.added == 0));
+    assert!(result
+        .warnings
+        .iter()
+        .any(|warning| warning.message.contains("skipping the expansion")));
+
+    // Only NPAG uses the maximum grid size
+    let mut settings = test_settings(&[("ke", (0.05, 0.5))]);
+    settings.config.max_grid_size = Some(8);
+    assert!(settings.config.validate(&settings.random).is_ok());
+    settings.config.engine = "NPOD".to_string();
+    assert!(settings.config.validate(&settings.random).is_err());
+}
+
+/// Harmonic oscillator with frequency `omega`, integrated with [ode_solvers::Dopri5]
+#[cfg(test)]
+#[derive(Debug, Clone)]
+struct Oscillator {}
+
+#[cfg(test)]
+#[derive(Debug, Clone)]
+struct OscillatorSystem {
+    omega: f64,
+}
+
+#[cfg(test)]
+impl ode_solvers::System<ode_solvers::Vector2<f64>> for OscillatorSystem {
+    fn system(&self, _t: f64, y: &ode_solvers::Vector2<f64>, dy: &mut ode_solvers::Vector2<f64>) {
+        dy[0] = y[1];
+        dy[1] = -self.omega.powi(2) * y[0];
+    }
+}
+
+#[cfg(test)]
+impl<'a> Predict<'a> for Oscillator {
+    type Model = OscillatorSystem;
+    type State = ode_solvers::Vector2<f64>;
+    fn initial_system(&self, params: &Vec<f64>, scenario: Scenario) -> (Self::Model, Scenario) {
+        (OscillatorSystem { omega: params[0] }, scenario)
+    }
+    fn initial_state(&self) -> Self::State {
+        Self::State::new(1.0, 0.0)
+    }
+    fn add_covs(&self, _system: &mut Self::Model, _cov: Option<HashMap<String, CovLine>>) {}
+    fn add_infusion(&self, _system: &mut Self::Model, _infusion: Infusion) {}
+    fn add_dose(&self, state: &mut Self::State, dose: f64, compartment: usize) {
+        state[compartment] += dose;
+    }
+    fn get_output(
+        &self,
+        _time: f64,
+        state: &Self::State,
+        _system: &Self::Model,
+        _outeq: usize,
+    ) -> f64 {
+        state[0]
+    }
+    fn state_step(&self, state: &mut Self::State, system: &Self::Model, time: f64, next_time: f64) {
+        if time >= next_time {
+            return;
+        }
+        let mut stepper = ode_solvers::Dopri5::new(
+            system.clone(),
+            time,
+            next_time,
+            next_time - time,
+            *state,
+            1e-8,
+            1e-8,
+        );
+        if let Ok(stats) = stepper.integrate() {
+            simulation::stats::record(&stats);
+        }
+        *state = *stepper.y_out().last().unwrap();
+    }
+}
+
+#[test]
+fn solver_stats_are_collected() {
+    let engine = Engine::new(Oscillator {});
+    let scenarios = vec![
+        test_scenario("1", &[], &[0.0, 10.0, 20.0]),
+        test_scenario("2", &[], &[0.0, 5.0]),
+    ];
+    let theta = ndarray::array![[20.0]];
+
+    simulation::stats::enable(true);
+    let ypred = predict::sim_obs(&engine, &scenarios, &theta, false);
+    let stats = simulation::stats::take();
+    simulation::stats::enable(false);
+
+    // The oscillator is still integrated correctly
+    assert!((ypred[(0, 0)][1] - (20.0_f64 * 10.0).cos()).abs() < 1e-4);
+
+    assert_eq!(
+        stats.iter().map(|(i, _)| *i).collect::<Vec<_>>(),
+        vec![0, 1]
+    );
+    for (_, s) in &stats {
+        assert_eq!(s.steps, s.accepted + s.rejected);
+        assert!(s.accepted > 100);
+        assert!(s.evaluations > s.steps);
+    }
+    // The first subject is integrated over a longer time
+    assert!(stats[0].1.accepted > stats[1].1.accepted);
+}
+
+#[test]
+fn predict_between_observations() {
+    use ndarray::array;
+
+    let engine = Engine::new(OneCompartment {});
+    let settings = test_settings(&[("ke", (0.01, 1.0)), ("v", (1.0, 50.0))]);
+    let scenarios = vec![test_scenario("1", &[(0.0, 100.0)], &[1.0, 4.0])];
+    let theta = array![[0.1, 10.0], [0.3, 10.0]];
+    let w = array![0.5, 0.5];
+    let psi = array![[1.0, 1.0]];
+    let result = output::NPResult::new(scenarios, theta, psi, w, 0.0, 1, true, settings);
+
+    // The population mean is ke = 0.2, v = 10
+    let expected = |t: f64| 100.0 / 10.0 * (-0.2 * t).exp();
+    let pred = result.predict_at(&engine, "1", &[4.0, 2.5, 1.0]).unwrap();
+    for (y, t) in pred.iter().zip([4.0, 2.5, 1.0]) {
+        assert!((y - expected(t)).abs() < 1e-10);
+    }
+
+    // Extrapolation after the last observation is allowed
+    assert!(result.predict_at(&engine, "1", &[24.0]).is_ok());
+    assert!(result.predict_at(&engine, "1", &[-1.0]).is_err());
+    assert!(result.predict_at(&engine, "1", &[f64::NAN]).is_err());
+    assert!(result.predict_at(&engine, "2", &[1.0]).is_err());
+}
+
+/// Integrates `system` from `time` to `next_time` with [ode_solvers::Dopri5], using `tol` as both relative and absolute tolerance
+///
+/// Sparse output is used, such that the last output is at `next_time` rather than at the last multiple of a dense output step.
+#[cfg(test)]
+fn dopri5<const N: usize, F>(
+    system: F,
+    state: ode_solvers::SVector<f64, N>,
+    time: f64,
+    next_time: f64,
+    tol: f64,
+) -> ode_solvers::SVector<f64, N>
+where
+    F: ode_solvers::System<ode_solvers::SVector<f64, N>>,
+{
+    let mut stepper = ode_solvers::Dopri5::from_param(
+        system,
+        time,
+        next_time,
+        0.0,
+        state,
+        tol,
+        tol,
+        0.9,
+        0.04,
+        0.2,
+        10.0,
+        next_time - time,
+        0.0,
+        100000,
+        1000,
+        ode_solvers::dop_shared::OutputType::Sparse,
+    );
+    let _ = stepper.integrate();
+    *stepper.y_out().last().unwrap()
+}
+
+/// The one-compartment model with infusions from `examples/bimodal_ke`
+#[cfg(test)]
+#[derive(Debug, Clone)]
+struct BimodalKe {}
+
+#[cfg(test)]
+#[derive(Debug, Clone)]
+struct BimodalKeSystem {
+    ke: f64,
+    v: f64,
+    infusions: Vec<Infusion>,
+}
+
+#[cfg(test)]
+impl ode_solvers::System<ode_solvers::Vector1<f64>> for BimodalKeSystem {
+    fn system(&self, t: f64, y: &ode_solvers::Vector1<f64>, dy: &mut ode_solvers::Vector1<f64>) {
+        let mut rateiv = [0.0];
+        for infusion in &self.infusions {
+            if t >= infusion.time && t < (infusion.dur + infusion.time) {
+                rateiv[infusion.compartment] += infusion.amount / infusion.dur;
+            }
+        }
+        dy[0] = -self.ke * y[0] + rateiv[0];
+    }
+}
+
+#[cfg(test)]
+impl<'a> Predict<'a> for BimodalKe {
+    type Model = BimodalKeSystem;
+    type State = ode_solvers::Vector1<f64>;
+    fn initial_system(&self, params: &Vec<f64>, scenario: Scenario) -> (Self::Model, Scenario) {
+        let system = BimodalKeSystem {
+            ke: params[0],
+            v: params[1],
+            infusions: vec![],
+        };
+        (system, scenario.reorder_with_lag(vec![(0.0, 1)]))
+    }
+    fn initial_state(&self) -> Self::State {
+        Self::State::default()
+    }
+    fn add_covs(&self, _system: &mut Self::Model, _cov: Option<HashMap<String, CovLine>>) {}
+    fn add_infusion(&self, system: &mut Self::Model, infusion: Infusion) {
+        system.infusions.push(infusion);
+    }
+    fn add_dose(&self, state: &mut Self::State, dose: f64, compartment: usize) {
+        state[compartment] += dose;
+    }
+    fn get_output(
+        &self,
+        _time: f64,
+        state: &Self::State,
+        system: &Self::Model,
+        _outeq: usize,
+    ) -> f64 {
+        state[0] / system.v
+    }
+    fn state_step(&self, state: &mut Self::State, system: &Self::Model, time: f64, next_time: f64) {
+        if time >= next_time {
+            return;
+        }
+        *state = dopri5(system.clone(), *state, time, next_time, 1e-4);
+    }
+}
+
+#[test]
+fn cached_predictions_are_consistent() {
+    let engine = Engine::new(BimodalKe {});
+    let mut scenarios = datafile::parse(&"examples/data/bimodal_ke.csv".to_string()).unwrap();
+    scenarios.truncate(5);
+    let theta = sobol::generate(5, &vec![(0.001, 3.0), (25.0, 250.0)], 1348);
+
+    // Prime the cache, as in the first cycles of a run
+    predict::sim_obs(&engine, &scenarios, &theta, true);
+    assert!(predict::cache_is_consistent(&engine, &scenarios, &theta));
+
+    // Predictions cached for other data are detected
+    let mut other_scenarios = scenarios.clone();
+    for event in other_scenarios
+        .iter_mut()
+        .flat_map(|s| s.blocks.iter_mut())
+        .flat_map(|b| b.events.iter_mut())
+    {
+        event.dose = event.dose.map(|dose| 2.0 * dose);
+    }
+    assert!(!predict::cache_is_consistent(
+        &engine,
+        &other_scenarios,
+        &theta
+    ));
+}
+
+#[test]
+fn cli_arguments_override_settings() {
+    use crate::entrypoints::CliArgs;
+
+    let args = [
+        "config.toml",
+        "--resume",
+        "--output-dir",
+        "run1",
+        "--threads",
+        "4",
+        "--dry-run",
+    ];
+    let cli = CliArgs::parse(args.iter().map(|a| a.to_string())).unwrap();
+    assert_eq!(
+        cli,
+        CliArgs {
+            config: "config.toml".to_string(),
+            resume: true,
+            dry_run: true,
+            output_dir: Some("run1".to_string()),
+            threads: Some(4),
+            help: false,
+        }
+    );
+
+    let mut settings = test_settings(&[("ke", (0.01, 1.0))]);
+    cli.apply(&mut settings, std::path::Path::new("/launch"));
+    assert_eq!(settings.paths.data, "/launch/data.csv");
+    assert_eq!(settings.paths.prior, Some("theta.csv".to_string()));
+
+    // Without an output directory, paths are left as is
+    let cli = CliArgs::parse(["config.toml".to_string()]).unwrap();
+    let mut settings = test_settings(&[("ke", (0.01, 1.0))]);
+    cli.apply(&mut settings, std::path::Path::new("/launch"));
+    assert_eq!(settings.paths.data, "data.csv");
+    assert_eq!(settings.paths.prior, None);
+
+    for invalid in [
+        vec![],
+        vec!["a.toml", "b.toml"],
+        vec!["config.toml", "--threads", "zero"],
+        vec!["config.toml", "--output-dir"],
+        vec!["config.toml", "--verbose"],
+    ] {
+        assert!(CliArgs::parse(invalid.iter().map(|a| a.to_string())).is_err());
+    }
+}
+
+#[test]
+fn environment_overrides_settings() {
+    let vars = |vars: &[(&str, &str)]| {
+        vars.iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect::<Vec<_>>()
+    };
+    let settings = settings::parse_settings(
+        "src/tests/config.toml",
+        vars(&[
+            ("NPCORE_CONFIG_CYCLES", "500"),
+            ("NPCORE_CONFIG_INIT_POINTS", "77"),
+            ("NPCORE_CONFIG_TUI", "true"),
+            ("NPCORE_CONFIG_IDELTA", "0.5"),
+            ("NPCORE_CONFIG_ENGINE", "NPOD"),
+            ("NPCORE_PATHS_DATA", "other.csv"),
+            ("NPCORE_RANDOM_V", "10.0, 100.0"),
+            ("NPCORE_FIXED_KCP", "6.0"),
+            ("NPCORE_ERROR_POLY", "0.1,0.2,0.0,0.0"),
+            ("UNRELATED_CONFIG_CYCLES", "1"),
+        ]),
+    )
+    .unwrap();
+    assert_eq!(settings.config.cycles, 500);
+    assert_eq!(settings.config.init_points, 77);
+    assert!(settings.config.tui);
+    assert_eq!(settings.config.idelta, 0.5);
+    assert_eq!(settings.config.engine, "NPOD");
+    assert_eq!(settings.paths.data, "other.csv");
+    assert_eq!(settings.random.get("v"), Some(&(10.0, 100.0)));
+    assert_eq!(settings.random.names(), vec!["ka", "ke", "v"]);
+    // Matched to the parameter name in the file, instead of adding `kcp`
+    let fixed = settings.fixed.unwrap().parameters;
+    assert_eq!(fixed.get("KCP"), Some(&6.0));
+    assert_eq!(fixed.len(), 2);
+    assert_eq!(settings.error.poly, (0.1, 0.2, 0.0, 0.0));
+
+    // Without overrides, the file is used as is
+    let settings = settings::parse_settings("src/tests/config.toml", vec![]).unwrap();
+    assert_eq!(settings.config.cycles, 1024);
+    assert_eq!(settings.config.init_points, 500);
+
+    // Variables which do not name a section are ignored, while unknown keys are rejected
+    for var in ["NPCORE_TUI", "NPCORE_CONFIG_", "NPCORE_GIT_COMMIT"] {
+        let settings =
+            settings::parse_settings("src/tests/config.toml", vars(&[(var, "1")])).unwrap();
+        assert_eq!(settings.config.cycles, 1024);
+    }
+    assert!(settings::parse_settings(
+        "src/tests/config.toml",
+        vars(&[("NPCORE_CONFIG_UNKNOWN", "1")])
+    )
+    .is_err());
+
+    // Values with commas which are not lists of numbers are kept as strings
+    let settings = settings::parse_settings(
+        "src/tests/config.toml",
+        vars(&[("NPCORE_PATHS_DATA", "data,2024.csv")]),
+    )
+    .unwrap();
+    assert_eq!(settings.paths.data, "data,2024.csv");
+    assert!(settings::parse_settings(
+        "src/tests/config.toml",
+        vars(&[("NPCORE_RANDOM_V", "10.0,high")])
+    )
+    .is_err());
+}
+
+/// Two-compartment model with first-order absorption from a depot (compartment 1) into the central compartment (compartment 2)
+///
+/// The parameters are `ka` and `ke`, and the output equations are the amounts in the central compartment (1) and depot (2)
+#[cfg(test)]
+#[derive(Debug, Clone)]
+struct OralIv {}
+
+#[cfg(test)]
+#[derive(Debug, Clone)]
+struct OralIvSystem {
+    ka: f64,
+    ke: f64,
+    infusions: Vec<Infusion>,
+}
+
+#[cfg(test)]
+impl ode_solvers::System<ode_solvers::Vector2<f64>> for OralIvSystem {
+    fn system(&self, t: f64, y: &ode_solvers::Vector2<f64>, dy: &mut ode_solvers::Vector2<f64>) {
+        let mut rateiv = [0.0, 0.0];
+        for infusion in &self.infusions {
+            if t >= infusion.time && t < (infusion.dur + infusion.time) {
+                rateiv[infusion.compartment] += infusion.amount / infusion.dur;
+            }
+        }
+        dy[0] = -self.ka * y[0] + rateiv[0];
+        dy[1] = self.ka * y[0] - self.ke * y[1] + rateiv[1];
+    }
+}
+
+#[cfg(test)]
+impl<'a> Predict<'a> for OralIv {
+    type Model = OralIvSystem;
+    type State = ode_solvers::Vector2<f64>;
+    fn initial_system(&self, params: &Vec<f64>, scenario: Scenario) -> (Self::Model, Scenario) {
+        let system = OralIvSystem {
+            ka: params[0],
+            ke: params[1],
+            infusions: vec![],
+        };
+        (system, scenario)
+    }
+    fn initial_state(&self) -> Self::State {
+        Self::State::default()
+    }
+    fn n_compartments(&self) -> Option<usize> {
+        Some(2)
+    }
+    fn amounts(&self, state: &Self::State) -> Vec<f64> {
+        vec![state[0], state[1]]
+    }
+    fn add_covs(&self, _system: &mut Self::Model, _cov: Option<HashMap<String, CovLine>>) {}
+    fn add_infusion(&self, system: &mut Self::Model, infusion: Infusion) {
+        system.infusions.push(infusion);
+    }
+    fn add_dose(&self, state: &mut Self::State, dose: f64, compartment: usize) {
+        state[compartment] += dose;
+    }
+    fn get_output(
+        &self,
+        _time: f64,
+        state: &Self::State,
+        _system: &Self::Model,
+        outeq: usize,
+    ) -> f64 {
+        match outeq {
+            1 => state[1],
+            2 => state[0],
+            _ => panic!("Invalid output equation"),
+        }
+    }
+    fn state_step(&self, state: &mut Self::State, system: &Self::Model, time: f64, next_time: f64) {
+        if time >= next_time {
+            return;
+        }
+        *state = dopri5(system.clone(), *state, time, next_time, 1e-10);
+    }
+}
+
+#[test]
+fn oral_and_iv_doses_into_different_compartments() {
+    let engine = Engine::new(OralIv {});
+    let event = |evid: isize, time: f64| Event {
+        id: "1".to_string(),
+        evid,
+        time,
+        dur: None,
+        dose: None,
+        addl: None,
+        ii: None,
+        input: None,
+        out: None,
+        interval: None,
+        outeq: None,
+        _c0: None,
+        _c1: None,
+        _c2: None,
+        _c3: None,
+        covs: HashMap::new(),
+    };
+    let oral = Event {
+        dose: Some(100.0),
+        input: Some(1),
+        ..event(1, 0.0)
+    };
+    let iv = Event {
+        dose: Some(50.0),
+        dur: Some(1.0),
+        input: Some(2),
+        ..event(1, 0.0)
+    };
+    let obs = |time: f64, outeq: usize| Event {
+        out: Some(0.0),
+        interval: None,
+        outeq: Some(outeq),
+        ..event(0, time)
+    };
+    let scenario = Scenario::new(vec![oral.clone(), iv.clone(), obs(2.0, 1), obs(2.0, 2)]).unwrap();
+    assert!(engine.validate(&[scenario.clone()]).is_ok());
+
+    // Without elimination, the central compartment holds the infusion and the absorbed part of the oral dose
+    let ka = std::f64::consts::LN_2;
+    let pred = engine.pred(scenario, vec![ka, 0.0]);
+    assert!((pred[0] - (50.0 + 75.0)).abs() < 1e-6);
+    assert!((pred[1] - 25.0).abs() < 1e-6);
+
+    // Inputs outside the compartments of the model are rejected
+    for input in [0, 3] {
+        let invalid = Event {
+            input: Some(input),
+            ..iv.clone()
+        };
+        let scenario = Scenario::new(vec![oral.clone(), invalid, obs(2.0, 1)]).unwrap();
+        assert!(engine.validate(&[scenario.clone()]).is_err());
+
+        // The initialization returns the error, rather than exiting
+        let settings = test_settings(&[("ka", (0.1, 1.0)), ("ke", (0.0, 0.5))]);
+        let (tx, _rx) = tokio::sync::mpsc::unbounded_channel();
+        let err = algorithms::initialize_algorithm(engine.clone(), settings, vec![scenario], tx)
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("compartment"), "{}", err);
+    }
+}
+
+/// [OralIv], counting the number of integration steps over a non-empty interval
+#[cfg(test)]
+#[derive(Debug, Clone, Default)]
+struct CountingOralIv {
+    steps: std::sync::Arc<std::sync::atomic::AtomicUsize>,
+}
+
+#[cfg(test)]
+impl<'a> Predict<'a> for CountingOralIv {
+    type Model = OralIvSystem;
+    type State = ode_solvers::Vector2<f64>;
+    fn initial_system(&self, params: &Vec<f64>, scenario: Scenario) -> (Self::Model, Scenario) {
+        OralIv {}.initial_system(params, scenario)
+    }
+    fn initial_state(&self) -> Self::State {
+        OralIv {}.initial_state()
+    }
+    fn add_covs(&self, system: &mut Self::Model, cov: Option<HashMap<String, CovLine>>) {
+        OralIv {}.add_covs(system, cov)
+    }
+    fn add_infusion(&self, system: &mut Self::Model, infusion: Infusion) {
+        OralIv {}.add_infusion(system, infusion)
+    }
+    fn add_dose(&self, state: &mut Self::State, dose: f64, compartment: usize) {
+        OralIv {}.add_dose(state, dose, compartment)
+    }
+    fn get_output(
+        &self,
+        time: f64,
+        state: &Self::State,
+        system: &Self::Model,
+        outeq: usize,
+    ) -> f64 {
+        OralIv {}.get_output(time, state, system, outeq)
+    }
+    fn state_step(&self, state: &mut Self::State, system: &Self::Model, time: f64, next_time: f64) {
+        if time < next_time {
+            self.steps
+                .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        }
+        OralIv {}.state_step(state, system, time, next_time)
+    }
+}
+
+#[test]
+fn all_outputs_from_one_integration() {
+    let model = CountingOralIv::default();
+    let engine = Engine::new(model.clone());
+    let times = [0.5, 1.0, 2.0, 4.0];
+    let scenario_for = |outeqs: &[usize]| {
+        let mut events: Vec<Event> = test_scenario("1", &[(0.0, 100.0)], &[])
+            .blocks
+            .into_iter()
+            .flat_map(|block| block.events)
+            .collect();
+        let dose = events[0].clone();
+        for &time in &times {
+            for &outeq in outeqs {
+                events.push(Event {
+                    evid: 0,
+                    time,
+                    dose: None,
+                    input: None,
+                    out: Some(0.0),
+                    interval: None,
+                    outeq: Some(outeq),
+                    ..dose.clone()
+                });
+            }
+        }
+        Scenario::new(events).unwrap()
+    };
+    let params = vec![1.0, 0.2];
+
+    let steps = |f: &dyn Fn()| {
+        model.steps.store(0, std::sync::atomic::Ordering::Relaxed);
+        f();
+        model.steps.load(std::sync::atomic::Ordering::Relaxed)
+    };
+    let both = steps(&|| {
+        let _ = engine.pred(scenario_for(&[1, 2]), params.clone());
+    });
+    let single = steps(&|| {
+        let _ = engine.pred(scenario_for(&[1]), params.clone());
+    });
+    // Observations of both outputs are predicted with the integrations of a single output
+    assert_eq!(both, single);
+
+    let outputs = engine.pred(scenario_for(&[1, 2]), params.clone());
+    assert_eq!(outputs.len(), 2 * times.len());
+    let central = engine.pred(scenario_for(&[1]), params.clone());
+    let depot = engine.pred(scenario_for(&[2]), params.clone());
+    for (i, y) in outputs.chunks(2).enumerate() {
+        assert_eq!(y, &[central[i], depot[i]]);
+    }
+}
+
+#[test]
+fn compartment_amounts_conserve_mass() {
+    let engine = Engine::new(OralIv {});
+    let event = |evid: isize, time: f64| Event {
+        id: "1".to_string(),
+        evid,
+        time,
+        dur: None,
+        dose: None,
+        addl: None,
+        ii: None,
+        input: None,
+        out: None,
+        interval: None,
+        outeq: None,
+        _c0: None,
+        _c1: None,
+        _c2: None,
+        _c3: None,
+        covs: HashMap::new(),
+    };
+    let dose = Event {
+        dose: Some(100.0),
+        input: Some(1),
+        ..event(1, 0.0)
+    };
+    let obs = |outeq: usize| Event {
+        out: Some(0.0),
+        interval: None,
+        outeq: Some(outeq),
+        ..event(0, 12.0)
+    };
+    let scenario = Scenario::new(vec![dose, obs(1), obs(2)]).unwrap();
+
+    let (ka, ke) = (1.0, 0.2);
+    let trajectory = engine.simulate_amounts(&scenario, &vec![ka, ke], 0.5, 0.0);
+
+    // One row per time on the grid, even though both output equations are observed
+    assert_eq!(trajectory.len(), 24);
+    assert!(trajectory.windows(2).all(|w| w[0].0 < w[1].0));
+
+    // The total amount is the dose minus the amount eliminated from the central compartment
+    for (t, amounts) in &trajectory {
+        assert_eq!(amounts.len(), 2);
+        let auc =
+            100.0 * ka / (ka - ke) * ((1.0 - (-ke * t).exp()) / ke - (1.0 - (-ka * t).exp()) / ka);
+        let eliminated = ke * auc;
+        assert!((amounts[0] - 100.0 * (-ka * t).exp()).abs() < 1e-6);
+        assert!((amounts.iter().sum::<f64>() - (100.0 - eliminated)).abs() < 1e-6);
+    }
+}
+
+/// Standard normal samples, using the Box-Muller transform on a Sobol sequence
+#[cfg(test)]
+fn normal_samples(n: usize, seed: u32) -> Vec<f64> {
+    (0..n)
+        .map(|i| {
+            let u1 = sobol_burley::sample(i as u32, 0, seed) as f64;
+            let u2 = sobol_burley::sample(i as u32, 1, seed) as f64;
+            (-2.0 * u1.max(1e-12).ln()).sqrt() * (2.0 * std::f64::consts::PI * u2).cos()
+        })
+        .collect()
+}
+
+#[test]
+fn prediction_metrics_match_noise_level() {
+    use ndarray::array;
+
+    let engine = Engine::new(OneCompartment {});
+    let (ke, v, sd) = (0.2, 10.0, 0.5);
+    let obs_times = [0.5, 1.0, 2.0, 4.0, 6.0, 8.0];
+    let n_subjects = 100;
+    let noise = normal_samples(n_subjects * obs_times.len(), 1358);
+    let scenarios: Vec<Scenario> = (0..n_subjects)
+        .map(|i| {
+            let mut scenario = test_scenario(&i.to_string(), &[(0.0, 100.0)], &obs_times);
+            let pred = engine.pred(scenario.clone(), vec![ke, v]);
+            scenario.obs = pred
+                .iter()
+                .zip(&noise[i * obs_times.len()..])
+                .map(|(y, e)| y + sd * e)
+                .collect();
+            scenario
+        })
+        .collect();
+    let settings = test_settings(&[("ke", (0.01, 1.0)), ("v", (1.0, 50.0))]);
+    let psi = ndarray::Array2::ones((n_subjects, 1));
+    let result = output::NPResult::new(
+        scenarios,
+        array![[ke, v]],
+        psi,
+        array![1.0],
+        0.0,
+        1,
+        true,
+        settings,
+    );
+
+    let metrics = result.prediction_metrics(&engine);
+    assert_eq!(metrics.n, n_subjects * obs_times.len());
+    assert!((metrics.rmse - sd).abs() / sd < 0.1);
+    assert!((metrics.mae - sd * (2.0 / std::f64::consts::PI).sqrt()).abs() / sd < 0.1);
+    assert!(metrics.bias.abs() < 0.1 * sd);
+}
+
+#[test]
+fn batched_psi_matches_unbatched() {
+    use sigma::{ErrorPoly, ErrorType};
+
+    let engine = Engine::new(OneCompartment {});
+    let obs_times = [1.0, 2.0, 4.0];
+    let noise = normal_samples(7 * obs_times.len(), 1359);
+    let scenarios: Vec<Scenario> = (0..7)
+        .map(|i| {
+            let mut scenario = test_scenario(&i.to_string(), &[(0.0, 100.0)], &obs_times);
+            scenario.obs = engine
+                .pred(scenario.clone(), vec![0.2, 10.0])
+                .iter()
+                .zip(&noise[i * obs_times.len()..])
+                .map(|(y, e)| y + 0.5 * e)
+                .collect();
+            scenario
+        })
+        .collect();
+    let theta = sobol::generate(20, &vec![(0.01, 1.0), (1.0, 50.0)], 1359);
+    let sigma = ErrorPoly::new((0.1, 0.1, 0.0, 0.0), 0.5, &ErrorType::Add);
+    let other_sigma = ErrorPoly::new((0.1, 0.1, 0.0, 0.0), 1.0, &ErrorType::Add);
+
+    let sigmas = [&sigma, &other_sigma];
+    let unbatched = prob::simulate_psi(&engine, &scenarios, &theta, &sigmas, false, None);
+    assert_eq!(unbatched[0].dim(), (7, 20));
+    assert_eq!(
+        unbatched[0],
+        prob::calculate_psi(
+            &predict::sim_obs(&engine, &scenarios, &theta, false),
+            &scenarios,
+            &sigma
+        )
+    );
+    for batch_size in [1, 3, 7, 100] {
+        let batched = prob::simulate_psi(
+            &engine,
+            &scenarios,
+            &theta,
+            &sigmas,
+            false,
+            Some(batch_size),
+        );
+        assert_eq!(batched, unbatched);
+    }
+}
+
+#[test]
+fn correlated_samples_match_requested_correlation() {
+    use parametric::Marginal;
+
+    let mut settings =
+        test_settings(&[("cl", (0.0, 10.0)), ("ke", (0.0, 1.0)), ("v", (0.0, 100.0))]);
+    let requested = [[1.0, 0.7, -0.3], [0.7, 1.0, 0.0], [-0.3, 0.0, 1.0]];
+    let simulation = settings::Simulation {
+        n_samples: 20_000,
+        marginals: HashMap::from([
+            ("cl".to_string(), Marginal::Normal { mean: 5.0, sd: 1.0 }),
+            (
+                "ke".to_string(),
+                Marginal::Lognormal {
+                    mu: -2.0,
+                    sigma: 0.3,
+                },
+            ),
+            (
+                "v".to_string(),
+                Marginal::Normal {
+                    mean: 50.0,
+                    sd: 10.0,
+                },
+            ),
+        ]),
+        correlation: Some(requested.iter().map(|row| row.to_vec()).collect()),
+    };
+    simulation.validate(&settings.random).unwrap();
+    let samples = simulation.sample(&settings.random, 1446).unwrap();
+    assert_eq!(samples.dim(), (20_000, 3));
+    assert!(samples.column(1).iter().all(|ke| *ke > 0.0));
+    assert_eq!(samples, simulation.sample(&settings.random, 1446).unwrap());
+
+    // The correlations apply to the logarithm of log-normal parameters
+    let mut normal = samples.clone();
+    normal.column_mut(1).mapv_inplace(f64::ln);
+    let mean = normal.mean_axis(ndarray::Axis(0)).unwrap();
+    let centered = &normal - &mean;
+    let covariance = centered.t().dot(&centered) / (normal.nrows() - 1) as f64;
+    for i in 0..3 {
+        for j in 0..3 {
+            let correlation = covariance[(i, j)] / (covariance[(i, i)] * covariance[(j, j)]).sqrt();
+            assert!(
+                (correlation - requested[i][j]).abs() < 0.02,
+                "correlation {} between {} and {}, expected {}",
+                correlation,
+                i,
+                j,
+                requested[i][j]
+            );
+        }
+    }
+    assert!((mean[0] - 5.0).abs() < 0.05 && (mean[1] + 2.0).abs() < 0.01);
+
+    // The samples are truncated to the ranges, e.g. a normal volume is never negative
+    let marginals = [Marginal::Normal { mean: 0.5, sd: 1.0 }];
+    let identity = ndarray::Array2::eye(1);
+    let truncated =
+        parametric::sample_correlated(&marginals, &identity, &[(0.0, 10.0)], 20_000, 1).unwrap();
+    assert!(truncated.iter().all(|x| *x >= 0.0));
+    // The mean of the normal distribution truncated at zero, 0.5 + φ(0.5) / Φ(0.5)
+    let mean = truncated.mean().unwrap();
+    assert!((mean - 1.0092).abs() < 0.02, "{}", mean);
+    assert!(
+        parametric::sample_correlated(&marginals, &identity, &[(100.0, 101.0)], 10, 1).is_err()
+    );
+
+    // The correlation matrix must be a valid correlation matrix for the random parameters
+    let invalid = |correlation: Vec<Vec<f64>>| settings::Simulation {
+        correlation: Some(correlation),
+        ..simulation.clone()
+    };
+    let not_positive_definite = vec![
+        vec![1.0, 0.9, 0.9],
+        vec![0.9, 1.0, -0.9],
+        vec![0.9, -0.9, 1.0],
+    ];
+    assert!(invalid(not_positive_definite)
+        .validate(&settings.random)
+        .is_err());
+    assert!(invalid(vec![vec![1.0, 0.5], vec![0.5, 1.0]])
+        .validate(&settings.random)
+        .is_err());
+    let asymmetric = vec![
+        vec![1.0, 0.5, 0.0],
+        vec![0.4, 1.0, 0.0],
+        vec![0.0, 0.0, 1.0],
+    ];
+    assert!(invalid(asymmetric).validate(&settings.random).is_err());
+    settings
+        .random
+        .parameters
+        .insert("ka".to_string(), (0.0, 1.0));
+    assert!(simulation.validate(&settings.random).is_err());
+}
+
+#[test]
+fn sampled_residuals_match_error_model() {
+    use rand::{rngs::StdRng, SeedableRng};
+    use sigma::{sample_residual, ErrorPoly, ErrorType};
+
+    let c = (0.5, 0.1, 0.0, 0.0);
+    let additive = ErrorPoly::new(c, 2.0, &ErrorType::Add);
+    let proportional = ErrorPoly::new(c, 3.0, &ErrorType::Prop);
+    let mut rng = StdRng::seed_from_u64(1360);
+    let n = 20_000;
+    for pred in [1.0, 10.0, 100.0] {
+        let alpha: f64 = c.0 + c.1 * pred;
+        for (error_poly, expected_sd) in [
+            (&additive, (alpha.powi(2) + 4.0).sqrt()),
+            (&proportional, 3.0 * alpha),
+        ] {
+            let residuals: Vec<f64> = (0..n)
+                .map(|_| sample_residual(pred, error_poly, &mut rng))
+                .collect();
+            let mean = residuals.iter().sum::<f64>() / n as f64;
+            let var = residuals.iter().map(|r| (r - mean).powi(2)).sum::<f64>() / (n - 1) as f64;
+            assert!(mean.abs() < 0.05 * expected_sd, "mean {} at {}", mean, pred);
+            assert!(
+                (var.sqrt() / expected_sd - 1.0).abs() < 0.03,
+                "sd {} != {} at {}",
+                var.sqrt(),
+                expected_sd,
+                pred
+            );
+        }
+    }
+
+    let exact = ErrorPoly::new((0.0, 0.0, 0.0, 0.0), 0.0, &ErrorType::Prop);
+    assert_eq!(sample_residual(5.0, &exact, &mut rng), 0.0);
+}
+
+#[test]
+fn unused_parameter_is_flagged() {
+    // The fourth parameter is never read by the one-compartment model
+    let engine = Engine::new(OneCompartment {});
+    let scenarios = vec![
+        test_scenario("1", &[(0.0, 100.0)], &[1.0, 4.0, 12.0]),
+        test_scenario("2", &[(0.0, 50.0), (12.0, 50.0)], &[2.0, 14.0]),
+    ];
+    let ranges = [(0.05, 0.5), (5.0, 50.0), (0.0, 20.0), (1.0, 10.0)];
+    assert_eq!(engine.insensitive_parameters(&scenarios, &ranges), vec![3]);
+    assert!(engine
+        .insensitive_parameters(&scenarios, &ranges[..3])
+        .is_empty());
+    // An initial amount of at most 1e-12 changes the predictions only within the relative tolerance
+    let negligible = [(0.05, 0.5), (5.0, 50.0), (0.0, 1e-12)];
+    assert_eq!(
+        engine.insensitive_parameters(&scenarios, &negligible),
+        vec![2]
+    );
+}
+
+#[test]
+fn output_precision_is_applied() {
+    use output::{format_float, CycleWriter};
+
+    assert_eq!(format_float(0.123456, Some(3)), "0.123");
+    assert_eq!(format_float(123456.0, Some(2)), "120000");
+    assert_eq!(format_float(-2.0 / 3.0, Some(4)), "-0.6667");
+    assert_eq!(format_float(1.0, Some(5)), "1");
+    assert_eq!(format_float(2.0 / 3.0, None), (2.0_f64 / 3.0).to_string());
+    assert_eq!(format_float(f64::NAN, Some(3)), "NaN");
+
+    let path = std::env::temp_dir().join(format!("npcore_cycles_{}.csv", std::process::id()));
+    let mut writer = CycleWriter::new(
+        path.to_str().unwrap(),
+        vec!["ke".to_string()],
+        Some(3),
+        None,
+    );
+    let theta = ndarray::Array2::from_shape_vec((2, 1), vec![1.0 / 3.0, 2.0 / 3.0]).unwrap();
+    writer.write(1, 1234.5678, 0.987654, None, 3, 1, &theta);
+    writer.flush();
+    let written = std::fs::read_to_string(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    let row: Vec<&str> = written.lines().nth(1).unwrap().split(',').collect();
+    assert_eq!(
+        row,
+        ["1", "1230", "0.988", "2", "3", "1", "0.5", "0.5", "0.236"]
+    );
+}
+
+#[test]
+fn stop_criterion_requires_consecutive_cycles() {
+    use convergence::StopCriterion;
+
+    // The objective function dips by a lucky cycle, then keeps improving before it levels out
+    let objf = [
+        -500.0, -400.0, -350.0, -349.99999, -340.0, -335.0, -334.99999, -334.99998, -334.99998,
+    ];
+    let deltas: Vec<f64> = objf.windows(2).map(|w| w[1] - w[0]).collect();
+    let first_met = |required: usize| {
+        let mut criterion = StopCriterion::new(1e-4, required);
+        deltas.iter().position(|&delta| criterion.update(delta))
+    };
+    assert_eq!(first_met(1), Some(2));
+    assert_eq!(first_met(3), Some(7));
+    assert_eq!(first_met(4), None);
+}
+
+#[test]
+fn distribution_criterion_prevents_premature_convergence() {
+    use convergence::{Combine, StopCriterion};
+    use ndarray::array;
+
+    // The objective function is flat from the second cycle, while weight keeps moving to the second support point until the sixth cycle
+    let theta = array![[1.0, 10.0], [2.0, 20.0]];
+    let weights = [0.9, 0.8, 0.7, 0.6, 0.5, 0.4, 0.4, 0.4, 0.4];
+    let deltas = [50.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0];
+    let first_met = |mut criterion: StopCriterion| {
+        deltas.iter().zip(weights).position(|(&delta, weight)| {
+            criterion.update_with_distribution(delta, &theta, &array![weight, 1.0 - weight])
+        })
+    };
+    assert_eq!(first_met(StopCriterion::new(1e-4, 1)), Some(1));
+    assert_eq!(
+        first_met(StopCriterion::new(1e-4, 1).with_distribution(1e-3, Combine::And)),
+        Some(6)
+    );
+    assert_eq!(
+        first_met(StopCriterion::new(1e-4, 2).with_distribution(1e-3, Combine::And)),
+        Some(7)
+    );
+
+    // With OR, either criterion suffices, but the distribution cannot be stable in the first cycle
+    let deltas = [50.0, 50.0, 50.0];
+    let mut criterion = StopCriterion::new(1e-4, 1).with_distribution(1e-3, Combine::Or);
+    let stable = array![0.5, 0.5];
+    let met: Vec<bool> = deltas
+        .iter()
+        .map(|&delta| criterion.update_with_distribution(delta, &theta, &stable))
+        .collect();
+    assert_eq!(met, [false, true, true]);
+
+    assert_eq!(Combine::from_name("OR"), Ok(Combine::Or));
+    assert!(Combine::from_name("xor").is_err());
+
+    // Only NPAG uses the distribution criterion
+    let mut settings = test_settings(&[("ke", (0.05, 0.5))]);
+    settings.config.distribution_tolerance = Some(1e-3);
+    assert!(settings.config.validate(&settings.random).is_ok());
+    settings.config.engine = "NPOD".to_string();
+    assert!(settings.config.validate(&settings.random).is_err());
+}
+
+/// Settings and simulated one-compartment data for a short run of NPAG, see [short_fit]
+#[cfg(test)]
+fn short_fit_setup(cycles: usize) -> (Engine<OneCompartment>, settings::Settings, Vec<Scenario>) {
+    let engine = Engine::new(OneCompartment {});
+    let mut settings = test_settings(&[("ke", (0.05, 0.5)), ("v", (5.0, 50.0))]);
+    settings.config.cycles = cycles;
+    settings.config.init_points = 50;
+    settings.config.output = false;
+    settings.config.cache = false;
+    let scenarios: Vec<Scenario> = [(0.1, 10.0), (0.2, 20.0), (0.3, 30.0)]
+        .iter()
+        .enumerate()
+        .map(|(i, (ke, v))| {
+            let mut scenario = test_scenario(&i.to_string(), &[(0.0, 100.0)], &[1.0, 4.0, 8.0]);
+            scenario.obs = engine.pred(scenario.clone(), vec![*ke, *v]);
+            scenario
+        })
+        .collect();
+    (engine, settings, scenarios)
+}
+
+/// Runs NPAG for a few cycles on simulated one-compartment data, returning the result and the cycles sent over the channel
+#[cfg(test)]
+fn short_fit(cycles: usize) -> (output::NPResult, Vec<output::NPCycle>) {
+    let (engine, settings, scenarios) = short_fit_setup(cycles);
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    let result = algorithms::initialize_algorithm(engine, settings, scenarios, tx)
+        .unwrap()
+        .fit();
+
+    let mut sent = Vec::new();
+    while let Ok(msg) = rx.try_recv() {
+        if let Comm::NPCycle(cycle) = msg {
+            sent.push(cycle);
+        }
+    }
+    (result, sent)
+}
+
+#[test]
+fn cycle_log_matches_sent_cycles() {
+    let (result, sent) = short_fit(3);
+    let sent: Vec<output::CycleRecord> = sent.iter().map(output::CycleRecord::from).collect();
+    assert_eq!(sent.len(), 3);
+    assert_eq!(result.cycle_log(), sent.as_slice());
+    let last = result.cycle_log().last().unwrap();
+    assert_eq!(last.cycle, result.cycles);
+    assert_eq!(last.nspp, result.theta.nrows());
+
+    // NPOD also records the last cycle, which ends the run
+    let (engine, mut settings, scenarios) = short_fit_setup(3);
+    settings.config.engine = "NPOD".to_string();
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    let result = algorithms::initialize_algorithm(engine, settings, scenarios, tx)
+        .unwrap()
+        .fit();
+    let mut sent = Vec::new();
+    while let Ok(msg) = rx.try_recv() {
+        if let Comm::NPCycle(cycle) = msg {
+            sent.push(output::CycleRecord::from(&cycle));
+        }
+    }
+    assert!(!sent.is_empty());
+    assert_eq!(result.cycle_log(), sent.as_slice());
+    assert_eq!(result.cycle_log().last().unwrap().cycle, result.cycles);
+}
+
+#[test]
+fn cycle_log_counts_added_and_removed_points() {
+    let (result, _) = short_fit(4);
+    let records = result.cycle_log();
+    assert_eq!(records.len(), 4);
+    assert_eq!(records[0].added, 50);
+    let mut nspp = 0;
+    for record in records {
+        assert_eq!(record.nspp, nspp + record.added - record.removed);
+        nspp = record.nspp;
+    }
+    assert!(records[1..].iter().any(|record| record.added > 0));
+}
+
+#[test]
+fn typical_value_predictions_differ_from_mixture_for_nonlinear_models() {
+    use ndarray::array;
+
+    let engine = Engine::new(OneCompartment {});
+    let settings = test_settings(&[
+        ("ke", (0.05, 0.5)),
+        ("v", (5.0, 50.0)),
+        ("a0", (0.0, 100.0)),
+    ]);
+    let scenarios = vec![test_scenario("1", &[(0.0, 100.0)], &[1.0, 4.0, 8.0])];
+    let w = array![0.25, 0.75];
+    let mixture_mean = |theta: &ndarray::Array2<f64>| {
+        let pred = predict::sim_obs(&engine, &scenarios, theta, false);
+        &pred[(0, 0)] * w[0] + &pred[(0, 1)] * w[1]
+    };
+    let typical = |theta: ndarray::Array2<f64>| {
+        let psi = ndarray::Array2::ones((1, 2));
+        let result = output::NPResult::new(
+            scenarios.clone(),
+            theta,
+            psi,
+            w.clone(),
+            0.0,
+            1,
+            true,
+            settings.clone(),
+        );
+        result.typical_value_predictions(&engine, 0.0, 0.0)
+    };
+
+    // The predictions are linear in the initial amount, so the typical value is the mean of the mixture
+    let theta = array![[0.2, 10.0, 0.0], [0.2, 10.0, 40.0]];
+    let mixture = mixture_mean(&theta);
+    let pred = typical(theta);
+    assert_eq!(pred.len(), 1);
+    assert_eq!(
+        pred[0].iter().map(|(time, _)| *time).collect::<Vec<f64>>(),
+        [1.0, 4.0, 8.0]
+    );
+    for ((_, y), expected) in pred[0].iter().zip(mixture.iter()) {
+        assert!((y - expected).abs() < 1e-10, "{} != {}", y, expected);
+    }
+
+    // Predictions are convex in the elimination rate, so the typical value underestimates the mean of the mixture
+    let theta = array![[0.05, 10.0, 0.0], [0.5, 10.0, 0.0]];
+    let mixture = mixture_mean(&theta);
+    let pred = typical(theta);
+    for ((_, y), expected) in pred[0].iter().zip(mixture.iter()) {
+        assert!(*y < expected - 1e-3, "{} >= {}", y, expected);
+    }
+
+    // Intermediate predictions are added with idelta
+    let result = output::NPResult::new(
+        scenarios.clone(),
+        array![[0.2, 10.0, 0.0]],
+        ndarray::Array2::ones((1, 1)),
+        array![1.0],
+        0.0,
+        1,
+        true,
+        settings.clone(),
+    );
+    assert!(result.typical_value_predictions(&engine, 0.5, 0.0)[0].len() > 3);
+}
+
+#[test]
+fn recorded_messages_replay_in_order() {
+    use crate::tui::replay;
+
+    let (engine, settings, scenarios) = short_fit_setup(3);
+    let path = std::env::temp_dir().join(format!("npcore_messages_{}.jsonl", std::process::id()));
+    let path = path.to_str().unwrap();
+    let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+    let mut rx = replay::record_messages(rx, path).unwrap();
+    tx.send(Comm::Message("Starting".to_string())).unwrap();
+    let result = algorithms::initialize_algorithm(engine, settings, scenarios, tx.clone())
+        .unwrap()
+        .fit();
+    // Non-finite values, e.g. of a failed cycle, are recorded too
+    let failed = output::NPCycle {
+        cycle: result.cycles + 1,
+        objf: f64::INFINITY,
+        delta_objf: f64::NEG_INFINITY,
+        theta: ndarray::array![[0.1, f64::INFINITY]],
+        weights: ndarray::array![f64::NEG_INFINITY],
+        ..output::NPCycle::new()
+    };
+    tx.send(Comm::NPCycle(failed)).unwrap();
+    tx.send(Comm::StopUI).unwrap();
+    drop(tx);
+
+    // The receiver is closed once every message has been recorded and forwarded
+    let mut sent = Vec::new();
+    while let Some(comm) = rx.blocking_recv() {
+        sent.push(comm);
+    }
+    let cycles = sent
+        .iter()
+        .filter(|comm| matches!(comm, Comm::NPCycle(_)))
+        .count();
+    assert_eq!(cycles, result.cycles + 1);
+    assert_eq!(sent.first(), Some(&Comm::Message("Starting".to_string())));
+    assert_eq!(sent.last(), Some(&Comm::StopUI));
+
+    assert_eq!(replay::read_messages(path).unwrap(), sent);
+    let mut replayed = replay::replay_messages(path, std::time::Duration::ZERO).unwrap();
+    let mut received = Vec::new();
+    while let Some(comm) = replayed.blocking_recv() {
+        received.push(comm);
+    }
+    std::fs::remove_file(path).unwrap();
+    assert_eq!(received, sent);
+
+    let nan = Comm::NPCycle(output::NPCycle {
+        gamlam: f64::NAN,
+        weights: ndarray::array![f64::NAN, 1.0],
+        ..output::NPCycle::new()
+    });
+    let json = serde_json::to_string(&nan).unwrap();
+    match serde_json::from_str(&json).unwrap() {
+        Comm::NPCycle(cycle) => {
+            assert!(cycle.gamlam.is_nan());
+            assert!(cycle.weights[0].is_nan());
+            assert_eq!(cycle.weights[1], 1.0);
+        }
+        other => panic!("Expected a cycle, found {:?}", other),
+    }
+}
+
+#[test]
+fn shrinkage_is_higher_for_sparse_subjects() {
+    let fit = |obs_times: &[f64]| {
+        let (engine, mut settings, _) = short_fit_setup(50);
+        settings.config.init_points = 200;
+        settings.error.poly = (0.0, 0.3, 0.0, 0.0);
+        let scenarios: Vec<Scenario> = (0..12)
+            .map(|i| {
+                let ke = 0.1 + 0.025 * i as f64;
+                let v = 10.0 + 30.0 * ((i * 5) % 12) as f64 / 11.0;
+                let mut scenario = test_scenario(&i.to_string(), &[(0.0, 100.0)], obs_times);
+                scenario.obs = engine.pred(scenario.clone(), vec![ke, v]);
+                scenario
+            })
+            .collect();
+        let (tx, _rx) = tokio::sync::mpsc::unbounded_channel();
+        algorithms::initialize_algorithm(engine, settings, scenarios, tx)
+            .unwrap()
+            .fit()
+    };
+    let rich = fit(&[
+        0.5, 1.0, 2.0, 3.0, 4.0, 6.0, 8.0, 10.0, 12.0, 16.0, 20.0, 24.0,
+    ])
+    .shrinkage();
+    let sparse = fit(&[4.0]).shrinkage();
+    assert_eq!(rich.len(), 2);
+    for (rich, sparse) in rich.iter().zip(sparse.iter()) {
+        assert!((0.0..=1.0).contains(rich) && (0.0..=1.0).contains(sparse));
+        assert!(rich + 0.1 < *sparse, "rich {}, sparse {}", rich, sparse);
+    }
+}
+
+#[test]
+fn compare_results() {
+    let (result, _) = short_fit(2);
+    let report = result.compare(&result);
+    assert_eq!(
+        (report.delta_objf, report.delta_aic, report.delta_bic),
+        (0.0, 0.0, 0.0)
+    );
+    assert_eq!(report.nspp, (result.theta.nrows(), result.theta.nrows()));
+    assert_eq!(report.parameters.len(), 2);
+    for parameter in &report.parameters {
+        assert_eq!(parameter.mean.0, parameter.mean.1);
+        assert!((parameter.overlap - 1.0).abs() < 1e-12);
+    }
+
+    // A copy with a worse fit, the support point of least weight dropped, and ke shifted by more than a bin
+    let mut theta = result.theta.clone();
+    theta.column_mut(0).mapv_inplace(|ke| ke + 0.1);
+    let n = theta.nrows() - 1;
+    let lightest = (0..=n)
+        .min_by(|&a, &b| result.w[a].total_cmp(&result.w[b]))
+        .unwrap();
+    let kept: Vec<usize> = (0..=n).filter(|&i| i != lightest).collect();
+    let perturbed = output::NPResult::new(
+        result.scenarios.clone(),
+        theta.select(ndarray::Axis(0), &kept),
+        result.psi.clone(),
+        result.w.select(ndarray::Axis(0), &kept),
+        result.objf + 2.0,
+        result.cycles,
+        result.converged,
+        result.settings.clone(),
+    );
+    let report = result.compare(&perturbed);
+    assert_eq!(report.delta_objf, 2.0);
+    // Three free parameters less
+    assert!((report.delta_aic - (2.0 - 6.0)).abs() < 1e-9);
+    assert!((report.delta_bic - (2.0 - 3.0 * 9f64.ln())).abs() < 1e-9);
+    assert_eq!(report.nspp, (n + 1, n));
+    let ke = &report.parameters[0];
+    assert_eq!(ke.name, "ke");
+    assert!(ke.mean.1 > ke.mean.0 + 0.05);
+    assert!(ke.overlap < 1.0);
+    assert!(report.summary().contains("Support points"));
+}
+
+#[test]
+fn cycle_timings_cover_the_cycles() {
+    let (engine, mut settings, scenarios) = short_fit_setup(3);
+    settings.config.timing = true;
+    let (tx, _rx) = tokio::sync::mpsc::unbounded_channel();
+    let result = algorithms::initialize_algorithm(engine, settings, scenarios, tx)
+        .unwrap()
+        .fit();
+
+    assert_eq!(result.timings.len(), result.cycles);
+    for (i, timing) in result.timings.iter().enumerate() {
+        assert_eq!(timing.cycle, i + 1);
+        assert!(timing.simulation > std::time::Duration::ZERO);
+        assert!(timing.ipm > std::time::Duration::ZERO);
+        assert!(timing.stages() <= timing.total);
+    }
+    let stages: f64 = result
+        .timings
+        .iter()
+        .map(|t| t.stages().as_secs_f64())
+        .sum();
+    let total: f64 = result.timings.iter().map(|t| t.total.as_secs_f64()).sum();
+    assert!(stages > 0.5 * total);
+
+    // Without the setting, nothing is recorded
+    let (result, _) = short_fit(2);
+    assert!(result.timings.is_empty());
+}
+
+#[test]
+fn metadata_is_echoed_into_individual_estimates() {
+    let data = "\
+ID,EVID,TIME,DUR,DOSE,ADDL,II,INPUT,OUT,OUTEQ,C0,C1,C2,C3,WT,site
+1,1,0,0,100,.,.,1,.,.,.,.,.,.,70,Oslo
+1,0,1,.,.,.,.,.,9.05,1,.,.,.,.,70,Oslo
+1,0,4,.,.,.,.,.,6.70,1,.,.,.,.,70,Oslo
+2,1,0,0,100,.,.,1,.,.,.,.,.,.,.,Los Angeles
+2,0,1,.,.,.,.,.,4.09,1,.,.,.,.,.,Los Angeles
+2,0,4,.,.,.,.,.,2.25,1,.,.,.,.,.,Los Angeles
+";
+    let path = std::env::temp_dir().join(format!("npcore_metadata_{}.csv", std::process::id()));
+    std::fs::write(&path, data).unwrap();
+    let scenarios = datafile::parse(&path.to_str().unwrap().to_string()).unwrap();
+    assert_eq!(scenarios[0].metadata["site"], "Oslo");
+    assert_eq!(scenarios[1].metadata["site"], "Los Angeles");
+    assert_eq!(scenarios[0].metadata["WT"], "70");
+    // The site is not a covariate, but the weight is
+    let covs = &scenarios[0].blocks[0].events[0].covs;
+    assert!(!covs.contains_key("site"));
+    assert_eq!(covs["WT"], Some(70.0));
+    assert_eq!(
+        scenarios[0].add_event_interval(1.0, 0.0).metadata,
+        scenarios[0].metadata
+    );
+
+    let (engine, settings, _) = short_fit_setup(2);
+    let (tx, _rx) = tokio::sync::mpsc::unbounded_channel();
+    let result = algorithms::initialize_algorithm(engine, settings, scenarios, tx)
+        .unwrap()
+        .fit();
+    result
+        .write_individual_estimates(path.to_str().unwrap())
+        .unwrap();
+    let mut reader = csv::Reader::from_path(&path).unwrap();
+    let headers = reader.headers().unwrap().clone();
+    assert_eq!(
+        headers.iter().collect::<Vec<_>>(),
+        vec![
+            "id",
+            "ke_mean",
+            "v_mean",
+            "ke_median",
+            "v_median",
+            "WT",
+            "site"
+        ]
+    );
+    let rows: Vec<csv::StringRecord> = reader.records().map(|r| r.unwrap()).collect();
+    assert_eq!(rows.len(), 2);
+    assert_eq!((&rows[0][0], &rows[0][5], &rows[0][6]), ("1", "70", "Oslo"));
+    assert_eq!(
+        (&rows[1][0], &rows[1][5], &rows[1][6]),
+        ("2", ".", "Los Angeles")
+    );
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn predictions_extend_to_time_after_last_dose() {
+    let engine = Engine::new(OneCompartment {});
+    let scenario = test_scenario("1", &[(0.0, 100.0), (48.0, 100.0)], &[1.0, 2.0]);
+
+    let expanded = scenario.add_event_interval(5.0, 24.5);
+    assert_eq!(*expanded.obs_times.last().unwrap(), 72.5);
+    assert!(expanded.obs_times.contains(&70.0));
+    assert!(expanded.obs_times.windows(2).all(|w| w[0] < w[1]));
+    let pred = engine.pred(expanded.clone(), vec![0.1, 10.0]);
+    assert_eq!(pred.len(), expanded.obs_times.len());
+    let expected = 10.0 * ((-0.1 * 72.5f64).exp() + (-0.1 * 24.5f64).exp());
+    assert!((pred.last().unwrap() - expected).abs() < 1e-4);
+
+    // Without an interval, only the end time is added
+    let expanded = scenario.add_event_interval(0.0, 24.0);
+    assert_eq!(expanded.obs_times, vec![1.0, 2.0, 72.0]);
+
+    // A time after dose before the last event does not truncate the predictions
+    let expanded = scenario.add_event_interval(1.0, 0.0);
+    assert_eq!(*expanded.obs_times.last().unwrap(), 48.0);
+}
+
+#[test]
+fn output_equations_label_and_scale_outputs() {
+    let path = std::env::temp_dir().join(format!("npcore_outputs_{}.toml", std::process::id()));
+    let config = std::fs::read_to_string("src/tests/config.toml").unwrap();
+    std::fs::write(
+        &path,
+        format!(
+            "{}\n[[outputs]]\nouteq = 1\nname = \"conc\"\nunit = \"ng/mL\"\nscale = 1000.0\n",
+            config
+        ),
+    )
+    .unwrap();
+    let parsed = settings::parse_settings(path.to_str().unwrap(), vec![]).unwrap();
+    assert_eq!(parsed.outputs.len(), 1);
+    assert_eq!(
+        parsed.output_equation(1).unwrap().unit.as_deref(),
+        Some("ng/mL")
+    );
+    assert!(parsed.output_equation(2).is_none());
+
+    let mut duplicate = parsed.outputs.clone();
+    duplicate.push(settings::OutputEquation {
+        scale: 0.0,
+        ..duplicate[0].clone()
+    });
+    assert!(settings::OutputEquation::validate(&duplicate).is_err());
+
+    // The second observation of each subject is of output equation 2, which the model does not distinguish
+    let (engine, mut settings, mut scenarios) = short_fit_setup(2);
+    for scenario in &mut scenarios {
+        for event in scenario.blocks.iter_mut().flat_map(|b| b.events.iter_mut()) {
+            if event.evid == 0 && event.time == 4.0 {
+                event.outeq = Some(2);
+            }
+        }
+        assert_eq!(scenario.obs_outeqs(), vec![1, 2, 1]);
+    }
+    settings.outputs = parsed.outputs;
+    settings.outputs.push(settings::OutputEquation {
+        outeq: 2,
+        name: Some("metabolite".to_string()),
+        unit: Some("mg/L".to_string()),
+        scale: 1.0,
+        ..settings.outputs[0].clone()
+    });
+    let (tx, _rx) = tokio::sync::mpsc::unbounded_channel();
+    let result = algorithms::initialize_algorithm(engine.clone(), settings, scenarios.clone(), tx)
+        .unwrap()
+        .fit();
+    let csv_path = path.with_extension("csv");
+    result.write_obs_to(csv_path.to_str().unwrap()).unwrap();
+
+    let mut reader = csv::Reader::from_path(&csv_path).unwrap();
+    assert_eq!(
+        reader.headers().unwrap().iter().collect::<Vec<_>>(),
+        vec!["id", "time", "obs", "outeq", "name", "unit"]
+    );
+    let rows: Vec<csv::StringRecord> = reader.records().map(|r| r.unwrap()).collect();
+    assert_eq!(rows.len(), 9);
+    let obs: f64 = rows[0][2].parse().unwrap();
+    assert!((obs - 1000.0 * scenarios[0].obs[0]).abs() < 1e-6);
+    assert_eq!(
+        (&rows[0][3], &rows[0][4], &rows[0][5]),
+        ("1", "conc", "ng/mL")
+    );
+    let obs: f64 = rows[1][2].parse().unwrap();
+    assert!((obs - scenarios[0].obs[1]).abs() < 1e-9);
+    assert_eq!(
+        (&rows[1][3], &rows[1][4], &rows[1][5]),
+        ("2", "metabolite", "mg/L")
+    );
+
+    // The predictions are labelled and scaled by their own output equation as well
+    result
+        .write_pred_to(&engine, 0.0, 0.0, csv_path.to_str().unwrap())
+        .unwrap();
+    let mut reader = csv::Reader::from_path(&csv_path).unwrap();
+    let rows: Vec<csv::StringRecord> = reader.records().map(|r| r.unwrap()).collect();
+    assert_eq!(rows.len(), 9);
+    let outeqs: Vec<&str> = rows[..3].iter().map(|row| &row[2]).collect();
+    assert_eq!(outeqs, vec!["1", "2", "1"]);
+    assert_eq!((&rows[1][7], &rows[1][8]), ("metabolite", "mg/L"));
+    let (pred_1, pred_4): (f64, f64) = (rows[0][3].parse().unwrap(), rows[1][3].parse().unwrap());
+    // The prediction at 1 h is scaled by 1000, that at 4 h is not, and less than 10-fold lower unscaled
+    assert!(pred_1 / pred_4 > 100.0, "{} {}", pred_1, pred_4);
+
+    std::fs::remove_file(&path).unwrap();
+    std::fs::remove_file(&csv_path).unwrap();
+}
+
+#[test]
+fn interval_censored_observations() {
+    assert_eq!(datafile::parse_out("2.5"), Ok((Some(2.5), None)));
+    assert_eq!(datafile::parse_out("."), Ok((None, None)));
+    assert_eq!(
+        datafile::parse_out("2..5"),
+        Ok((Some(3.5), Some((2.0, 5.0))))
+    );
+    assert_eq!(
+        datafile::parse_out("..0.5"),
+        Ok((Some(0.5), Some((f64::NEG_INFINITY, 0.5))))
+    );
+    assert_eq!(
+        datafile::parse_out("10.."),
+        Ok((Some(10.0), Some((10.0, f64::INFINITY))))
+    );
+    for invalid in ["5..2", "..", "a..2"] {
+        assert!(datafile::parse_out(invalid).is_err(), "{}", invalid);
+    }
+
+    let data = "\
+ID,EVID,TIME,DUR,DOSE,ADDL,II,INPUT,OUT,OUTEQ,C0,C1,C2,C3
+1,1,0,0,100,.,.,1,.,.,.,.,.,.
+1,0,1,.,.,.,.,.,9.05,1,.,.,.,.
+1,0,4,.,.,.,.,.,2..5,1,.,.,.,.
+1,0,24,.,.,.,.,.,..0.5,1,.,.,.,.
+";
+    let path = std::env::temp_dir().join(format!("npcore_interval_{}.csv", std::process::id()));
+    std::fs::write(&path, data).unwrap();
+    let scenarios = datafile::parse(&path.to_str().unwrap().to_string()).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(scenarios[0].obs, vec![9.05, 3.5, 0.5]);
+    assert_eq!(
+        scenarios[0].obs_intervals,
+        vec![None, Some((2.0, 5.0)), Some((f64::NEG_INFINITY, 0.5))]
+    );
+
+    // Phi(2) - Phi(-1) and Phi(-1), respectively
+    let ypred = ndarray::array![9.0, 3.0, 1.0];
+    let yobs = ndarray::array![9.05, 3.5, 0.5];
+    let sigma = ndarray::array![1.0, 1.0, 0.5];
+    let point = prob::normal_likelihood(
+        &ndarray::array![9.0],
+        &ndarray::array![9.05],
+        &ndarray::array![1.0],
+    );
+    let expected = point * (0.977249868051821 - 0.158655253931457) * 0.158655253931457;
+    let ll = prob::censored_likelihood(&ypred, &yobs, &sigma, &scenarios[0].obs_intervals, None);
+    assert!((ll - expected).abs() < 1e-12, "{} != {}", ll, expected);
+
+    // Without intervals, the likelihood is the normal likelihood
+    let ll = prob::censored_likelihood(&ypred, &yobs, &sigma, &[None, None, None], None);
+    assert_eq!(ll, prob::normal_likelihood(&ypred, &yobs, &sigma));
+
+    // The probability of a narrow interval is the density at the observation times the width, on the same scale
+    let (ypred, yobs, sigma) = (
+        ndarray::array![3.0],
+        ndarray::array![4.2],
+        ndarray::array![0.8],
+    );
+    let width = 1e-4;
+    let density = prob::normal_likelihood(&ypred, &yobs, &sigma);
+    let interval = [Some((4.2 - width / 2.0, 4.2 + width / 2.0))];
+    let probability = prob::censored_likelihood(&ypred, &yobs, &sigma, &interval, None);
+    assert!(
+        (probability / (density * width) - 1.0).abs() < 1e-6,
+        "{} != {}",
+        probability,
+        density * width
+    );
+}
+
+#[test]
+fn unsupported_evid_is_reported() {
+    let data = "\
+ID,EVID,TIME,DUR,DOSE,ADDL,II,INPUT,OUT,OUTEQ,C0,C1,C2,C3
+1,1,0,0,100,.,.,1,.,.,.,.,.,.
+1,0,1,.,.,.,.,.,5.0,1,.,.,.,.
+2,1,0,0,100,.,.,1,.,.,.,.,.,.
+2,4,12,0,100,.,.,1,.,.,.,.,.,.
+2,0,13,.,.,.,.,.,5.5,1,.,.,.,.
+";
+    let path = std::env::temp_dir().join(format!("npcore_evid_{}.csv", std::process::id()));
+    std::fs::write(&path, data).unwrap();
+    let result = datafile::parse(&path.to_str().unwrap().to_string());
+    std::fs::remove_file(&path).unwrap();
+    let err = result.unwrap_err().to_string();
+    assert!(err.contains("Subject 2"), "{}", err);
+    assert!(err.contains("EVID 4"), "{}", err);
+
+    let mut events: Vec<Event> = test_scenario("1", &[(0.0, 100.0)], &[1.0])
+        .blocks
+        .into_iter()
+        .flat_map(|block| block.events)
+        .collect();
+    events[1].evid = 2;
+    assert!(Scenario::new(events).is_err());
+}
+
+#[test]
+fn proportional_error_relative_to_reference() {
+    use ndarray::array;
+    use sigma::{ErrorPoly, ErrorType, Sigma};
+
+    let yobs = array![1.0, 10.0, 100.0];
+    let instantaneous = ErrorPoly::new((0.1, 0.1, 0.0, 0.0), 2.0, &ErrorType::Prop);
+    let reference =
+        ErrorPoly::new((0.1, 0.1, 0.0, 0.0), 2.0, &ErrorType::Prop).with_reference(Some(10.0));
+
+    // The error follows the observations, or is fixed at the polynomial evaluated at the reference
+    assert_eq!(instantaneous.sigma(&yobs), array![0.4, 2.2, 20.2]);
+    assert_eq!(reference.sigma(&yobs), array![2.2, 2.2, 2.2]);
+    assert_eq!(
+        instantaneous.sigma(&array![10.0]),
+        reference.sigma(&array![10.0])
+    );
+
+    // The reference only applies to the proportional error class
+    let additive =
+        ErrorPoly::new((0.1, 0.1, 0.0, 0.0), 2.0, &ErrorType::Add).with_reference(Some(10.0));
+    assert_eq!(additive.sigma(&yobs)[2], (10.1f64.powi(2) + 4.0).sqrt());
+    let error = settings::Error {
+        value: 2.0,
+        class: "additive".to_string(),
+        poly: (0.1, 0.1, 0.0, 0.0),
+        reference: Some(10.0),
+        df: None,
+        optimize_poly: false,
+        transform: None,
+    };
+    assert!(error.validate().is_err());
+    let error = settings::Error {
+        class: "proportional".to_string(),
+        ..error
+    };
+    assert!(error.validate().is_ok());
+}
+
+#[test]
+fn two_fold_cross_validation() {
+    let (engine, settings, scenarios) = short_fit_setup(5);
+    let folds =
+        cross_validate_internal(engine.clone(), settings.clone(), scenarios.clone(), 2).unwrap();
+    assert_eq!(folds.len(), 2);
+    assert_eq!(
+        folds.iter().map(|f| f.n_test).sum::<usize>(),
+        scenarios.len()
+    );
+    for fold in &folds {
+        assert_eq!(fold.n_train + fold.n_test, scenarios.len());
+        assert!(fold.log_likelihood.is_finite(), "{:?}", fold);
+    }
+
+    // The partition is seeded from the settings
+    let again =
+        cross_validate_internal(engine.clone(), settings.clone(), scenarios.clone(), 2).unwrap();
+    assert_eq!(folds, again);
+
+    assert!(cross_validate_internal(engine, settings, scenarios, 1).is_err());
+}
+
+#[test]
+fn empty_data_is_rejected() {
+    let (engine, settings, _) = short_fit_setup(1);
+    let path = std::env::temp_dir().join(format!("npcore_empty_{}.csv", std::process::id()));
+    std::fs::write(
+        &path,
+        "ID,EVID,TIME,DUR,DOSE,ADDL,II,INPUT,OUT,OUTEQ,C0,C1,C2,C3\n",
+    )
+    .unwrap();
+    let scenarios = datafile::parse(&path.to_str().unwrap().to_string()).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert!(scenarios.is_empty());
+    let err = start_internal(engine.clone(), settings.clone(), scenarios).unwrap_err();
+    assert_eq!(err.to_string(), "The data contains no subjects");
+
+    // Subjects with only doses, or only missing observations
+    let mut scenario = test_scenario("1", &[(0.0, 100.0)], &[1.0]);
+    scenario.obs = vec![-99.0];
+    let err = start_internal(engine, settings, vec![scenario]).unwrap_err();
+    assert!(err.to_string().contains("no observations"));
+}
+
+#[cfg(feature = "server")]
+#[test]
+fn server_responds_to_scripted_commands() {
+    let dir = std::env::temp_dir();
+    let data = dir.join(format!("npcore_server_{}.csv", std::process::id()));
+    let config = dir.join(format!("npcore_server_{}.toml", std::process::id()));
+    std::fs::write(
+        &data,
+        "\
+ID,EVID,TIME,DUR,DOSE,ADDL,II,INPUT,OUT,OUTEQ,C0,C1,C2,C3
+1,1,0,0,100,.,.,1,.,.,.,.,.,.
+1,0,1,.,.,.,.,.,9.05,1,.,.,.,.
+1,0,4,.,.,.,.,.,6.70,1,.,.,.,.
+2,1,0,0,100,.,.,1,.,.,.,.,.,.
+2,0,1,.,.,.,.,.,4.09,1,.,.,.,.
+2,0,4,.,.,.,.,.,2.25,1,.,.,.,.
+",
+    )
+    .unwrap();
+    std::fs::write(
+        &config,
+        format!(
+            "\
+[paths]
+data = \"{}\"
+
+[config]
+cycles = 3
+engine = \"NPAG\"
+init_points = 50
+tui = true
+output = false
+cache = false
+
+[random]
+ke = [0.05, 0.5]
+v = [5.0, 50.0]
+
+[error]
+value = 0.5
+class = \"additive\"
+poly = [0.0, 0.5, 0.0, 0.0]
+",
+            data.display()
+        ),
+    )
+    .unwrap();
+
+    let script = [
+        r#"{"command": "get_result"}"#.to_string(),
+        format!(
+            r#"{{"command": "set_settings", "path": "{}"}}"#,
+            config.display()
+        ),
+        r#"{"command": "fit"}"#.to_string(),
+        format!(
+            r#"{{"command": "load_data", "path": "{}"}}"#,
+            data.display()
+        ),
+        r#"{"command": "simulate", "support_point": [0.1, 10.0], "id": "1"}"#.to_string(),
+        r#"{"command": "simulate", "support_point": [0.1, 10.0], "id": "3"}"#.to_string(),
+        String::new(),
+        r#"{"command": "fit"}"#.to_string(),
+        r#"{"command": "get_result"}"#.to_string(),
+        r#"{"command": "unknown"}"#.to_string(),
+        r#"{"command": "exit"}"#.to_string(),
+        r#"{"command": "fit"}"#.to_string(),
+    ]
+    .join("\n");
+    let mut output = Vec::new();
+    serve(
+        Engine::new(OneCompartment {}),
+        script.as_bytes(),
+        &mut output,
+    )
+    .unwrap();
+    let responses: Vec<serde_json::Value> = String::from_utf8(output)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+
+    // The empty line is skipped, and nothing is read after exit
+    assert_eq!(responses.len(), 10);
+    let ok: Vec<bool> = responses
+        .iter()
+        .map(|r| r["ok"].as_bool().unwrap())
+        .collect();
+    assert_eq!(
+        ok,
+        vec![false, true, false, true, true, false, true, true, false, true]
+    );
+    assert!(responses[0]["error"]
+        .as_str()
+        .unwrap()
+        .contains("fit first"));
+    assert_eq!(
+        responses[1]["result"]["parameters"],
+        serde_json::json!(["ke", "v"])
+    );
+    assert!(responses[2]["error"]
+        .as_str()
+        .unwrap()
+        .contains("load_data"));
+    assert_eq!(responses[3]["result"]["subjects"], 2);
+    assert_eq!(responses[3]["result"]["observations"], 4);
+
+    let simulated = &responses[4]["result"][0];
+    assert_eq!(simulated["id"], "1");
+    assert_eq!(simulated["times"], serde_json::json!([1.0, 4.0]));
+    let pred = simulated["predictions"][0].as_f64().unwrap();
+    assert!((pred - 10.0 * (-0.1f64).exp()).abs() < 1e-4);
+    assert!(responses[5]["error"]
+        .as_str()
+        .unwrap()
+        .contains("Subject 3"));
+
+    let fit = &responses[6]["result"];
+    assert_eq!(fit["cycles"], 3);
+    assert!(fit["objf"].as_f64().unwrap().is_finite());
+    let model = &responses[7]["result"];
+    assert_eq!(model["parameters"], serde_json::json!(["ke", "v"]));
+    assert_eq!(
+        model["support_points"].as_array().unwrap().len(),
+        fit["nspp"].as_u64().unwrap() as usize
+    );
+    assert!(responses[8]["error"].as_str().unwrap().contains("unknown"));
+    assert!(responses[9]["result"].is_null());
+
+    // The data is read as configured, and read again when the settings change
+    let windowed = dir.join(format!(
+        "npcore_server_windowed_{}.toml",
+        std::process::id()
+    ));
+    std::fs::write(
+        &windowed,
+        std::fs::read_to_string(&config).unwrap().replace(
+            "cache = false",
+            "cache = false\nobservation_windows = [[0.0, 2.0]]",
+        ),
+    )
+    .unwrap();
+    let script = [
+        format!(
+            r#"{{"command": "load_data", "path": "{}"}}"#,
+            data.display()
+        ),
+        format!(
+            r#"{{"command": "set_settings", "path": "{}"}}"#,
+            windowed.display()
+        ),
+        r#"{"command": "simulate", "support_point": [0.1, 10.0], "id": "1"}"#.to_string(),
+        format!(
+            r#"{{"command": "load_data", "path": "{}"}}"#,
+            data.display()
+        ),
+    ]
+    .join("\n");
+    let mut output = Vec::new();
+    serve(
+        Engine::new(OneCompartment {}),
+        script.as_bytes(),
+        &mut output,
+    )
+    .unwrap();
+    let responses: Vec<serde_json::Value> = String::from_utf8(output)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(responses[0]["result"]["observations"], 4);
+    assert_eq!(responses[2]["result"][0]["times"], serde_json::json!([1.0]));
+    assert_eq!(responses[3]["result"]["observations"], 2);
+
+    // A failed fit is an error, and discards the result of the previous fit
+    let failing = dir.join(format!("npcore_server_failing_{}.toml", std::process::id()));
+    std::fs::write(
+        &failing,
+        std::fs::read_to_string(&config)
+            .unwrap()
+            .replace("cache = false", "cache = false\nipm_max_iter = 1"),
+    )
+    .unwrap();
+    let script = [
+        format!(
+            r#"{{"command": "set_settings", "path": "{}"}}"#,
+            config.display()
+        ),
+        format!(
+            r#"{{"command": "load_data", "path": "{}"}}"#,
+            data.display()
+        ),
+        r#"{"command": "fit"}"#.to_string(),
+        format!(
+            r#"{{"command": "set_settings", "path": "{}"}}"#,
+            failing.display()
+        ),
+        r#"{"command": "fit"}"#.to_string(),
+        r#"{"command": "get_result"}"#.to_string(),
+    ]
+    .join("\n");
+    let mut output = Vec::new();
+    serve(
+        Engine::new(OneCompartment {}),
+        script.as_bytes(),
+        &mut output,
+    )
+    .unwrap();
+    let responses: Vec<serde_json::Value> = String::from_utf8(output)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(responses[2]["ok"], true);
+    assert_eq!(responses[4]["ok"], false);
+    assert!(responses[4]["error"]
+        .as_str()
+        .unwrap()
+        .contains("did not converge within 1 iterations"));
+    assert!(responses[5]["error"]
+        .as_str()
+        .unwrap()
+        .contains("fit first"));
+
+    std::fs::remove_file(&data).unwrap();
+    std::fs::remove_file(&config).unwrap();
+    std::fs::remove_file(&windowed).unwrap();
+    std::fs::remove_file(&failing).unwrap();
+}
+
+#[cfg(feature = "plots")]
+#[test]
+fn objf_is_plotted() {
+    let (result, _) = short_fit(3);
+    let path = std::env::temp_dir().join(format!("npcore_objf_{}.svg", std::process::id()));
+    output::plot_objf(result.cycle_log(), path.to_str().unwrap()).unwrap();
+    let image = std::fs::read_to_string(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert!(image.contains("<svg") && image.contains("<polyline"));
+    assert!(output::plot_objf(&[], path.to_str().unwrap()).is_err());
+}
+
+#[cfg(feature = "parquet")]
+#[test]
+fn parquet_outputs_match_csv() {
+    use arrow_array::{Array, Float64Array, StringArray, UInt64Array};
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+
+    let (engine, settings, scenarios) = short_fit_setup(3);
+    let (tx, _rx) = tokio::sync::mpsc::unbounded_channel();
+    let result = algorithms::initialize_algorithm(engine.clone(), settings, scenarios, tx)
+        .unwrap()
+        .fit();
+    let path = |name: &str| {
+        std::env::temp_dir()
+            .join(format!("npcore_parquet_{}_{}", std::process::id(), name))
+            .to_str()
+            .unwrap()
+            .to_string()
+    };
+    let read_csv = |path: &str| {
+        let mut reader = csv::Reader::from_path(path).unwrap();
+        let header: Vec<String> = reader.headers().unwrap().iter().map(String::from).collect();
+        let rows: Vec<Vec<String>> = reader
+            .records()
+            .map(|record| record.unwrap().iter().map(String::from).collect())
+            .collect();
+        std::fs::remove_file(path).unwrap();
+        (header, rows)
+    };
+    let read_parquet = |path: &str| {
+        let file = std::fs::File::open(path).unwrap();
+        let reader = ParquetRecordBatchReaderBuilder::try_new(file)
+            .unwrap()
+            .build()
+            .unwrap();
+        let batches: Vec<_> = reader.map(|batch| batch.unwrap()).collect();
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(batches.len(), 1);
+        batches.into_iter().next().unwrap()
+    };
+    let floats = |array: &dyn Array| -> Vec<f64> {
+        let array = array.as_any().downcast_ref::<Float64Array>().unwrap();
+        array.values().to_vec()
+    };
+
+    // Support points
+    result.write_theta_to(&path("theta.csv")).unwrap();
+    result.write_theta_parquet(&path("theta.parquet")).unwrap();
+    let (header, rows) = read_csv(&path("theta.csv"));
+    let batch = read_parquet(&path("theta.parquet"));
+    let names: Vec<&String> = batch
+        .schema_ref()
+        .fields()
+        .iter()
+        .map(|f| f.name())
+        .collect();
+    assert_eq!(names, header.iter().collect::<Vec<_>>());
+    assert_eq!(batch.num_rows(), rows.len());
+    for (column, array) in batch.columns().iter().enumerate() {
+        let csv: Vec<f64> = rows
+            .iter()
+            .map(|row| row[column].parse().unwrap())
+            .collect();
+        assert_eq!(floats(array.as_ref()), csv);
+    }
+
+    // Predictions, with an event interval
+    result
+        .write_pred_to(&engine, 2.0, 0.0, &path("pred.csv"))
+        .unwrap();
+    result
+        .write_pred_parquet(&engine, 2.0, 0.0, &path("pred.parquet"))
+        .unwrap();
+    let (header, rows) = read_csv(&path("pred.csv"));
+    let batch = read_parquet(&path("pred.parquet"));
+    let names: Vec<&String> = batch
+        .schema_ref()
+        .fields()
+        .iter()
+        .map(|f| f.name())
+        .collect();
+    assert_eq!(names, header.iter().collect::<Vec<_>>());
+    assert_eq!(batch.num_rows(), rows.len());
+    assert!(rows.len() > 9);
+    let ids = batch
+        .column(0)
+        .as_any()
+        .downcast_ref::<StringArray>()
+        .unwrap();
+    let outeq = batch
+        .column(2)
+        .as_any()
+        .downcast_ref::<UInt64Array>()
+        .unwrap();
+    for (i, row) in rows.iter().enumerate() {
+        assert_eq!(ids.value(i), row[0]);
+        assert_eq!(outeq.value(i).to_string(), row[2]);
+    }
+    for column in [1, 3, 4, 5, 6] {
+        let csv: Vec<f64> = rows
+            .iter()
+            .map(|row| row[column].parse().unwrap())
+            .collect();
+        assert_eq!(floats(batch.column(column).as_ref()), csv);
+    }
+}
+
+#[test]
+fn parameter_correlation_of_weighted_grid() {
+    use ndarray::array;
+
+    let (result, _) = short_fit(1);
+    let with_grid = |theta: ndarray::Array2<f64>, w: ndarray::Array1<f64>| {
+        output::NPResult::new(
+            result.scenarios.clone(),
+            theta,
+            result.psi.clone(),
+            w,
+            result.objf,
+            result.cycles,
+            result.converged,
+            result.settings.clone(),
+        )
+    };
+
+    // Mass on the diagonal of the unit square induces a correlation of (0.4 - 0.5 * 0.5) / 0.25 = 0.6
+    let theta = array![[0.0, 0.0], [1.0, 1.0], [0.0, 1.0], [1.0, 0.0]];
+    let corr = with_grid(theta.clone(), array![0.4, 0.4, 0.1, 0.1]).parameter_correlation();
+    assert_eq!(corr.dim(), (2, 2));
+    assert_eq!((corr[(0, 0)], corr[(1, 1)]), (1.0, 1.0));
+    assert!((corr[(0, 1)] - 0.6).abs() < 1e-12);
+    assert_eq!(corr[(0, 1)], corr[(1, 0)]);
+
+    // Weights need not be normalized, and mass on the anti-diagonal gives a negative correlation
+    let corr = with_grid(theta, array![1.0, 1.0, 4.0, 4.0]).parameter_correlation();
+    assert!((corr[(0, 1)] + 0.6).abs() < 1e-12);
+
+    // A single support point has no variance, so the correlation is undefined
+    let corr = with_grid(array![[0.2, 10.0]], array![1.0]).parameter_correlation();
+    assert!(corr.iter().all(|c| c.is_nan()));
+}
+
+#[test]
+fn seed_points_are_added_to_the_initial_grid() {
+    let (engine, mut settings, scenarios) = short_fit_setup(1);
+    let path = std::env::temp_dir().join(format!("npcore_seeds_{}.csv", std::process::id()));
+    // Columns in another order than the (sorted) random parameters, at the parameters the data was simulated from
+    std::fs::write(&path, "v,ke\n10.0,0.1\n20.0,0.2\n30.0,0.3\n").unwrap();
+    settings.paths.seed_points = Some(path.to_str().unwrap().to_string());
+
+    let ranges = settings.random.ranges();
+    let seeds = initialization::seed_points(&settings, &ranges).unwrap();
+    assert_eq!(
+        seeds,
+        ndarray::array![[0.1, 10.0], [0.2, 20.0], [0.3, 30.0]]
+    );
+
+    // The seed points support the data exactly, and with a precise assay they survive the first cycle
+    settings.error.value = 0.01;
+    settings.error.poly = (0.01, 0.01, 0.0, 0.0);
+    let (tx, _rx) = tokio::sync::mpsc::unbounded_channel();
+    let result = algorithms::initialize_algorithm(engine, settings.clone(), scenarios, tx)
+        .unwrap()
+        .fit();
+    for seed in seeds.rows() {
+        assert!(result.theta.rows().into_iter().any(|point| point == seed));
+    }
+
+    // Points outside the ranges are rejected
+    std::fs::write(&path, "ke,v\n0.1,10.0\n1.0,20.0\n").unwrap();
+    let err = initialization::seed_points(&settings, &ranges).unwrap_err();
+    assert!(err.to_string().contains("ke = 1"), "{}", err);
+
+    // An invalid seed file is returned as an error by the initialization, rather than exiting
+    let (engine, _, scenarios) = short_fit_setup(1);
+    let (tx, _rx) = tokio::sync::mpsc::unbounded_channel();
+    let err = algorithms::initialize_algorithm(engine, settings.clone(), scenarios, tx)
+        .err()
+        .unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert!(err.to_string().contains("ke = 1"), "{}", err);
+
+    settings.paths.seed_points = None;
+    assert_eq!(
+        initialization::seed_points(&settings, &ranges)
+            .unwrap()
+            .nrows(),
+        0
+    );
+}
+
+#[test]
+fn evaluate_distribution_of_a_fit() {
+    let (engine, _, scenarios) = short_fit_setup(3);
+    let (result, _) = short_fit(3);
+
+    // With the final error model of the fit, the likelihood of its own distribution is its objective function
+    let mut settings = result.settings.clone();
+    settings.error = output::PopulationModel::from(&result).error;
+    let ll = evaluate_distribution_internal(
+        engine.clone(),
+        settings.clone(),
+        scenarios.clone(),
+        result.theta.clone(),
+        result.w.clone(),
+    )
+    .unwrap();
+    assert!((-2.0 * ll - result.objf).abs() < 1e-6 * result.objf.abs().max(1.0));
+
+    // Weights are normalized
+    let scaled = evaluate_distribution_internal(
+        engine.clone(),
+        settings.clone(),
+        scenarios.clone(),
+        result.theta.clone(),
+        &result.w * 10.0,
+    )
+    .unwrap();
+    assert!((scaled - ll).abs() < 1e-9);
+
+    let mismatched = evaluate_distribution_internal(
+        engine,
+        settings,
+        scenarios,
+        result.theta.clone(),
+        ndarray::Array1::ones(result.w.len() + 1),
+    );
+    assert!(mismatched.is_err());
+}
+
+#[test]
+fn covariate_effects_scale_parameters() {
+    use simulation::covariates::CovariateEffect;
+
+    let cl = 2.0;
+    let weight = CovariateEffect::power("WT", 70.0, 0.75);
+    assert_eq!(weight.apply(cl, 70.0), cl);
+    assert!((weight.apply(cl, 35.0) - 2.0 * 0.5f64.powf(0.75)).abs() < 1e-12);
+    assert!((CovariateEffect::linear("AGE", 40.0, 0.01).apply(cl, 50.0) - 2.2).abs() < 1e-12);
+    assert!(
+        (CovariateEffect::exponential("CRCL", 100.0, -0.01).apply(cl, 0.0) - 2.0 * 1f64.exp())
+            .abs()
+            < 1e-12
+    );
+
+    // Covariates of a subject, constant within the block of the dose
+    let mut events: Vec<Event> = test_scenario("1", &[(0.0, 100.0)], &[1.0])
+        .blocks
+        .into_iter()
+        .flat_map(|block| block.events)
+        .collect();
+    for event in events.iter_mut() {
+        event.covs.insert("WT".to_string(), Some(35.0));
+    }
+    let scenario = Scenario::new(events).unwrap();
+    let covs = &scenario.blocks[0].covs;
+    assert_eq!(weight.apply_at(cl, covs, 0.5), Some(weight.apply(cl, 35.0)));
+    assert_eq!(
+        CovariateEffect::power("HT", 170.0, 1.0).apply_at(cl, covs, 0.5),
+        None
+    );
+}
+
+#[test]
+fn subject_with_many_observations_dominates_objf() {
+    let (engine, settings, mut scenarios) = short_fit_setup(3);
+    let many: Vec<f64> = (1..=30).map(|i| i as f64 * 0.3).collect();
+    let mut scenario = test_scenario("0", &[(0.0, 100.0)], &many);
+    scenario.obs = engine.pred(scenario.clone(), vec![0.1, 10.0]);
+    scenarios[0] = scenario;
+
+    let (tx, _rx) = tokio::sync::mpsc::unbounded_channel();
+    let result = algorithms::initialize_algorithm(engine, settings, scenarios, tx)
+        .unwrap()
+        .fit();
+    let contributions = result.objf_contributions();
+    assert_eq!(contributions.len(), 3);
+    assert!((contributions.sum() - result.objf).abs() < 1e-6 * result.objf.abs());
+
+    let share = &contributions / contributions.sum();
+    assert!(share[0] > 0.5, "{}", share);
+    assert!(share[0] > 5.0 * share[1].max(share[2]), "{}", share);
+
+    // With subject weights, the objective function is the weighted sum of the contributions
+    let (engine, mut settings, scenarios) = short_fit_setup(3);
+    settings.config.subject_weights = Some(HashMap::from([("1".to_string(), 2.0)]));
+    let (tx, _rx) = tokio::sync::mpsc::unbounded_channel();
+    let result = algorithms::initialize_algorithm(engine, settings, scenarios, tx)
+        .unwrap()
+        .fit();
+    let weights: ndarray::Array1<f64> = result
+        .scenarios
+        .iter()
+        .map(|scenario| if scenario.id == "1" { 2.0 } else { 1.0 })
+        .collect();
+    let contributions = result.objf_contributions();
+    assert!((weights.dot(&contributions) - result.objf).abs() < 1e-6 * result.objf.abs());
+    assert!((contributions.sum() - result.objf).abs() > 1e-3 * result.objf.abs());
+}
+
+#[test]
+fn qr_drops_zero_columns() {
+    use ndarray::array;
+
+    // The third support point has zero likelihood for every subject
+    let psi = array![
+        [0.5, 0.1, 0.0, 0.2],
+        [0.1, 0.6, 0.0, 0.3],
+        [0.3, 0.2, 0.0, 0.9]
+    ];
+    let mut keep = qr::keep_independent(&psi);
+    keep.sort();
+    assert_eq!(keep, vec![0, 1, 3]);
+
+    // Only the zero column is dropped when the remaining columns are independent
+    let psi = array![[0.5, 0.0, 0.1], [0.1, 0.0, 0.6], [0.3, 0.0, 0.2]];
+    let mut keep = qr::keep_independent(&psi);
+    keep.sort();
+    assert_eq!(keep, vec![0, 2]);
+
+    // A subject with zero likelihood everywhere does not poison the factorization
+    let psi = array![[0.5, 0.1], [0.0, 0.0], [0.3, 0.2]];
+    let (r, _) = qr::calculate_r(&psi);
+    assert!(r.iter().all(|x| x.is_finite()));
+    assert_eq!(qr::keep_independent(&psi).len(), 2);
+}
+
+#[test]
+fn empty_keep_set_retains_highest_weight() {
+    use condensation::fallback::retain_highest_weight;
+    use ndarray::array;
+
+    // Every support point has zero likelihood, so the QR factorization drops all of them
+    let psi = ndarray::Array2::<f64>::zeros((3, 4));
+    let mut keep = qr::keep_independent(&psi);
+    assert!(keep.is_empty());
+    let lambda = array![0.1, f64::NAN, 0.6, 0.3];
+    assert!(retain_highest_weight(&mut keep, &lambda));
+    assert_eq!(keep, vec![2]);
+
+    let theta = ndarray::Array2::from_shape_fn((4, 2), |(i, j)| (i + j) as f64);
+    let condensed = theta.select(ndarray::Axis(0), &keep);
+    assert_eq!(condensed, array![[2.0, 3.0]]);
+
+    // A non-empty keep-set is left untouched, and all-NaN weights retain the first point
+    assert!(!retain_highest_weight(&mut keep, &array![1.0, 2.0, 3.0]));
+    assert_eq!(keep, vec![2]);
+    let mut keep = Vec::new();
+    assert!(retain_highest_weight(
+        &mut keep,
+        &array![f64::NAN, f64::NAN]
+    ));
+    assert_eq!(keep, vec![0]);
+}
+
+#[test]
+fn population_model_round_trip() {
+    use ndarray::array;
+    use output::{posterior_mean_median, PopulationModel};
+    use sigma::{ErrorPoly, ErrorType};
+
+    let engine = Engine::new(OneCompartment {});
+    let settings = test_settings(&[("ke", (0.01, 1.0)), ("v", (1.0, 50.0))]);
+    let scenarios: Vec<Scenario> = [(0.15, 12.0), (0.3, 8.0)]
+        .iter()
+        .enumerate()
+        .map(|(i, (ke, v))| {
+            let mut scenario = test_scenario(&i.to_string(), &[(0.0, 100.0)], &[1.0, 4.0]);
+            scenario.obs = engine.pred(scenario.clone(), vec![*ke, *v]);
+            scenario
+        })
+        .collect();
+    let theta = array![[0.1, 10.0], [0.3, 10.0], [0.2, 12.0]];
+    let w = array![0.2, 0.5, 0.3];
+    let sigma = |error: &settings::Error, e_type| {
+        ErrorPoly::new(error.poly, error.value, e_type)
+            .with_reference(error.reference)
+            .with_df(error.df)
+            .with_transform(error.transform())
+    };
+    let e_type = ErrorType::from_class(&settings.error.class).unwrap();
+    let psi = prob::simulate_psi(
+        &engine,
+        &scenarios,
+        &theta,
+        &[&sigma(&settings.error, &e_type)],
+        false,
+        None,
+    )
+    .remove(0);
+    let result = output::NPResult::new(scenarios.clone(), theta, psi, w, 0.0, 1, true, settings);
+    let (post_mean, _) = posterior_mean_median(&result.theta, &result.psi, &result.w);
+    let expected = predict::post_predictions(&engine, post_mean, &scenarios).unwrap();
+
+    let path = std::env::temp_dir().join(format!("npcore_pop_{}.json", std::process::id()));
+    let path = path.to_str().unwrap();
+    result.export_population_model(path).unwrap();
+    let model = PopulationModel::read(path).unwrap();
+    std::fs::remove_file(path).unwrap();
+
+    assert_eq!(model.parameters, vec!["ke", "v"]);
+    assert_eq!(model.theta(), result.theta);
+    assert_eq!(model.weights(), result.w);
+    assert!(model.fixed.is_some());
+
+    // Simulating from the exported model reproduces the posterior predictions
+    let e_type = ErrorType::from_class(&model.error.class).unwrap();
+    let theta = model.theta();
+    let psi = prob::simulate_psi(
+        &engine,
+        &scenarios,
+        &theta,
+        &[&sigma(&model.error, &e_type)],
+        false,
+        None,
+    )
+    .remove(0);
+    let (post_mean, _) = posterior_mean_median(&theta, &psi, &model.weights());
+    let simulated = predict::post_predictions(&engine, post_mean, &scenarios).unwrap();
+    assert_eq!(simulated, expected);
+}
+
+/// Relaxation towards a steady state `c`, dy/dt = -k (y - c), which is stiff for large `k`
+///
+/// The primary solver is [ode_solvers::Dopri5] with the maximum number of steps of the engine, which fails for stiff systems if the maximum is small,
+/// while the fallback is [ode_solvers::Rk4] with a fixed step size small enough to be stable.
+#[cfg(test)]
+#[derive(Debug, Clone)]
+struct Stiff {
+    fixed_step: Option<f64>,
+    with_fallback: bool,
+}
+
+#[cfg(test)]
+#[derive(Debug, Clone)]
+struct StiffSystem {
+    k: f64,
+    c: f64,
+}
+
+#[cfg(test)]
+impl ode_solvers::System<ode_solvers::Vector1<f64>> for StiffSystem {
+    fn system(&self, _t: f64, y: &ode_solvers::Vector1<f64>, dy: &mut ode_solvers::Vector1<f64>) {
+        dy[0] = -self.k * (y[0] - self.c);
+    }
+}
+
+#[cfg(test)]
+impl<'a> Predict<'a> for Stiff {
+    type Model = StiffSystem;
+    type State = ode_solvers::Vector1<f64>;
+    fn initial_system(&self, params: &Vec<f64>, scenario: Scenario) -> (Self::Model, Scenario) {
+        let system = StiffSystem {
+            k: params[0],
+            c: params[1],
+        };
+        (system, scenario)
+    }
+    fn initial_state(&self) -> Self::State {
+        ode_solvers::Vector1::new(0.0)
+    }
+    fn add_covs(&self, _system: &mut Self::Model, _cov: Option<HashMap<String, CovLine>>) {}
+    fn add_infusion(&self, _system: &mut Self::Model, _infusion: Infusion) {}
+    fn add_dose(&self, state: &mut Self::State, dose: f64, _compartment: usize) {
+        state[0] += dose;
+    }
+    fn get_output(
+        &self,
+        _time: f64,
+        state: &Self::State,
+        _system: &Self::Model,
+        _outeq: usize,
+    ) -> f64 {
+        state[0]
+    }
+    fn state_step(&self, state: &mut Self::State, system: &Self::Model, time: f64, next_time: f64) {
+        let result = match self.fixed_step {
+            Some(step) => {
+                let mut stepper =
+                    ode_solvers::Rk4::new(system.clone(), time, *state, next_time, step);
+                stepper
+                    .integrate()
+                    .map(|_| *stepper.y_out().last().unwrap())
+            }
+            None => {
+                let mut stepper = ode_solvers::Dopri5::from_param(
+                    system.clone(),
+                    time,
+                    next_time,
+                    next_time - time,
+                    *state,
+                    1e-6,
+                    1e-8,
+                    0.9,
+                    0.04,
+                    0.2,
+                    10.0,
+                    next_time - time,
+                    0.0,
+                    simulation::stats::max_steps(),
+                    1000,
+                    ode_solvers::dop_shared::OutputType::Dense,
+                );
+                stepper
+                    .integrate()
+                    .map(|_| *stepper.y_out().last().unwrap())
+            }
+        };
+        // A failed integration is signalled by a non-finite state
+        *state = result.unwrap_or(ode_solvers::Vector1::new(f64::NAN));
+    }
+    fn fallback(&self) -> Option<Self> {
+        match (self.fixed_step, self.with_fallback) {
+            (None, true) => Some(Stiff {
+                fixed_step: Some(1e-3),
+                with_fallback: false,
+            }),
+            _ => None,
+        }
+    }
+}
+
+#[test]
+fn fallback_solver_rescues_stiff_predictions() {
+    let scenario = test_scenario("1", &[], &[0.0, 5.0, 10.0]);
+
+    // Without a fallback, the stiff system cannot be integrated within the maximum number of steps
+    let engine = Engine::new(Stiff {
+        fixed_step: None,
+        with_fallback: false,
+    })
+    .with_max_steps(200);
+    assert!(engine
+        .pred(scenario.clone(), vec![0.5, 3.0])
+        .iter()
+        .all(|y| y.is_finite()));
+    assert!(engine.pred(scenario.clone(), vec![1000.0, 3.0])[1].is_nan());
+
+    let engine = Engine::new(Stiff {
+        fixed_step: None,
+        with_fallback: true,
+    })
+    .with_max_steps(200);
+    let pred = engine.pred(scenario.clone(), vec![1000.0, 3.0]);
+    assert_eq!(pred[0], 0.0);
+    assert!((pred[1] - 3.0).abs() < 1e-8 && (pred[2] - 3.0).abs() < 1e-8);
+    let retries = engine.take_retries();
+    assert_eq!((retries.attempted, retries.rescued), (1, 1));
+    assert_eq!(engine.take_retries(), simulation::stats::Retries::default());
+}
+
+#[test]
+fn step_limit_penalizes_support_point() {
+    use ndarray::array;
+    use sigma::{ErrorPoly, ErrorType};
+
+    let mut scenario = test_scenario("1", &[], &[0.0, 5.0, 10.0]);
+    scenario.obs = vec![0.0, 3.0, 3.0];
+    let scenarios = vec![scenario];
+    let support_points = array![[0.5, 3.0], [200.0, 3.0]];
+    let sigma = ErrorPoly::new((0.1, 0.1, 0.0, 0.0), 0.0, &ErrorType::Add);
+    let model = Stiff {
+        fixed_step: None,
+        with_fallback: false,
+    };
+
+    // With the default maximum, the stiff support point is integrated
+    let engine = Engine::new(model.clone());
+    let psi = &prob::simulate_psi(&engine, &scenarios, &support_points, &[&sigma], false, None)[0];
+    assert!(psi.iter().all(|p| p.is_finite() && *p > 0.0));
+
+    // With a lower maximum, the integration is aborted, and only the stiff support point is excluded
+    let engine = Engine::new(model).with_max_steps(200);
+    let psi = &prob::simulate_psi(&engine, &scenarios, &support_points, &[&sigma], false, None)[0];
+    assert!(psi[(0, 0)].is_finite() && psi[(0, 0)] > 0.0);
+    assert_eq!(psi[(0, 1)], 0.0);
+    assert_eq!(engine.take_non_finite(), 1);
+    assert_eq!(engine.take_non_finite(), 0);
+    let (lambda, _) = ipm::burke(psi).unwrap();
+    assert!(lambda[0] > 0.99);
+}
+
+#[test]
+fn ipm_iteration_cap_is_reported() {
+    let psi = ndarray::array![[0.9, 0.1, 0.3], [0.2, 0.8, 0.3], [0.1, 0.4, 0.6]];
+    let (lambda, objf) = ipm::burke(&psi).unwrap();
+
+    let err = ipm::burke_with(&psi, ipm::DEFAULT_TOL, 1).unwrap_err();
+    assert!(
+        err.to_string()
+            .contains("did not converge within 1 iterations"),
+        "{}",
+        err
+    );
+
+    // A looser tolerance converges to about the same weights
+    let (loose, loose_objf) = ipm::burke_with(&psi, 1e-4, ipm::DEFAULT_MAX_ITER).unwrap();
+    assert!((&loose - &lambda).iter().all(|d| d.abs() < 1e-2));
+    assert!((loose_objf - objf).abs() < 1e-2);
+
+    let mut settings = test_settings(&[("ke", (0.05, 0.5))]);
+    assert_eq!(settings.config.ipm_max_iter, ipm::DEFAULT_MAX_ITER);
+    settings.config.ipm_max_iter = 0;
+    assert!(settings.config.validate(&settings.random).is_err());
+}
+
+#[test]
+fn ipm_failure_stops_the_run() {
+    use convergence::StopReason;
+
+    for engine_name in ["NPAG", "NPOD", "POSTPROB"] {
+        let (engine, mut settings, scenarios) = short_fit_setup(3);
+        settings.config.engine = engine_name.to_string();
+        settings.config.ipm_max_iter = 1;
+        let (tx, _rx) = tokio::sync::mpsc::unbounded_channel();
+        let result = algorithms::initialize_algorithm(engine, settings, scenarios, tx)
+            .unwrap()
+            .fit();
+
+        // The run fails in the first cycle, so there are no support points to return
+        assert_eq!(
+            result.stop_reason(),
+            Some(StopReason::Error),
+            "{}",
+            engine_name
+        );
+        assert!(!result.converged);
+        assert_eq!(result.theta.nrows(), 0);
+        assert_eq!(result.w.len(), 0);
+        assert_eq!(result.psi.dim(), (3, 0));
+        assert!(result
+            .warnings
+            .iter()
+            .any(|w| w.message.contains("did not converge within 1 iterations")));
+    }
+}
+
+#[test]
+fn keep_set_is_independent_of_grid_order() {
+    use condensation::order::sort_lexicographic;
+    use ndarray::Axis;
+
+    // The first and last support points have identical likelihoods, and thereby tied weights
+    let theta = ndarray::array![[0.3, 30.0], [0.1, 10.0], [0.2, 20.0], [0.05, 40.0]];
+    let psi = ndarray::array![
+        [0.9, 0.1, 0.2, 0.9],
+        [0.2, 0.8, 0.1, 0.2],
+        [0.1, 0.3, 0.7, 0.1]
+    ];
+    let condense = |order: &[usize]| {
+        let theta = theta.select(Axis(0), order);
+        let psi = psi.select(Axis(1), order);
+        let (lambda, _) = ipm::burke(&psi).unwrap();
+        let max = lambda.iter().cloned().fold(f64::MIN, f64::max);
+        let mut keep: Vec<usize> = (0..lambda.len())
+            .filter(|&i| lambda[i] > max / 1000.0)
+            .collect();
+        sort_lexicographic(&theta, &mut keep);
+        let (theta, psi) = (theta.select(Axis(0), &keep), psi.select(Axis(1), &keep));
+        let mut keep = qr::keep_independent(&psi);
+        sort_lexicographic(&theta, &mut keep);
+        theta.select(Axis(0), &keep)
+    };
+
+    let kept = condense(&[0, 1, 2, 3]);
+    assert_eq!(kept.nrows(), 3);
+    for order in [[3, 2, 1, 0], [1, 3, 0, 2], [2, 0, 3, 1]] {
+        assert_eq!(condense(&order), kept);
+    }
+    // Of the tied support points, the first in lexicographic order is kept
+    assert_eq!(kept.row(0), ndarray::array![0.05, 40.0]);
+
+    let mut keep = vec![2, 0, 1, 3];
+    sort_lexicographic(
+        &ndarray::array![[1.0, 2.0], [1.0, 1.0], [0.0, 5.0], [1.0, 1.0]],
+        &mut keep,
+    );
+    assert_eq!(keep, vec![2, 1, 3, 0]);
+}
+
+#[test]
+fn combined_error_components_are_recovered() {
+    use rand::{rngs::StdRng, SeedableRng};
+    use rand_distr::{Distribution, Normal};
+
+    // Additive SD 0.5 and proportional CV 0.1, i.e. SD = 0.5 + 0.1 y
+    let (additive, cv) = (0.5, 0.1);
+    let engine = Engine::new(OneCompartment {});
+    let mut rng = StdRng::seed_from_u64(42);
+    let scenarios: Vec<Scenario> = (0..16)
+        .map(|i| {
+            let (ke, v) = [(0.1, 5.0), (0.3, 8.0)][i % 2];
+            let mut scenario = test_scenario(
+                &i.to_string(),
+                &[(0.0, 100.0)],
+                &(1..=24).map(|t| t as f64).collect::<Vec<_>>(),
+            );
+            scenario.obs = engine
+                .pred(scenario.clone(), vec![ke, v])
+                .into_iter()
+                .map(|y: f64| {
+                    let sd = additive + cv * y;
+                    y + Normal::new(0.0, sd).unwrap().sample(&mut rng)
+                })
+                .collect();
+            scenario
+        })
+        .collect();
+
+    let mut settings = test_settings(&[("ke", (0.05, 0.5)), ("v", (2.0, 20.0))]);
+    settings.config.cycles = 300;
+    settings.config.init_points = 100;
+    settings.config.output = false;
+    settings.config.cache = false;
+    settings.error.class = "proportional".to_string();
+    settings.error.value = 1.0;
+    settings.error.poly = (2.0, 0.05, 0.0, 0.0);
+    settings.error.optimize_poly = true;
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    let result = algorithms::initialize_algorithm(engine, settings, scenarios, tx)
+        .unwrap()
+        .fit();
+
+    // Gamma is held fixed while the polynomial is optimized
+    let model = output::PopulationModel::from(&result);
+    assert_eq!(model.error.value, 1.0);
+    let (estimated_additive, estimated_cv) = (model.error.poly.0, model.error.poly.1);
+    assert!(
+        (estimated_additive - additive).abs() < 0.1,
+        "additive SD {}",
+        estimated_additive
+    );
+    assert!((estimated_cv - cv).abs() < 0.02, "CV {}", estimated_cv);
+    assert_eq!(model.error.poly.2, 0.0);
+
+    // The cycles carry the optimized polynomial, from which the magnitude of the error is computed
+    let mut last = None;
+    while let Ok(msg) = rx.try_recv() {
+        if let Comm::NPCycle(cycle) = msg {
+            last = Some(cycle);
+        }
+    }
+    assert_eq!(last.unwrap().poly, Some(model.error.poly));
+}
+
+#[test]
+fn cycles_carry_support_point_weights() {
+    let (result, sent) = short_fit(2);
+    for cycle in &sent {
+        assert_eq!(cycle.weights.len(), cycle.nspp);
+        assert_eq!(cycle.weights.len(), cycle.theta.nrows());
+    }
+    assert_eq!(sent.last().unwrap().weights, result.w);
+
+    let top = crate::tui::components::top_support_points(sent.last().unwrap(), 3);
+    assert_eq!(top.len(), 3.min(result.w.len()));
+    assert!(top.windows(2).all(|w| w[0].1 >= w[1].1));
+    assert_eq!(top[0].1, result.w.iter().cloned().fold(f64::MIN, f64::max));
+}
+
+#[test]
+fn infusion_end_is_a_breakpoint() {
+    let (ke, v, amount, dur) = (0.2, 10.0, 1000.0, 1.0);
+    // Observations every 0.7 h, none of which coincide with the end of the infusion
+    let obs_times: Vec<f64> = (1..=30).map(|i| i as f64 * 0.7).collect();
+    let mut scenario = test_scenario("1", &[(0.0, amount)], &obs_times);
+    for event in scenario.blocks.iter_mut().flat_map(|b| b.events.iter_mut()) {
+        if event.evid == 1 {
+            event.dur = Some(dur);
+        }
+    }
+    let analytic = |t: f64| {
+        let rate = amount / dur / (ke * v);
+        if t <= dur {
+            rate * (1.0 - (-ke * t).exp())
+        } else {
+            rate * (1.0 - (-ke * dur).exp()) * (-ke * (t - dur)).exp()
+        }
+    };
+    let auc = |conc: &[f64]| {
+        let mut times = vec![0.0];
+        times.extend(&obs_times);
+        let mut conc = conc.to_vec();
+        conc.insert(0, 0.0);
+        times
+            .windows(2)
+            .zip(conc.windows(2))
+            .map(|(t, c)| (t[1] - t[0]) * (c[0] + c[1]) / 2.0)
+            .sum::<f64>()
+    };
+    let expected = auc(&obs_times.iter().map(|&t| analytic(t)).collect::<Vec<f64>>());
+
+    let engine = Engine::new(BimodalKe {});
+    let pred = engine.pred(scenario.clone(), vec![ke, v]);
+
+    // The same integration, stepping across the end of the infusion
+    let model = BimodalKe {};
+    let (mut system, _) = model.initial_system(&vec![ke, v], scenario.clone());
+    model.add_infusion(
+        &mut system,
+        Infusion {
+            time: 0.0,
+            dur,
+            amount,
+            compartment: 0,
+        },
+    );
+    let mut state = model.initial_state();
+    let mut unbroken = vec![];
+    let mut time = 0.0;
+    for &t in &obs_times {
+        model.state_step(&mut state, &system, time, t);
+        unbroken.push(model.get_output(t, &state, &system, 1));
+        time = t;
+    }
+
+    let error = ((auc(&pred) - expected) / expected).abs();
+    let unbroken_error = ((auc(&unbroken) - expected) / expected).abs();
+    assert!(error < 1e-3, "relative AUC error {}", error);
+    assert!(
+        unbroken_error > 2.0 * error,
+        "{} vs {}",
+        unbroken_error,
+        error
+    );
+}
+
+#[test]
+fn degenerate_error_polynomials_are_rejected() {
+    let error = |class: &str, value: f64, poly: (f64, f64, f64, f64)| settings::Error {
+        value,
+        class: class.to_string(),
+        poly,
+        reference: None,
+        df: None,
+        optimize_poly: false,
+        transform: None,
+    };
+    let observations = [0.5, 2.0, 10.0, -99.0];
+
+    let zero = error("proportional", 2.0, (0.0, 0.0, 0.0, 0.0));
+    assert!(zero.validate().is_ok());
+    assert!(zero.validate_observations(&observations).is_err());
+
+    // The polynomial is negative above an observation of 5
+    let negative = error("proportional", 2.0, (1.0, -0.2, 0.0, 0.0));
+    assert!(negative.validate_observations(&[0.5, 2.0]).is_ok());
+    assert!(negative.validate_observations(&observations).is_err());
+
+    // Additive errors are positive as long as either lambda or the polynomial is non-zero
+    assert!(error("additive", 0.5, (0.0, 0.0, 0.0, 0.0))
+        .validate_observations(&observations)
+        .is_ok());
+    assert!(error("additive", 0.0, (0.0, 0.0, 0.0, 0.0))
+        .validate_observations(&observations)
+        .is_err());
+    assert!(error("proportional", 2.0, (0.1, 0.1, 0.0, 0.0))
+        .validate_observations(&observations)
+        .is_ok());
+
+    // The reference concentration only applies to the proportional class, as in the likelihood
+    let with_reference = |class: &str, value: f64| settings::Error {
+        reference: Some(1.0),
+        ..error(class, value, (1.0, -0.2, 0.0, 0.0))
+    };
+    assert!(with_reference("proportional", 2.0)
+        .validate_observations(&observations)
+        .is_ok());
+    assert!(with_reference("additive", 0.0)
+        .validate_observations(&[5.0])
+        .is_err());
+
+    assert!(error("exponential", 2.0, (0.1, 0.1, 0.0, 0.0))
+        .validate()
+        .is_err());
+}
+
+#[test]
+fn covariate_summary_lists_missing_subjects() {
+    let data = "\
+ID,EVID,TIME,DUR,DOSE,ADDL,II,INPUT,OUT,OUTEQ,C0,C1,C2,C3,WT,AGE
+1,1,0,0,100,.,.,1,.,.,.,.,.,.,70,40
+1,0,1,.,.,.,.,.,5.0,1,.,.,.,.,.,.
+1,1,12,0,100,.,.,1,.,.,.,.,.,.,74,40
+1,0,13,.,.,.,.,.,5.5,1,.,.,.,.,.,.
+2,1,0,0,100,.,.,1,.,.,.,.,.,.,.,55
+2,0,1,.,.,.,.,.,4.0,1,.,.,.,.,.,.
+3,1,0,0,100,.,.,1,.,.,.,.,.,.,60,30
+3,0,1,.,.,.,.,.,6.0,1,.,.,.,.,.,.
+";
+    let path = std::env::temp_dir().join(format!("npcore_covs_{}.csv", std::process::id()));
+    std::fs::write(&path, data).unwrap();
+    let scenarios = datafile::parse(&path.to_str().unwrap().to_string()).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(scenarios.len(), 3);
+
+    let summary = datafile::covariate_summary(&scenarios, &["CRCL".to_string(), "WT".to_string()]);
+    let names: Vec<&str> = summary.iter().map(|s| s.name.as_str()).collect();
+    assert_eq!(names, vec!["AGE", "CRCL", "WT"]);
+
+    assert_eq!(summary[0].n_subjects, 3);
+    assert_eq!(summary[0].range, Some((30.0, 55.0)));
+    assert!(summary[0].missing.is_empty());
+
+    // Required by the model, but not in the data
+    assert_eq!(summary[1].range, None);
+    assert_eq!(summary[1].missing, vec!["1", "2", "3"]);
+
+    assert_eq!(summary[2].n_subjects, 2);
+    assert_eq!(summary[2].range, Some((60.0, 74.0)));
+    assert_eq!(summary[2].missing, vec!["2"]);
+
+    // The subject without a weight has no weight in its blocks, rather than panicking
+    assert!(scenarios[1].blocks[0].covs.get("WT").is_none());
+    assert!(scenarios[1].blocks[0].covs.get("AGE").is_some());
+}
+
+#[test]
+fn restart_from_population_model_with_other_error_model() {
+    let (result, _) = short_fit(3);
+    let path = std::env::temp_dir().join(format!("npcore_prior_{}.json", std::process::id()));
+    let path = path.to_str().unwrap().to_string();
+    result.export_population_model(&path).unwrap();
+
+    let (engine, mut settings, scenarios) = short_fit_setup(3);
+    settings.paths.prior = Some(path.clone());
+    settings.error = settings::Error {
+        value: 2.0,
+        class: "proportional".to_string(),
+        poly: (0.1, 0.1, 0.0, 0.0),
+        reference: None,
+        df: None,
+        optimize_poly: false,
+        transform: None,
+    };
+
+    // The grid starts from the previous solution
+    let theta = initialization::sample_space(&settings, &settings.random.ranges());
+    assert_eq!(theta, result.theta);
+
+    let (tx, _rx) = tokio::sync::mpsc::unbounded_channel();
+    let restarted = algorithms::initialize_algorithm(engine, settings, scenarios, tx)
+        .unwrap()
+        .fit();
+    std::fs::remove_file(&path).unwrap();
+
+    assert!(restarted.objf.is_finite());
+    assert!(restarted.theta.nrows() >= 1);
+    assert!((restarted.w.sum() - 1.0).abs() < 1e-6);
+    // Gamma is re-optimized from the value in the settings
+    let first = restarted.cycle_log().first().unwrap();
+    assert!(first.gamlam > 0.0 && first.gamlam != result.cycle_log().last().unwrap().gamlam);
+}
+
+#[test]
+fn paused_run_waits_until_resumed_or_stopped() {
+    use crate::tui::control::{Control, RunControl};
+    use std::sync::mpsc::channel;
+    use std::time::Duration;
+
+    // A running fit does not wait
+    let control = RunControl::new();
+    assert!(!control.wait_while_paused());
+
+    control.send(Control::Toggle);
+    assert!(control.is_paused());
+    control.send(Control::Toggle);
+    assert!(!control.is_paused());
+
+    // The worker waits while paused, and continues when resumed
+    control.send(Control::Pause);
+    let (done_tx, done_rx) = channel();
+    let worker = {
+        let control = control.clone();
+        std::thread::spawn(move || done_tx.send(control.wait_while_paused()).unwrap())
+    };
+    assert!(done_rx.recv_timeout(Duration::from_millis(100)).is_err());
+    control.send(Control::Resume);
+    assert_eq!(done_rx.recv_timeout(Duration::from_secs(5)), Ok(false));
+    worker.join().unwrap();
+
+    // A paused worker is woken up and told to stop
+    control.send(Control::Pause);
+    let (done_tx, done_rx) = channel();
+    let worker = {
+        let control = control.clone();
+        std::thread::spawn(move || done_tx.send(control.wait_while_paused()).unwrap())
+    };
+    assert!(done_rx.recv_timeout(Duration::from_millis(100)).is_err());
+    control.send(Control::Stop);
+    assert_eq!(done_rx.recv_timeout(Duration::from_secs(5)), Ok(true));
+    worker.join().unwrap();
+    assert!(control.is_paused() && control.is_stopped());
+}
+
+#[test]
+fn gzipped_data_parses_like_plain_data() {
+    use flate2::{write::GzEncoder, Compression};
+    use std::io::Write;
+
+    let plain = "examples/data/two_eq_lag.csv".to_string();
+    let expected = datafile::parse(&plain).unwrap();
+
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(&std::fs::read(&plain).unwrap()).unwrap();
+    let compressed = encoder.finish().unwrap();
+
+    // Detected by extension, and by magic bytes when the extension is missing
+    let dir = std::env::temp_dir();
+    for name in ["npcore_gzip_{}.csv.gz", "npcore_gzip_{}.csv"] {
+        let path = dir.join(name.replace("{}", &std::process::id().to_string()));
+        std::fs::write(&path, &compressed).unwrap();
+        let scenarios = datafile::parse(&path.to_str().unwrap().to_string()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(scenarios.len(), expected.len());
+        for (scenario, expected) in scenarios.iter().zip(&expected) {
+            assert_eq!(scenario.id, expected.id);
+            assert_eq!(scenario.times, expected.times);
+            assert_eq!(scenario.obs_times, expected.obs_times);
+            assert_eq!(scenario.obs, expected.obs);
+            assert_eq!(scenario.metadata, expected.metadata);
+            assert_eq!(scenario.blocks.len(), expected.blocks.len());
+        }
+    }
+}
+
+#[test]
+fn support_points_below_output_weight_threshold_are_omitted() {
+    use ndarray::array;
+
+    let mut settings = test_settings(&[("ke", (0.01, 1.0)), ("v", (1.0, 50.0))]);
+    settings.config.output_weight_threshold = Some(0.05);
+    let scenarios = vec![test_scenario("1", &[(0.0, 100.0)], &[1.0, 4.0])];
+    let theta = array![[0.1, 10.0], [0.2, 20.0], [0.3, 30.0], [0.4, 40.0]];
+    let w = array![0.6, 0.01, 0.37, 0.02];
+    let psi = array![[1.0, 1.0, 1.0, 1.0]];
+    let result = output::NPResult::new(scenarios, theta, psi, w, 0.0, 1, true, settings);
+
+    let (reported, omitted, omitted_weight) = result.reported_support_points();
+    assert_eq!(reported, vec![0, 2]);
+    assert_eq!(omitted, 2);
+    assert!((omitted_weight - 0.03).abs() < 1e-12);
+    let reported_weight: f64 = reported.iter().map(|&i| result.w[i]).sum();
+    assert!((reported_weight + omitted_weight - 1.0).abs() < 1e-12);
+
+    let path = std::env::temp_dir().join(format!("npcore_theta_{}.csv", std::process::id()));
+    result.write_theta_to(path.to_str().unwrap()).unwrap();
+    let written = std::fs::read_to_string(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    let rows: Vec<&str> = written.lines().skip(1).collect();
+    assert_eq!(rows, ["0.1,10,0.6", "0.3,30,0.37"]);
+
+    // The result itself keeps all support points
+    assert_eq!(result.theta.nrows(), 4);
+}
+
+#[test]
+fn parameter_with_equal_bounds_is_held_constant() {
+    use initialization::sobol;
+
+    let (engine, mut settings, scenarios) = short_fit_setup(5);
+    settings
+        .random
+        .parameters
+        .insert("v".to_string(), (20.0, 20.0));
+    assert!(settings.random.validate().is_ok());
+    assert_eq!(settings.random.degenerate(), vec![("v".to_string(), 20.0)]);
+    settings
+        .random
+        .parameters
+        .insert("v".to_string(), (20.0, 10.0));
+    assert!(settings.random.validate().is_err());
+    settings
+        .random
+        .parameters
+        .insert("v".to_string(), (20.0, 20.0));
+
+    // The Sobol sequence of the other parameters is as if the parameter was not random
+    let ranges = settings.random.ranges();
+    let grid = sobol::generate(50, &ranges, settings.config.seed);
+    let reduced = sobol::generate(50, &vec![ranges[0]], settings.config.seed);
+    assert!(grid.column(1).iter().all(|&v| v == 20.0));
+    assert_eq!(grid.column(0), reduced.column(0));
+
+    // The parameter is held through the fit, without duplicating support points
+    let (tx, _rx) = tokio::sync::mpsc::unbounded_channel();
+    let result = algorithms::initialize_algorithm(engine, settings, scenarios, tx)
+        .unwrap()
+        .fit();
+    assert!(result.theta.column(1).iter().all(|&v| v == 20.0));
+    let mut ke: Vec<f64> = result.theta.column(0).to_vec();
+    ke.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    ke.dedup();
+    assert_eq!(ke.len(), result.theta.nrows());
+}
+
+#[test]
+fn volume_output_is_referenced_by_name() {
+    use predict::VolumeOutput;
+
+    /// One-compartment model which reads only the elimination rate by index, and the volume by name
+    #[derive(Debug, Clone)]
+    struct NamedVolume {
+        ke: usize,
+        volume: &'static str,
+    }
+    impl<'a> Predict<'a> for NamedVolume {
+        type Model = f64;
+        type State = f64;
+        fn initial_system(&self, params: &Vec<f64>, scenario: Scenario) -> (f64, Scenario) {
+            (params[self.ke], scenario)
+        }
+        fn initial_state(&self) -> f64 {
+            0.0
+        }
+        fn add_covs(&self, _system: &mut f64, _cov: Option<HashMap<String, CovLine>>) {}
+        fn add_infusion(&self, _system: &mut f64, _infusion: Infusion) {}
+        fn add_dose(&self, state: &mut f64, dose: f64, _compartment: usize) {
+            *state += dose;
+        }
+        fn get_output(&self, _time: f64, _state: &f64, _system: &f64, outeq: usize) -> f64 {
+            panic!("Output equation {} is a volume output", outeq)
+        }
+        fn state_step(&self, state: &mut f64, ke: &f64, time: f64, next_time: f64) {
+            *state *= (-ke * (next_time - time)).exp();
+        }
+        fn amounts(&self, state: &f64) -> Vec<f64> {
+            vec![*state]
+        }
+        fn volume_outputs(&self) -> Vec<VolumeOutput> {
+            vec![VolumeOutput {
+                outeq: 1,
+                compartment: 0,
+                volume: self.volume.to_string(),
+            }]
+        }
+    }
+
+    let scenario = test_scenario("1", &[(0.0, 100.0)], &[1.0, 4.0]);
+    let expected: Vec<f64> = [1.0, 4.0]
+        .iter()
+        .map(|t| 100.0 / 20.0 * (-0.1_f64 * t).exp())
+        .collect();
+
+    // The volume sorts after, and then before, the elimination rate
+    let names = ["ke".to_string(), "v".to_string()];
+    let engine = Engine::new(NamedVolume { ke: 0, volume: "v" })
+        .with_parameters(&names)
+        .unwrap();
+    let pred = engine.pred(scenario.clone(), vec![0.1, 20.0]);
+    for (y, expected) in pred.iter().zip(&expected) {
+        assert!((y - expected).abs() < 1e-12);
+    }
+    let names = ["a_v".to_string(), "ke".to_string()];
+    let engine = Engine::new(NamedVolume {
+        ke: 1,
+        volume: "a_v",
+    })
+    .with_parameters(&names)
+    .unwrap();
+    assert_eq!(engine.pred(scenario.clone(), vec![20.0, 0.1]), pred);
+
+    // The volume must be one of the parameters
+    assert!(Engine::new(NamedVolume { ke: 0, volume: "v" })
+        .with_parameters(&["ke".to_string()])
+        .is_err());
+
+    // Without the parameter names, the predictions fail rather than panic, and the engine does not validate
+    let unnamed = Engine::new(NamedVolume { ke: 0, volume: "v" });
+    assert!(unnamed
+        .pred(scenario.clone(), vec![0.1, 20.0])
+        .iter()
+        .all(|y| y.is_nan()));
+    assert!(unnamed.validate(&[scenario.clone()]).is_err());
+    assert!(engine.validate(&[scenario]).is_ok());
+}
+
+#[test]
+fn volume_parameter_is_named_in_the_settings() {
+    /// One-compartment model which only reports the amount, and reads the elimination rate by index
+    #[derive(Debug, Clone)]
+    struct AmountOnly {
+        ke: usize,
+    }
+    impl<'a> Predict<'a> for AmountOnly {
+        type Model = f64;
+        type State = f64;
+        fn initial_system(&self, params: &Vec<f64>, scenario: Scenario) -> (f64, Scenario) {
+            (params[self.ke], scenario)
+        }
+        fn initial_state(&self) -> f64 {
+            0.0
+        }
+        fn add_covs(&self, _system: &mut f64, _cov: Option<HashMap<String, CovLine>>) {}
+        fn add_infusion(&self, _system: &mut f64, _infusion: Infusion) {}
+        fn add_dose(&self, state: &mut f64, dose: f64, _compartment: usize) {
+            *state += dose;
+        }
+        fn get_output(&self, _time: f64, _state: &f64, _system: &f64, outeq: usize) -> f64 {
+            panic!("Output equation {} is given by the settings", outeq)
+        }
+        fn state_step(&self, state: &mut f64, ke: &f64, time: f64, next_time: f64) {
+            *state *= (-ke * (next_time - time)).exp();
+        }
+        fn amounts(&self, state: &f64) -> Vec<f64> {
+            vec![*state]
+        }
+    }
+
+    let path = std::env::temp_dir().join(format!("npcore_volume_{}.toml", std::process::id()));
+    let config = std::fs::read_to_string("src/tests/config.toml").unwrap();
+    std::fs::write(
+        &path,
+        format!("{}\n[[outputs]]\nouteq = 1\nvolume = \"vol\"\n", config),
+    )
+    .unwrap();
+    let parsed = settings::parse_settings(path.to_str().unwrap(), vec![]).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(parsed.outputs[0].compartment, 0);
+    let volumes = parsed.volume_outputs();
+    assert_eq!(volumes.len(), 1);
+
+    let scenario = test_scenario("1", &[(0.0, 100.0)], &[1.0, 4.0]);
+    let expected: Vec<f64> = [1.0, 4.0]
+        .iter()
+        .map(|t| 100.0 / 20.0 * (-0.1_f64 * t).exp())
+        .collect();
+    // The volume sorts after, in the middle of, and before the other parameters
+    for (names, ke, params) in [
+        (vec!["ke", "vol"], 0, vec![0.1, 20.0]),
+        (vec!["ke", "vol", "z"], 0, vec![0.1, 20.0, 1.0]),
+        (vec!["a", "ke", "vol"], 1, vec![1.0, 0.1, 20.0]),
+    ] {
+        let names: Vec<String> = names.iter().map(|name| name.to_string()).collect();
+        let engine = Engine::new(AmountOnly { ke })
+            .with_volume_outputs(volumes.clone())
+            .with_parameters(&names)
+            .unwrap();
+        let pred = engine.pred(scenario.clone(), params);
+        for (y, expected) in pred.iter().zip(&expected) {
+            assert!((y - expected).abs() < 1e-12, "{} != {}", y, expected);
+        }
+    }
+
+    // A fit computes the concentrations from the settings
+    let (_, mut settings, scenarios) = short_fit_setup(2);
+    settings.random.parameters = [("avol", (5.0, 50.0)), ("ke", (0.05, 0.5))]
+        .iter()
+        .map(|(name, range)| (name.to_string(), *range))
+        .collect();
+    settings.outputs = parsed.outputs.clone();
+    settings.outputs[0].volume = Some("avol".to_string());
+    let (tx, _rx) = tokio::sync::mpsc::unbounded_channel();
+    let result = algorithms::initialize_algorithm(
+        Engine::new(AmountOnly { ke: 1 }),
+        settings.clone(),
+        scenarios,
+        tx,
+    )
+    .unwrap()
+    .fit();
+    assert!(result.objf.is_finite());
+
+    // The volume outputs of earlier settings are kept, so other settings are applied to the engine of the model, e.g. by the server
+    let model = Engine::new(AmountOnly { ke: 0 });
+    let configured = model.clone().configure(&settings).unwrap();
+    let mut other = settings;
+    other.outputs.clear();
+    other.random.parameters.remove("avol");
+    assert!(configured.configure(&other).is_err());
+    assert!(model.configure(&other).is_ok());
+
+    // The volume must be one of the parameters
+    assert!(Engine::new(AmountOnly { ke: 0 })
+        .with_volume_outputs(volumes)
+        .with_parameters(&["ke".to_string()])
+        .is_err());
+}
+
+#[test]
+fn fit_is_invariant_to_subject_order() {
+    let fit = |shuffle_seed: Option<u64>| {
+        let (engine, settings, mut scenarios) = short_fit_setup(10);
+        if let Some(seed) = shuffle_seed {
+            let permutation = datafile::shuffle(&mut scenarios, seed);
+            assert_ne!(permutation, vec![0, 1, 2]);
+            assert_eq!(datafile::shuffle(&mut scenarios.clone(), seed), permutation);
+        }
+        let (tx, _rx) = tokio::sync::mpsc::unbounded_channel();
+        algorithms::initialize_algorithm(engine, settings, scenarios, tx)
+            .unwrap()
+            .fit()
+    };
+    let original = fit(None);
+    let shuffled = fit(Some(2));
+    assert!((original.objf - shuffled.objf).abs() < 1e-6 * original.objf.abs().max(1.0));
+    assert_eq!(original.theta.nrows(), shuffled.theta.nrows());
+}
+
+#[test]
+fn dropped_support_points_are_recorded_as_warnings() {
+    use warnings::Category;
+
+    // Duplicated support points have identical likelihoods, so one of each pair is dropped by the QR decomposition
+    let prior = std::env::temp_dir().join(format!("npcore_warnings_{}.csv", std::process::id()));
+    std::fs::write(
+        &prior,
+        "ke,v\n0.1,10\n0.1,10\n0.2,20\n0.2,20\n0.3,30\n0.3,30\n",
+    )
+    .unwrap();
+    let (engine, mut settings, scenarios) = short_fit_setup(4);
+    settings.paths.prior = Some(prior.to_str().unwrap().to_string());
+    settings.config.max_grid_size = Some(10);
+    let (tx, _rx) = tokio::sync::mpsc::unbounded_channel();
+    let result = algorithms::initialize_algorithm(engine, settings, scenarios, tx)
+        .unwrap()
+        .fit();
+    std::fs::remove_file(&prior).unwrap();
+
+    let of = |category: Category| -> Vec<&warnings::Warning> {
+        result
+            .warnings
+            .iter()
+            .filter(|warning| warning.category == category)
+            .collect()
+    };
+    assert!(!of(Category::Grid).is_empty());
+    let condensation = of(Category::Condensation);
+    assert_eq!(condensation[0].cycle, Some(1));
+    assert!(condensation[0]
+        .message
+        .contains("dropped 3 support point(s)"));
+    assert_eq!(of(Category::Convergence).len(), 1);
+    assert_eq!(of(Category::Convergence)[0].cycle, Some(4));
+
+    // An error model which widens with every evaluation lowers the likelihood in each cycle, so the -2LL increases
+    struct Widening(std::sync::atomic::AtomicUsize);
+    impl sigma::Sigma for Widening {
+        fn sigma(&self, yobs: &ndarray::Array1<f64>) -> ndarray::Array1<f64> {
+            let calls = self.0.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            yobs.mapv(|_| 0.1 * (1 + calls) as f64)
+        }
+    }
+    let (engine, settings, scenarios) = short_fit_setup(4);
+    let (tx, _rx) = tokio::sync::mpsc::unbounded_channel();
+    let mut algorithm = algorithms::initialize_algorithm(engine, settings, scenarios, tx).unwrap();
+    algorithm.set_sigma(Box::new(Widening(Default::default())));
+    let result = algorithm.fit();
+    let objective: Vec<&warnings::Warning> = result
+        .warnings
+        .iter()
+        .filter(|warning| warning.category == Category::Objective)
+        .collect();
+    assert!(!objective.is_empty());
+    assert!(objective[0].message.starts_with("-2LL increased from"));
+    assert!(objective[0].cycle.unwrap() > 1);
+}
+
+#[test]
+fn subjects_are_predicted_at_fixed_times() {
+    let engine = Engine::new(OneCompartment {});
+    let times = [0.0, 1.0, 2.0, 4.0, 8.0, 24.0];
+    let scenarios = [
+        test_scenario("1", &[(0.0, 100.0)], &[0.5, 3.0]),
+        test_scenario("2", &[(0.0, 100.0)], &[6.0, 12.0, 36.0]),
+    ];
+    for scenario in &scenarios {
+        let scenario = scenario.with_prediction_times(&times);
+        assert_eq!(scenario.obs_times, times);
+        let pred = engine.pred(scenario, vec![0.1, 10.0]);
+        for (y, t) in pred.iter().zip(times) {
+            assert!((y - 10.0 * (-0.1 * t).exp()).abs() < 1e-10);
+        }
+    }
+
+    let mut settings = test_settings(&[("ke", (0.01, 1.0)), ("v", (1.0, 50.0))]);
+    settings.config.prediction_times = Some(times.to_vec());
+    assert!(settings.config.validate(&settings.random).is_ok());
+    settings.config.prediction_times = Some(vec![0.0, 2.0, 1.0]);
+    assert!(settings.config.validate(&settings.random).is_err());
+    settings.config.prediction_times = Some(vec![-1.0, 2.0]);
+    assert!(settings.config.validate(&settings.random).is_err());
+}
+
+#[test]
+fn missing_or_malformed_prior_is_handled() {
+    let mut settings = test_settings(&[("ke", (0.01, 1.0)), ("v", (1.0, 50.0))]);
+    settings.config.init_points = 20;
+    let ranges = settings.random.ranges();
+    let uniform = initialization::sobol::generate(20, &ranges, settings.config.seed);
+
+    let missing = std::env::temp_dir()
+        .join(format!("npcore_missing_prior_{}.csv", std::process::id()))
+        .to_str()
+        .unwrap()
+        .to_string();
+    let malformed = std::env::temp_dir()
+        .join(format!("npcore_malformed_prior_{}.csv", std::process::id()))
+        .to_str()
+        .unwrap()
+        .to_string();
+    std::fs::write(&malformed, "ke,v\n0.1,ten\n0.2\n").unwrap();
+
+    // Fitting falls back to a uniform grid
+    for prior in [&missing, &malformed] {
+        settings.paths.prior = Some(prior.clone());
+        assert_eq!(initialization::sample_space(&settings, &ranges), uniform);
+    }
+
+    // Simulation requires the support points, and fails with an error naming the prior
+    for prior in [&missing, &malformed] {
+        let err = crate::entrypoints::read_theta(Some(prior)).unwrap_err();
+        assert!(err.to_string().contains(prior.as_str()));
+    }
+    assert!(crate::entrypoints::read_theta(None).is_err());
+    std::fs::remove_file(&malformed).unwrap();
+}
+
+#[test]
+fn subjects_are_fitted_by_group() {
+    let (engine, settings, _) = short_fit_setup(3);
+    let scenarios: Vec<Scenario> = [
+        ("a", 0.1, 10.0),
+        ("b", 0.4, 40.0),
+        ("a", 0.12, 12.0),
+        ("b", 0.38, 38.0),
+    ]
+    .iter()
+    .enumerate()
+    .map(|(i, (group, ke, v))| {
+        let mut scenario = test_scenario(&i.to_string(), &[(0.0, 100.0)], &[1.0, 4.0, 8.0]);
+        scenario.obs = engine.pred(scenario.clone(), vec![*ke, *v]);
+        scenario
+            .metadata
+            .insert("GROUP".to_string(), group.to_string());
+        scenario
+    })
+    .collect();
+
+    let groups =
+        fit_by_group_internal(engine.clone(), settings.clone(), scenarios.clone(), "GROUP")
+            .unwrap();
+    assert_eq!(groups.len(), 2);
+    assert_eq!(groups[0].0, "a");
+    assert_eq!(groups[1].0, "b");
+    let ids = |result: &output::NPResult| -> Vec<String> {
+        result.scenarios.iter().map(|s| s.id.clone()).collect()
+    };
+    assert_eq!(ids(&groups[0].1), ["0", "2"]);
+    assert_eq!(ids(&groups[1].1), ["1", "3"]);
+    assert_ne!(groups[0].1.objf, groups[1].1.objf);
+    let mean_ke = |result: &output::NPResult| output::population_mean(&result.theta, &result.w)[0];
+    assert!(mean_ke(&groups[0].1) < mean_ke(&groups[1].1));
+
+    let mut missing = scenarios;
+    missing[3].metadata.clear();
+    let err = fit_by_group_internal(engine, settings, missing, "GROUP").unwrap_err();
+    assert!(err.to_string().contains("Subject 3"));
+}
+
+#[test]
+fn error_magnitude_is_back_transformed() {
+    use output::CycleWriter;
+    use sigma::{ErrorPoly, ErrorType};
+
+    let close = |a: f64, b: f64| (a - b).abs() < 1e-12;
+    // Proportional: gamma scales both the additive and the proportional coefficient
+    let proportional = ErrorPoly::new((0.5, 0.1, 0.0, 0.0), 2.0, &ErrorType::Prop).magnitude();
+    assert!(close(proportional.sd, 1.0));
+    assert!(close(proportional.cv, 20.0));
+    // With a reference concentration, the error is the same for all observations
+    let reference = ErrorPoly::new((0.5, 0.1, 0.0, 0.0), 2.0, &ErrorType::Prop)
+        .with_reference(Some(10.0))
+        .magnitude();
+    assert!(close(reference.sd, 3.0));
+    assert!(close(reference.cv, 0.0));
+    // Additive: lambda is combined with C0, and the CV is given by C1 alone
+    let additive = ErrorPoly::new((0.3, 0.15, 0.0, 0.0), 0.4, &ErrorType::Add).magnitude();
+    assert!(close(additive.sd, 0.5));
+    assert!(close(additive.cv, 15.0));
+
+    let mut settings = test_settings(&[("ke", (0.1, 1.0))]);
+    settings.error.class = "proportional".to_string();
+    settings.error.poly = (0.0, 0.1, 0.0, 0.0);
+    assert_eq!(
+        settings
+            .error
+            .magnitude(settings.error.poly, 1.5)
+            .unwrap()
+            .to_string(),
+        "SD 0.0000, CV 15.00%"
+    );
+
+    let path = std::env::temp_dir().join(format!("npcore_magnitude_{}.csv", std::process::id()));
+    let mut writer = CycleWriter::new(
+        path.to_str().unwrap(),
+        vec!["ke".to_string()],
+        Some(3),
+        Some(settings.error.clone()),
+    );
+    let theta = ndarray::Array2::from_shape_vec((1, 1), vec![0.5]).unwrap();
+    writer.write(1, 100.0, 1.5, None, 0, 0, &theta);
+    // An optimized polynomial of the cycle replaces that of the settings
+    writer.write(2, 90.0, 1.5, Some((0.0, 0.2, 0.0, 0.0)), 0, 0, &theta);
+    writer.flush();
+    let written = std::fs::read_to_string(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    let mut lines = written.lines();
+    let header: Vec<&str> = lines.next().unwrap().split(',').collect();
+    assert_eq!(
+        &header[..5],
+        ["cycle", "neg2ll", "gamlam", "error.sd", "error.cv"]
+    );
+    let row: Vec<&str> = lines.next().unwrap().split(',').collect();
+    assert_eq!(&row[..5], ["1", "100", "1.5", "0", "15"]);
+    let row: Vec<&str> = lines.next().unwrap().split(',').collect();
+    assert_eq!(&row[..5], ["2", "90", "1.5", "0", "30"]);
+}
+
+#[test]
+fn continued_run_does_not_worsen_objf() {
+    let (engine, settings, scenarios) = short_fit_setup(500);
+    let (tx, _rx) = tokio::sync::mpsc::unbounded_channel();
+    let result = algorithms::initialize_algorithm(engine.clone(), settings, scenarios, tx)
+        .unwrap()
+        .fit();
+    assert!(result.converged);
+    let (objf, cycles) = (result.objf, result.cycles);
+
+    let continued = result.continue_run(engine, 10).unwrap();
+    assert!(continued.cycles > cycles && continued.cycles <= cycles + 10);
+    assert!(
+        continued.objf <= objf + 1e-6,
+        "objf {} -> {}",
+        objf,
+        continued.objf
+    );
+    let numbers: Vec<usize> = continued.cycle_log().iter().map(|r| r.cycle).collect();
+    assert_eq!(numbers, (1..=continued.cycles).collect::<Vec<_>>());
+}
+
+#[test]
+fn additional_doses_match_explicit_doses() {
+    let header = "ID,EVID,TIME,DUR,DOSE,ADDL,II,INPUT,OUT,OUTEQ,C0,C1,C2,C3\n";
+    let compact = "1,1,0,0,100,3,12,1,.,.,.,.,.,.\n\
+                   1,0,6,.,.,.,.,.,5,1,.,.,.,.\n\
+                   1,0,24,.,.,.,.,.,4,1,.,.,.,.\n\
+                   1,0,40,.,.,.,.,.,3,1,.,.,.,.\n";
+    let explicit = "1,1,0,0,100,.,.,1,.,.,.,.,.,.\n\
+                    1,0,6,.,.,.,.,.,5,1,.,.,.,.\n\
+                    1,1,12,0,100,.,.,1,.,.,.,.,.,.\n\
+                    1,0,24,.,.,.,.,.,4,1,.,.,.,.\n\
+                    1,1,24,0,100,.,.,1,.,.,.,.,.,.\n\
+                    1,1,36,0,100,.,.,1,.,.,.,.,.,.\n\
+                    1,0,40,.,.,.,.,.,3,1,.,.,.,.\n";
+    let parse = |name: &str, rows: &str| {
+        let path =
+            std::env::temp_dir().join(format!("npcore_addl_{}_{}.csv", name, std::process::id()));
+        std::fs::write(&path, format!("{}{}", header, rows)).unwrap();
+        let scenarios = datafile::parse(path.to_str().unwrap());
+        std::fs::remove_file(&path).unwrap();
+        scenarios
+    };
+    let compact = parse("compact", compact).unwrap();
+    let explicit = parse("explicit", explicit).unwrap();
+    let (compact, explicit) = (&compact[0], &explicit[0]);
+    assert_eq!(compact.times, [0.0, 6.0, 12.0, 24.0, 24.0, 36.0, 40.0]);
+    assert_eq!(compact.times, explicit.times);
+    assert_eq!(compact.obs_times, explicit.obs_times);
+    assert_eq!(compact.obs, explicit.obs);
+    let doses = |scenario: &Scenario| -> Vec<(f64, Option<f64>)> {
+        scenario
+            .blocks
+            .iter()
+            .flat_map(|block| block.events.iter())
+            .filter(|event| event.evid == 1)
+            .map(|event| (event.time, event.dose))
+            .collect()
+    };
+    assert_eq!(doses(compact), doses(explicit));
+    assert_eq!(doses(compact).len(), 4);
+
+    let engine = Engine::new(OneCompartment {});
+    assert_eq!(
+        engine.pred(compact.clone(), vec![0.1, 10.0]),
+        engine.pred(explicit.clone(), vec![0.1, 10.0])
+    );
+
+    // Additional doses require a positive interval
+    let err = parse(
+        "no_ii",
+        "1,1,0,0,100,3,.,1,.,.,.,.,.,.\n1,0,6,.,.,.,.,.,5,1,.,.,.,.\n",
+    )
+    .unwrap_err();
+    assert!(err.to_string().contains("positive II"));
+}
+
+#[test]
+fn negative_predictions_are_clamped_to_floor() {
+    use ndarray::array;
+    use sigma::{ErrorPoly, ErrorType, Sigma};
+
+    // A tiny negative baseline amount, as from numerical error, without any doses
+    let scenario = test_scenario("1", &[], &[1.0, 2.0, 4.0]);
+    let params = vec![0.1, 10.0, -1e-9];
+    let engine = Engine::new(OneCompartment {});
+    assert!(engine
+        .pred(scenario.clone(), params.clone())
+        .iter()
+        .all(|y| *y < 0.0));
+    assert_eq!(engine.take_clamped(), 0);
+
+    let engine = Engine::new(OneCompartment {}).with_clamp(Some(0.0), None);
+    let ypred = engine.pred(scenario.clone(), params.clone());
+    assert_eq!(ypred, [0.0, 0.0, 0.0]);
+    assert_eq!(engine.take_clamped(), 3);
+    assert_eq!(engine.take_clamped(), 0);
+
+    let yobs = array![0.01, 0.005, 0.001];
+    let sigma = ErrorPoly::new((0.0, 0.1, 0.0, 0.0), 0.001, &ErrorType::Add).sigma(&yobs);
+    let likelihood = prob::normal_likelihood(&ndarray::Array1::from(ypred), &yobs, &sigma);
+    assert!(likelihood.is_finite() && likelihood > 0.0);
+
+    // Predictions within the bounds are not changed
+    let engine = Engine::new(OneCompartment {}).with_clamp(Some(0.0), Some(1e6));
+    let scenario = test_scenario("1", &[(0.0, 100.0)], &[1.0, 2.0]);
+    assert_eq!(
+        engine.pred(scenario.clone(), vec![0.1, 10.0]),
+        Engine::new(OneCompartment {}).pred(scenario, vec![0.1, 10.0])
+    );
+    assert_eq!(engine.take_clamped(), 0);
+}
+
+#[test]
+fn snapshots_are_written_at_interval() {
+    use output::{CycleLog, NPCycle};
+
+    let dir = std::env::temp_dir().join(format!("npcore_snapshots_{}", std::process::id()));
+    let names = vec!["ke".to_string(), "v".to_string()];
+    let mut cycle_log = CycleLog::new(&names, Some(4)).with_snapshots(3, &dir);
+    for cycle in 1..=7 {
+        let theta =
+            ndarray::Array2::from_shape_vec((2, 2), vec![0.1, 10.0, 0.2 * cycle as f64, 20.0])
+                .unwrap();
+        cycle_log.push_and_write(
+            NPCycle {
+                cycle,
+                theta,
+                weights: ndarray::array![0.25, 0.75],
+                ..NPCycle::new()
+            },
+            false,
+        );
+    }
+    let mut files: Vec<String> = std::fs::read_dir(&dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+        .collect();
+    files.sort();
+    assert_eq!(files, ["theta_3.csv", "theta_6.csv"]);
+    let snapshot = std::fs::read_to_string(dir.join("theta_6.csv")).unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(snapshot, "ke,v,prob\n0.1,10,0.25\n1.2,20,0.75\n");
+    assert_eq!(cycle_log.cycles.len(), 7);
+}
+
+/// One-compartment model whose output drifts with the number of outputs computed so far, i.e. which is not deterministic
+#[cfg(test)]
+#[derive(Debug, Clone)]
+struct Drifting {}
+
+#[cfg(test)]
+static DRIFT: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+
+#[cfg(test)]
+impl<'a> Predict<'a> for Drifting {
+    type Model = OneCompartmentSystem;
+    type State = f64;
+    fn initial_system(&self, params: &Vec<f64>, scenario: Scenario) -> (Self::Model, Scenario) {
+        OneCompartment {}.initial_system(params, scenario)
+    }
+    fn initial_state(&self) -> Self::State {
+        0.0
+    }
+    fn add_covs(&self, _system: &mut Self::Model, _cov: Option<HashMap<String, CovLine>>) {}
+    fn add_infusion(&self, _system: &mut Self::Model, _infusion: Infusion) {}
+    fn add_dose(&self, state: &mut Self::State, dose: f64, _compartment: usize) {
+        *state += dose;
+    }
+    fn get_output(
+        &self,
+        time: f64,
+        state: &Self::State,
+        system: &Self::Model,
+        outeq: usize,
+    ) -> f64 {
+        let drift = DRIFT.fetch_add(1, std::sync::atomic::Ordering::Relaxed) as f64;
+        OneCompartment {}.get_output(time, state, system, outeq) + 1e-12 * drift
+    }
+    fn state_step(&self, state: &mut Self::State, system: &Self::Model, time: f64, next_time: f64) {
+        OneCompartment {}.state_step(state, system, time, next_time)
+    }
+}
+
+#[test]
+fn nondeterministic_predictions_are_detected() {
+    let mut scenarios = datafile::parse("examples/data/bimodal_ke.csv").unwrap();
+    scenarios.truncate(5);
+    let theta = sobol::generate(5, &vec![(0.001, 3.0), (25.0, 250.0)], 1348);
+    assert!(predict::predictions_are_deterministic(
+        &Engine::new(BimodalKe {}),
+        &scenarios,
+        &theta
+    ));
+    assert!(!predict::predictions_are_deterministic(
+        &Engine::new(Drifting {}),
+        &scenarios,
+        &theta
+    ));
+}
+
+#[test]
+fn objf_is_reported_without_normalization_constants() {
+    let (result, _) = short_fit(3);
+    let n_obs: usize = result.scenarios.iter().map(|s| s.obs.len()).sum();
+    assert_eq!(result.reported_objf(), result.objf);
+
+    let mut settings = result.settings.clone();
+    settings.config.likelihood_constants = false;
+    let without = output::NPResult::new(
+        result.scenarios.clone(),
+        result.theta.clone(),
+        result.psi.clone(),
+        result.w.clone(),
+        result.objf,
+        result.cycles,
+        result.converged,
+        settings,
+    );
+    // -2 ln(1/(2π)^0.5) = ln(2π) per observation
+    let expected = n_obs as f64 * (2.0 * std::f64::consts::PI).ln();
+    assert!((result.reported_objf() - without.reported_objf() - expected).abs() < 1e-9);
+    assert!((result.aic() - without.aic() - expected).abs() < 1e-9);
+    assert!(
+        (prob::log_normalization(&result.scenarios, None) + expected / 2.0).abs() < 1e-9,
+        "{}",
+        prob::log_normalization(&result.scenarios, None)
+    );
+}
+
+#[test]
+fn adaptive_eps_schedule_changes_cycle_count() {
+    let fit = |adaptive: bool| {
+        let (engine, mut settings, scenarios) = short_fit_setup(1000);
+        settings.config.adaptive_eps = adaptive;
+        let (tx, _rx) = tokio::sync::mpsc::unbounded_channel();
+        algorithms::initialize_algorithm(engine, settings, scenarios, tx)
+            .unwrap()
+            .fit()
+    };
+    let fixed = fit(false);
+    let adaptive = fit(true);
+    assert!(fixed.converged && adaptive.converged);
+    // The objective function converges within a few cycles at each resolution, so the adaptive schedule refines the grid faster
+    assert!(
+        adaptive.cycles < fixed.cycles,
+        "{} >= {}",
+        adaptive.cycles,
+        fixed.cycles
+    );
+    assert!((adaptive.objf - fixed.objf).abs() < 0.01);
+}
+
+#[test]
+fn predictions_are_annotated_with_doses() {
+    use datafile::EventType::{Dose, Interpolated, Observation};
+
+    let mut scenario = test_scenario("1", &[(0.0, 100.0), (12.0, 50.0)], &[2.0, 14.0]);
+    scenario.obs = vec![3.0, 4.0];
+    let expanded = scenario.add_event_interval(5.0, 0.0).add_dose_times();
+    assert_eq!(expanded.obs_times, [0.0, 2.0, 5.0, 10.0, 12.0, 14.0]);
+    assert_eq!(
+        expanded.obs_annotations(),
+        [
+            (Dose, Some(100.0)),
+            (Observation, Some(100.0)),
+            (Interpolated, Some(100.0)),
+            (Interpolated, Some(100.0)),
+            (Dose, Some(50.0)),
+            (Observation, Some(50.0)),
+        ]
+    );
+    // The predictions at the dose times include the doses
+    let engine = Engine::new(OneCompartment {});
+    let pred = engine.pred(expanded, vec![0.1, 10.0]);
+    assert!((pred[0] - 10.0).abs() < 1e-12);
+
+    // Observations before the first dose have no last dose
+    let scenario = test_scenario("2", &[(6.0, 100.0)], &[1.0, 8.0]);
+    let annotations = scenario.add_dose_times().obs_annotations();
+    assert_eq!(annotations[0].1, None);
+    assert_eq!(annotations[1], (Dose, Some(100.0)));
+}
+
+#[test]
+fn covariates_on_dose_rows_are_interpolated() {
+    let data = "ID,EVID,TIME,DUR,DOSE,ADDL,II,INPUT,OUT,OUTEQ,C0,C1,C2,C3,WT\n\
+                1,0,0,.,.,.,.,.,1,1,.,.,.,.,.\n\
+                1,1,1,0,100,.,.,1,.,.,.,.,.,.,70\n\
+                1,0,4,.,.,.,.,.,5,1,.,.,.,.,.\n\
+                1,0,7,.,.,.,.,.,4,1,.,.,.,.,.\n\
+                1,1,13,0,100,.,.,1,.,.,.,.,.,.,82\n\
+                1,0,15,.,.,.,.,.,6,1,.,.,.,.,.\n";
+    let path = std::env::temp_dir().join(format!("npcore_dose_covs_{}.csv", std::process::id()));
+    std::fs::write(&path, data).unwrap();
+    let scenarios = datafile::parse(path.to_str().unwrap()).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    // The covariate at each observation, from the line of the block containing it
+    let at_obs: Vec<f64> = scenarios[0]
+        .blocks
+        .iter()
+        .flat_map(|block| {
+            block
+                .events
+                .iter()
+                .filter(|event| event.evid == 0)
+                .map(move |event| block.covs["WT"].interp(event.time))
+        })
+        .collect();
+    // Before the first dose, the first value is used, and between doses the value is interpolated
+    let expected = [70.0, 73.0, 76.0, 82.0];
+    assert_eq!(at_obs.len(), expected.len());
+    for (value, expected) in at_obs.iter().zip(expected) {
+        assert!(
+            (value - expected).abs() < 1e-12,
+            "{} != {}",
+            value,
+            expected
+        );
+    }
+}
+
+#[test]
+fn covariates_before_the_first_measurement_use_the_first_value() {
+    let data = "ID,EVID,TIME,DUR,DOSE,ADDL,II,INPUT,OUT,OUTEQ,C0,C1,C2,C3,WT\n\
+                1,0,0,.,.,.,.,.,1,1,.,.,.,.,.\n\
+                1,0,1,.,.,.,.,.,5,1,.,.,.,.,70\n\
+                1,1,2,0,100,.,.,1,.,.,.,.,.,.,.\n\
+                1,0,4,.,.,.,.,.,4,1,.,.,.,.,.\n\
+                1,1,6,0,100,.,.,1,.,.,.,.,.,.,80\n\
+                1,0,8,.,.,.,.,.,6,1,.,.,.,.,.\n";
+    let path = std::env::temp_dir().join(format!("npcore_early_covs_{}.csv", std::process::id()));
+    std::fs::write(&path, data).unwrap();
+    let scenarios = datafile::parse(path.to_str().unwrap()).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    let blocks = &scenarios[0].blocks;
+    assert_eq!(blocks.len(), 3);
+    // The observations at t=0 and t=1 precede the first dose, and the covariate is first measured at t=1
+    assert_eq!(blocks[0].covs["WT"].interp(0.0), 70.0);
+    assert_eq!(blocks[0].covs["WT"].interp(1.0), 70.0);
+    // Between doses the value is interpolated, and not extrapolated before the start of the block
+    assert!((blocks[1].covs["WT"].interp(4.0) - 75.0).abs() < 1e-12);
+    assert!((blocks[1].covs["WT"].interp(0.0) - 70.0).abs() < 1e-12);
+    assert_eq!(blocks[2].covs["WT"].interp(8.0), 80.0);
+}
+
+#[test]
+fn scenarios_are_built_from_events() {
+    let (engine, mut settings, _) = short_fit_setup(5);
+    settings.config.init_points = 20;
+    let event = |id: &str, evid: isize, time: f64| Event {
+        id: id.to_string(),
+        evid,
+        time,
+        dur: None,
+        dose: None,
+        addl: None,
+        ii: None,
+        input: None,
+        out: None,
+        interval: None,
+        outeq: None,
+        _c0: None,
+        _c1: None,
+        _c2: None,
+        _c3: None,
+        covs: HashMap::new(),
+    };
+    let observation = |id: &str, time: f64| Event {
+        out: Some(0.0),
+        outeq: Some(1),
+        ..event(id, 0, time)
+    };
+    let scenarios: Vec<Scenario> = [(0.1, 10.0), (0.3, 30.0)]
+        .iter()
+        .enumerate()
+        .map(|(i, &(ke, v))| {
+            let id = i.to_string();
+            // Out of order, and with a repeated dose given as ADDL
+            let events = vec![
+                observation(&id, 8.0),
+                Event {
+                    dose: Some(100.0),
+                    input: Some(1),
+                    addl: Some(1),
+                    ii: Some(6.0),
+                    ..event(&id, 1, 0.0)
+                },
+                observation(&id, 1.0),
+                observation(&id, 4.0),
+            ];
+            let mut scenario = Scenario::from_events(&id, events).unwrap();
+            assert_eq!(scenario.obs_times, vec![1.0, 4.0, 8.0]);
+            scenario.obs = engine.pred(scenario.clone(), vec![ke, v]);
+            scenario
+        })
+        .collect();
+    assert_eq!(
+        scenarios[0].times,
+        vec![0.0, 1.0, 4.0, 6.0, 8.0],
+        "The additional dose is expanded"
+    );
+
+    let (tx, _rx) = tokio::sync::mpsc::unbounded_channel();
+    let result = algorithms::initialize_algorithm(engine, settings, scenarios, tx)
+        .unwrap()
+        .fit();
+    assert!(result.objf.is_finite());
+    assert_eq!(result.scenarios.len(), 2);
+
+    // Events of another subject, and doses without an amount, are rejected
+    assert!(Scenario::from_events("0", vec![observation("1", 1.0)]).is_err());
+    assert!(Scenario::from_events("0", vec![event("0", 1, 0.0)]).is_err());
+    assert!(Scenario::from_events("0", vec![]).is_err());
+}
+
+#[test]
+fn reported_objf_does_not_increase() {
+    let (result, cycles) = short_fit(20);
+    assert!(cycles.len() > 2);
+    for pair in cycles.windows(2) {
+        assert!(
+            pair[1].objf <= pair[0].objf + 1e-6,
+            "-2LL increased from {} to {} in cycle {}",
+            pair[0].objf,
+            pair[1].objf,
+            pair[1].cycle
+        );
+        assert!((pair[1].delta_objf - (pair[0].objf - pair[1].objf).abs()).abs() < 1e-6);
+    }
+    assert_eq!(result.objf, cycles.last().unwrap().objf);
+}
+
+#[test]
+fn time_offsets_align_subjects() {
+    let data = "\
+ID,EVID,TIME,DUR,DOSE,ADDL,II,INPUT,OUT,OUTEQ,C0,C1,C2,C3,TOFFSET
+1,1,0,0,100,.,.,1,.,.,.,.,.,.,.
+1,0,1,.,.,.,.,.,9.05,1,.,.,.,.,.
+1,0,4,.,.,.,.,.,6.7,1,.,.,.,.,.
+2,1,100,0,100,1,12,1,.,.,.,.,.,.,100
+2,0,101,.,.,.,.,.,9.05,1,.,.,.,.,.
+2,0,104,.,.,.,.,.,6.7,1,.,.,.,.,100
+";
+    let path = std::env::temp_dir().join(format!("npcore_offset_{}.csv", std::process::id()));
+    std::fs::write(&path, data).unwrap();
+    let scenarios = datafile::parse(&path.to_str().unwrap().to_string()).unwrap();
+    assert_eq!(scenarios[0].times, vec![0.0, 1.0, 4.0]);
+    assert_eq!(scenarios[1].obs_times, scenarios[0].obs_times);
+    // The additional dose is given on the common clock too
+    assert_eq!(scenarios[1].times, vec![0.0, 1.0, 4.0, 12.0]);
+    assert!(!scenarios[1].metadata.contains_key("TOFFSET"));
+
+    // Different offsets within a subject are rejected
+    std::fs::write(
+        &path,
+        data.replace(
+            "104,.,.,.,.,.,6.7,1,.,.,.,.,100",
+            "104,.,.,.,.,.,6.7,1,.,.,.,.,90",
+        ),
+    )
+    .unwrap();
+    let result = datafile::parse(&path.to_str().unwrap().to_string());
+    std::fs::remove_file(&path).unwrap();
+    assert!(result.is_err());
+}
+
+#[test]
+fn effective_support_counts_posterior_modes() {
+    use ndarray::array;
+
+    let settings = test_settings(&[("ke", (0.01, 1.0)), ("v", (1.0, 50.0))]);
+    let scenarios = vec![
+        test_scenario("1", &[(0.0, 100.0)], &[1.0]),
+        test_scenario("2", &[(0.0, 100.0)], &[1.0]),
+    ];
+    let theta = array![[0.1, 10.0], [0.3, 10.0]];
+    let w = array![0.5, 0.5];
+    // The first subject clearly matches the first support point, the second cannot distinguish them
+    let psi = array![[1.0, 1e-12], [1.0, 1.0]];
+    let result = output::NPResult::new(scenarios, theta, psi, w, 0.0, 1, true, settings);
+
+    let effective = result.effective_support_per_subject();
+    assert!((effective[0] - 1.0).abs() < 1e-6, "{}", effective[0]);
+    assert!((effective[1] - 2.0).abs() < 1e-12, "{}", effective[1]);
+}
+
+/// Error model with a standard deviation proportional to the square root of the observation, which the error polynomial cannot express
+#[cfg(test)]
+struct SqrtSigma;
+
+#[cfg(test)]
+impl sigma::Sigma for SqrtSigma {
+    fn sigma(&self, yobs: &ndarray::Array1<f64>) -> ndarray::Array1<f64> {
+        yobs.mapv(|y| 0.2 * y.abs().sqrt() + 0.01)
+    }
+}
+
+#[test]
+fn custom_sigma_replaces_error_polynomial() {
+    let (engine, settings, scenarios) = short_fit_setup(10);
+    let gamma = settings.error.value;
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    let mut algorithm =
+        algorithms::initialize_algorithm(engine.clone(), settings, scenarios.clone(), tx).unwrap();
+    algorithm.set_sigma(Box::new(SqrtSigma));
+    let result = algorithm.fit();
+
+    // The likelihoods are those of the custom error model
+    let psi = prob::simulate_psi(
+        &engine,
+        &scenarios,
+        &result.theta,
+        &[&SqrtSigma],
+        false,
+        None,
+    )
+    .remove(0);
+    assert_eq!(psi.dim(), result.psi.dim());
+    for (a, b) in psi.iter().zip(result.psi.iter()) {
+        assert!(
+            (a - b).abs() <= 1e-9 * a.abs().max(1e-300),
+            "{} != {}",
+            a,
+            b
+        );
+    }
+
+    // Gamma is not optimized
+    let mut cycles = 0;
+    while let Ok(msg) = rx.try_recv() {
+        if let Comm::NPCycle(cycle) = msg {
+            assert_eq!(cycle.gamlam, gamma);
+            cycles += 1;
+        }
+    }
+    assert!(cycles > 0);
+}
+
+#[test]
+fn continued_run_keeps_custom_sigma() {
+    let (engine, settings, scenarios) = short_fit_setup(5);
+    let gamma = settings.error.value;
+    let (tx, _rx) = tokio::sync::mpsc::unbounded_channel();
+    let mut algorithm =
+        algorithms::initialize_algorithm(engine.clone(), settings, scenarios.clone(), tx).unwrap();
+    algorithm.set_sigma(Box::new(SqrtSigma));
+    let result = algorithm.fit();
+    let cycles = result.cycles;
+
+    let continued = result
+        .continue_run_with_sigma(engine.clone(), 3, Box::new(SqrtSigma))
+        .unwrap();
+    assert!(continued.cycles > cycles);
+    assert!(continued.cycle_log().iter().all(|r| r.gamlam == gamma));
+    let psi = prob::simulate_psi(
+        &engine,
+        &scenarios,
+        &continued.theta,
+        &[&SqrtSigma],
+        false,
+        None,
+    )
+    .remove(0);
+    for (a, b) in psi.iter().zip(continued.psi.iter()) {
+        assert!(
+            (a - b).abs() <= 1e-9 * a.abs().max(1e-300),
+            "{} != {}",
+            a,
+            b
+        );
+    }
+
+    // A volume which is not one of the parameters is an error, not a panic
+    let (_, mut settings, _) = short_fit_setup(5);
+    settings.outputs.push(settings::OutputEquation {
+        outeq: 1,
+        name: None,
+        unit: None,
+        scale: 1.0,
+        volume: Some("vd".to_string()),
+        compartment: 0,
+    });
+    let theta = ndarray::Array2::from_elem((1, 2), 0.5);
+    let psi = ndarray::Array2::ones((scenarios.len(), 1));
+    let w = ndarray::array![1.0];
+    let result = output::NPResult::new(scenarios, theta, psi, w, 0.0, 1, true, settings);
+    let err = result.continue_run(engine, 3).unwrap_err();
+    assert!(err.to_string().contains("vd"), "{}", err);
+}
+
+#[test]
+fn observations_on_dose_rows_follow_policy() {
+    use datafile::DoseRowObservations;
+
+    let data = "\
+ID,EVID,TIME,DUR,DOSE,ADDL,II,INPUT,OUT,OUTEQ,C0,C1,C2,C3
+1,1,0,0,100,.,.,1,.,.,.,.,.,.
+1,0,4,.,.,.,.,.,6.7,1,.,.,.,.
+1,1,12,0,100,.,.,1,2.1,1,.,.,.,.
+1,0,16,.,.,.,.,.,8.8,1,.,.,.,.
+";
+    let path = std::env::temp_dir().join(format!("npcore_dose_row_{}.csv", std::process::id()));
+    let parse = |data: &str, policy| {
+        std::fs::write(&path, data).unwrap();
+        datafile::parse_with(path.to_str().unwrap(), policy)
+    };
+
+    let ignored = parse(data, DoseRowObservations::Ignore).unwrap();
+    assert_eq!(ignored[0].obs, vec![6.7, 8.8]);
+    assert_eq!(ignored[0].obs_times, vec![4.0, 16.0]);
+
+    // The observation is taken before the dose at the same time
+    let split = parse(data, DoseRowObservations::Split).unwrap();
+    assert_eq!(split[0].obs, vec![6.7, 2.1, 8.8]);
+    assert_eq!(split[0].obs_times, vec![4.0, 12.0, 16.0]);
+    let events: Vec<isize> = split[0]
+        .blocks
+        .iter()
+        .flat_map(|block| block.events.iter().map(|event| event.evid))
+        .collect();
+    assert_eq!(events, vec![1, 0, 0, 1, 0]);
+
+    // MDV 1 on the dose row discards its observation, and MDV 0 requires it to be split
+    let with_mdv = |mdv: [&str; 4]| {
+        let mut lines = data.lines();
+        let mut data = format!("{},MDV\n", lines.next().unwrap());
+        for (line, mdv) in lines.zip(mdv) {
+            data.push_str(&format!("{},{}\n", line, mdv));
+        }
+        data
+    };
+    let discarded = parse(&with_mdv(["1", "0", "1", "0"]), DoseRowObservations::Split).unwrap();
+    assert_eq!(discarded[0].obs, vec![6.7, 8.8]);
+    assert!(parse(&with_mdv(["1", "0", "0", "0"]), DoseRowObservations::Ignore).is_err());
+    assert_eq!(
+        parse(&with_mdv(["1", "0", "0", "0"]), DoseRowObservations::Split).unwrap()[0].obs,
+        vec![6.7, 2.1, 8.8]
+    );
+    assert!(parse(&with_mdv(["1", "1", "1", "0"]), DoseRowObservations::Ignore).is_err());
+    assert!(parse(&with_mdv(["1", "0", "2", "0"]), DoseRowObservations::Ignore).is_err());
+    std::fs::remove_file(&path).unwrap();
+
+    assert_eq!(
+        DoseRowObservations::from_name("Split"),
+        Ok(DoseRowObservations::Split)
+    );
+    assert!(DoseRowObservations::from_name("keep").is_err());
+}
+
+#[test]
+fn thread_pool_honors_thread_count() {
+    use simulation::stats;
+
+    let pool = stats::thread_pool(3).build().unwrap();
+    assert_eq!(pool.current_num_threads(), 3);
+    assert_eq!(pool.install(rayon::current_num_threads), 3);
+
+    // The pool runs on as many distinct worker threads
+    let threads = std::sync::Mutex::new(std::collections::HashSet::new());
+    pool.broadcast(|_| {
+        threads.lock().unwrap().insert(std::thread::current().id());
+    });
+    assert_eq!(threads.lock().unwrap().len(), 3);
+
+    assert_eq!(stats::parallel_efficiency(6, 3), 1.0);
+    assert_eq!(stats::parallel_efficiency(4, 3), 4.0 / 6.0);
+    assert_eq!(stats::parallel_efficiency(2, 8), 0.25);
+    assert_eq!(stats::parallel_efficiency(0, 8), 0.0);
+}
+
+#[test]
+fn observations_outside_windows_are_removed() {
+    let engine = Engine::new(OneCompartment {});
+    let mut settings = test_settings(&[("ke", (0.05, 0.5)), ("v", (5.0, 50.0))]);
+    let theta = ndarray::array![[0.1, 10.0], [0.3, 30.0]];
+    let simulated = |id: &str, obs_times: &[f64]| {
+        let mut scenario = test_scenario(id, &[(0.0, 100.0), (12.0, 100.0)], obs_times);
+        scenario.obs = engine.pred(scenario.clone(), vec![0.2, 20.0]);
+        scenario
+    };
+    // The observations at 6 and 8 are disturbed
+    let mut disturbed = simulated("1", &[1.0, 4.0, 6.0, 8.0, 13.0, 16.0]);
+    disturbed.obs[2] *= 10.0;
+    disturbed.obs[3] *= 10.0;
+    let mut scenarios = vec![disturbed, simulated("2", &[1.0, 4.0, 6.0])];
+
+    let windows = [(0.0, 5.0), (12.0, 24.0)];
+    let subject_windows = HashMap::from([("2".to_string(), vec![(0.0, 24.0)])]);
+    let removed = datafile::filter_observation_windows(
+        &mut scenarios,
+        Some(&windows),
+        Some(&subject_windows),
+    );
+    assert_eq!(removed, 2);
+    assert_eq!(scenarios[0].obs_times, vec![1.0, 4.0, 13.0, 16.0]);
+    assert_eq!(scenarios[1].obs_times, vec![1.0, 4.0, 6.0]);
+    // The doses are kept, also the one outside the windows
+    assert_eq!(scenarios[0].times, vec![0.0, 1.0, 4.0, 12.0, 13.0, 16.0]);
+
+    // The likelihood is that of the observations within the windows only
+    let psi = |scenarios: &[Scenario]| {
+        let sigma = sigma::ErrorPoly::new((0.1, 0.1, 0.0, 0.0), 1.0, &sigma::ErrorType::Add);
+        prob::simulate_psi(&engine, scenarios, &theta, &[&sigma], false, None).remove(0)
+    };
+    let expected = psi(&[simulated("1", &[1.0, 4.0, 13.0, 16.0])]);
+    assert_eq!(psi(&scenarios[..1]), expected);
+
+    settings.config.observation_windows = Some(vec![(5.0, 1.0)]);
+    assert!(settings.config.validate(&settings.random).is_err());
+}
+
+#[test]
+fn datafile_errors_are_reported() {
+    use datafile::DatafileError;
+
+    let header = "ID,EVID,TIME,DUR,DOSE,ADDL,II,INPUT,OUT,OUTEQ,C0,C1,C2,C3\n";
+    let path = std::env::temp_dir().join(format!("npcore_errors_{}.csv", std::process::id()));
+    let parse = |data: &str| {
+        std::fs::write(&path, data).unwrap();
+        datafile::parse(path.to_str().unwrap())
+    };
+
+    let missing = datafile::parse("does/not/exist.csv").unwrap_err();
+    assert!(matches!(missing, DatafileError::Io { .. }), "{:?}", missing);
+
+    let extra_field = format!("{}1,1,0,0,100,.,.,1,.,.,.,.,.,.,5\n", header);
+    let err = parse(&extra_field).unwrap_err();
+    assert!(matches!(err, DatafileError::Csv(_)), "{:?}", err);
+
+    let invalid_time = format!(
+        "{}1,1,0,0,100,.,.,1,.,.,.,.,.,.\n1,0,soon,.,.,.,.,.,9.05,1,.,.,.,.\n",
+        header
+    );
+    match parse(&invalid_time).unwrap_err() {
+        DatafileError::Validation { line, column, .. } => {
+            assert_eq!(line, Some(3));
+            assert_eq!(column.as_deref(), Some("TIME"));
+        }
+        err => panic!("Expected a validation error, got {:?}", err),
+    }
+    let err = parse(&invalid_time).unwrap_err();
+    assert!(
+        err.to_string().starts_with("Line 3, column TIME:"),
+        "{}",
+        err
+    );
+
+    let missing_column = "ID,EVID,TIME,DOSE\n1,1,0,100\n";
+    match parse(missing_column).unwrap_err() {
+        DatafileError::Validation { column, .. } => assert_eq!(column.as_deref(), Some("DUR")),
+        err => panic!("Expected a validation error, got {:?}", err),
+    }
+
+    let err = parse("").unwrap_err();
+    assert!(matches!(err, DatafileError::Empty), "{:?}", err);
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn manifest_records_seed_and_data_hash() {
+    let data = "\
+ID,EVID,TIME,DUR,DOSE,ADDL,II,INPUT,OUT,OUTEQ,C0,C1,C2,C3
+1,1,0,0,100,.,.,1,.,.,.,.,.,.
+1,0,1,.,.,.,.,.,9.05,1,.,.,.,.
+";
+    let dir = std::env::temp_dir();
+    let data_path = dir.join(format!("npcore_manifest_{}.csv", std::process::id()));
+    let manifest_path = dir.join(format!("npcore_manifest_{}.json", std::process::id()));
+    std::fs::write(&data_path, data).unwrap();
+
+    let mut settings = test_settings(&[("ke", (0.05, 0.5)), ("v", (5.0, 50.0))]);
+    settings.paths.data = data_path.to_str().unwrap().to_string();
+    settings.config.seed = 1234;
+    let fitted = |scenarios, settings| {
+        output::NPResult::new(
+            scenarios,
+            ndarray::array![[0.1, 10.0]],
+            ndarray::array![[1.0]],
+            ndarray::array![1.0],
+            0.0,
+            1,
+            true,
+            settings,
+        )
+    };
+    let result = fitted(
+        datafile::parse(&settings.paths.data).unwrap(),
+        settings.clone(),
+    )
+    .with_data_hash(output::hash_file(&settings.paths.data));
+    // The data is hashed when it is read, not when the manifest is written
+    std::fs::write(
+        &data_path,
+        format!("{}1,0,2,.,.,.,.,.,8.2,1,.,.,.,.\n", data),
+    )
+    .unwrap();
+    result
+        .write_manifest(manifest_path.to_str().unwrap())
+        .unwrap();
+    let manifest: output::RunManifest =
+        serde_json::from_reader(std::fs::File::open(&manifest_path).unwrap()).unwrap();
+    std::fs::remove_file(&data_path).unwrap();
+    std::fs::remove_file(&manifest_path).unwrap();
+
+    assert_eq!(manifest.seed, 1234);
+    assert_eq!(
+        manifest.data_hash.as_deref(),
+        Some("8c2e5bfa105c6361f3d3a9c3ca278d146cb0496bb84786d2682c4955b10dfbdf")
+    );
+    assert_eq!(manifest.npcore_version, env!("CARGO_PKG_VERSION"));
+    assert!(manifest.converged);
+    assert_eq!(manifest.settings_hash.len(), 64);
+    // The settings hash changes with the settings, and only then
+    let same = fitted(vec![], settings.clone()).manifest();
+    assert_eq!(same.settings_hash, manifest.settings_hash);
+    assert_eq!(same.data_hash, None);
+    settings.config.seed = 4321;
+    let other = fitted(vec![], settings).manifest();
+    assert_ne!(other.settings_hash, manifest.settings_hash);
+}
+
+#[test]
+fn student_t_likelihood_downweights_outliers() {
+    use sigma::{ErrorPoly, ErrorType, Sigma};
+    let yobs = ndarray::array![1.0, 1.1, 0.9, 1.0, 10.0];
+    let error = |df| ErrorPoly::new((0.3, 0.0, 0.0, 0.0), 0.0, &ErrorType::Add).with_df(df);
+    // The prediction, constant over the observations, which maximizes the likelihood
+    let best = |sigma: &ErrorPoly| {
+        (0..=500)
+            .map(|i| i as f64 / 50.0)
+            .map(|pred| {
+                let ypred = ndarray::Array1::from_elem(yobs.len(), pred);
+                let ll = prob::residual_likelihood(&ypred, &yobs, &sigma.sigma(&yobs), sigma.df());
+                (pred, ll)
+            })
+            .max_by(|a, b| a.1.total_cmp(&b.1))
+            .unwrap()
+            .0
+    };
+    // The normal likelihood is maximized by the mean, which is pulled towards the outlier
+    let normal = best(&error(None));
+    assert!((normal - 2.8).abs() < 0.02, "{}", normal);
+    let robust = best(&error(Some(4.0)));
+    assert!((robust - 1.0).abs() < 0.1, "{}", robust);
+
+    // The Student-t density integrates to one
+    let dx = 0.01;
+    let total: f64 = (-100_000..=100_000)
+        .map(|i| {
+            prob::student_t_likelihood(
+                &ndarray::array![0.0],
+                &ndarray::array![i as f64 * dx],
+                &ndarray::array![2.0],
+                4.0,
+            ) * dx
+        })
+        .sum();
+    assert!((total - 1.0).abs() < 1e-3, "{}", total);
+
+    // The CDF at known quantiles, of the Cauchy distribution and the 95th percentile with 4 degrees of freedom
+    assert!((prob::student_t_cdf(1.0, 1.0) - 0.75).abs() < 1e-12);
+    assert!((prob::student_t_cdf(-1.0, 1.0) - 0.25).abs() < 1e-12);
+    assert!((prob::student_t_cdf(2.131846786, 4.0) - 0.95).abs() < 1e-9);
+    assert_eq!(prob::student_t_cdf(0.0, 4.0), 0.5);
+    assert!((prob::student_t_cdf(1.5, 1e6) - prob::normal_cdf(1.5)).abs() < 1e-6);
+
+    // A narrow censoring interval has the probability of the Student-t density times its width
+    let (ypred, yobs, sigma) = (
+        ndarray::array![3.0],
+        ndarray::array![4.2],
+        ndarray::array![0.8],
+    );
+    let width = 1e-4;
+    let density = prob::student_t_likelihood(&ypred, &yobs, &sigma, 4.0);
+    let interval = [Some((4.2 - width / 2.0, 4.2 + width / 2.0))];
+    let probability = prob::censored_likelihood(&ypred, &yobs, &sigma, &interval, Some(4.0));
+    assert!((probability / (density * width) - 1.0).abs() < 1e-6);
+
+    let mut settings = test_settings(&[("ke", (0.1, 1.0))]);
+    settings.error.df = Some(0.0);
+    assert!(settings.error.validate().is_err());
+    settings.error.df = Some(4.0);
+    assert!(settings.error.validate().is_ok());
+}
+
+#[test]
+fn initial_samplers_concentrate_parameters() {
+    let config = std::fs::read_to_string("src/tests/config.toml").unwrap()
+        + "\n[config.init_samplers]\nka = { kind = \"normal\", mean = 0.3, sd = 0.05 }\nv = { kind = \"sobol\" }\n";
+    let path = std::env::temp_dir().join(format!("npcore_samplers_{}.toml", std::process::id()));
+    std::fs::write(&path, config).unwrap();
+    let settings = settings::parse_settings(path.to_str().unwrap(), vec![]).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert!(settings.config.validate(&settings.random).is_ok());
+
+    // The columns are ka, ke and v, of which only ka is concentrated
+    let ranges = settings.random.ranges();
+    let theta = initialization::sample_space(&settings, &ranges);
+    assert_eq!(theta.nrows(), 500);
+    let ka = theta.column(0);
+    let mean = ka.mean().unwrap();
+    let sd = ka.std(1.0);
+    assert!((mean - 0.3).abs() < 0.01, "{}", mean);
+    assert!((sd - 0.05).abs() < 0.01, "{}", sd);
+    assert!(ka.iter().all(|x| (0.1..=0.9).contains(x)));
+    // The other parameters still cover their ranges uniformly, as a Sobol sequence without the concentrated dimension
+    let uniform = sobol::generate(500, &vec![(0.001, 0.1), (30.0, 120.0)], 347);
+    assert_eq!(theta.slice(ndarray::s![.., 1..]), uniform);
+
+    // Samplers must refer to random parameters, and concentrate within their range
+    let mut invalid = settings.clone();
+    let sampler = initialization::Sampler::Normal { mean: 2.0, sd: 0.1 };
+    invalid.config.init_samplers = Some(HashMap::from([("ka".to_string(), sampler)]));
+    assert!(invalid.config.validate(&invalid.random).is_err());
+    invalid.config.init_samplers = Some(HashMap::from([("tlag".to_string(), sampler)]));
+    assert!(invalid.config.validate(&invalid.random).is_err());
+}
+
+#[test]
+fn stop_reason_records_why_the_run_stopped() {
+    use crate::tui::control::{Control, RunControl};
+    use convergence::{check_stop, StopReason};
+
+    let (engine, settings, scenarios) = short_fit_setup(500);
+    let (tx, _rx) = tokio::sync::mpsc::unbounded_channel();
+    let result =
+        algorithms::initialize_algorithm(engine.clone(), settings.clone(), scenarios.clone(), tx)
+            .unwrap()
+            .fit();
+    assert!(result.converged);
+    assert_eq!(result.stop_reason(), Some(StopReason::Converged));
+    assert_eq!(result.manifest().stop_reason, Some(StopReason::Converged));
+
+    let (result, _) = short_fit(2);
+    assert!(!result.converged);
+    assert_eq!(result.stop_reason(), Some(StopReason::MaxCycles));
+
+    // A stop requested before the run starts stops it after the first cycle
+    let control = RunControl::new();
+    control.send(Control::Stop);
+    let (tx, _rx) = tokio::sync::mpsc::unbounded_channel();
+    let mut algorithm =
+        algorithms::initialize_algorithm(engine.clone(), settings.clone(), scenarios.clone(), tx)
+            .unwrap();
+    algorithm.set_control(control);
+    let result = algorithm.fit();
+    assert_eq!(result.cycles, 1);
+    assert_eq!(result.stop_reason(), Some(StopReason::Requested));
+
+    // A time limit which has passed stops the run after the first cycle
+    let mut limited = settings.clone();
+    limited.config.time_limit = Some(1e-9);
+    let (tx, _rx) = tokio::sync::mpsc::unbounded_channel();
+    let result = algorithms::initialize_algorithm(engine.clone(), limited, scenarios.clone(), tx)
+        .unwrap()
+        .fit();
+    assert_eq!(result.cycles, 1);
+    assert_eq!(result.stop_reason(), Some(StopReason::TimeLimit));
+    let mut invalid = settings.clone();
+    invalid.config.time_limit = Some(0.0);
+    assert!(invalid.config.validate(&invalid.random).is_err());
+
+    // NPOD also records convergence
+    let mut npod = settings.clone();
+    npod.config.engine = "NPOD".to_string();
+    let (tx, _rx) = tokio::sync::mpsc::unbounded_channel();
+    let result = algorithms::initialize_algorithm(engine, npod, scenarios, tx)
+        .unwrap()
+        .fit();
+    assert!(result.converged);
+    assert_eq!(result.stop_reason(), Some(StopReason::Converged));
+
+    // The stop file is checked after a requested stop, and only stops a run which is otherwise continued
+    let stop_file = std::env::temp_dir().join(format!("npcore_stop_{}", std::process::id()));
+    let control = RunControl::new();
+    assert_eq!(check_stop(1, 10, &control, &stop_file, None), None);
+    std::fs::write(&stop_file, "").unwrap();
+    assert_eq!(
+        check_stop(1, 10, &control, &stop_file, None),
+        Some(StopReason::StopFile)
+    );
+    assert_eq!(
+        check_stop(10, 10, &control, &stop_file, None),
+        Some(StopReason::MaxCycles)
+    );
+    std::fs::remove_file(&stop_file).unwrap();
+    let passed = Some(std::time::Instant::now());
+    assert_eq!(
+        check_stop(1, 10, &control, &stop_file, passed),
+        Some(StopReason::TimeLimit)
+    );
+    assert_eq!(
+        check_stop(
+            1,
+            10,
+            &control,
+            &stop_file,
+            convergence::deadline(Some(3600.0))
+        ),
+        None
+    );
+}
+
+#[test]
+fn subject_weights_scale_influence() {
+    // Doubling the weight of a subject is equivalent to duplicating it
+    let psi = ndarray::array![[0.9, 0.1, 0.3], [0.2, 0.8, 0.4], [0.3, 0.3, 0.9]];
+    let weights = ndarray::array![2.0, 1.0, 1.0];
+    let (lambda, objf) = ipm::burke_weighted(&psi, &weights, 1e-10, 1000).unwrap();
+    let duplicated = ndarray::concatenate![ndarray::Axis(0), psi.slice(ndarray::s![0..1, ..]), psi];
+    let (expected, expected_objf) = ipm::burke_with(&duplicated, 1e-10, 1000).unwrap();
+    assert!(
+        (&lambda - &expected).mapv(f64::abs).sum() < 1e-6,
+        "{} != {}",
+        lambda,
+        expected
+    );
+    assert!((objf - expected_objf).abs() < 1e-6);
+    let (unweighted, _) = ipm::burke(&psi).unwrap();
+    assert!(lambda[0] > unweighted[0]);
+    let ones = ndarray::Array1::ones(3);
+    let (lambda, objf) = ipm::burke_weighted(&psi, &ones, ipm::DEFAULT_TOL, 1000).unwrap();
+    let (expected, expected_objf) = ipm::burke(&psi).unwrap();
+    assert!((&lambda - &expected).mapv(f64::abs).sum() < 1e-12);
+    assert!((objf - expected_objf).abs() < 1e-12);
+    assert!(ipm::burke_weighted(&psi, &ndarray::array![1.0, 0.0, 1.0], 1e-10, 1000).is_err());
+
+    // Doubling the weight of the subject with the lowest ke pulls the population mean of ke towards it
+    let mean_ke = |weight: Option<f64>| {
+        let (engine, mut settings, scenarios) = short_fit_setup(50);
+        settings.config.subject_weights =
+            weight.map(|weight| HashMap::from([("0".to_string(), weight)]));
+        assert!(settings.config.validate(&settings.random).is_ok());
+        let (tx, _rx) = tokio::sync::mpsc::unbounded_channel();
+        let result = algorithms::initialize_algorithm(engine, settings, scenarios, tx)
+            .unwrap()
+            .fit();
+        result.w.dot(&result.theta.column(0))
+    };
+    let unweighted = mean_ke(None);
+    assert!((unweighted - mean_ke(Some(1.0))).abs() < 1e-9);
+    let weighted = mean_ke(Some(2.0));
+    assert!(
+        weighted < unweighted - 0.005,
+        "{} >= {}",
+        weighted,
+        unweighted
+    );
+
+    let mut config = test_settings(&[("ke", (0.1, 1.0))]).config;
+    config.subject_weights = Some(HashMap::from([("0".to_string(), 0.0)]));
+    assert!(config.validate(&test_settings(&[]).random).is_err());
+
+    // Weights of IDs which are not in the data are reported
+    let (engine, mut settings, scenarios) = short_fit_setup(1);
+    settings.config.subject_weights = Some(HashMap::from([("17".to_string(), 2.0)]));
+    let (tx, _rx) = tokio::sync::mpsc::unbounded_channel();
+    let result = algorithms::initialize_algorithm(engine, settings, scenarios, tx)
+        .unwrap()
+        .fit();
+    assert!(result
+        .warnings
+        .iter()
+        .any(|warning| warning.message.contains("\"17\"")));
+}
+
+#[test]
+fn weighted_d_function_matches_duplicated_subjects() {
+    use crate::routines::optimization::d_optimizer::SppOptimizer;
+    use argmin::core::CostFunction;
+    use sigma::{ErrorPoly, ErrorType};
+
+    // Doubling the weight of a subject is equivalent to duplicating it
+    let (engine, _, scenarios) = short_fit_setup(1);
+    let sigma = ErrorPoly::new((0.0, 0.5, 0.0, 0.0), 0.5, &ErrorType::Add);
+    let pyl = ndarray::array![0.2, 0.05, 0.1];
+    let weights = ndarray::array![2.0, 1.0, 1.0];
+    let duplicated_scenarios = vec![
+        scenarios[0].clone(),
+        scenarios[0].clone(),
+        scenarios[1].clone(),
+        scenarios[2].clone(),
+    ];
+    let duplicated_pyl = ndarray::array![0.2, 0.2, 0.05, 0.1];
+    let ones = ndarray::Array1::ones(4);
+    let weighted = SppOptimizer::new(&engine, &scenarios, &sigma, &pyl, &weights);
+    let duplicated = SppOptimizer::new(
+        &engine,
+        &duplicated_scenarios,
+        &sigma,
+        &duplicated_pyl,
+        &ones,
+    );
+    for spp in [ndarray::array![0.1, 10.0], ndarray::array![0.3, 25.0]] {
+        let a = weighted.cost(&spp).unwrap();
+        let b = duplicated.cost(&spp).unwrap();
+        assert!((a - b).abs() < 1e-9 * a.abs().max(1.0), "{} != {}", a, b);
+    }
+}
+
+#[test]
+fn support_point_predictions_cover_every_point_and_observation() {
+    let (engine, mut settings, mut scenarios) = short_fit_setup(3);
+    // The last observation of each subject is of output equation 2, which is labelled and scaled
+    for scenario in &mut scenarios {
+        for event in scenario.blocks.iter_mut().flat_map(|b| b.events.iter_mut()) {
+            if event.evid == 0 && event.time == 8.0 {
+                event.outeq = Some(2);
+            }
+        }
+    }
+    let output = |outeq, name: &str, scale| settings::OutputEquation {
+        outeq,
+        name: Some(name.to_string()),
+        unit: None,
+        scale,
+        volume: None,
+        compartment: 0,
+    };
+    settings.outputs = vec![output(1, "parent", 1.0), output(2, "metabolite", 10.0)];
+    let (tx, _rx) = tokio::sync::mpsc::unbounded_channel();
+    let result = algorithms::initialize_algorithm(engine.clone(), settings, scenarios, tx)
+        .unwrap()
+        .fit();
+    let path = std::env::temp_dir().join(format!("npcore_spp_pred_{}.csv", std::process::id()));
+    result
+        .write_support_point_predictions(&engine, path.to_str().unwrap())
+        .unwrap();
+    let mut reader = csv::Reader::from_path(&path).unwrap();
+    assert_eq!(
+        reader.headers().unwrap(),
+        vec!["id", "point", "weight", "time", "outeq", "pred", "name", "unit"]
+    );
+    let rows: Vec<csv::StringRecord> = reader.records().map(Result::unwrap).collect();
+    std::fs::remove_file(&path).unwrap();
+
+    let n_obs = result.scenarios[0].obs.len();
+    assert_eq!(
+        rows.len(),
+        result.scenarios.len() * result.theta.nrows() * n_obs
+    );
+    // The first rows are the predictions of the first support point for the first subject, with its weight
+    let spp = result.theta.row(0).to_vec();
+    let pred = engine.pred(result.scenarios[0].clone(), spp);
+    for ((row, (time, pred)), (outeq, name, scale)) in rows
+        .iter()
+        .zip(result.scenarios[0].obs_times.iter().zip(&pred))
+        .zip([
+            ("1", "parent", 1.0),
+            ("1", "parent", 1.0),
+            ("2", "metabolite", 10.0),
+        ])
+    {
+        assert_eq!(&row[0], result.scenarios[0].id.as_str());
+        assert_eq!(&row[1], "0");
+        assert_eq!(row[2].parse::<f64>().unwrap(), result.w[0]);
+        assert_eq!(row[3].parse::<f64>().unwrap(), *time);
+        assert_eq!((&row[4], &row[6]), (outeq, name));
+        assert_eq!(row[5].parse::<f64>().unwrap(), scale * pred);
+    }
+}
+
+#[test]
+fn large_initial_grids_complete_in_batches() {
+    let (engine, mut settings, scenarios) = short_fit_setup(1);
+    settings.config.init_points = 70_000;
+    settings.config.subject_batch_size = Some(1);
+
+    // The Sobol sequence of a seed has 2^16 points, after which the grid continues with the next seed
+    let grid = sobol::generate(70_000, &settings.random.ranges(), 347);
+    assert_eq!(grid.nrows(), 70_000);
+    assert_ne!(grid.row(0), grid.row(1 << 16));
+
+    // Three subjects with three observations each, and two parameters
+    let vector = std::mem::size_of::<ndarray::Array1<f64>>();
+    let per_point = |batch: usize| 8 * (2 + 3 * 3) + batch * (3 * 8 + vector);
+    assert_eq!(
+        prob::projected_memory(&scenarios, 100_000, 2, None),
+        100_000 * per_point(3)
+    );
+    assert_eq!(
+        prob::projected_memory(&scenarios, 100_000, 2, Some(1)),
+        100_000 * per_point(1)
+    );
+    let limit = 100_000 * per_point(2);
+    assert_eq!(
+        prob::batch_size_within(&scenarios, 100_000, 2, limit),
+        Some(2)
+    );
+    assert_eq!(prob::batch_size_within(&scenarios, 100_000, 2, 1), None);
+
+    let (tx, _rx) = tokio::sync::mpsc::unbounded_channel();
+    let result = algorithms::initialize_algorithm(engine, settings, scenarios, tx)
+        .unwrap()
+        .fit();
+    assert_eq!(result.cycles, 1);
+    assert!(result.objf.is_finite());
+    assert!((result.w.sum() - 1.0).abs() < 1e-9);
+}
+
+#[test]
+fn output_level_selects_output_files() {
+    use output::{OutputFile, OutputLevel};
+    let (mut result, _) = short_fit(2);
+    let default = result.output_files();
+    let mut files = |level: OutputLevel| {
+        result.settings.config.output_level = level;
+        result.output_files()
+    };
+
+    // Only the cycle log, which is written during the run
+    assert!(files(OutputLevel::Minimal).is_empty());
+    let standard = files(OutputLevel::Standard);
+    assert!(standard.contains(&OutputFile::Theta) && standard.contains(&OutputFile::Posterior));
+    assert!(!standard.contains(&OutputFile::Pred) && !standard.contains(&OutputFile::Diagnostics));
+    let full = files(OutputLevel::Full);
+    for file in [
+        OutputFile::Theta,
+        OutputFile::Posterior,
+        OutputFile::IndividualEstimates,
+        OutputFile::Obs,
+        OutputFile::Pred,
+        OutputFile::Diagnostics,
+        OutputFile::EffectiveSupport,
+        OutputFile::Shrinkage,
+        OutputFile::Correlation,
+        OutputFile::ObjfContributions,
+        OutputFile::Meta,
+        OutputFile::PopulationModel,
+        OutputFile::Manifest,
+    ] {
+        assert!(full.contains(&file), "{} is not written", file);
+    }
+    assert!(standard.iter().all(|file| full.contains(file)));
+    // The default is to write everything
+    assert_eq!(default, full);
+
+    // The level is parsed case-insensitively from the settings
+    let config = std::fs::read_to_string("src/tests/config.toml").unwrap();
+    let parse = |level: &str| {
+        let config = config.replace(
+            "[config]\n",
+            &format!("[config]\noutput_level = \"{}\"\n", level),
+        );
+        toml::from_str::<settings::Settings>(&config).map(|settings| settings.config.output_level)
+    };
+    assert_eq!(parse("Standard").unwrap(), OutputLevel::Standard);
+    assert!(parse("verbose").is_err());
+}
+
+#[test]
+fn output_files_are_written_to_disk() {
+    use output::OutputLevel;
+    let (engine, settings, scenarios) = short_fit_setup(2);
+    let (tx, _rx) = tokio::sync::mpsc::unbounded_channel();
+    let mut result = algorithms::initialize_algorithm(engine.clone(), settings, scenarios, tx)
+        .unwrap()
+        .fit();
+    let written = |result: &output::NPResult, name: &str| {
+        let dir =
+            std::env::temp_dir().join(format!("npcore_outputs_{}_{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        result.write_outputs_to(&dir, &engine, 0.0, 0.0);
+        let mut files: Vec<String> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+            .collect();
+        files.sort();
+        std::fs::remove_dir_all(&dir).unwrap();
+        files
+    };
+
+    // The minimal level writes nothing but the cycle log, which is written during the run
+    result.settings.config.output_level = OutputLevel::Minimal;
+    assert!(written(&result, "minimal").is_empty());
+
+    // The full level writes every file of the list, and nothing else
+    result.settings.config.output_level = OutputLevel::Full;
+    let mut expected: Vec<String> = result
+        .output_files()
+        .iter()
+        .map(|file| file.name().to_string())
+        .collect();
+    expected.sort();
+    assert_eq!(written(&result, "full"), expected);
+    assert!(expected.contains(&"effective_support.csv".to_string()));
+    assert!(expected.contains(&"shrinkage.csv".to_string()));
+}
+
+#[test]
+fn result_returns_the_fitted_data() {
+    let (engine, settings, scenarios) = short_fit_setup(2);
+    let (tx, _rx) = tokio::sync::mpsc::unbounded_channel();
+    let result = algorithms::initialize_algorithm(engine, settings.clone(), scenarios.clone(), tx)
+        .unwrap()
+        .fit();
+    assert_eq!(result.scenarios().len(), scenarios.len());
+    assert_eq!(result.scenarios().len(), result.psi.nrows());
+    for (fitted, input) in result.scenarios().iter().zip(&scenarios) {
+        assert_eq!(fitted.id, input.id);
+        assert_eq!(fitted.obs, input.obs);
+        assert_eq!(fitted.obs_times, input.obs_times);
+    }
+    assert_eq!(result.settings().config.cycles, settings.config.cycles);
+    assert_eq!(result.settings().random.names(), settings.random.names());
+}
+
+#[test]
+fn transforms_apply_to_observations_and_predictions() {
+    use sigma::{ErrorPoly, ErrorType, Sigma, Transform};
+    let mut scenario = test_scenario("1", &[(0.0, 100.0)], &[1.0, 2.0, 4.0]);
+    scenario.obs = vec![4.0, 2.5, 1.0];
+    let ypred = ndarray::Array2::from_elem((1, 1), ndarray::array![3.5, 2.0, 1.5]);
+    let yobs = ndarray::Array1::from(scenario.obs.clone());
+
+    let transforms = [
+        ("identity", Transform::Identity, yobs.clone()),
+        ("log", Transform::Log, yobs.mapv(f64::ln)),
+        ("sqrt", Transform::Sqrt, yobs.mapv(f64::sqrt)),
+        (
+            "BoxCox(0.5)",
+            Transform::BoxCox(0.5),
+            yobs.mapv(|y| (y.sqrt() - 1.0) / 0.5),
+        ),
+        ("boxcox(0)", Transform::BoxCox(0.0), yobs.mapv(f64::ln)),
+    ];
+    for (name, transform, transformed) in transforms {
+        assert_eq!(Transform::from_name(name), Ok(transform));
+        let sigma =
+            ErrorPoly::new((0.1, 0.1, 0.0, 0.0), 0.5, &ErrorType::Add).with_transform(transform);
+        let pred = ypred[(0, 0)].mapv(|x| transform.apply(x).unwrap());
+        for (value, expected) in pred.iter().zip(&ypred[(0, 0)]) {
+            let expected = match transform {
+                Transform::Identity => *expected,
+                Transform::Log | Transform::BoxCox(0.0) => expected.ln(),
+                Transform::Sqrt => expected.sqrt(),
+                Transform::BoxCox(_) => (expected.sqrt() - 1.0) / 0.5,
+            };
+            assert!((value - expected).abs() < 1e-12);
+        }
+        let expected = prob::normal_likelihood(&pred, &transformed, &sigma.sigma(&transformed));
+        let psi = prob::calculate_psi(&ypred, &[scenario.clone()], &sigma);
+        assert!(
+            (psi[(0, 0)] - expected).abs() < 1e-12 * expected,
+            "{}: {} != {}",
+            name,
+            psi[(0, 0)],
+            expected
+        );
+    }
+
+    // Values outside the domain are errors, or a likelihood of zero for predictions
+    assert!(Transform::Sqrt.apply(-1.0).is_err());
+    assert!(Transform::Log.apply(0.0).is_err());
+    assert_eq!(Transform::Log.apply_bound(0.0), f64::NEG_INFINITY);
+    assert!(Transform::from_name("boxcox(x)").is_err());
+    assert!(Transform::from_name("exp").is_err());
+    let sigma =
+        ErrorPoly::new((0.1, 0.0, 0.0, 0.0), 0.5, &ErrorType::Add).with_transform(Transform::Sqrt);
+    let negative = ndarray::Array2::from_elem((1, 1), ndarray::array![3.5, -2.0, 1.5]);
+    assert_eq!(
+        prob::calculate_psi(&negative, &[scenario.clone()], &sigma)[(0, 0)],
+        0.0
+    );
+    let mut error = test_settings(&[("ke", (0.1, 1.0))]).error;
+    error.transform = Some("log".to_string());
+    assert!(error.validate().is_ok());
+    assert!(error.validate_observations(&[1.0, 2.0]).is_ok());
+    let err = error.validate_observations(&[1.0, 0.0]).unwrap_err();
+    assert!(
+        err.contains("outside the domain of the log transform"),
+        "{}",
+        err
+    );
+    error.transform = Some("cube".to_string());
+    assert!(error.validate().is_err());
+
+    // A missing observation is skipped rather than transformed, so it does not fail the support point
+    let sigma =
+        ErrorPoly::new((0.1, 0.1, 0.0, 0.0), 0.5, &ErrorType::Add).with_transform(Transform::Log);
+    let mut missing = scenario.clone();
+    missing.obs[1] = -99.0;
+    let observed = ndarray::array![4.0f64, 1.0].mapv(f64::ln);
+    let pred = ndarray::array![3.5f64, 1.5].mapv(f64::ln);
+    let expected = prob::normal_likelihood(&pred, &observed, &sigma.sigma(&observed));
+    let psi = prob::calculate_psi(&ypred, &[missing], &sigma);
+    assert!((psi[(0, 0)] - expected).abs() < 1e-12 * expected);
+
+    // Simulated residuals are drawn on the transformed scale
+    use rand::{rngs::StdRng, SeedableRng};
+    let sigma =
+        ErrorPoly::new((0.2, 0.0, 0.0, 0.0), 0.0, &ErrorType::Add).with_transform(Transform::Log);
+    let mut rng = StdRng::seed_from_u64(1438);
+    let n = 20_000;
+    let residuals: Vec<f64> = (0..n)
+        .map(|_| {
+            let obs = 50.0 + sigma::sample_residual(50.0, &sigma, &mut rng);
+            assert!(obs > 0.0);
+            obs.ln() - 50f64.ln()
+        })
+        .collect();
+    let mean = residuals.iter().sum::<f64>() / n as f64;
+    let sd = (residuals.iter().map(|r| (r - mean).powi(2)).sum::<f64>() / (n - 1) as f64).sqrt();
+    assert!(mean.abs() < 0.01, "{}", mean);
+    assert!((sd / 0.2 - 1.0).abs() < 0.03, "{}", sd);
+    assert!((Transform::Log.invert(Transform::Log.apply(3.0).unwrap()) - 3.0).abs() < 1e-12);
+    assert!(
+        (Transform::BoxCox(0.5).invert(Transform::BoxCox(0.5).apply(3.0).unwrap()) - 3.0).abs()
+            < 1e-12
+    );
+    assert_eq!(Transform::Sqrt.invert(-1.0), 0.0);
+}
+
+#[test]
+fn median_of_a_single_or_dominant_support_point() {
+    use ndarray::array;
+    use output::{population_mean_median, posterior_mean_median};
+
+    // A single support point is the median
+    let theta = array![[2.0, 5.0]];
+    let (_, median) = population_mean_median(&theta, &array![1.0]);
+    assert_eq!(median, array![2.0, 5.0]);
+    let (_, median) = posterior_mean_median(&theta, &array![[1.0], [0.5]], &array![1.0]);
+    assert_eq!(median, array![[2.0, 5.0], [2.0, 5.0]]);
+
+    // As is the lowest support point if it holds more than half of the weight
+    let theta = array![[1.0], [2.0], [3.0]];
+    let (_, median) = population_mean_median(&theta, &array![0.7, 0.2, 0.1]);
+    assert_eq!(median[0], 1.0);
+
+    // Otherwise the median is interpolated between the support points around half of the weight
+    let (_, median) = population_mean_median(&theta, &array![0.2, 0.4, 0.4]);
+    assert!((median[0] - 1.75).abs() < 1e-12);
+    let w = array![1.0 / 3.0, 1.0 / 3.0, 1.0 / 3.0];
+    let (_, median) = posterior_mean_median(&theta, &array![[0.2, 0.4, 0.4]], &w);
+    assert!((median[[0, 0]] - 1.75).abs() < 1e-12);
+}
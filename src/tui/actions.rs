@@ -10,12 +10,13 @@ pub enum Action {
     Quit,
     Stop,
     Next,
+    Pause,
 }
 
 impl Action {
     /// All available actions
     pub fn iterator() -> Iter<'static, Action> {
-        static ACTIONS: [Action; 3] = [Action::Quit, Action::Stop, Action::Next];
+        static ACTIONS: [Action; 4] = [Action::Quit, Action::Stop, Action::Next, Action::Pause];
         ACTIONS.iter()
     }
 
@@ -25,6 +26,7 @@ impl Action {
             Action::Quit => &[Key::Char('q')],
             Action::Stop => &[Key::Ctrl('d')],
             Action::Next => &[Key::Char('n')],
+            Action::Pause => &[Key::Char('p')],
         }
     }
 }
@@ -36,6 +38,7 @@ impl Display for Action {
             Action::Next => "Next",
             Action::Quit => "Quit",
             Action::Stop => "Stop",
+            Action::Pause => "Pause/Resume",
         };
         write!(f, "{}", str)
     }
@@ -15,7 +15,7 @@ use ratatui::{
 
 use super::App;
 
-use crate::prelude::settings::Settings;
+use crate::prelude::{output::NPCycle, settings::Settings};
 
 pub fn draw_title<'a>() -> Paragraph<'a> {
     Paragraph::new("NPcore Execution")
@@ -29,7 +29,7 @@ pub fn draw_title<'a>() -> Paragraph<'a> {
         )
 }
 
-pub fn draw_status<'a>(app: &App, elapsed_time: Duration) -> Table<'a> {
+pub fn draw_status<'a>(app: &App, elapsed_time: Duration, settings: &Settings) -> Table<'a> {
     // Define (formatted) texts
     let cycle_text = format!("{}", app.state.cycle);
     let objf_text = format!("{:.5}", app.state.objf);
@@ -37,19 +37,45 @@ pub fn draw_status<'a>(app: &App, elapsed_time: Duration) -> Table<'a> {
     let gamma_text = format!("{:.5}", app.state.gamlam);
     let spp_text = format!("{}", app.state.nspp);
     let time_text = format_time(elapsed_time);
+    let eta_text = format_eta(
+        app.state.cycle,
+        settings.config.cycles,
+        elapsed_time,
+        app.finished,
+    );
     let conv_text = "Placeholder".to_string();
+    let state_text = if app.finished {
+        "Finished"
+    } else if app.paused() {
+        "Paused"
+    } else {
+        "Running"
+    }
+    .to_string();
 
     // Define the table data
-    let data = vec![
+    let mut data = vec![
         ("Current cycle", cycle_text),
         ("Objective function", objf_text),
         ("Δ Objective function", delta_objf_text),
         ("Gamma/Lambda", gamma_text),
         ("Support points", spp_text),
         ("Elapsed time", time_text),
+        ("Estimated time left", eta_text),
         ("Convergence", conv_text),
+        ("State", state_text),
         // Add more rows as needed
     ];
+    if settings.config.error_magnitude {
+        let magnitude_text = match settings.error.magnitude(
+            app.state.poly.unwrap_or(settings.error.poly),
+            app.state.gamlam,
+        ) {
+            Ok(magnitude) => magnitude.to_string(),
+            Err(_) => "Unknown".to_string(),
+        };
+        data.insert(4, ("Error magnitude", magnitude_text));
+    }
 
     // Populate the table rows
     let rows: Vec<Row> = data
@@ -80,7 +106,7 @@ pub fn draw_options<'a>(settings: &Settings) -> Table<'a> {
 
     let cycles = settings.config.cycles.to_string();
     let engine = settings.config.engine.to_string();
-    let conv_crit = "Placeholder".to_string();
+    let conv_crit = format!("{} stable cycles", settings.config.convergence_cycles);
     let indpts = settings.config.init_points.to_string();
     let error = settings.error.class.to_string();
     let cache = match settings.config.cache {
@@ -88,6 +114,7 @@ pub fn draw_options<'a>(settings: &Settings) -> Table<'a> {
         false => "Disabled".to_string(),
     };
     let seed = settings.config.seed.to_string();
+    let threads = rayon::current_num_threads().to_string();
 
     let data = vec![
         ("Maximum cycles", cycles),
@@ -97,6 +124,7 @@ pub fn draw_options<'a>(settings: &Settings) -> Table<'a> {
         ("Error model", error),
         ("Cache", cache),
         ("Random seed", seed),
+        ("Threads", threads),
         // Add more rows as needed
     ];
 
@@ -241,6 +269,61 @@ pub fn draw_logs<'a>(log_history: &'a Vec<String>, height: u16) -> Paragraph<'a>
         .wrap(Wrap { trim: true })
 }
 
+/// Lists the `n` support points with the highest weights in the current cycle
+pub fn draw_support_points<'a>(state: &NPCycle, par_names: &[String], n: usize) -> Table<'a> {
+    let header_style = Style::default().add_modifier(Modifier::BOLD);
+    let header = Row::new(
+        par_names
+            .iter()
+            .map(|name| Cell::from(Span::styled(name.clone(), header_style)))
+            .chain(std::iter::once(Cell::from(Span::styled(
+                "Weight",
+                header_style,
+            ))))
+            .collect::<Vec<Cell>>(),
+    );
+
+    let rows: Vec<Row> = top_support_points(state, n)
+        .into_iter()
+        .map(|(point, weight)| {
+            Row::new(
+                point
+                    .iter()
+                    .map(|value| Cell::from(format!("{:.5}", value)))
+                    .chain(std::iter::once(Cell::from(format!("{:.5}", weight))))
+                    .collect::<Vec<Cell>>(),
+            )
+        })
+        .collect();
+
+    let widths = vec![Constraint::Ratio(1, par_names.len() as u32 + 1); par_names.len() + 1];
+    Table::default()
+        .header(header)
+        .rows(rows)
+        .block(
+            Block::default()
+                .borders(Borders::ALL)
+                .border_type(BorderType::Plain)
+                .title(format!(" Top {} support points ", n)),
+        )
+        .widths(&widths)
+        .column_spacing(1)
+}
+
+/// Returns the `n` support points with the highest weights, as `(coordinates, weight)` in descending order of weight
+pub(crate) fn top_support_points(state: &NPCycle, n: usize) -> Vec<(Vec<f64>, f64)> {
+    let mut points: Vec<(Vec<f64>, f64)> = state
+        .theta
+        .rows()
+        .into_iter()
+        .zip(state.weights.iter())
+        .map(|(point, &weight)| (point.to_vec(), weight))
+        .collect();
+    points.sort_by(|a, b| b.1.total_cmp(&a.1));
+    points.truncate(n);
+    points
+}
+
 pub fn draw_tabs<'a>(app: &App) -> Tabs<'a> {
     let titles = app.tab_titles.clone();
     let index = app.tab_index.clone();
@@ -254,6 +337,26 @@ pub fn draw_tabs<'a>(app: &App) -> Tabs<'a> {
     tabs
 }
 
+/// Estimates the remaining run time from the average time per cycle so far
+///
+/// Assumes the run continues until `max_cycles`, so the estimate is an upper bound if the run converges earlier.
+pub(crate) fn format_eta(
+    cycle: usize,
+    max_cycles: usize,
+    elapsed_time: Duration,
+    finished: bool,
+) -> String {
+    if finished {
+        return format_time(Duration::ZERO);
+    }
+    if cycle == 0 {
+        return "Estimating...".to_string();
+    }
+    let remaining_cycles = max_cycles.saturating_sub(cycle) as u32;
+    let time_per_cycle = elapsed_time / cycle as u32;
+    format_time(time_per_cycle * remaining_cycles)
+}
+
 fn format_time(elapsed_time: std::time::Duration) -> String {
     let elapsed_seconds = elapsed_time.as_secs();
     let (elapsed, unit) = if elapsed_seconds < 60 {
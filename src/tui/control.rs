@@ -0,0 +1,65 @@
+use std::sync::{Arc, Condvar, Mutex};
+
+/// A control message sent from the TUI to the running algorithm
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Control {
+    Pause,
+    Resume,
+    /// Pauses a running fit, or resumes a paused one
+    Toggle,
+    /// Stops the fit, also if it is paused
+    Stop,
+}
+
+#[derive(Debug, Default)]
+struct ControlState {
+    paused: bool,
+    stopped: bool,
+}
+
+/// Shared handle used to pause, resume and stop a running fit
+///
+/// The TUI sends [Control] messages with [RunControl::send], and the algorithm calls
+/// [RunControl::wait_while_paused] once per cycle, which blocks the algorithm thread for as long as the run is paused.
+#[derive(Debug, Clone, Default)]
+pub struct RunControl {
+    inner: Arc<(Mutex<ControlState>, Condvar)>,
+}
+
+impl RunControl {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Applies a control message, and wakes up the algorithm if it is waiting
+    pub fn send(&self, control: Control) {
+        let (state, condvar) = &*self.inner;
+        let mut state = state.lock().unwrap();
+        match control {
+            Control::Pause => state.paused = true,
+            Control::Resume => state.paused = false,
+            Control::Toggle => state.paused = !state.paused,
+            Control::Stop => state.stopped = true,
+        }
+        condvar.notify_all();
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.inner.0.lock().unwrap().paused
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.inner.0.lock().unwrap().stopped
+    }
+
+    /// Blocks while the run is paused, and returns whether the run should stop
+    pub fn wait_while_paused(&self) -> bool {
+        let (state, condvar) = &*self.inner;
+        let state = condvar
+            .wait_while(state.lock().unwrap(), |state| {
+                state.paused && !state.stopped
+            })
+            .unwrap();
+        state.stopped
+    }
+}
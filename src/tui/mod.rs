@@ -1,12 +1,15 @@
 pub mod actions;
 pub mod components;
+pub mod control;
 pub mod inputs;
+pub mod replay;
 pub mod state;
 pub mod ui;
 
 use crate::prelude::output::NPCycle;
 
 use self::actions::{Action, Actions};
+use self::control::{Control, RunControl};
 use self::inputs::key::Key;
 use std::fs::File;
 
@@ -26,12 +29,15 @@ pub struct App {
     tab_index: usize,
     /// Tab titles
     tab_titles: Vec<&'static str>,
+    /// Whether the run has finished
+    finished: bool,
+    /// Control of the running algorithm
+    control: RunControl,
 }
 
 impl App {
-    #[allow(clippy::new_without_default)]
-    pub fn new() -> Self {
-        let actions = vec![Action::Quit, Action::Stop, Action::Next].into();
+    pub fn new(control: RunControl) -> Self {
+        let actions = vec![Action::Quit, Action::Stop, Action::Next, Action::Pause].into();
         let state = NPCycle::new();
         let tab_index = 0;
         let tab_titles = vec!["Logs", "Plot", "Parameters"];
@@ -41,6 +47,8 @@ impl App {
             state,
             tab_index,
             tab_titles,
+            finished: false,
+            control,
         }
     }
 
@@ -55,6 +63,17 @@ impl App {
                     tracing::info!("Stop signal received, program will stop after current cycle");
                     let stopfile = "stop";
                     File::create(stopfile).unwrap();
+                    // Wake up the algorithm if it is paused
+                    self.control.send(Control::Stop);
+                    AppReturn::Continue
+                }
+                Action::Pause => {
+                    self.control.send(Control::Toggle);
+                    if self.control.is_paused() {
+                        tracing::info!("Run paused after the current cycle, press p to resume");
+                    } else {
+                        tracing::info!("Run resumed");
+                    }
                     AppReturn::Continue
                 }
                 Action::Next => {
@@ -80,4 +99,7 @@ impl App {
     pub fn state(&self) -> &NPCycle {
         &self.state
     }
+    pub fn paused(&self) -> bool {
+        self.control.is_paused()
+    }
 }
@@ -0,0 +1,91 @@
+//! Recording and replaying of the [Comm] messages of a run
+//!
+//! The messages are written as JSON lines, one message per line, in the order they were sent.
+//! A recording can be replayed into [start_ui] without running the algorithm, e.g. to develop the TUI or to review the progress of a run.
+
+use eyre::Result;
+use std::{
+    fs::File,
+    io::{BufRead, BufReader, BufWriter, Write},
+    thread::spawn,
+    time::Duration,
+};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
+
+use super::{
+    control::RunControl,
+    ui::{start_ui, Comm},
+};
+use crate::prelude::settings::Settings;
+
+/// Records the messages of `rx` to `path`, and forwards them to the returned receiver
+///
+/// The messages are recorded by a separate thread, which stops when every sender of `rx` is dropped.
+/// Each message is written before it is forwarded, so the recording is complete once the returned receiver is closed.
+/// Messages are still forwarded if writing to the file fails, in which case the error is logged once.
+pub fn record_messages(
+    mut rx: UnboundedReceiver<Comm>,
+    path: &str,
+) -> Result<UnboundedReceiver<Comm>> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    let (tx, forwarded) = unbounded_channel();
+    spawn(move || {
+        let mut failed = false;
+        while let Some(comm) = rx.blocking_recv() {
+            if !failed {
+                let written = serde_json::to_writer(&mut writer, &comm)
+                    .map_err(std::io::Error::from)
+                    .and_then(|_| writer.write_all(b"\n"))
+                    .and_then(|_| writer.flush());
+                if let Err(e) = written {
+                    tracing::error!("Error while recording messages: {}", e);
+                    failed = true;
+                }
+            }
+            // Messages are still recorded after the receiver is dropped, e.g. when the TUI is closed
+            let _ = tx.send(comm);
+        }
+    });
+    Ok(forwarded)
+}
+
+/// Reads the messages recorded by [record_messages] from `path`, in the order they were sent
+pub fn read_messages(path: &str) -> Result<Vec<Comm>> {
+    let reader = BufReader::new(File::open(path)?);
+    let mut messages = Vec::new();
+    for (index, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let comm = serde_json::from_str(&line)
+            .map_err(|e| eyre::eyre!("Invalid message on line {} of {}: {}", index + 1, path, e))?;
+        messages.push(comm);
+    }
+    Ok(messages)
+}
+
+/// Replays the messages recorded in `path` into the returned receiver, waiting `delay` before each message
+pub fn replay_messages(path: &str, delay: Duration) -> Result<UnboundedReceiver<Comm>> {
+    let messages = read_messages(path)?;
+    let (tx, rx) = unbounded_channel();
+    spawn(move || {
+        for comm in messages {
+            if !delay.is_zero() {
+                std::thread::sleep(delay);
+            }
+            if tx.send(comm).is_err() {
+                break;
+            }
+        }
+    });
+    Ok(rx)
+}
+
+/// Starts the TUI on the messages recorded in `path`, without running the algorithm
+///
+/// `settings` are the settings shown by the TUI, usually those of the recorded run. Controls of the run, such as pausing, have no effect.
+pub fn replay_ui(path: &str, settings: Settings, delay: Duration) -> Result<()> {
+    let rx = replay_messages(path, delay)?;
+    start_ui(rx, settings, RunControl::new())
+}
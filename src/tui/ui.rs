@@ -7,6 +7,7 @@ use ratatui::{
     layout::{Constraint, Direction, Layout},
     Frame, Terminal,
 };
+use serde_derive::{Deserialize, Serialize};
 use std::{
     io::stdout,
     process::exit,
@@ -20,6 +21,8 @@ use super::{
     App, AppReturn,
 };
 
+/// Messages sent from a run to the TUI, which can be recorded and replayed, see [replay](super::replay)
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum Comm {
     NPCycle(NPCycle),
     Message(String),
@@ -30,15 +33,20 @@ pub enum Comm {
 
 use crate::prelude::{output::NPCycle, settings::Settings};
 use crate::tui::components::*;
+use crate::tui::control::RunControl;
 
-pub fn start_ui(mut rx: UnboundedReceiver<Comm>, settings: Settings) -> Result<()> {
+pub fn start_ui(
+    mut rx: UnboundedReceiver<Comm>,
+    settings: Settings,
+    control: RunControl,
+) -> Result<()> {
     initialize_panic_handler();
     let mut stdout = stdout();
     execute!(stdout, crossterm::terminal::EnterAlternateScreen)?;
     crossterm::terminal::enable_raw_mode()?;
     let backend = CrosstermBackend::new(stdout);
     let mut terminal = Terminal::new(backend)?;
-    let mut app = App::new();
+    let mut app = App::new(control);
     let mut cycle_history = CycleHistory::new();
     let mut log_history: Vec<String> = Vec::new();
 
@@ -68,6 +76,7 @@ pub fn start_ui(mut rx: UnboundedReceiver<Comm>, settings: Settings) -> Result<(
                     exit(0);
                 }
                 Comm::StopUI => {
+                    app.finished = true;
                     break;
                 }
                 Comm::LogMessage(msg) => log_history.push(msg),
@@ -177,7 +186,7 @@ pub fn draw(
         .split(body_chunk);
 
     // First chunk
-    let status = draw_status(app, elapsed_time);
+    let status = draw_status(app, elapsed_time, settings);
     rect.render_widget(status, body_layout[0]);
 
     // Second chunk
@@ -229,9 +238,12 @@ pub fn draw(
             rect.render_widget(plot, tab_layout[1]);
         }
         2 => {
-            // TODO: Return this to show the parameter boundaries
-            let plot = draw_plot(&mut norm_data);
-            rect.render_widget(plot, tab_layout[1]);
+            let support_points = draw_support_points(
+                &app.state,
+                &settings.random.names(),
+                settings.config.tui_support_points,
+            );
+            rect.render_widget(support_points, tab_layout[1]);
         }
         _ => unreachable!(),
     };